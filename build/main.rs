@@ -0,0 +1,45 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+mod openapi;
+
+fn main() -> io::Result<()> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    embed_migrations(&out_dir)?;
+    openapi::generate(&out_dir.join("openapi.json"))
+}
+
+/// Embeds the migrations in `migrations`, with both `up.sql` and `down.sql`,
+/// so that the `migrate` command can revert them.
+fn embed_migrations(out_dir: &Path) -> io::Result<()> {
+    println!("cargo:rerun-if-changed=migrations");
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir("migrations")? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            println!("cargo:rerun-if-changed={}", entry.path().display());
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+
+    let mut file = fs::File::create(out_dir.join("migrations.rs"))?;
+    writeln!(
+        file,
+        "pub(crate) const MIGRATIONS: &[EmbeddedMigration] = &["
+    )?;
+    for name in &names {
+        // The same version as the one diesel records for the directory.
+        let version = name.split('_').next().unwrap_or_default().replace('-', "");
+        writeln!(
+            file,
+            "    EmbeddedMigration {{ version: {:?}, name: {:?}, up: include_str!(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/migrations/{}/up.sql\")), down: include_str!(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/migrations/{}/down.sql\")) }},",
+            version, name, name, name
+        )?;
+    }
+    writeln!(file, "];")?;
+    Ok(())
+}
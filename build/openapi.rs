@@ -0,0 +1,916 @@
+//! Generates `openapi.json`, the document of the API.
+//!
+//! The paths and the methods come from the routes in `src/server/route.rs`,
+//! and the parameters and the request bodies from the types their handlers
+//! extract with `Path`, `Query`, and `Json`. The prose, the responses, and the
+//! parameters of handlers that take untyped values come from
+//! `docs/openapi.yaml`. Every route must be described there, or in the doc
+//! comment of its handler, and every operation described there must be
+//! routed.
+
+use serde_json::{json, Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use syn::{
+    Attribute, Expr, Fields, FnArg, GenericArgument, Item, ItemEnum, ItemStruct, Lit, Meta,
+    NestedMeta, PathArguments, Stmt, Type,
+};
+
+/// The prefix of the paths in the document.
+const API_PREFIX: &str = "/api/v1";
+
+/// The methods an operation can be described under.
+const METHODS: [&str; 5] = ["delete", "get", "patch", "post", "put"];
+
+/// How deep the schemas of nested types are generated.
+const MAX_SCHEMA_DEPTH: usize = 4;
+
+/// A handler registered for a method on a path.
+struct Route {
+    path: String,
+    method: String,
+    handler: String,
+}
+
+/// What a handler extracts from a request.
+#[derive(Default)]
+struct Handler {
+    doc: Option<String>,
+    path: Vec<Type>,
+    query: Vec<Type>,
+    body: Option<Type>,
+}
+
+/// The structs and the enums in the source, by name.
+#[derive(Default)]
+struct Types {
+    structs: HashMap<String, ItemStruct>,
+    enums: HashMap<String, ItemEnum>,
+}
+
+/// A field of a struct, as serde deserializes it.
+struct Field {
+    name: String,
+    ty: Type,
+    required: bool,
+    doc: Option<String>,
+}
+
+/// Writes the document to `out`.
+pub(crate) fn generate(out: &Path) -> io::Result<()> {
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=docs/openapi.yaml");
+
+    let routes = load_routes(Path::new("src/server/route.rs"))?;
+    let mut handlers = HashMap::new();
+    let mut types = Types::default();
+    load_items(Path::new("src"), &mut handlers, &mut types)?;
+    let yaml: serde_yaml::Value =
+        serde_yaml::from_str(&fs::read_to_string("docs/openapi.yaml")?).map_err(invalid_data)?;
+    let mut document = serde_json::to_value(yaml).map_err(invalid_data)?;
+
+    let mut described = HashMap::new();
+    if let Some(paths) = document.get("paths").and_then(Value::as_object) {
+        for (path, operations) in paths {
+            if let Some(operations) = operations.as_object() {
+                for (method, operation) in operations {
+                    if METHODS.contains(&method.as_str()) {
+                        described.insert((template_of(path), method.clone()), operation.clone());
+                    }
+                }
+            }
+        }
+    }
+    let definitions = document
+        .get("definitions")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut paths = Map::new();
+    let mut seen = HashSet::new();
+    let mut errors = Vec::new();
+    for route in &routes {
+        let path = format!("{}{}", API_PREFIX, route.path);
+        let key = (template_of(&path), route.method.clone());
+        let handler = if let Some(handler) = handlers.get(&route.handler) {
+            handler
+        } else {
+            errors.push(format!("the handler {} is not found", route.handler));
+            continue;
+        };
+        let operation = match (described.get(&key), &handler.doc) {
+            (Some(operation), doc) => {
+                let mut operation = operation.clone();
+                if let (Some(doc), Some(operation)) = (doc, operation.as_object_mut()) {
+                    if !operation.contains_key("summary") {
+                        operation.insert("summary".to_string(), json!(summary_of(doc)));
+                    }
+                }
+                operation
+            }
+            (None, Some(doc)) => operation_of(&route.path, doc),
+            (None, None) => {
+                errors.push(format!(
+                    "{} {} is not described in docs/openapi.yaml or in the doc comment of {}",
+                    route.method.to_uppercase(),
+                    path,
+                    route.handler
+                ));
+                continue;
+            }
+        };
+        let operation = with_parameters(operation, route, handler, &types, &definitions);
+        if let Some(operations) = paths
+            .entry(path)
+            .or_insert_with(|| json!({}))
+            .as_object_mut()
+        {
+            operations.insert(route.method.clone(), operation);
+        }
+        seen.insert(key);
+    }
+    let mut stale = described
+        .keys()
+        .filter(|key| !seen.contains(key))
+        .map(|(path, method)| {
+            format!(
+                "{} {} is described in docs/openapi.yaml, but not routed",
+                method.to_uppercase(),
+                path
+            )
+        })
+        .collect::<Vec<_>>();
+    stale.sort();
+    errors.extend(stale);
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "the API does not match docs/openapi.yaml",
+        ));
+    }
+
+    add_tags(&mut document, &paths);
+    if let Some(document) = document.as_object_mut() {
+        document.insert("paths".to_string(), Value::Object(paths));
+    }
+    fs::write(
+        out,
+        serde_json::to_string_pretty(&document).map_err(invalid_data)?,
+    )
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn parse_file(path: &Path) -> io::Result<syn::File> {
+    syn::parse_file(&fs::read_to_string(path)?).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Returns a path with the names of its parameters removed, which matches
+/// the same requests as the path.
+fn template_of(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if segment.starts_with('{') {
+                "{}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Reads the routes registered in `init_app`.
+fn load_routes(path: &Path) -> io::Result<Vec<Route>> {
+    let file = parse_file(path)?;
+    let mut routes = Vec::new();
+    for item in &file.items {
+        if let Item::Fn(f) = item {
+            if f.sig.ident == "init_app" {
+                for stmt in &f.block.stmts {
+                    match stmt {
+                        Stmt::Expr(expr) | Stmt::Semi(expr, _) => collect_routes(expr, &mut routes),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    Ok(routes)
+}
+
+/// Collects the routes of the resources in a chain of `service` calls.
+fn collect_routes(expr: &Expr, routes: &mut Vec<Route>) {
+    if let Expr::MethodCall(call) = expr {
+        collect_routes(&call.receiver, routes);
+        if call.method == "service" {
+            for arg in &call.args {
+                collect_resource_routes(arg, routes);
+            }
+        }
+    }
+}
+
+/// Collects the routes of `resource(path)`, followed by calls such as
+/// `.route(get().to(handler))`.
+fn collect_resource_routes(expr: &Expr, routes: &mut Vec<Route>) {
+    let mut methods = Vec::new();
+    let mut expr = expr;
+    loop {
+        match expr {
+            Expr::MethodCall(call) => {
+                if call.method == "route" {
+                    if let Some(method) = call.args.first().and_then(method_and_handler) {
+                        methods.push(method);
+                    }
+                }
+                expr = &call.receiver;
+            }
+            Expr::Call(call) if is_function(&call.func, "resource") => {
+                if let Some(Expr::Lit(lit)) = call.args.first() {
+                    if let Lit::Str(path) = &lit.lit {
+                        for (method, handler) in methods.into_iter().rev() {
+                            routes.push(Route {
+                                path: path.value(),
+                                method,
+                                handler,
+                            });
+                        }
+                    }
+                }
+                return;
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Returns the method and the handler of `get().to(handler)`.
+fn method_and_handler(expr: &Expr) -> Option<(String, String)> {
+    let call = match expr {
+        Expr::MethodCall(call) if call.method == "to" => call,
+        _ => return None,
+    };
+    let method = match &*call.receiver {
+        Expr::Call(method) => match &*method.func {
+            Expr::Path(path) => path.path.segments.last()?.ident.to_string(),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let handler = match call.args.first()? {
+        Expr::Path(path) => path.path.segments.last()?.ident.to_string(),
+        _ => return None,
+    };
+    if METHODS.contains(&method.as_str()) {
+        Some((method, handler))
+    } else {
+        None
+    }
+}
+
+fn is_function(expr: &Expr, name: &str) -> bool {
+    match expr {
+        Expr::Path(path) => path.path.segments.last().map_or(false, |s| s.ident == name),
+        _ => false,
+    }
+}
+
+/// Reads the async functions, the structs, and the enums in the Rust files
+/// under `dir`.
+fn load_items(
+    dir: &Path,
+    handlers: &mut HashMap<String, Handler>,
+    types: &mut Types,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            load_items(&path, handlers, types)?;
+        } else if path.extension().map_or(false, |ext| ext == "rs") {
+            add_items(&parse_file(&path)?.items, handlers, types);
+        }
+    }
+    Ok(())
+}
+
+fn add_items(items: &[Item], handlers: &mut HashMap<String, Handler>, types: &mut Types) {
+    for item in items {
+        match item {
+            Item::Fn(f) if f.sig.asyncness.is_some() => {
+                let mut handler = Handler {
+                    doc: doc_of(&f.attrs),
+                    ..Handler::default()
+                };
+                for input in &f.sig.inputs {
+                    let ty = match input {
+                        FnArg::Typed(arg) => &*arg.ty,
+                        FnArg::Receiver(_) => continue,
+                    };
+                    match generic_of(ty) {
+                        Some(("Path", inner)) => handler.path.push(inner.clone()),
+                        Some(("Query", inner)) => handler.query.push(inner.clone()),
+                        Some(("Json", inner)) => handler.body = Some(inner.clone()),
+                        _ => {}
+                    }
+                }
+                handlers.entry(f.sig.ident.to_string()).or_insert(handler);
+            }
+            Item::Struct(s) => {
+                types
+                    .structs
+                    .entry(s.ident.to_string())
+                    .or_insert_with(|| s.clone());
+            }
+            Item::Enum(e) => {
+                types
+                    .enums
+                    .entry(e.ident.to_string())
+                    .or_insert_with(|| e.clone());
+            }
+            Item::Mod(m) => {
+                if let Some((_, items)) = &m.content {
+                    add_items(items, handlers, types);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the name and the first type argument of `Name<T>`.
+fn generic_of(ty: &Type) -> Option<(&str, &Type)> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    let name = match segment.ident.to_string().as_str() {
+        "Path" => "Path",
+        "Query" => "Query",
+        "Json" => "Json",
+        "Option" => "Option",
+        "Vec" | "HashSet" | "BTreeSet" => "Vec",
+        "HashMap" | "BTreeMap" => "Map",
+        _ => return None,
+    };
+    let args = match &segment.arguments {
+        PathArguments::AngleBracketed(args) => &args.args,
+        _ => return None,
+    };
+    // The value type of a map.
+    let index = usize::from(name == "Map");
+    match args.iter().nth(index)? {
+        GenericArgument::Type(ty) => Some((name, ty)),
+        _ => None,
+    }
+}
+
+/// Returns the doc comment in `attrs`, with the lines of each paragraph
+/// joined.
+fn doc_of(attrs: &[Attribute]) -> Option<String> {
+    let lines = attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("doc"))
+        .filter_map(|attr| match attr.parse_meta() {
+            Ok(Meta::NameValue(meta)) => match meta.lit {
+                Lit::Str(line) => Some(line.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let doc = lines
+        .split(String::is_empty)
+        .filter(|paragraph| !paragraph.is_empty())
+        .map(|paragraph| paragraph.join(" "))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if doc.is_empty() {
+        None
+    } else {
+        Some(doc)
+    }
+}
+
+/// Returns the first sentence of a doc comment.
+fn summary_of(doc: &str) -> String {
+    let paragraph = doc.split("\n\n").next().unwrap_or_default();
+    let sentence = paragraph.split(". ").next().unwrap_or_default();
+    sentence.trim_end_matches('.').to_string()
+}
+
+/// Returns an operation described only by the doc comment of its handler,
+/// tagged with the first segment of its path.
+fn operation_of(path: &str, doc: &str) -> Value {
+    let tag = path
+        .trim_start_matches('/')
+        .split(&['/', '.'][..])
+        .next()
+        .unwrap_or_default();
+    json!({
+        "tags": [tag],
+        "summary": summary_of(doc),
+        "description": doc,
+        "responses": {
+            "200": {"description": "OK"}
+        }
+    })
+}
+
+/// Adds the tags of the operations missing in the list of tags.
+fn add_tags(document: &mut Value, paths: &Map<String, Value>) {
+    let mut tags = document
+        .get("tags")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    for operation in paths
+        .values()
+        .filter_map(Value::as_object)
+        .flat_map(Map::values)
+    {
+        for tag in operation
+            .get("tags")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+        {
+            if !tags.iter().any(|t| t.get("name") == Some(tag)) {
+                tags.push(json!({ "name": tag }));
+            }
+        }
+    }
+    if let Some(document) = document.as_object_mut() {
+        document.insert("tags".to_string(), Value::Array(tags));
+    }
+}
+
+/// Replaces the parameters of `operation` with the ones generated from the
+/// types its handler extracts. The descriptions of the parameters and their
+/// fields are kept. Parameters the handler takes untyped, as `Value` or as a
+/// raw payload, are kept as they are described.
+#[allow(clippy::too_many_lines)]
+fn with_parameters(
+    mut operation: Value,
+    route: &Route,
+    handler: &Handler,
+    types: &Types,
+    definitions: &Map<String, Value>,
+) -> Value {
+    let described = operation
+        .get("parameters")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    let described_in = |location: &str| {
+        described
+            .iter()
+            .filter(|p| p.get("in").and_then(Value::as_str) == Some(location))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let mut parameters = Vec::new();
+
+    let described_path = described_in("path");
+    let path_types = handler
+        .path
+        .iter()
+        .flat_map(|ty| match ty {
+            Type::Tuple(tuple) => tuple.elems.iter().cloned().collect(),
+            ty => vec![ty.clone()],
+        })
+        .collect::<Vec<_>>();
+    for (i, name) in route
+        .path
+        .split('/')
+        .filter_map(|s| s.strip_prefix('{').and_then(|s| s.strip_suffix('}')))
+        .enumerate()
+    {
+        let schema = path_types
+            .get(i)
+            .map_or_else(|| json!({"type": "string"}), |ty| schema_of(ty, types, 0));
+        let mut parameter = described_path
+            .iter()
+            .find(|p| p.get("name").and_then(Value::as_str) == Some(name))
+            .or_else(|| described_path.get(i))
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+        set_simple_type(&mut parameter, &schema);
+        insert(&mut parameter, "name", json!(name));
+        insert(&mut parameter, "in", json!("path"));
+        insert(&mut parameter, "required", json!(true));
+        parameters.push(parameter);
+    }
+
+    let described_query = described_in("query");
+    let query_fields = handler
+        .query
+        .iter()
+        .map(|ty| fields_of_type(ty, types))
+        .collect::<Option<Vec<_>>>();
+    match query_fields {
+        Some(fields) if !fields.is_empty() => {
+            for field in fields.into_iter().flatten() {
+                let mut parameter = described_query
+                    .iter()
+                    .find(|p| p.get("name").and_then(Value::as_str) == Some(field.name.as_str()))
+                    .cloned()
+                    .unwrap_or_else(|| json!({}));
+                set_simple_type(&mut parameter, &schema_of(&field.ty, types, 0));
+                insert(&mut parameter, "name", json!(field.name));
+                insert(&mut parameter, "in", json!("query"));
+                insert(&mut parameter, "required", json!(field.required));
+                if parameter.get("description").is_none() {
+                    if let Some(doc) = field.doc {
+                        insert(&mut parameter, "description", json!(doc));
+                    }
+                }
+                parameters.push(parameter);
+            }
+        }
+        _ => parameters.extend(described_query),
+    }
+
+    let described_body = described_in("body");
+    let body_schema = handler
+        .body
+        .as_ref()
+        .filter(|ty| is_typed(ty, types))
+        .map(|ty| schema_of(ty, types, 0));
+    match body_schema {
+        Some(mut schema) => {
+            let mut parameter = described_body
+                .into_iter()
+                .next()
+                .unwrap_or_else(|| json!({"name": "body"}));
+            if let Some(described) = parameter.get("schema") {
+                describe(&mut schema, described, definitions, 0);
+            }
+            insert(&mut parameter, "in", json!("body"));
+            insert(&mut parameter, "required", json!(true));
+            insert(&mut parameter, "schema", schema);
+            parameters.push(parameter);
+        }
+        None => parameters.extend(described_body),
+    }
+
+    parameters.extend(
+        described
+            .iter()
+            .filter(|p| {
+                !matches!(
+                    p.get("in").and_then(Value::as_str),
+                    Some("path") | Some("query") | Some("body")
+                )
+            })
+            .cloned(),
+    );
+    if let Some(operation) = operation.as_object_mut() {
+        if parameters.is_empty() {
+            operation.remove("parameters");
+        } else {
+            operation.insert("parameters".to_string(), Value::Array(parameters));
+        }
+    }
+    operation
+}
+
+fn insert(object: &mut Value, key: &str, value: Value) {
+    if let Some(object) = object.as_object_mut() {
+        object.insert(key.to_string(), value);
+    }
+}
+
+/// Sets the type of a parameter other than the body, which can only be a
+/// primitive or an array of primitives. Anything else is a string.
+fn set_simple_type(parameter: &mut Value, schema: &Value) {
+    let object = match parameter.as_object_mut() {
+        Some(object) => object,
+        None => return,
+    };
+    match schema.get("type").and_then(Value::as_str) {
+        Some("array") => {
+            object.insert("type".to_string(), json!("array"));
+            let mut items = schema.get("items").cloned().unwrap_or_else(|| json!({}));
+            if !matches!(
+                items.get("type").and_then(Value::as_str),
+                Some("string") | Some("integer") | Some("number") | Some("boolean")
+            ) {
+                items = json!({"type": "string"});
+            }
+            object.insert("items".to_string(), items);
+            object.remove("format");
+        }
+        Some(ty @ "string") | Some(ty @ "integer") | Some(ty @ "number") | Some(ty @ "boolean") => {
+            object.insert("type".to_string(), json!(ty));
+            match schema.get("format") {
+                Some(format) => object.insert("format".to_string(), format.clone()),
+                None => object.remove("format"),
+            };
+            if let Some(values) = schema.get("enum") {
+                object.insert("enum".to_string(), values.clone());
+            }
+        }
+        _ => {
+            object.insert("type".to_string(), json!("string"));
+            object.remove("format");
+        }
+    }
+}
+
+/// Copies the descriptions in `described`, or in the definition it refers
+/// to, to the same properties of `schema` that have none.
+fn describe(schema: &mut Value, described: &Value, definitions: &Map<String, Value>, depth: usize) {
+    if depth > MAX_SCHEMA_DEPTH {
+        return;
+    }
+    let described = match described.get("$ref").and_then(Value::as_str) {
+        Some(reference) => match reference
+            .strip_prefix("#/definitions/")
+            .and_then(|name| definitions.get(name))
+        {
+            Some(definition) => definition,
+            None => return,
+        },
+        None => described,
+    };
+    let schema = match schema.as_object_mut() {
+        Some(schema) => schema,
+        None => return,
+    };
+    if !schema.contains_key("description") {
+        if let Some(description) = described.get("description") {
+            schema.insert("description".to_string(), description.clone());
+        }
+    }
+    if let (Some(items), Some(described)) = (schema.get_mut("items"), described.get("items")) {
+        describe(items, described, definitions, depth + 1);
+    }
+    if let (Some(properties), Some(described)) = (
+        schema.get_mut("properties").and_then(Value::as_object_mut),
+        described.get("properties"),
+    ) {
+        for (name, property) in properties {
+            if let Some(described) = described.get(name) {
+                describe(property, described, definitions, depth + 1);
+            }
+        }
+    }
+}
+
+fn last_ident(ty: &Type) -> Option<String> {
+    match ty {
+        Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+        Type::Reference(reference) => last_ident(&reference.elem),
+        Type::Paren(paren) => last_ident(&paren.elem),
+        _ => None,
+    }
+}
+
+/// Tells whether the schema of `ty` can be generated, which is not the case
+/// for an untyped `Value`.
+fn is_typed(ty: &Type, types: &Types) -> bool {
+    match generic_of(ty) {
+        Some((_, inner)) => is_typed(inner, types),
+        None => last_ident(ty).map_or(false, |name| {
+            types.structs.contains_key(&name) || types.enums.contains_key(&name)
+        }),
+    }
+}
+
+/// Returns the fields of a struct, or `None` if `ty` is not a struct in the
+/// source.
+fn fields_of_type(ty: &Type, types: &Types) -> Option<Vec<Field>> {
+    let item = types.structs.get(&last_ident(ty)?)?;
+    Some(fields_of(item, types, 0))
+}
+
+fn fields_of(item: &ItemStruct, types: &Types, depth: usize) -> Vec<Field> {
+    let named = match &item.fields {
+        Fields::Named(named) => &named.named,
+        _ => return Vec::new(),
+    };
+    let container = serde_attrs(&item.attrs);
+    let mut fields = Vec::new();
+    for field in named {
+        let attrs = serde_attrs(&field.attrs);
+        if attrs.contains_key("skip") || attrs.contains_key("skip_deserializing") {
+            continue;
+        }
+        if attrs.contains_key("flatten") {
+            if depth < MAX_SCHEMA_DEPTH {
+                if let Some(inner) = last_ident(&field.ty).and_then(|name| types.structs.get(&name))
+                {
+                    fields.extend(fields_of(inner, types, depth + 1));
+                }
+            }
+            continue;
+        }
+        let ident = match &field.ident {
+            Some(ident) => ident.to_string(),
+            None => continue,
+        };
+        let ident = ident.trim_start_matches("r#");
+        let name = match attrs.get("rename").and_then(Option::as_ref) {
+            Some(name) => name.clone(),
+            None => match container.get("rename_all").and_then(Option::as_ref) {
+                Some(rule) => rename_field(ident, rule),
+                None => ident.to_string(),
+            },
+        };
+        let optional = matches!(generic_of(&field.ty), Some(("Option", _)));
+        fields.push(Field {
+            name,
+            ty: field.ty.clone(),
+            required: !optional
+                && !attrs.contains_key("default")
+                && !container.contains_key("default"),
+            doc: doc_of(&field.attrs),
+        });
+    }
+    fields
+}
+
+/// Returns the `#[serde(...)]` attributes, with their values if any.
+fn serde_attrs(attrs: &[Attribute]) -> HashMap<String, Option<String>> {
+    let mut serde = HashMap::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("serde")) {
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list,
+            _ => continue,
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::Path(path)) => {
+                    if let Some(ident) = path.get_ident() {
+                        serde.insert(ident.to_string(), None);
+                    }
+                }
+                NestedMeta::Meta(Meta::NameValue(meta)) => {
+                    if let (Some(ident), Lit::Str(value)) = (meta.path.get_ident(), &meta.lit) {
+                        serde.insert(ident.to_string(), Some(value.value()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    serde
+}
+
+/// Renames a field in `snake_case` by a `rename_all` rule of serde.
+fn rename_field(name: &str, rule: &str) -> String {
+    match rule {
+        "UPPERCASE" | "SCREAMING_SNAKE_CASE" => name.to_uppercase(),
+        "kebab-case" => name.replace('_', "-"),
+        "SCREAMING-KEBAB-CASE" => name.replace('_', "-").to_uppercase(),
+        "camelCase" | "PascalCase" => {
+            let mut renamed = String::new();
+            for (i, word) in name.split('_').enumerate() {
+                let mut chars = word.chars();
+                if let Some(first) = chars.next() {
+                    if i == 0 && rule == "camelCase" {
+                        renamed.push(first);
+                    } else {
+                        renamed.extend(first.to_uppercase());
+                    }
+                    renamed.extend(chars);
+                }
+            }
+            renamed
+        }
+        _ => name.to_string(),
+    }
+}
+
+/// Renames a variant in `PascalCase` by a `rename_all` rule of serde.
+fn rename_variant(name: &str, rule: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    match rule {
+        "lowercase" => name.to_lowercase(),
+        "UPPERCASE" => name.to_uppercase(),
+        "snake_case" => snake,
+        "camelCase" => {
+            let mut chars = name.chars();
+            chars.next().map_or_else(String::new, |first| {
+                first.to_lowercase().chain(chars).collect()
+            })
+        }
+        rule => rename_field(&snake, rule),
+    }
+}
+
+/// Returns the schema of `ty` as serde deserializes it.
+fn schema_of(ty: &Type, types: &Types, depth: usize) -> Value {
+    match generic_of(ty) {
+        Some(("Option", inner)) => return schema_of(inner, types, depth),
+        Some(("Vec", inner)) => {
+            return json!({"type": "array", "items": schema_of(inner, types, depth)})
+        }
+        Some(("Map", inner)) => {
+            return json!({
+                "type": "object",
+                "additionalProperties": schema_of(inner, types, depth)
+            })
+        }
+        _ => {}
+    }
+    let name = match last_ident(ty) {
+        Some(name) => name,
+        None => return json!({}),
+    };
+    match name.as_str() {
+        "String" | "str" | "char" | "IpNetwork" | "IpAddr" => json!({"type": "string"}),
+        "bool" => json!({"type": "boolean"}),
+        "i8" | "i16" | "i32" | "u8" | "u16" => json!({"type": "integer", "format": "int32"}),
+        "u32" => json!({"type": "integer", "format": "uint32"}),
+        "i64" | "isize" => json!({"type": "integer", "format": "int64"}),
+        "u64" | "usize" => json!({"type": "integer", "format": "uint64"}),
+        "f32" => json!({"type": "number", "format": "float"}),
+        "f64" => json!({"type": "number", "format": "double"}),
+        "BigDecimal" => json!({"type": "number"}),
+        "NaiveDateTime" | "DateTime" => json!({"type": "string", "format": "date-time"}),
+        "NaiveDate" => json!({"type": "string", "format": "date"}),
+        name => {
+            if let Some(item) = types.structs.get(name) {
+                if depth >= MAX_SCHEMA_DEPTH {
+                    return json!({"type": "object"});
+                }
+                let mut properties = Map::new();
+                let mut required = Vec::new();
+                for field in fields_of(item, types, depth) {
+                    let mut schema = schema_of(&field.ty, types, depth + 1);
+                    if let Some(doc) = &field.doc {
+                        insert(&mut schema, "description", json!(doc));
+                    }
+                    if field.required {
+                        required.push(json!(field.name));
+                    }
+                    properties.insert(field.name, schema);
+                }
+                let mut schema = json!({"type": "object", "properties": properties});
+                if !required.is_empty() {
+                    insert(&mut schema, "required", Value::Array(required));
+                }
+                schema
+            } else if let Some(item) = types.enums.get(name) {
+                enum_schema(item)
+            } else {
+                json!({})
+            }
+        }
+    }
+}
+
+/// Returns the schema of an enum of unit variants, which serde represents by
+/// their names.
+fn enum_schema(item: &ItemEnum) -> Value {
+    if item
+        .variants
+        .iter()
+        .any(|v| !matches!(v.fields, Fields::Unit))
+    {
+        return json!({});
+    }
+    let container = serde_attrs(&item.attrs);
+    let values = item
+        .variants
+        .iter()
+        .map(|variant| {
+            let name = variant.ident.to_string();
+            match serde_attrs(&variant.attrs)
+                .get("rename")
+                .and_then(Option::as_ref)
+            {
+                Some(renamed) => renamed.clone(),
+                None => match container.get("rename_all").and_then(Option::as_ref) {
+                    Some(rule) => rename_variant(&name, rule),
+                    None => name,
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+    json!({"type": "string", "enum": values})
+}
@@ -0,0 +1,4 @@
+DROP TABLE indicator_tag;
+DROP TABLE outlier_tag;
+DROP TABLE cluster_tag;
+DROP TABLE tag;
@@ -0,0 +1,23 @@
+CREATE TABLE tag (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL,
+  UNIQUE (name)
+);
+
+CREATE TABLE cluster_tag (
+  cluster_id INTEGER NOT NULL REFERENCES cluster (id) ON DELETE CASCADE,
+  tag_id INTEGER NOT NULL REFERENCES tag (id) ON DELETE CASCADE,
+  PRIMARY KEY (cluster_id, tag_id)
+);
+
+CREATE TABLE outlier_tag (
+  outlier_id INTEGER NOT NULL REFERENCES outlier (id) ON DELETE CASCADE,
+  tag_id INTEGER NOT NULL REFERENCES tag (id) ON DELETE CASCADE,
+  PRIMARY KEY (outlier_id, tag_id)
+);
+
+CREATE TABLE indicator_tag (
+  indicator_id INTEGER NOT NULL REFERENCES indicator (id) ON DELETE CASCADE,
+  tag_id INTEGER NOT NULL REFERENCES tag (id) ON DELETE CASCADE,
+  PRIMARY KEY (indicator_id, tag_id)
+);
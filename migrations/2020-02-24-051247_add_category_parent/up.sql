@@ -0,0 +1 @@
+ALTER TABLE category ADD COLUMN parent_id INTEGER REFERENCES category (id) ON DELETE SET NULL;
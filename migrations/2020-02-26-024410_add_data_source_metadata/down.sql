@@ -0,0 +1,7 @@
+ALTER TABLE data_source
+  DROP CONSTRAINT data_source_data_type_check;
+ALTER TABLE data_source
+  DROP COLUMN archived,
+  DROP COLUMN owner,
+  DROP COLUMN enabled,
+  DROP COLUMN description;
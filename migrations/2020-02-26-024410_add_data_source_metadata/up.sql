@@ -0,0 +1,13 @@
+ALTER TABLE data_source
+  ADD COLUMN description TEXT,
+  ADD COLUMN enabled BOOLEAN NOT NULL DEFAULT TRUE,
+  ADD COLUMN owner TEXT,
+  ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Data types were free text. Existing rows that still do not match are left
+-- as they are, but new and updated rows are checked.
+UPDATE data_source SET data_type = lower(data_type)
+  WHERE lower(data_type) IN ('csv', 'email', 'log', 'packet');
+ALTER TABLE data_source
+  ADD CONSTRAINT data_source_data_type_check
+  CHECK (data_type IN ('csv', 'email', 'log', 'packet')) NOT VALID;
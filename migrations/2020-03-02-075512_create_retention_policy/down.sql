@@ -0,0 +1,5 @@
+DROP TABLE retention_policy;
+
+ALTER TABLE outlier DROP COLUMN creation_time;
+ALTER TABLE kafka_metadata DROP COLUMN creation_time;
+ALTER TABLE event DROP COLUMN creation_time;
@@ -0,0 +1,11 @@
+ALTER TABLE event ADD COLUMN creation_time TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE kafka_metadata ADD COLUMN creation_time TIMESTAMP NOT NULL DEFAULT NOW();
+ALTER TABLE outlier ADD COLUMN creation_time TIMESTAMP NOT NULL DEFAULT NOW();
+
+CREATE TABLE retention_policy (
+  data_source_id INTEGER PRIMARY KEY REFERENCES data_source(id) ON DELETE CASCADE,
+  max_age_days INTEGER,
+  max_rows BIGINT,
+  description_rounds INTEGER,
+  purge_referenced_events BOOLEAN NOT NULL DEFAULT FALSE
+);
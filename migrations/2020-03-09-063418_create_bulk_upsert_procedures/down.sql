@@ -0,0 +1,3 @@
+DROP FUNCTION IF EXISTS merge_staged_outliers(NUMERIC, INTEGER);
+DROP FUNCTION IF EXISTS merge_staged_clusters(NUMERIC, INTEGER);
+DROP FUNCTION IF EXISTS cap_event_ids(NUMERIC[], NUMERIC);
@@ -0,0 +1,247 @@
+/******************************************************
+ * CAP EVENT IDS
+ *
+ * split event ids into the largest ones, at most
+ * max_event_id_num of them in their original order,
+ * and the rest, which are expired. the smallest ids
+ * expire together with their duplicates, as in
+ * attempt_cluster_upsert.
+ * defined as a table function so that it is inlined.
+ ******************************************************/
+CREATE OR REPLACE FUNCTION cap_event_ids(
+  event_ids NUMERIC(20, 0)[],
+  max_event_id_num NUMERIC
+)
+RETURNS TABLE (
+  kept NUMERIC(20, 0)[],
+  expired NUMERIC(20, 0)[]
+) AS
+$$
+  SELECT
+    COALESCE(ARRAY_AGG(id ORDER BY n) FILTER (WHERE newer <= $2), '{}'),
+    COALESCE(ARRAY_AGG(DISTINCT id) FILTER (WHERE newer > $2), '{}')
+  FROM (
+    SELECT id, n, COUNT(*) OVER (ORDER BY id DESC) AS newer
+    FROM UNNEST($1) WITH ORDINALITY AS ids(id, n)
+  ) counted;
+$$ LANGUAGE sql IMMUTABLE;
+
+/******************************************************
+ * MERGE STAGED CLUSTERS
+ *
+ * upsert the clusters of a round in cluster_staging,
+ * a temporary table filled by the caller, with the
+ * same rules as attempt_cluster_upsert.
+ * each cluster appears at most once in a round, and
+ * cluster_staging should be analyzed before merging.
+ * return the number of rows upserted
+ ******************************************************/
+CREATE OR REPLACE FUNCTION merge_staged_clusters(
+  max_event_id_num NUMERIC,
+  merge_round INTEGER
+)
+RETURNS INTEGER AS
+$$
+DECLARE
+  merged_count INTEGER;
+BEGIN
+  INSERT INTO data_source
+    (topic_name, data_type)
+  SELECT data_source, data_source_type
+  FROM (
+    SELECT DISTINCT ON (data_source) data_source, data_source_type, position
+    FROM cluster_staging
+    WHERE cluster_staging.round = $2
+      AND NOT EXISTS (
+        SELECT 1 FROM data_source
+        WHERE data_source.topic_name = cluster_staging.data_source
+      )
+    ORDER BY data_source, position
+  ) first_seen
+  ORDER BY position
+  ON CONFLICT (topic_name) DO NOTHING;
+
+  WITH staged AS (
+    SELECT
+      s.position,
+      s.cluster_id,
+      s.detector_id,
+      s.event_ids,
+      s.signature,
+      s.score,
+      s.size,
+      d.id AS data_source_id,
+      c.id AS current_id,
+      c.signature AS current_signature,
+      c.event_ids AS current_event_ids,
+      c.size AS current_size
+    FROM cluster_staging s
+    INNER JOIN data_source d ON d.topic_name = s.data_source
+    LEFT JOIN cluster c
+      ON c.cluster_id = s.cluster_id AND c.data_source_id = d.id
+    WHERE s.round = $2
+      AND (c.id IS NULL OR s.event_ids IS NOT NULL)
+  ),
+  merged AS (
+    SELECT
+      staged.*,
+      CASE
+        WHEN current_id IS NULL OR current_event_ids IS NULL OR size >= $1 THEN
+          event_ids
+        ELSE
+          capped.kept
+      END AS new_event_ids,
+      CASE
+        WHEN current_id IS NULL OR current_event_ids IS NULL THEN
+          NULL
+        WHEN size >= $1 THEN
+          current_event_ids
+        ELSE
+          capped.expired
+      END AS removed_event_ids
+    FROM staged
+    LEFT JOIN LATERAL
+      cap_event_ids(array_cat(event_ids, current_event_ids), $1) capped
+      ON current_id IS NOT NULL
+  ),
+  removed AS (
+    DELETE FROM event
+    USING (
+      SELECT data_source_id, UNNEST(removed_event_ids) AS message_id
+      FROM merged
+    ) r
+    WHERE event.data_source_id = r.data_source_id
+      AND event.message_id = r.message_id
+  )
+  INSERT INTO cluster (
+    cluster_id,
+    detector_id,
+    event_ids,
+    signature,
+    size,
+    score,
+    data_source_id,
+    last_modification_time)
+  SELECT
+    cluster_id,
+    detector_id,
+    new_event_ids,
+    COALESCE(signature, current_signature, '-'),
+    CASE
+      WHEN current_size IS NULL THEN COALESCE(size, 1)
+      ELSE current_size + COALESCE(size, 0)
+    END,
+    score,
+    data_source_id,
+    NULL
+  FROM merged
+  ORDER BY position
+  ON CONFLICT (cluster_id, data_source_id)
+  DO UPDATE
+    SET
+      signature = EXCLUDED.signature,
+      event_ids = EXCLUDED.event_ids,
+      size = EXCLUDED.size,
+      last_modification_time = CURRENT_TIMESTAMP(0) at time zone 'UTC';
+
+  GET DIAGNOSTICS merged_count = ROW_COUNT;
+  RETURN merged_count;
+END;
+$$ LANGUAGE plpgsql;
+
+/******************************************************
+ * MERGE STAGED OUTLIERS
+ *
+ * insert or update the outliers of a round in
+ * outlier_staging, a temporary table filled by the
+ * caller, with the same rules as attempt_outlier_upsert.
+ * each outlier with a non-zero id appears at most once
+ * in a round, and outlier_staging should be analyzed
+ * before merging.
+ * return the number of rows inserted or updated
+ ******************************************************/
+CREATE OR REPLACE FUNCTION merge_staged_outliers(
+  max_event_id_num NUMERIC,
+  merge_round INTEGER
+)
+RETURNS INTEGER AS
+$$
+DECLARE
+  merged_count INTEGER;
+BEGIN
+  INSERT INTO data_source
+    (topic_name, data_type)
+  SELECT data_source, data_source_type
+  FROM (
+    SELECT DISTINCT ON (data_source) data_source, data_source_type, position
+    FROM outlier_staging
+    WHERE outlier_staging.round = $2
+      AND NOT EXISTS (
+        SELECT 1 FROM data_source
+        WHERE data_source.topic_name = outlier_staging.data_source
+      )
+    ORDER BY data_source, position
+  ) first_seen
+  ORDER BY position
+  ON CONFLICT (topic_name) DO NOTHING;
+
+  WITH staged AS (
+    SELECT
+      s.position,
+      s.id,
+      s.raw_event,
+      s.event_ids,
+      s.size,
+      s.compressed,
+      s.dictionary_id,
+      d.id AS data_source_id,
+      o.event_ids AS current_event_ids
+    FROM outlier_staging s
+    INNER JOIN data_source d ON d.topic_name = s.data_source
+    LEFT JOIN outlier o ON s.id <> 0 AND o.id = s.id
+    WHERE s.round = $2
+      AND (s.id = 0 OR o.id IS NOT NULL)
+  ),
+  merged AS (
+    SELECT
+      staged.*,
+      capped.kept AS new_event_ids,
+      capped.expired AS removed_event_ids
+    FROM staged
+    CROSS JOIN LATERAL
+      cap_event_ids(array_cat(event_ids, current_event_ids), $1) capped
+  ),
+  removed AS (
+    DELETE FROM event
+    USING (
+      SELECT data_source_id, UNNEST(removed_event_ids) AS message_id
+      FROM merged
+    ) r
+    WHERE event.data_source_id = r.data_source_id
+      AND event.message_id = r.message_id
+  ),
+  inserted AS (
+    INSERT INTO outlier
+      (raw_event, data_source_id, event_ids, size, compressed, dictionary_id)
+    SELECT
+      raw_event, data_source_id, new_event_ids, size, compressed, dictionary_id
+    FROM merged
+    WHERE id = 0
+    ORDER BY position
+    RETURNING 1
+  ),
+  updated AS (
+    UPDATE outlier
+      SET
+        event_ids = merged.new_event_ids,
+        size = merged.size
+      FROM merged
+      WHERE merged.id <> 0 AND outlier.id = merged.id
+    RETURNING 1
+  )
+  SELECT (SELECT COUNT(*) FROM inserted) + (SELECT COUNT(*) FROM updated)
+  INTO merged_count;
+
+  RETURN merged_count;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,16 @@
+/******************************************************
+ * COUNT ESTIMATE
+ *
+ * return the number of rows the planner expects `query`
+ * to return, without running it
+ ******************************************************/
+CREATE OR REPLACE FUNCTION count_estimate(query TEXT)
+RETURNS BIGINT AS
+$$
+DECLARE
+  plan JSONB;
+BEGIN
+  EXECUTE 'EXPLAIN (FORMAT JSON) ' || query INTO plan;
+  RETURN (plan -> 0 -> 'Plan' ->> 'Plan Rows')::BIGINT;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,55 @@
+/******************************************************
+ * ATTEMPT_EVENT_IDS_UPDATE
+ *
+ * remove the smallest event_ids of clusters and
+ * outliers with more than max_event_id_num of them,
+ * with the same rule as cap_event_ids, and delete
+ * their events.
+ ******************************************************/
+CREATE OR REPLACE FUNCTION attempt_event_ids_update(
+  max_event_id_num NUMERIC(20, 0)
+)
+RETURNS VOID AS
+$$
+BEGIN
+  WITH capped AS (
+    SELECT cluster.id, cluster.data_source_id, c.kept, c.expired
+    FROM cluster
+    CROSS JOIN LATERAL cap_event_ids(cluster.event_ids, $1) c
+    WHERE array_length(cluster.event_ids, 1) > $1
+  ),
+  removed AS (
+    DELETE FROM event
+    USING (
+      SELECT data_source_id, UNNEST(expired) AS message_id
+      FROM capped
+    ) r
+    WHERE event.data_source_id = r.data_source_id
+      AND event.message_id = r.message_id
+  )
+  UPDATE cluster
+    SET event_ids = capped.kept
+    FROM capped
+    WHERE cluster.id = capped.id;
+
+  WITH capped AS (
+    SELECT outlier.id, outlier.data_source_id, c.kept, c.expired
+    FROM outlier
+    CROSS JOIN LATERAL cap_event_ids(outlier.event_ids, $1) c
+    WHERE array_length(outlier.event_ids, 1) > $1
+  ),
+  removed AS (
+    DELETE FROM event
+    USING (
+      SELECT data_source_id, UNNEST(expired) AS message_id
+      FROM capped
+    ) r
+    WHERE event.data_source_id = r.data_source_id
+      AND event.message_id = r.message_id
+  )
+  UPDATE outlier
+    SET event_ids = capped.kept
+    FROM capped
+    WHERE outlier.id = capped.id;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS top_n_ipaddr_value;
+DROP INDEX IF EXISTS description_ipaddr_mode;
+
+ALTER TABLE top_n_ipaddr ALTER COLUMN value TYPE TEXT USING host(value);
+ALTER TABLE description_ipaddr ALTER COLUMN mode TYPE TEXT USING host(mode);
@@ -0,0 +1,5 @@
+ALTER TABLE description_ipaddr ALTER COLUMN mode TYPE INET USING mode::INET;
+ALTER TABLE top_n_ipaddr ALTER COLUMN value TYPE INET USING value::INET;
+
+CREATE INDEX description_ipaddr_mode ON description_ipaddr USING GIST (mode inet_ops);
+CREATE INDEX top_n_ipaddr_value ON top_n_ipaddr USING GIST (value inet_ops);
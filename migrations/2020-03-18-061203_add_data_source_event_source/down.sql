@@ -0,0 +1,6 @@
+ALTER TABLE data_source
+  DROP COLUMN id_field,
+  DROP COLUMN record_field,
+  DROP COLUMN event_path,
+  DROP COLUMN event_format,
+  DROP COLUMN event_source;
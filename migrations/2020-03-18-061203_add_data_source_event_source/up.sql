@@ -0,0 +1,6 @@
+ALTER TABLE data_source
+  ADD COLUMN event_source TEXT NOT NULL DEFAULT 'kafka',
+  ADD COLUMN event_format TEXT NOT NULL DEFAULT 'fluentd',
+  ADD COLUMN event_path TEXT,
+  ADD COLUMN record_field TEXT NOT NULL DEFAULT 'message',
+  ADD COLUMN id_field TEXT;
@@ -0,0 +1,7 @@
+DROP TRIGGER indicator_change ON indicator;
+DROP FUNCTION record_indicator_change;
+DROP TRIGGER outlier_change ON outlier;
+DROP FUNCTION record_outlier_change;
+DROP TRIGGER cluster_change ON cluster;
+DROP FUNCTION record_cluster_change;
+DROP TABLE change_event;
@@ -0,0 +1,122 @@
+CREATE TABLE change_event (
+  id BIGSERIAL PRIMARY KEY,
+  data_source_id INTEGER NOT NULL,
+  kind TEXT NOT NULL,
+  payload JSONB NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp()
+);
+CREATE INDEX change_event_data_source_id ON change_event (data_source_id, id);
+CREATE INDEX change_event_created_at ON change_event (created_at);
+
+CREATE OR REPLACE FUNCTION record_cluster_change()
+RETURNS TRIGGER AS
+$$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO change_event (data_source_id, kind, payload)
+    VALUES (NEW.data_source_id, 'cluster_created', jsonb_build_object(
+      'id', NEW.id,
+      'cluster_id', NEW.cluster_id,
+      'category_id', NEW.category_id,
+      'qualifier_id', NEW.qualifier_id,
+      'status_id', NEW.status_id,
+      'size', NEW.size,
+      'score', NEW.score
+    ));
+    RETURN NULL;
+  END IF;
+
+  IF NEW.qualifier_id IS DISTINCT FROM OLD.qualifier_id THEN
+    INSERT INTO change_event (data_source_id, kind, payload)
+    VALUES (NEW.data_source_id, 'qualifier_changed', jsonb_build_object(
+      'id', NEW.id,
+      'cluster_id', NEW.cluster_id,
+      'old', OLD.qualifier_id,
+      'new', NEW.qualifier_id
+    ));
+  END IF;
+  IF NEW.category_id IS DISTINCT FROM OLD.category_id THEN
+    INSERT INTO change_event (data_source_id, kind, payload)
+    VALUES (NEW.data_source_id, 'category_changed', jsonb_build_object(
+      'id', NEW.id,
+      'cluster_id', NEW.cluster_id,
+      'old', OLD.category_id,
+      'new', NEW.category_id
+    ));
+  END IF;
+  IF NEW.status_id IS DISTINCT FROM OLD.status_id THEN
+    INSERT INTO change_event (data_source_id, kind, payload)
+    VALUES (NEW.data_source_id, 'status_changed', jsonb_build_object(
+      'id', NEW.id,
+      'cluster_id', NEW.cluster_id,
+      'old', OLD.status_id,
+      'new', NEW.status_id
+    ));
+  END IF;
+  IF NEW.size IS DISTINCT FROM OLD.size
+    OR NEW.signature IS DISTINCT FROM OLD.signature
+    OR NEW.score IS DISTINCT FROM OLD.score THEN
+    INSERT INTO change_event (data_source_id, kind, payload)
+    VALUES (NEW.data_source_id, 'cluster_updated', jsonb_build_object(
+      'id', NEW.id,
+      'cluster_id', NEW.cluster_id,
+      'size', NEW.size,
+      'score', NEW.score
+    ));
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER cluster_change
+  AFTER INSERT OR UPDATE ON cluster
+  FOR EACH ROW EXECUTE PROCEDURE record_cluster_change();
+
+CREATE OR REPLACE FUNCTION record_outlier_change()
+RETURNS TRIGGER AS
+$$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO change_event (data_source_id, kind, payload)
+    VALUES (NEW.data_source_id, 'outlier_added', jsonb_build_object(
+      'id', NEW.id,
+      'size', NEW.size
+    ));
+  ELSE
+    INSERT INTO change_event (data_source_id, kind, payload)
+    VALUES (OLD.data_source_id, 'outlier_deleted', jsonb_build_object(
+      'id', OLD.id
+    ));
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER outlier_change
+  AFTER INSERT OR DELETE ON outlier
+  FOR EACH ROW EXECUTE PROCEDURE record_outlier_change();
+
+CREATE OR REPLACE FUNCTION record_indicator_change()
+RETURNS TRIGGER AS
+$$
+DECLARE
+  changed indicator%ROWTYPE;
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    changed := OLD;
+  ELSE
+    changed := NEW;
+  END IF;
+  INSERT INTO change_event (data_source_id, kind, payload)
+  VALUES (changed.data_source_id, 'indicator_changed', jsonb_build_object(
+    'id', changed.id,
+    'name', changed.name,
+    'operation', lower(TG_OP)
+  ));
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER indicator_change
+  AFTER INSERT OR UPDATE OR DELETE ON indicator
+  FOR EACH ROW EXECUTE PROCEDURE record_indicator_change();
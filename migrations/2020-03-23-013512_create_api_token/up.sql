@@ -0,0 +1,6 @@
+CREATE TABLE api_token (
+  id SERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  token_hash BYTEA NOT NULL UNIQUE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
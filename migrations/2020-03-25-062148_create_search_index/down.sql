@@ -0,0 +1,7 @@
+DROP TRIGGER outlier_search_vector_reset ON outlier;
+DROP TRIGGER event_search_vector_reset ON event;
+DROP FUNCTION reset_search_vector;
+ALTER TABLE outlier DROP COLUMN search_vector;
+ALTER TABLE event DROP COLUMN search_vector;
+DROP INDEX indicator_description_trgm;
+DROP INDEX cluster_signature_trgm;
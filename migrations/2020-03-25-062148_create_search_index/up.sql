@@ -0,0 +1,34 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX cluster_signature_trgm ON cluster USING GIN (signature gin_trgm_ops);
+CREATE INDEX indicator_description_trgm ON indicator USING GIN (description gin_trgm_ops);
+
+-- Raw events are stored compressed, so their search vectors are set by the
+-- server after decompressing them. NULL means not indexed yet.
+ALTER TABLE event ADD COLUMN search_vector TSVECTOR;
+CREATE INDEX event_search_vector ON event USING GIN (search_vector);
+CREATE INDEX event_search_pending ON event (id)
+  WHERE search_vector IS NULL AND raw_event IS NOT NULL;
+
+ALTER TABLE outlier ADD COLUMN search_vector TSVECTOR;
+CREATE INDEX outlier_search_vector ON outlier USING GIN (search_vector);
+CREATE INDEX outlier_search_pending ON outlier (id) WHERE search_vector IS NULL;
+
+CREATE OR REPLACE FUNCTION reset_search_vector()
+RETURNS TRIGGER AS
+$$
+BEGIN
+  NEW.search_vector := NULL;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER event_search_vector_reset
+  BEFORE UPDATE OF raw_event ON event
+  FOR EACH ROW WHEN (OLD.raw_event IS DISTINCT FROM NEW.raw_event)
+  EXECUTE PROCEDURE reset_search_vector();
+
+CREATE TRIGGER outlier_search_vector_reset
+  BEFORE UPDATE OF raw_event ON outlier
+  FOR EACH ROW WHEN (OLD.raw_event IS DISTINCT FROM NEW.raw_event)
+  EXECUTE PROCEDURE reset_search_vector();
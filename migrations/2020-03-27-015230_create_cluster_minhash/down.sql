@@ -0,0 +1,5 @@
+DROP TRIGGER cluster_minhash_reset ON cluster;
+DROP FUNCTION reset_minhash;
+DROP INDEX column_description_cluster_id;
+ALTER TABLE cluster DROP COLUMN minhash_bands;
+ALTER TABLE cluster DROP COLUMN minhash;
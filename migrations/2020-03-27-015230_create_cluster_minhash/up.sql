@@ -0,0 +1,24 @@
+-- The MinHash of the tokens of each cluster signature, and the hashes of its
+-- bands, set by the server. NULL means not computed yet. Two clusters sharing
+-- a band hash are likely to have similar signatures.
+ALTER TABLE cluster ADD COLUMN minhash INT8[];
+ALTER TABLE cluster ADD COLUMN minhash_bands INT8[];
+CREATE INDEX cluster_minhash_bands ON cluster USING GIN (minhash_bands);
+CREATE INDEX cluster_minhash_pending ON cluster (id) WHERE minhash IS NULL;
+
+CREATE INDEX column_description_cluster_id ON column_description (cluster_id, id);
+
+CREATE OR REPLACE FUNCTION reset_minhash()
+RETURNS TRIGGER AS
+$$
+BEGIN
+  NEW.minhash := NULL;
+  NEW.minhash_bands := NULL;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER cluster_minhash_reset
+  BEFORE UPDATE OF signature ON cluster
+  FOR EACH ROW WHEN (OLD.signature IS DISTINCT FROM NEW.signature)
+  EXECUTE PROCEDURE reset_minhash();
@@ -0,0 +1,4 @@
+DROP TRIGGER cluster_history_record ON cluster;
+DROP FUNCTION record_cluster_history;
+ALTER TABLE cluster DROP COLUMN first_seen;
+DROP TABLE cluster_history;
@@ -0,0 +1,52 @@
+CREATE TABLE cluster_history (
+  id BIGSERIAL PRIMARY KEY,
+  cluster_id INTEGER NOT NULL REFERENCES cluster (id) ON DELETE CASCADE,
+  recorded_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+  size NUMERIC(20, 0) NOT NULL,
+  size_delta NUMERIC(20, 0) NOT NULL,
+  score FLOAT8,
+  new_event_count INTEGER NOT NULL
+);
+CREATE INDEX cluster_history_cluster_id ON cluster_history (cluster_id, recorded_at);
+
+-- Clusters created before this migration have no first_seen.
+ALTER TABLE cluster ADD COLUMN first_seen TIMESTAMP;
+ALTER TABLE cluster
+  ALTER COLUMN first_seen SET DEFAULT (CURRENT_TIMESTAMP(0) at time zone 'UTC');
+
+/******************************************************
+ * RECORD CLUSTER HISTORY
+ *
+ * append a snapshot of a cluster whenever an upsert
+ * changes its size, score, or event ids. an update
+ * that only removes event ids is not recorded.
+ ******************************************************/
+CREATE OR REPLACE FUNCTION record_cluster_history()
+RETURNS TRIGGER AS
+$$
+DECLARE
+  delta NUMERIC(20, 0);
+  added INTEGER;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    delta := NEW.size;
+    added := COALESCE(cardinality(NEW.event_ids), 0);
+  ELSE
+    delta := NEW.size - OLD.size;
+    added := cardinality(ARRAY(
+      SELECT unnest(NEW.event_ids) EXCEPT SELECT unnest(OLD.event_ids)
+    ));
+    IF delta = 0 AND added = 0 AND NEW.score IS NOT DISTINCT FROM OLD.score THEN
+      RETURN NULL;
+    END IF;
+  END IF;
+
+  INSERT INTO cluster_history (cluster_id, size, size_delta, score, new_event_count)
+  VALUES (NEW.id, NEW.size, delta, NEW.score, added);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER cluster_history_record
+  AFTER INSERT OR UPDATE OF size, score, event_ids ON cluster
+  FOR EACH ROW EXECUTE PROCEDURE record_cluster_history();
@@ -0,0 +1,2 @@
+ALTER TABLE data_source
+  ADD COLUMN template TEXT REFERENCES template(name) ON UPDATE CASCADE ON DELETE SET NULL;
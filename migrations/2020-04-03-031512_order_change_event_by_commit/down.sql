@@ -0,0 +1,8 @@
+DROP INDEX change_event_data_source_id;
+CREATE INDEX change_event_data_source_id ON change_event (data_source_id, id);
+DROP INDEX change_event_unpublished;
+DROP INDEX change_event_position;
+DROP SEQUENCE change_event_position_seq;
+ALTER TABLE change_event
+  DROP COLUMN position,
+  DROP COLUMN tx_id;
@@ -0,0 +1,24 @@
+/******************************************************
+ * CHANGE EVENT POSITION
+ *
+ * The ids of change_event are taken when the changes
+ * are made, not when they are committed, so a change
+ * can appear after others with larger ids. position is
+ * given to a change once every transaction that started
+ * before its own has ended, in the order of the
+ * transaction ids, and is the id clients see.
+ ******************************************************/
+ALTER TABLE change_event
+  ADD COLUMN tx_id BIGINT NOT NULL DEFAULT txid_current(),
+  ADD COLUMN position BIGINT;
+
+CREATE SEQUENCE change_event_position_seq;
+UPDATE change_event SET position = id;
+SELECT setval('change_event_position_seq', COALESCE(MAX(position), 0) + 1, false)
+  FROM change_event;
+
+CREATE UNIQUE INDEX change_event_position ON change_event (position);
+CREATE INDEX change_event_unpublished ON change_event (tx_id, id)
+  WHERE position IS NULL;
+DROP INDEX change_event_data_source_id;
+CREATE INDEX change_event_data_source_id ON change_event (data_source_id, position);
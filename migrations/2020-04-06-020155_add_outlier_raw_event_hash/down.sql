@@ -0,0 +1,88 @@
+CREATE OR REPLACE FUNCTION merge_staged_outliers(
+  max_event_id_num NUMERIC,
+  merge_round INTEGER
+)
+RETURNS INTEGER AS
+$$
+DECLARE
+  merged_count INTEGER;
+BEGIN
+  INSERT INTO data_source
+    (topic_name, data_type)
+  SELECT data_source, data_source_type
+  FROM (
+    SELECT DISTINCT ON (data_source) data_source, data_source_type, position
+    FROM outlier_staging
+    WHERE outlier_staging.round = $2
+      AND NOT EXISTS (
+        SELECT 1 FROM data_source
+        WHERE data_source.topic_name = outlier_staging.data_source
+      )
+    ORDER BY data_source, position
+  ) first_seen
+  ORDER BY position
+  ON CONFLICT (topic_name) DO NOTHING;
+
+  WITH staged AS (
+    SELECT
+      s.position,
+      s.id,
+      s.raw_event,
+      s.event_ids,
+      s.size,
+      s.compressed,
+      s.dictionary_id,
+      d.id AS data_source_id,
+      o.event_ids AS current_event_ids
+    FROM outlier_staging s
+    INNER JOIN data_source d ON d.topic_name = s.data_source
+    LEFT JOIN outlier o ON s.id <> 0 AND o.id = s.id
+    WHERE s.round = $2
+      AND (s.id = 0 OR o.id IS NOT NULL)
+  ),
+  merged AS (
+    SELECT
+      staged.*,
+      capped.kept AS new_event_ids,
+      capped.expired AS removed_event_ids
+    FROM staged
+    CROSS JOIN LATERAL
+      cap_event_ids(array_cat(event_ids, current_event_ids), $1) capped
+  ),
+  removed AS (
+    DELETE FROM event
+    USING (
+      SELECT data_source_id, UNNEST(removed_event_ids) AS message_id
+      FROM merged
+    ) r
+    WHERE event.data_source_id = r.data_source_id
+      AND event.message_id = r.message_id
+  ),
+  inserted AS (
+    INSERT INTO outlier
+      (raw_event, data_source_id, event_ids, size, compressed, dictionary_id)
+    SELECT
+      raw_event, data_source_id, new_event_ids, size, compressed, dictionary_id
+    FROM merged
+    WHERE id = 0
+    ORDER BY position
+    RETURNING 1
+  ),
+  updated AS (
+    UPDATE outlier
+      SET
+        event_ids = merged.new_event_ids,
+        size = merged.size
+      FROM merged
+      WHERE merged.id <> 0 AND outlier.id = merged.id
+    RETURNING 1
+  )
+  SELECT (SELECT COUNT(*) FROM inserted) + (SELECT COUNT(*) FROM updated)
+  INTO merged_count;
+
+  RETURN merged_count;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP INDEX outlier_raw_event_hash;
+ALTER TABLE outlier DROP COLUMN raw_event_hash;
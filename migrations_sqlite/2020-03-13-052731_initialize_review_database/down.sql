@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS qualifier_update_trigger;
+DROP TABLE IF EXISTS kafka_metadata;
+DROP TABLE IF EXISTS event;
+DROP TABLE IF EXISTS outlier;
+DROP TABLE IF EXISTS cluster;
+DROP TABLE IF EXISTS status;
+DROP TABLE IF EXISTS qualifier;
+DROP TABLE IF EXISTS data_source;
+DROP TABLE IF EXISTS category;
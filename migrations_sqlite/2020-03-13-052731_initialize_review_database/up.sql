@@ -0,0 +1,104 @@
+/******************************************************
+ * The tables used by the SQLite storage backend.
+ *
+ * event_ids are JSON arrays of numbers, and message ids
+ * are zero-padded to 20 digits so that they compare in
+ * numeric order.
+ ******************************************************/
+CREATE TABLE category (
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL,
+  parent_id INTEGER REFERENCES category (id)
+);
+INSERT INTO category (name) VALUES('Non-Specified Alert');
+
+CREATE TABLE data_source (
+  id INTEGER PRIMARY KEY,
+  topic_name TEXT NOT NULL,
+  data_type TEXT NOT NULL,
+  description TEXT,
+  enabled BOOLEAN NOT NULL DEFAULT 1,
+  owner TEXT,
+  archived BOOLEAN NOT NULL DEFAULT 0,
+  UNIQUE (topic_name)
+);
+
+CREATE TABLE qualifier (
+  id INTEGER PRIMARY KEY,
+  description TEXT NOT NULL
+);
+INSERT INTO qualifier VALUES(1,'benign');
+INSERT INTO qualifier VALUES(2,'unknown');
+INSERT INTO qualifier VALUES(3,'suspicious');
+
+CREATE TABLE status (
+  id INTEGER PRIMARY KEY,
+  description TEXT NOT NULL
+);
+INSERT INTO status VALUES(1,'reviewed');
+INSERT INTO status VALUES(2,'pending review');
+INSERT INTO status VALUES(3,'disabled');
+
+CREATE TABLE cluster (
+  id INTEGER PRIMARY KEY,
+  cluster_id TEXT,
+  category_id INTEGER NOT NULL DEFAULT 1,
+  detector_id INTEGER NOT NULL,
+  event_ids TEXT,
+  qualifier_id INTEGER NOT NULL DEFAULT 2,
+  status_id INTEGER NOT NULL DEFAULT 2,
+  signature TEXT NOT NULL,
+  size BIGINT NOT NULL,
+  score DOUBLE,
+  data_source_id INTEGER NOT NULL,
+  last_modification_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+  UNIQUE (cluster_id, data_source_id)
+);
+
+CREATE TABLE outlier (
+  id INTEGER PRIMARY KEY,
+  raw_event BLOB NOT NULL,
+  data_source_id INTEGER NOT NULL,
+  event_ids TEXT NOT NULL,
+  size BIGINT NOT NULL,
+  compressed BOOLEAN NOT NULL DEFAULT 0,
+  dictionary_id INTEGER
+);
+
+CREATE TABLE event (
+  id INTEGER PRIMARY KEY,
+  message_id TEXT NOT NULL,
+  data_source_id INTEGER NOT NULL,
+  raw_event BLOB,
+  compressed BOOLEAN NOT NULL DEFAULT 0,
+  dictionary_id INTEGER,
+  UNIQUE (message_id, data_source_id)
+);
+
+CREATE TABLE kafka_metadata (
+  id INTEGER PRIMARY KEY,
+  data_source_id INTEGER NOT NULL,
+  partition INTEGER NOT NULL,
+  offsets BIGINT NOT NULL,
+  lower_message_id TEXT,
+  upper_message_id TEXT,
+  UNIQUE (data_source_id, partition, offsets)
+);
+
+/******************************************************
+ * QUALIFIER UPDATE TRIGGER
+ *
+ * mark a cluster as reviewed when its qualifier
+ * changes, as status_id_update does.
+ ******************************************************/
+CREATE TRIGGER qualifier_update_trigger
+  AFTER UPDATE OF qualifier_id ON cluster
+  FOR EACH ROW
+  WHEN old.qualifier_id != new.qualifier_id
+BEGIN
+  UPDATE cluster
+  SET status_id = COALESCE(
+    (SELECT id FROM status WHERE description = 'reviewed' LIMIT 1),
+    status_id)
+  WHERE id = new.id;
+END;
@@ -0,0 +1,191 @@
+-- Compares the per-row upsert procedures with the set-based merges used by
+-- `PUT /api/cluster` and `PUT /api/outlier`.
+--
+-- Run against a database with all migrations applied:
+--
+--   psql -q -v rows=10000 -f scripts/bench-upsert.sql review
+--
+-- Each step inserts `rows` clusters or outliers with 20 event ids, then
+-- updates them with 10 more event ids, which makes every update trim its event
+-- ids to `max_event_id_num` (25) and delete the expired events. Everything
+-- runs in transactions that are rolled back. Before each pair of steps, the
+-- plans cached by the previous ones are discarded and the database is
+-- vacuumed, so that the order of the steps does not change the results. The
+-- per-row procedures are called once from a single statement and, to include
+-- the round trip per row the server used to make, once from a statement per
+-- row.
+--
+-- The results are in `bench-upsert.md`.
+
+\set ON_ERROR_STOP on
+\if :{?rows}
+\else
+  \set rows 10000
+\endif
+\pset tuples_only on
+\pset format unaligned
+\pset fieldsep '\t'
+
+\set setup 'INSERT INTO data_source (topic_name, data_type) SELECT ''bench-'' || i, ''log'' FROM generate_series(0, 2) i; INSERT INTO event (message_id, data_source_id) SELECT i * 100 + j, data_source.id FROM generate_series(1, :rows) i CROSS JOIN generate_series(1, 20) j INNER JOIN data_source ON data_source.topic_name = ''bench-'' || i % 3; ANALYZE event;'
+\set report 'SELECT :''step'', :rows || '' rows'', ROUND(EXTRACT(EPOCH FROM clock_timestamp() - :''started'')::NUMERIC, 2) || '' s'', ROUND(:rows / EXTRACT(EPOCH FROM clock_timestamp() - :''started'')) || '' rows/s'';'
+
+CREATE TEMPORARY TABLE cluster_staging (
+  position INTEGER NOT NULL,
+  round INTEGER NOT NULL,
+  cluster_id TEXT NOT NULL,
+  detector_id INTEGER NOT NULL,
+  event_ids NUMERIC(20, 0)[],
+  signature TEXT,
+  score FLOAT8,
+  size NUMERIC(20, 0) NOT NULL,
+  data_source TEXT NOT NULL,
+  data_source_type TEXT NOT NULL
+);
+CREATE TEMPORARY TABLE outlier_staging (
+  position INTEGER NOT NULL,
+  round INTEGER NOT NULL,
+  id INTEGER NOT NULL,
+  raw_event BYTEA NOT NULL,
+  data_source TEXT NOT NULL,
+  data_source_type TEXT NOT NULL,
+  event_ids NUMERIC(20, 0)[] NOT NULL,
+  size NUMERIC(20, 0) NOT NULL,
+  compressed BOOLEAN NOT NULL,
+  dictionary_id INTEGER
+);
+
+-- attempt_cluster_upsert, one call per cluster
+DISCARD PLANS;
+VACUUM ANALYZE;
+BEGIN;
+:setup
+\set step 'cluster insert, per row'
+SELECT clock_timestamp() AS started \gset
+SELECT SUM(attempt_cluster_upsert(
+  25, 'bench-' || i, 'bench-' || i % 3, 'log', 1,
+  ARRAY(SELECT i * 100 + j FROM generate_series(1, 20) j)::NUMERIC(20, 0)[],
+  'signature ' || i, 0.5, 20))
+FROM generate_series(1, :rows) i \g /dev/null
+:report
+\set step 'cluster update, per row'
+SELECT clock_timestamp() AS started \gset
+SELECT SUM(attempt_cluster_upsert(
+  25, 'bench-' || i, 'bench-' || i % 3, 'log', 1,
+  ARRAY(SELECT i * 100 + j FROM generate_series(21, 30) j)::NUMERIC(20, 0)[],
+  NULL, NULL, 10))
+FROM generate_series(1, :rows) i \g /dev/null
+:report
+ROLLBACK;
+
+-- attempt_cluster_upsert, one statement per cluster
+DISCARD PLANS;
+VACUUM ANALYZE;
+BEGIN;
+:setup
+\set step 'cluster insert, statement per row'
+SELECT clock_timestamp() AS started \gset
+\o /dev/null
+SELECT format(
+  'SELECT attempt_cluster_upsert(25, %L, %L, ''log'', 1, ARRAY(SELECT %s * 100 + j FROM generate_series(1, 20) j)::NUMERIC(20, 0)[], %L, 0.5, 20)',
+  'bench-' || i, 'bench-' || i % 3, i, 'signature ' || i)
+FROM generate_series(1, :rows) i \gexec
+\o
+:report
+\set step 'cluster update, statement per row'
+SELECT clock_timestamp() AS started \gset
+\o /dev/null
+SELECT format(
+  'SELECT attempt_cluster_upsert(25, %L, %L, ''log'', 1, ARRAY(SELECT %s * 100 + j FROM generate_series(21, 30) j)::NUMERIC(20, 0)[], NULL, NULL, 10)',
+  'bench-' || i, 'bench-' || i % 3, i)
+FROM generate_series(1, :rows) i \gexec
+\o
+:report
+ROLLBACK;
+
+-- cluster_staging and merge_staged_clusters
+DISCARD PLANS;
+VACUUM ANALYZE;
+BEGIN;
+:setup
+\set step 'cluster insert, bulk'
+SELECT clock_timestamp() AS started \gset
+INSERT INTO cluster_staging
+  SELECT i, 1, 'bench-' || i, 1,
+    ARRAY(SELECT i * 100 + j FROM generate_series(1, 20) j),
+    'signature ' || i, 0.5, 20, 'bench-' || i % 3, 'log'
+  FROM generate_series(1, :rows) i;
+ANALYZE cluster_staging;
+SELECT merge_staged_clusters(25, 1) \g /dev/null
+:report
+TRUNCATE cluster_staging;
+\set step 'cluster update, bulk'
+SELECT clock_timestamp() AS started \gset
+INSERT INTO cluster_staging
+  SELECT i, 1, 'bench-' || i, 1,
+    ARRAY(SELECT i * 100 + j FROM generate_series(21, 30) j),
+    NULL, NULL, 10, 'bench-' || i % 3, 'log'
+  FROM generate_series(1, :rows) i;
+ANALYZE cluster_staging;
+SELECT merge_staged_clusters(25, 1) \g /dev/null
+:report
+ROLLBACK;
+
+-- attempt_outlier_upsert, one call per outlier
+DISCARD PLANS;
+VACUUM ANALYZE;
+BEGIN;
+:setup
+\set step 'outlier insert, per row'
+SELECT clock_timestamp() AS started \gset
+SELECT SUM(attempt_outlier_upsert(
+  25, 0, convert_to('outlier ' || i, 'UTF8'), 'bench-' || i % 3, 'log',
+  ARRAY(SELECT i * 100 + j FROM generate_series(1, 20) j)::NUMERIC(20, 0)[],
+  20, FALSE, NULL))
+FROM generate_series(1, :rows) i \g /dev/null
+:report
+\set step 'outlier update, per row'
+SELECT clock_timestamp() AS started \gset
+SELECT SUM(attempt_outlier_upsert(
+  25, id, raw_event, 'bench-' || i % 3, 'log',
+  ARRAY(SELECT i * 100 + j FROM generate_series(21, 30) j)::NUMERIC(20, 0)[],
+  30, FALSE, NULL))
+FROM (
+  SELECT id, raw_event, ROW_NUMBER() OVER (ORDER BY id) AS i FROM outlier
+  WHERE data_source_id IN (
+    SELECT id FROM data_source WHERE topic_name LIKE 'bench-%'
+  )
+) bench \g /dev/null
+:report
+ROLLBACK;
+
+-- outlier_staging and merge_staged_outliers
+DISCARD PLANS;
+VACUUM ANALYZE;
+BEGIN;
+:setup
+\set step 'outlier insert, bulk'
+SELECT clock_timestamp() AS started \gset
+INSERT INTO outlier_staging
+  SELECT i, 1, 0, convert_to('outlier ' || i, 'UTF8'), 'bench-' || i % 3,
+    'log', ARRAY(SELECT i * 100 + j FROM generate_series(1, 20) j), 20, FALSE,
+    NULL
+  FROM generate_series(1, :rows) i;
+ANALYZE outlier_staging;
+SELECT merge_staged_outliers(25, 1) \g /dev/null
+:report
+TRUNCATE outlier_staging;
+\set step 'outlier update, bulk'
+SELECT clock_timestamp() AS started \gset
+INSERT INTO outlier_staging
+  SELECT i, 1, id, raw_event, 'bench-' || i % 3, 'log',
+    ARRAY(SELECT i * 100 + j FROM generate_series(21, 30) j), 30, FALSE, NULL
+  FROM (
+    SELECT id, raw_event, ROW_NUMBER() OVER (ORDER BY id) AS i FROM outlier
+    WHERE data_source_id IN (
+      SELECT id FROM data_source WHERE topic_name LIKE 'bench-%'
+    )
+  ) bench;
+ANALYZE outlier_staging;
+SELECT merge_staged_outliers(25, 1) \g /dev/null
+:report
+ROLLBACK;
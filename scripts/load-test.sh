@@ -0,0 +1,73 @@
+#!/bin/sh
+#
+# Sends large `PUT /api/cluster` batches concurrently while timing
+# `GET /api/cluster`, to check that slow writes do not block reads.
+#
+# Usage: load-test.sh [REVIEWD_ADDR] [WRITERS] [BATCH_SIZE] [READS]
+#
+# The data source `load-test` is created if it does not exist. For each kind of
+# request, the throughput, the latency percentiles, and the rate of 503
+# responses are printed. Run the server with `DATABASE_MAX_PENDING` below
+# WRITERS to see how requests over the limit are rejected.
+
+set -eu
+
+ADDR=${1:-127.0.0.1:8080}
+WRITERS=${2:-16}
+BATCH_SIZE=${3:-3000}
+READS=${4:-50}
+URL="http://$ADDR/api/v1"
+TMP=$(mktemp -d)
+trap 'rm -rf "$TMP"' EXIT
+
+curl -s -o /dev/null -X POST "$URL/data_source?data_source=load-test&data_type=log"
+
+batch() {
+  awk -v n="$BATCH_SIZE" -v w="$1" 'BEGIN {
+    printf "[";
+    for (i = 0; i < n; i++) {
+      if (i > 0) printf ",";
+      printf "{\"cluster_id\":\"load-%d-%d\",\"detector_id\":1,", w, i;
+      printf "\"signature\":\"load test %d %d\",\"score\":null,", w, i;
+      printf "\"data_source\":\"load-test\",\"data_source_type\":\"log\",";
+      printf "\"size\":1,\"event_ids\":[%d]}", i;
+    }
+    printf "]";
+  }'
+}
+
+for w in $(seq 1 "$WRITERS"); do
+  batch "$w" >"$TMP/batch-$w.json"
+done
+
+START=$(date +%s.%N)
+for w in $(seq 1 "$WRITERS"); do
+  curl -s -o /dev/null -w '%{http_code} %{time_total}\n' -X PUT \
+    -H 'Content-Type: application/json' --data-binary "@$TMP/batch-$w.json" \
+    "$URL/cluster" >>"$TMP/writes" &
+done
+
+for _ in $(seq 1 "$READS"); do
+  curl -s -o /dev/null -w '%{http_code} %{time_total}\n' \
+    "$URL/cluster?per_page=10" >>"$TMP/reads"
+done
+READS_END=$(date +%s.%N)
+wait
+WRITES_END=$(date +%s.%N)
+
+# summarize NAME FILE START END
+summarize() {
+  sort -k2 -n "$2" | awk -v name="$1" -v elapsed="$(awk "BEGIN { print $4 - $3 }")" '
+    { code[$1]++; t[NR] = $2 }
+    END {
+      printf "%s: %d request(s), %.1f/s, p50 %.3fs, p95 %.3fs, p99 %.3fs, max %.3fs", \
+        name, NR, NR / elapsed, t[int(NR * 0.5) + 1], t[int(NR * 0.95) + 1], \
+        t[int(NR * 0.99) + 1], t[NR];
+      printf ", 503 rate %.1f%%, status", 100 * ("503" in code ? code["503"] : 0) / NR;
+      for (c in code) printf " %s=%d", c, code[c];
+      printf "\n";
+    }'
+}
+
+summarize "PUT /api/cluster" "$TMP/writes" "$START" "$WRITES_END"
+summarize "GET /api/cluster" "$TMP/reads" "$START" "$READS_END"
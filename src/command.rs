@@ -0,0 +1,501 @@
+use anyhow::{anyhow, Context};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::result::DatabaseErrorKind;
+use std::env;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::database::{
+    apply_retention_policies, create_api_token, get_data_source_id, migrate, pending_migrations,
+    reindex_tables, update_event_ids, Conn, Error, MigrationStep, MigrationTarget,
+};
+use crate::kafka_consumer::BrokerConfig;
+use crate::server::TlsConfig;
+
+/// The exit codes, as in `sysexits.h`.
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 64;
+const EXIT_UNAVAILABLE: i32 = 69;
+const EXIT_CONFIG: i32 = 78;
+
+const USAGE: &str = "\
+Usage: review [COMMAND]
+
+Starts the server if no command is given.
+
+Commands:
+  migrate [--to VERSION | --revert]
+      Applies the pending migrations, migrates to VERSION, or reverts the
+      last migration applied.
+  check-config
+      Checks the environment variables, the TLS files, and the database.
+  create-token NAME
+      Creates an API token and prints it.
+  purge [--data-source NAME] [--dry-run]
+      Purges the data exceeding the retention policies.
+  recompute-event-ids [--max-event-id-num N]
+      Caps the event_ids of clusters and outliers.
+  reindex [TABLE...]
+      Rebuilds the indexes of the given tables, or of all tables.
+  help
+      Prints this message.
+
+Exit codes:
+  0   success
+  1   the command failed
+  64  invalid command line
+  69  the database is unavailable
+  78  invalid configuration, or the database needs migrations";
+
+/// Why a command failed, and the code to exit with.
+struct Failure {
+    code: i32,
+    error: anyhow::Error,
+}
+
+impl Failure {
+    fn new<E: Into<anyhow::Error>>(code: i32, error: E) -> Self {
+        Self {
+            code,
+            error: error.into(),
+        }
+    }
+
+    fn usage<T: Into<String>>(message: T) -> Self {
+        Self::new(EXIT_USAGE, anyhow!(message.into()))
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Connection(_) | Error::R2D2(_) => Self::new(EXIT_UNAVAILABLE, e),
+            e => Self::new(EXIT_FAILURE, e),
+        }
+    }
+}
+
+/// Runs the administrative command in `args`, without the program name, and
+/// returns the exit code. The commands work on the database at `DATABASE_URL`
+/// without starting the server.
+pub(crate) fn run(args: &[String]) -> i32 {
+    dotenv::dotenv().ok();
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => ("help", args),
+    };
+    let result = match command {
+        "migrate" => run_migrate(args),
+        "check-config" => run_check_config(args),
+        "create-token" => run_create_token(args),
+        "purge" => run_purge(args),
+        "recompute-event-ids" => run_recompute_event_ids(args),
+        "reindex" => run_reindex(args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(Failure::usage(format!("unknown command: {}", command))),
+    };
+    match result {
+        Ok(()) => EXIT_SUCCESS,
+        Err(failure) => {
+            eprintln!("Error: {:#}", failure.error);
+            if failure.code == EXIT_USAGE {
+                eprintln!("\n{}", USAGE);
+            }
+            failure.code
+        }
+    }
+}
+
+/// The options and the positional arguments of a command.
+#[derive(Default)]
+struct Arguments {
+    flags: Vec<String>,
+    options: Vec<(String, String)>,
+    positional: Vec<String>,
+}
+
+impl Arguments {
+    /// Parses `args`, where `flags` take no value and `options` take one, as
+    /// in `--name value` or `--name=value`.
+    fn parse(args: &[String], flags: &[&str], options: &[&str]) -> Result<Self, Failure> {
+        let mut parsed = Self::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") {
+                parsed.positional.push(arg.clone());
+                continue;
+            }
+            let (name, value) = match arg.find('=') {
+                Some(i) => (&arg[..i], Some(arg[i + 1..].to_string())),
+                None => (arg.as_str(), None),
+            };
+            if flags.contains(&name) && value.is_none() {
+                parsed.flags.push(name.to_string());
+            } else if options.contains(&name) {
+                let value = match value {
+                    Some(value) => value,
+                    None => args
+                        .next()
+                        .cloned()
+                        .ok_or_else(|| Failure::usage(format!("{} needs a value", name)))?,
+                };
+                parsed.options.push((name.to_string(), value));
+            } else {
+                return Err(Failure::usage(format!("unknown option: {}", arg)));
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn no_positional(&self) -> Result<(), Failure> {
+        match self.positional.first() {
+            Some(arg) => Err(Failure::usage(format!("unexpected argument: {}", arg))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Connects to the database at `DATABASE_URL`.
+fn connect() -> Result<Conn, Failure> {
+    let url = env::var("DATABASE_URL")
+        .context("DATABASE_URL is not set")
+        .map_err(|e| Failure::new(EXIT_CONFIG, e))?;
+    let pool = Pool::builder()
+        .max_size(1)
+        .connection_timeout(Duration::from_secs(10))
+        .build(ConnectionManager::<PgConnection>::new(url))
+        .map_err(|e| {
+            Failure::new(
+                EXIT_UNAVAILABLE,
+                anyhow!("could not connect to database: {}", e),
+            )
+        })?;
+    pool.get().map_err(|e| {
+        Failure::new(
+            EXIT_UNAVAILABLE,
+            anyhow!("could not connect to database: {}", e),
+        )
+    })
+}
+
+/// Connects to the database, which must have no pending migrations.
+fn connect_migrated() -> Result<Conn, Failure> {
+    let conn = connect()?;
+    let pending = pending_migrations(&conn)?;
+    if !pending.is_empty() {
+        return Err(Failure::new(
+            EXIT_CONFIG,
+            anyhow!(
+                "the database has {} pending migration(s); run `review migrate` first",
+                pending.len()
+            ),
+        ));
+    }
+    Ok(conn)
+}
+
+fn run_migrate(args: &[String]) -> Result<(), Failure> {
+    let args = Arguments::parse(args, &["--revert"], &["--to"])?;
+    args.no_positional()?;
+    // Accepts the name of a migration directory as well as its version.
+    let version = args
+        .option("--to")
+        .map(|to| to.split('_').next().unwrap_or_default().replace('-', ""));
+    let target = match (&version, args.flag("--revert")) {
+        (Some(_), true) => return Err(Failure::usage("--to and --revert cannot be used together")),
+        (Some(version), false) => MigrationTarget::Version(version),
+        (None, true) => MigrationTarget::RevertLast,
+        (None, false) => MigrationTarget::Latest,
+    };
+    let conn = connect()?;
+    let steps = migrate(&conn, target).map_err(|e| match e {
+        Error::NotFound(_) => Failure::new(
+            EXIT_FAILURE,
+            anyhow!("migration not found in this version of review"),
+        ),
+        e => e.into(),
+    })?;
+    if steps.is_empty() {
+        println!("Nothing to migrate");
+    }
+    for step in steps {
+        match step {
+            MigrationStep::Applied(m) => println!("Applied {}", m.name()),
+            MigrationStep::Reverted(m) => println!("Reverted {}", m.name()),
+        }
+    }
+    Ok(())
+}
+
+/// Checks what the server reads at start, and reports every problem found.
+fn run_check_config(args: &[String]) -> Result<(), Failure> {
+    Arguments::parse(args, &[], &[])?.no_positional()?;
+    let mut problems = Vec::new();
+    if env::var("DATABASE_URL").is_err() {
+        problems.push("DATABASE_URL is not set".to_string());
+    }
+    match env::var("REVIEWD_ADDR") {
+        Ok(addr) => {
+            if addr.parse::<SocketAddr>().is_err() {
+                problems.push(format!("invalid REVIEWD_ADDR: {}", addr));
+            }
+        }
+        Err(_) => problems.push("REVIEWD_ADDR is not set".to_string()),
+    }
+    if let Err(e) = BrokerConfig::from_env() {
+        problems.push(format!("{:#}", e));
+    }
+    match TlsConfig::from_env() {
+        Ok(Some(tls)) => {
+            if let Err(e) = tls
+                .load_certificate()
+                .and_then(|resolver| tls.ingest_config(&resolver))
+            {
+                problems.push(format!("{:#}", e));
+            }
+        }
+        Ok(None) => {}
+        Err(e) => problems.push(format!("{:#}", e)),
+    }
+    // The server falls back to the default value if these are invalid.
+    for name in &[
+        "TASK_TIME_INTERVAL",
+        "RETENTION_INTERVAL",
+        "DATABASE_TIMEOUT",
+    ] {
+        if let Ok(v) = env::var(name) {
+            if v.parse::<u64>().is_err() {
+                problems.push(format!("invalid {}: {}", name, v));
+            }
+        }
+    }
+    for name in &[
+        "MAX_OFFSET_COUNT",
+        "MAX_EVENT_ID_NUM",
+        "DATABASE_MAX_PENDING",
+    ] {
+        if let Ok(v) = env::var(name) {
+            if v.parse::<usize>().is_err() {
+                problems.push(format!("invalid {}: {}", name, v));
+            }
+        }
+    }
+    if let Ok(dir) = env::var("FRONTEND_DIR") {
+        if !Path::new(&dir).is_dir() {
+            problems.push(format!("FRONTEND_DIR is not a directory: {}", dir));
+        }
+    }
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        return Err(Failure::new(
+            EXIT_CONFIG,
+            anyhow!("{} problem(s) found", problems.len()),
+        ));
+    }
+
+    let conn = connect()?;
+    let pending = pending_migrations(&conn)?;
+    if pending.is_empty() {
+        println!("The configuration is valid, and the database is up to date");
+    } else {
+        println!(
+            "The configuration is valid. The database has {} pending migration(s), \
+             which the server applies at start:",
+            pending.len()
+        );
+        for m in pending {
+            println!("  {}", m.name());
+        }
+    }
+    Ok(())
+}
+
+fn run_create_token(args: &[String]) -> Result<(), Failure> {
+    let args = Arguments::parse(args, &[], &[])?;
+    let name = match args.positional.as_slice() {
+        [name] => name,
+        _ => return Err(Failure::usage("create-token needs a NAME")),
+    };
+    let conn = connect_migrated()?;
+    let token = create_api_token(&conn, name).map_err(|e| match e {
+        Error::Query(diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::UniqueViolation,
+            _,
+        )) => Failure::new(
+            EXIT_FAILURE,
+            anyhow!("a token named {} already exists", name),
+        ),
+        e => e.into(),
+    })?;
+    eprintln!("Created the token {}. It cannot be shown again.", name);
+    println!("{}", token);
+    Ok(())
+}
+
+fn run_purge(args: &[String]) -> Result<(), Failure> {
+    let args = Arguments::parse(args, &["--dry-run"], &["--data-source"])?;
+    args.no_positional()?;
+    let data_source = args.option("--data-source");
+    let conn = connect_migrated()?;
+    if let Some(data_source) = data_source {
+        get_data_source_id(&conn, data_source).map_err(|e| match e {
+            Error::Query(diesel::result::Error::NotFound) => Failure::new(
+                EXIT_FAILURE,
+                anyhow!("data source not found: {}", data_source),
+            ),
+            e => e.into(),
+        })?;
+    }
+    let reports = apply_retention_policies(&conn, data_source, args.flag("--dry-run"))?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&reports).map_err(|e| Failure::new(EXIT_FAILURE, e))?
+    );
+    Ok(())
+}
+
+fn run_recompute_event_ids(args: &[String]) -> Result<(), Failure> {
+    let args = Arguments::parse(args, &[], &["--max-event-id-num"])?;
+    args.no_positional()?;
+    let max_event_id_num = match args.option("--max-event-id-num") {
+        Some(v) => v
+            .parse::<usize>()
+            .map_err(|_| Failure::usage(format!("invalid --max-event-id-num: {}", v)))?,
+        None => match env::var("MAX_EVENT_ID_NUM") {
+            Ok(v) => v.parse::<usize>().map_err(|_| {
+                Failure::new(EXIT_CONFIG, anyhow!("invalid MAX_EVENT_ID_NUM: {}", v))
+            })?,
+            Err(_) => 25,
+        },
+    };
+    let conn = connect_migrated()?;
+    update_event_ids(&conn, max_event_id_num)
+        .map_err(|e| Failure::new(EXIT_FAILURE, anyhow!(e)))?;
+    println!(
+        "Capped the event_ids of clusters and outliers at {}",
+        max_event_id_num
+    );
+    Ok(())
+}
+
+fn run_reindex(args: &[String]) -> Result<(), Failure> {
+    let args = Arguments::parse(args, &[], &[])?;
+    let conn = connect()?;
+    let tables = reindex_tables(&conn, &args.positional).map_err(|e| match e {
+        Error::NotFound(_) => Failure::new(
+            EXIT_FAILURE,
+            anyhow!("table not found: {}", args.positional.join(", ")),
+        ),
+        e => e.into(),
+    })?;
+    for table in tables {
+        println!("Reindexed {}", table);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_string()).collect()
+    }
+
+    fn parse(args: &[&str]) -> Result<Arguments, Failure> {
+        Arguments::parse(&strings(args), &["--dry-run"], &["--to", "--data-source"])
+    }
+
+    fn parsed(args: &[&str]) -> Arguments {
+        match parse(args) {
+            Ok(args) => args,
+            Err(failure) => panic!("{:#}", failure.error),
+        }
+    }
+
+    fn usage_error(result: Result<Arguments, Failure>) -> String {
+        match result {
+            Ok(_) => panic!("parsed an invalid command line"),
+            Err(failure) => {
+                assert_eq!(failure.code, EXIT_USAGE);
+                failure.error.to_string()
+            }
+        }
+    }
+
+    #[test]
+    fn flags_options_and_positional() {
+        let args = parsed(&["a", "--dry-run", "--to", "1", "b", "--data-source=x=y"]);
+        assert!(args.flag("--dry-run"));
+        assert_eq!(args.option("--to"), Some("1"));
+        assert_eq!(args.option("--data-source"), Some("x=y"));
+        assert_eq!(args.positional, strings(&["a", "b"]));
+        assert!(args.no_positional().is_err());
+
+        let args = parsed(&["--to=1", "--to", "2"]);
+        assert!(!args.flag("--dry-run"));
+        assert_eq!(args.option("--to"), Some("2"));
+        assert_eq!(args.option("--data-source"), None);
+        assert!(args.no_positional().is_ok());
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        assert_eq!(usage_error(parse(&["--force"])), "unknown option: --force");
+        assert_eq!(
+            usage_error(parse(&["--dry-run=yes"])),
+            "unknown option: --dry-run=yes"
+        );
+        assert_eq!(usage_error(parse(&["--to"])), "--to needs a value");
+    }
+
+    #[test]
+    fn exit_codes_of_database_errors() {
+        let unavailable = Error::Connection(diesel::ConnectionError::BadConnection(
+            "refused".to_string(),
+        ));
+        assert_eq!(Failure::from(unavailable).code, EXIT_UNAVAILABLE);
+        assert_eq!(Failure::from(Error::Saturated).code, EXIT_FAILURE);
+        assert_eq!(Failure::from(Error::NotFound("table")).code, EXIT_FAILURE);
+    }
+
+    #[test]
+    fn exit_codes_of_command_lines() {
+        assert_eq!(run(&[]), EXIT_SUCCESS);
+        assert_eq!(run(&strings(&["help"])), EXIT_SUCCESS);
+        assert_eq!(run(&strings(&["--help"])), EXIT_SUCCESS);
+        // These fail before connecting to the database.
+        for args in &[
+            &["serve"][..],
+            &["migrate", "--to", "1", "--revert"],
+            &["migrate", "--force"],
+            &["check-config", "extra"],
+            &["create-token"],
+            &["create-token", "a", "b"],
+            &["purge", "--data-source"],
+            &["recompute-event-ids", "--max-event-id-num", "many"],
+        ] {
+            assert_eq!(run(&strings(args)), EXIT_USAGE, "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use diesel::result::DatabaseErrorKind;
+use serde::Serialize;
+use std::fmt;
+
+use crate::database::Error;
+
+/// An error returned by the API, serialized as
+/// `{code, message, field, request_id}`.
+#[derive(Clone, Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    body: ApiErrorBody,
+    /// What went wrong, for the log. Not sent to the client.
+    detail: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ApiErrorBody {
+    code: &'static str,
+    message: String,
+    field: Option<String>,
+    request_id: Option<String>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: String) -> Self {
+        Self {
+            status,
+            body: ApiErrorBody {
+                code,
+                message,
+                field: None,
+                request_id: None,
+            },
+            detail: None,
+        }
+    }
+
+    pub(crate) fn bad_request<T: Into<String>>(message: T) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "bad_request", message.into())
+    }
+
+    /// An invalid value of the query parameter or the body field `field`.
+    pub(crate) fn invalid<T: Into<String>>(field: &str, message: T) -> Self {
+        Self::bad_request(message).field(field)
+    }
+
+    /// A request that cannot be parsed. The field is taken from the first name
+    /// quoted in backticks, as in "missing field `data_source`".
+    pub(crate) fn unparsable<T: fmt::Display>(e: T) -> Self {
+        let message = e.to_string();
+        let mut quoted = message.split('`').skip(1);
+        let field = match (quoted.next(), quoted.next()) {
+            (Some(field), Some(_)) => Some(field.to_string()),
+            _ => None,
+        };
+        let mut error = Self::bad_request(message);
+        error.body.field = field;
+        error
+    }
+
+    pub(crate) fn unauthorized<T: Into<String>>(message: T) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, "unauthorized", message.into())
+    }
+
+    pub(crate) fn forbidden<T: Into<String>>(message: T) -> Self {
+        Self::new(StatusCode::FORBIDDEN, "forbidden", message.into())
+    }
+
+    /// `what` does not exist.
+    pub(crate) fn not_found(what: &str) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("{} not found", what),
+        )
+    }
+
+    pub(crate) fn conflict<T: Into<String>>(message: T) -> Self {
+        Self::new(StatusCode::CONFLICT, "conflict", message.into())
+    }
+
+    /// Maps an error from the database to the status the client should see.
+    /// The details of unexpected errors are only logged.
+    pub(crate) fn from_error(e: &(dyn std::error::Error + 'static)) -> Self {
+        let error = match e.downcast_ref::<Error>() {
+            Some(Error::NotFound(what)) => return Self::not_found(what),
+            Some(Error::Query(diesel::result::Error::NotFound)) => {
+                return Self::not_found("record")
+            }
+            Some(Error::Query(diesel::result::Error::DatabaseError(
+                DatabaseErrorKind::UniqueViolation,
+                _,
+            ))) => Self::conflict("a record with the same key already exists"),
+            Some(Error::Query(diesel::result::Error::DatabaseError(
+                DatabaseErrorKind::ForeignKeyViolation,
+                _,
+            ))) => Self::conflict("the record is referenced by, or refers to, another record"),
+            Some(Error::Saturated) | Some(Error::Timeout) => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                e.to_string(),
+            ),
+            _ => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "internal server error".to_string(),
+            ),
+        };
+        Self {
+            detail: Some(e.to_string()),
+            ..error
+        }
+    }
+
+    #[must_use]
+    pub(crate) fn field(mut self, field: &str) -> Self {
+        self.body.field = Some(field.to_string());
+        self
+    }
+
+    pub(crate) fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    pub(crate) fn code(&self) -> &'static str {
+        self.body.code
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        &self.body.message
+    }
+
+    pub(crate) fn field_name(&self) -> Option<&str> {
+        self.body.field.as_deref()
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Returns the JSON body with `request_id`.
+    pub(crate) fn body_with_request_id(&self, request_id: &str) -> String {
+        let body = ApiErrorBody {
+            request_id: Some(request_id.to_string()),
+            ..self.body.clone()
+        };
+        serde_json::to_string(&body).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.body.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Builds the response without `request_id`. The error is kept in the
+    /// extensions of the response, so that the request id can be added later.
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status).json(&self.body);
+        response.extensions_mut().insert(self.clone());
+        response
+    }
+}
+
+impl From<ApiError> for HttpResponse {
+    fn from(e: ApiError) -> Self {
+        e.error_response()
+    }
+}
@@ -0,0 +1,44 @@
+use diesel::prelude::*;
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::HashSet;
+
+use super::schema::api_token;
+use crate::database::{Conn, Error};
+
+/// The number of random bytes in a token.
+const TOKEN_LEN: usize = 32;
+
+/// Creates a token named `name` and returns it. Only the hash of the token is
+/// stored, so it cannot be shown again.
+///
+/// # Errors
+///
+/// Returns an error if `name` is already used, or if the database fails.
+pub(crate) fn create_api_token(conn: &Conn, name: &str) -> Result<String, Error> {
+    let mut bytes = [0_u8; TOKEN_LEN];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Random)?;
+    let token = base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD);
+    diesel::insert_into(api_token::table)
+        .values((
+            api_token::name.eq(name),
+            api_token::token_hash.eq(hash_api_token(&token)),
+        ))
+        .execute(conn)?;
+    Ok(token)
+}
+
+/// Returns the hashes of all tokens.
+pub(crate) fn load_api_token_hashes(conn: &Conn) -> Result<HashSet<Vec<u8>>, Error> {
+    Ok(api_token::table
+        .select(api_token::token_hash)
+        .load::<Vec<u8>>(conn)?
+        .into_iter()
+        .collect())
+}
+
+pub(crate) fn hash_api_token(token: &str) -> Vec<u8> {
+    digest(&SHA256, token.as_bytes()).as_ref().to_vec()
+}
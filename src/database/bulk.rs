@@ -0,0 +1,278 @@
+use actix_web::{http::StatusCode, HttpResponse};
+use diesel::Connection;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::database::{ApiError, Conn, Error};
+
+/// Query of the endpoints that change many items at once.
+#[derive(Debug, Deserialize)]
+pub(crate) struct BulkQuery {
+    /// Applies all items or none of them.
+    atomic: Option<bool>,
+}
+
+impl BulkQuery {
+    pub(crate) fn is_atomic(&self) -> bool {
+        self.atomic.unwrap_or(false)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ItemStatus {
+    Ok,
+    Failed,
+    RolledBack,
+}
+
+/// Why an item failed, as the `code`, `message`, and `field` of an `ApiError`.
+#[derive(Debug, Serialize)]
+struct ItemError {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ItemResult {
+    index: usize,
+    status: ItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ItemError>,
+}
+
+/// The outcome of each item in a request, in the order the items were given.
+#[derive(Debug)]
+pub(crate) struct BulkResults {
+    items: Vec<ItemResult>,
+    atomic: bool,
+}
+
+impl BulkResults {
+    pub(crate) fn new(len: usize, atomic: bool) -> Self {
+        Self {
+            items: (0..len)
+                .map(|index| ItemResult {
+                    index,
+                    status: ItemStatus::Ok,
+                    error: None,
+                })
+                .collect(),
+            atomic,
+        }
+    }
+
+    /// Marks the item at `index` as failed with `error`. The detail of the
+    /// error is logged, not sent.
+    pub(crate) fn fail(&mut self, index: usize, error: &ApiError) {
+        if let Some(detail) = error.detail() {
+            info!("item {} failed: {}", index, detail);
+        }
+        if let Some(item) = self.items.get_mut(index) {
+            item.status = ItemStatus::Failed;
+            item.error = Some(ItemError {
+                code: error.code(),
+                message: error.message().to_string(),
+                field: error.field_name().map(str::to_string),
+            });
+        }
+    }
+
+    /// Applies the items that have not failed yet, in order, in a single
+    /// transaction.
+    ///
+    /// `apply` is called with the indices of the items to apply. If it fails,
+    /// the items are split in halves and retried, until every failing item is
+    /// found and marked as failed with its error. A statement that timed out
+    /// or was canceled is not retried; the items not applied yet are marked as
+    /// failed with its error instead. In atomic mode, the transaction is
+    /// rolled back if any item failed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the transaction cannot be started or committed.
+    pub(crate) fn apply<F>(&mut self, conn: &Conn, mut apply: F) -> Result<(), Error>
+    where
+        F: FnMut(&[usize]) -> Result<(), Error>,
+    {
+        let pending = self
+            .items
+            .iter()
+            .filter(|item| item.status == ItemStatus::Ok)
+            .map(|item| item.index)
+            .collect::<Vec<_>>();
+        let atomic = self.atomic;
+        let outcome = conn.transaction::<(), Error, _>(|| {
+            self.bisect(conn, &pending, &mut apply);
+            if atomic && self.failed() > 0 {
+                Err(diesel::result::Error::RollbackTransaction.into())
+            } else {
+                Ok(())
+            }
+        });
+        match outcome {
+            Err(Error::Query(diesel::result::Error::RollbackTransaction)) => {
+                for item in &mut self.items {
+                    if item.status == ItemStatus::Ok {
+                        item.status = ItemStatus::RolledBack;
+                    }
+                }
+                Ok(())
+            }
+            outcome => outcome,
+        }
+    }
+
+    /// Applies the items at `indices`, bisecting them to find the ones that
+    /// fail. If a statement times out or is canceled, marks the items at
+    /// `indices` not applied yet as unavailable, and returns that error.
+    fn bisect<F>(&mut self, conn: &Conn, indices: &[usize], apply: &mut F) -> Option<ApiError>
+    where
+        F: FnMut(&[usize]) -> Result<(), Error>,
+    {
+        if indices.is_empty() {
+            return None;
+        }
+        let e = match conn.transaction::<(), Error, _>(|| apply(indices)) {
+            Ok(()) => return None,
+            Err(e) => e,
+        };
+        if is_canceled(&e) {
+            let error = ApiError::from_error(&Error::Timeout);
+            for &index in indices {
+                self.fail(index, &error);
+            }
+            return Some(error);
+        }
+        if let [index] = indices {
+            self.fail(*index, &ApiError::from_error(&e));
+            return None;
+        }
+        let (left, right) = indices.split_at(indices.len() / 2);
+        if let Some(error) = self.bisect(conn, left, apply) {
+            for &index in right {
+                self.fail(index, &error);
+            }
+            return Some(error);
+        }
+        self.bisect(conn, right, apply)
+    }
+
+    fn count(&self, status: ItemStatus) -> usize {
+        self.items
+            .iter()
+            .filter(|item| item.status == status)
+            .count()
+    }
+
+    fn failed(&self) -> usize {
+        self.count(ItemStatus::Failed)
+    }
+
+    /// Builds a response with the outcome of every item. The status is 200 if
+    /// all items succeeded, 400 if an atomic request was rolled back, and 207
+    /// otherwise.
+    pub(crate) fn into_response(self) -> HttpResponse {
+        let failed = self.failed();
+        let status = if failed == 0 {
+            StatusCode::OK
+        } else if self.atomic {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::MULTI_STATUS
+        };
+        HttpResponse::build(status).json(json!({
+            "succeeded": self.count(ItemStatus::Ok),
+            "failed": failed,
+            "results": self.items,
+        }))
+    }
+}
+
+/// Returns true if `e` is a timeout or a cancellation, which would only happen
+/// again if the items were retried.
+fn is_canceled(e: &Error) -> bool {
+    match e {
+        Error::Canceled | Error::Timeout => true,
+        // diesel does not expose SQLSTATE, so `query_canceled` (57014), raised
+        // by `statement_timeout` and by cancel requests, is told by its message.
+        Error::Query(diesel::result::Error::DatabaseError(_, info)) => {
+            info.message().starts_with("canceling statement due to")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+
+    use super::*;
+    use crate::database::test_conn;
+
+    fn failures(results: &BulkResults) -> Vec<(usize, &'static str)> {
+        results
+            .items
+            .iter()
+            .filter_map(|item| item.error.as_ref().map(|error| (item.index, error.code)))
+            .collect()
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn bisect_finds_failing_items() {
+        let conn = test_conn();
+        let mut results = BulkResults::new(8, false);
+        results
+            .apply(&conn, |indices| {
+                if indices.contains(&2) {
+                    return Err(Error::NotFound("cluster"));
+                }
+                if indices.contains(&5) {
+                    diesel::sql_query("SELECT * FROM no_such_table").execute(&conn)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(failures(&results), vec![(2, "not_found"), (5, "internal")]);
+        let internal = results.items[5].error.as_ref().unwrap();
+        assert_eq!(internal.message, "internal server error");
+        assert_eq!(results.count(ItemStatus::Ok), 6);
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn bisect_stops_at_timeout() {
+        let conn = test_conn();
+        diesel::sql_query("SET LOCAL statement_timeout = 50")
+            .execute(&conn)
+            .unwrap();
+        let mut results = BulkResults::new(8, false);
+        let mut calls = 0;
+        results
+            .apply(&conn, |indices| {
+                calls += 1;
+                if indices.contains(&5) {
+                    diesel::sql_query("SELECT pg_sleep(1)").execute(&conn)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(calls, 1);
+        assert_eq!(results.failed(), 8);
+        assert!(failures(&results)
+            .iter()
+            .all(|(_, code)| *code == "unavailable"));
+    }
+
+    #[test]
+    fn timeout_and_cancellation_are_not_retried() {
+        assert!(is_canceled(&Error::Timeout));
+        assert!(is_canceled(&Error::Canceled));
+        assert!(!is_canceled(&Error::NotFound("cluster")));
+        assert!(!is_canceled(&Error::Query(diesel::result::Error::NotFound)));
+    }
+}
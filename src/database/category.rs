@@ -4,73 +4,368 @@ use actix_web::{
     HttpResponse,
 };
 use diesel::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
-use super::schema::category;
-use crate::database::{build_http_500_response, Error, Pool};
+use super::schema::{category, cluster};
+use crate::database::{build_error_response, ApiError, Conn, Database, Error};
+
+/// The id of `Non-Specified Alert`, the category a cluster belongs to unless
+/// specified otherwise.
+const DEFAULT_CATEGORY_ID: i32 = 1;
 
 #[derive(Debug, Identifiable, Insertable, Queryable, Serialize)]
 #[table_name = "category"]
 struct CategoryTable {
     id: i32,
     name: String,
+    parent_id: Option<i32>,
 }
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct NewCategory {
     category: String,
+    parent: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CategoryUpdate {
+    category: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    parent: Option<Option<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CategoryDeleteQuery {
+    replacement: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CategoryMerge {
+    categories: Vec<String>,
+    into: String,
+}
+
+enum Outcome {
+    Changed,
+    NotFound,
+    Invalid(&'static str),
+    /// An invalid value of a query parameter or a body field, with its name.
+    InvalidField(&'static str, &'static str),
+}
+
+// Distinguishes `"parent": null` from a missing `parent`.
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
+fn find_category(conn: &Conn, name: &str) -> Result<Option<(i32, Option<i32>)>, Error> {
+    use category::dsl;
+    dsl::category
+        .filter(dsl::name.eq(name))
+        .select((dsl::id, dsl::parent_id))
+        .first::<(i32, Option<i32>)>(conn)
+        .optional()
+        .map_err(Into::into)
+}
+
+/// Returns true if `id` is `ancestor` or one of its subcategories.
+fn is_subcategory(conn: &Conn, id: i32, ancestor: i32) -> Result<bool, Error> {
+    use category::dsl;
+    let mut current = Some(id);
+    while let Some(c) = current {
+        if c == ancestor {
+            return Ok(true);
+        }
+        current = dsl::category
+            .filter(dsl::id.eq(c))
+            .select(dsl::parent_id)
+            .first::<Option<i32>>(conn)
+            .optional()?
+            .and_then(|p| p);
+    }
+    Ok(false)
+}
+
+/// Builds a condition that is true when a cluster belongs to the category
+/// `name` or, if `include_subcategories` is set, to any of its subcategories.
+pub(crate) fn category_condition(name: &str, include_subcategories: bool) -> String {
+    let name = name.replace('\'', "''");
+    if include_subcategories {
+        format!(
+            "Cluster.category_id IN (WITH RECURSIVE sub(id) AS (\
+             SELECT id FROM category WHERE name = '{}' \
+             UNION SELECT category.id FROM category INNER JOIN sub ON category.parent_id = sub.id\
+             ) SELECT id FROM sub)",
+            name
+        )
+    } else {
+        format!(
+            "Cluster.category_id = (SELECT id FROM category WHERE name = '{}')",
+            name
+        )
+    }
+}
+
+fn build_outcome_response(outcome: Result<Outcome, Error>, success: HttpResponse) -> HttpResponse {
+    match outcome {
+        Ok(Outcome::Changed) => success,
+        Ok(Outcome::NotFound) => ApiError::not_found("category").into(),
+        Ok(Outcome::Invalid(message)) => ApiError::bad_request(message).into(),
+        Ok(Outcome::InvalidField(field, message)) => ApiError::invalid(field, message).into(),
+        Err(e) => build_error_response(&e),
+    }
 }
 
 pub(crate) async fn add_category(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     new_category: Query<NewCategory>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use category::dsl;
-    let insert_result: Result<usize, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        diesel::insert_into(dsl::category)
-            .values(dsl::name.eq(&new_category.into_inner().category))
-            .execute(&conn)
-            .map_err(Into::into)
-    });
-
-    match insert_result {
-        Ok(_) => Ok(HttpResponse::Created().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
-    }
+    let new_category = new_category.into_inner();
+    let insert_result: Result<Outcome, Error> = pool
+        .run(move |conn| {
+            let parent_id = if let Some(parent) = &new_category.parent {
+                match find_category(conn, parent)? {
+                    Some((id, _)) => Some(id),
+                    None => return Ok(Outcome::InvalidField("parent", "Unknown parent category")),
+                }
+            } else {
+                None
+            };
+            diesel::insert_into(dsl::category)
+                .values((
+                    dsl::name.eq(&new_category.category),
+                    dsl::parent_id.eq(parent_id),
+                ))
+                .execute(conn)?;
+            Ok(Outcome::Changed)
+        })
+        .await;
+
+    Ok(build_outcome_response(
+        insert_result,
+        HttpResponse::Created().into(),
+    ))
 }
 
-pub(crate) async fn get_category_table(pool: Data<Pool>) -> Result<HttpResponse, actix_web::Error> {
-    let query_result: Result<Vec<CategoryTable>, Error> =
-        pool.get().map_err(Into::into).and_then(|conn| {
+pub(crate) async fn delete_category(
+    pool: Data<Database>,
+    category: Path<String>,
+    query: Query<CategoryDeleteQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let delete_result: Result<Outcome, Error> = pool
+        .run(move |conn| {
+            conn.transaction::<Outcome, Error, _>(|| {
+                let (id, parent_id) = match find_category(conn, &category)? {
+                    Some(c) => c,
+                    None => return Ok(Outcome::NotFound),
+                };
+                if id == DEFAULT_CATEGORY_ID {
+                    return Ok(Outcome::Invalid("The default category cannot be deleted"));
+                }
+                let replacement_id = if let Some(replacement) = &query.replacement {
+                    match find_category(conn, replacement)? {
+                        Some((replacement_id, _)) => replacement_id,
+                        None => {
+                            return Ok(Outcome::InvalidField(
+                                "replacement",
+                                "Unknown replacement category",
+                            ))
+                        }
+                    }
+                } else {
+                    DEFAULT_CATEGORY_ID
+                };
+                if replacement_id == id {
+                    return Ok(Outcome::InvalidField(
+                        "replacement",
+                        "A category cannot be replaced with itself",
+                    ));
+                }
+                remove_category(conn, id, parent_id, replacement_id)?;
+                Ok(Outcome::Changed)
+            })
+        })
+        .await;
+
+    Ok(build_outcome_response(
+        delete_result,
+        HttpResponse::Ok().into(),
+    ))
+}
+
+/// Moves the clusters in category `id` to `replacement_id`, attaches the
+/// subcategories of `id` to its parent, and deletes `id`.
+fn remove_category(
+    conn: &Conn,
+    id: i32,
+    parent_id: Option<i32>,
+    replacement_id: i32,
+) -> Result<(), Error> {
+    use category::dsl as ca_d;
+    use cluster::dsl as cl_d;
+    diesel::update(cl_d::cluster.filter(cl_d::category_id.eq(id)))
+        .set((
+            cl_d::category_id.eq(replacement_id),
+            cl_d::last_modification_time.eq(diesel::dsl::now),
+        ))
+        .execute(conn)?;
+    diesel::update(ca_d::category.filter(ca_d::parent_id.eq(id)))
+        .set(ca_d::parent_id.eq(parent_id))
+        .execute(conn)?;
+    diesel::delete(ca_d::category.filter(ca_d::id.eq(id))).execute(conn)?;
+    Ok(())
+}
+
+pub(crate) async fn get_category_table(
+    pool: Data<Database>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query_result: Result<Vec<CategoryTable>, Error> = pool
+        .run(move |conn| {
             category::dsl::category
-                .load::<CategoryTable>(&conn)
+                .load::<CategoryTable>(conn)
                 .map_err(Into::into)
-        });
+        })
+        .await;
 
     match query_result {
         Ok(category) => Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "application/json")
             .json(category)),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
+pub(crate) async fn merge_categories(
+    pool: Data<Database>,
+    merge: Json<CategoryMerge>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let merge = merge.into_inner();
+    let merge_result: Result<Outcome, Error> = pool
+        .run(move |conn| {
+            use category::dsl;
+            conn.transaction::<Outcome, Error, _>(|| {
+                let into_id = match find_category(conn, &merge.into)? {
+                    Some((id, _)) => id,
+                    None => return Ok(Outcome::NotFound),
+                };
+                let mut sources = Vec::with_capacity(merge.categories.len());
+                for name in &merge.categories {
+                    let id = match find_category(conn, name)? {
+                        Some((id, _)) => id,
+                        None => return Ok(Outcome::NotFound),
+                    };
+                    if id == into_id {
+                        continue;
+                    }
+                    if id == DEFAULT_CATEGORY_ID {
+                        return Ok(Outcome::Invalid(
+                            "The default category cannot be merged into another",
+                        ));
+                    }
+                    sources.push(id);
+                }
+                sources.sort_unstable();
+                sources.dedup();
+                for id in sources {
+                    let parent_id = dsl::category
+                        .filter(dsl::id.eq(id))
+                        .select(dsl::parent_id)
+                        .first::<Option<i32>>(conn)?;
+                    // If the target is a subcategory of the merged one, it takes
+                    // over the merged category's place in the hierarchy.
+                    if is_subcategory(conn, into_id, id)? {
+                        diesel::update(dsl::category.filter(dsl::id.eq(into_id)))
+                            .set(dsl::parent_id.eq(parent_id))
+                            .execute(conn)?;
+                    }
+                    remove_category(conn, id, Some(into_id), into_id)?;
+                }
+                Ok(Outcome::Changed)
+            })
+        })
+        .await;
+
+    Ok(build_outcome_response(
+        merge_result,
+        HttpResponse::Ok().into(),
+    ))
+}
+
 pub(crate) async fn update_category(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     current_category: Path<String>,
-    new_category: Json<NewCategory>,
+    new_category: Json<CategoryUpdate>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use category::dsl;
-    let update_result: Result<usize, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        diesel::update(dsl::category)
-            .filter(dsl::name.eq(&current_category.into_inner()))
-            .set(dsl::name.eq(new_category.into_inner().category))
-            .execute(&conn)
-            .map_err(Into::into)
-    });
-
-    match update_result {
-        Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+    let new_category = new_category.into_inner();
+    if new_category.category.is_none() && new_category.parent.is_none() {
+        return Ok(ApiError::bad_request("Either category or parent must be given").into());
+    }
+    let update_result: Result<Outcome, Error> = pool
+        .run(move |conn| {
+            conn.transaction::<Outcome, Error, _>(|| {
+                let id = match find_category(conn, &current_category)? {
+                    Some((id, _)) => id,
+                    None => return Ok(Outcome::NotFound),
+                };
+                if let Some(parent) = &new_category.parent {
+                    let parent_id = if let Some(parent) = parent {
+                        match find_category(conn, parent)? {
+                            Some((parent_id, _)) => {
+                                if is_subcategory(conn, parent_id, id)? {
+                                    return Ok(Outcome::InvalidField(
+                                        "parent",
+                                        "A category cannot be a subcategory of itself",
+                                    ));
+                                }
+                                Some(parent_id)
+                            }
+                            None => {
+                                return Ok(Outcome::InvalidField(
+                                    "parent",
+                                    "Unknown parent category",
+                                ))
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    diesel::update(dsl::category.filter(dsl::id.eq(id)))
+                        .set(dsl::parent_id.eq(parent_id))
+                        .execute(conn)?;
+                }
+                if let Some(name) = &new_category.category {
+                    diesel::update(dsl::category.filter(dsl::id.eq(id)))
+                        .set(dsl::name.eq(name))
+                        .execute(conn)?;
+                }
+                Ok(Outcome::Changed)
+            })
+        })
+        .await;
+
+    Ok(build_outcome_response(
+        update_result,
+        HttpResponse::Ok().into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::category_condition;
+
+    #[test]
+    fn category_condition_escapes_quotes() {
+        let name = "x' OR '1'='1";
+        for include_subcategories in &[false, true] {
+            let condition = category_condition(name, *include_subcategories);
+            assert!(condition.contains("name = 'x'' OR ''1''=''1'"));
+            assert_eq!(condition.matches('\'').count() % 2, 0);
+        }
     }
 }
@@ -0,0 +1,401 @@
+use actix_web::{
+    http,
+    web::{Bytes, Data, Query},
+    HttpRequest, HttpResponse,
+};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use futures::channel::mpsc;
+use futures::{future, stream, StreamExt};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::schema::{change_event, data_source};
+use crate::database::{build_error_response, ApiError, Conn, Database, Error, Pool};
+
+/// How often `change_event` is read for new changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The maximum number of changes read from `change_event` at a time.
+const POLL_LIMIT: i64 = 1_000;
+
+/// How often idle streams get a comment, which keeps proxies from closing
+/// them and finds the clients that have gone.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Keeps two servers from giving positions to changes at the same time.
+const PUBLISH_LOCK: &str = "SELECT pg_advisory_xact_lock(hashtext('change_event_position'))";
+
+/// Gives positions to up to `$1` changes, in the order of their transaction
+/// ids. A change gets its position once its transaction, and every transaction
+/// that started before it, has ended, so that no change can appear later
+/// before a position already given. Changes of rolled back transactions never
+/// appear, and do not hold back the others.
+const PUBLISH_CHANGES: &str = "WITH ready AS ( \
+                               SELECT id, ROW_NUMBER() OVER (ORDER BY tx_id, id) AS n \
+                               FROM change_event \
+                               WHERE position IS NULL \
+                               AND tx_id < txid_snapshot_xmin(txid_current_snapshot()) \
+                               ORDER BY tx_id, id \
+                               LIMIT $1 \
+                               ), base AS ( \
+                               SELECT setval('change_event_position_seq', \
+                               nextval('change_event_position_seq') + COUNT(*) - 1) \
+                               - COUNT(*) AS position \
+                               FROM ready \
+                               HAVING COUNT(*) > 0 \
+                               ) \
+                               UPDATE change_event \
+                               SET position = base.position + ready.n \
+                               FROM ready, base \
+                               WHERE change_event.id = ready.id";
+
+/// How long changes are kept to be replayed.
+const CHANGE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often the changes older than `CHANGE_RETENTION` are deleted.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of changes replayed to a client. A client that missed
+/// more than this gets a `reset` event instead.
+const MAX_REPLAY: i64 = 10_000;
+
+/// The number of messages queued for a client. A client that falls further
+/// behind is disconnected, and has to reconnect to replay what it missed.
+const CLIENT_BUFFER: usize = 4_096;
+
+/// A change committed to a cluster, an outlier, or an indicator, recorded by
+/// the triggers on those tables. `id` is the position of the change.
+#[derive(Debug, Serialize)]
+pub(crate) struct ChangeEvent {
+    id: i64,
+    data_source_id: i32,
+    kind: String,
+    payload: Value,
+    created_at: DateTime<Utc>,
+}
+
+impl ChangeEvent {
+    /// Formats the change as a server-sent event.
+    fn to_message(&self) -> Bytes {
+        let data = serde_json::to_string(self).unwrap_or_default();
+        Bytes::from(format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id, self.kind, data
+        ))
+    }
+}
+
+/// A client of `GET /api/change`, and the data sources it wants changes of.
+struct Client {
+    data_sources: Option<HashSet<i32>>,
+    sender: mpsc::Sender<(i64, Bytes)>,
+}
+
+/// Sends the changes read from `change_event` to the clients of
+/// `GET /api/change`. Each message comes with the id of its change, or 0 if it
+/// is not a change.
+#[derive(Clone, Default)]
+pub(crate) struct ChangeBroadcaster {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+impl ChangeBroadcaster {
+    fn subscribe(&self, data_sources: Option<HashSet<i32>>) -> mpsc::Receiver<(i64, Bytes)> {
+        let (sender, receiver) = mpsc::channel(CLIENT_BUFFER);
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.push(Client {
+                data_sources,
+                sender,
+            });
+        }
+        receiver
+    }
+
+    /// Sends `changes` to the clients that want them, and drops the clients
+    /// that have gone or fallen behind.
+    fn send(&self, changes: &[ChangeEvent]) {
+        let messages = changes
+            .iter()
+            .map(|change| (change.id, change.data_source_id, change.to_message()))
+            .collect::<Vec<_>>();
+        self.retain(|client| {
+            let Client {
+                data_sources,
+                sender,
+            } = client;
+            messages
+                .iter()
+                .filter(|(_, data_source_id, _)| {
+                    data_sources
+                        .as_ref()
+                        .map_or(true, |data_sources| data_sources.contains(data_source_id))
+                })
+                .all(|(id, _, message)| sender.try_send((*id, message.clone())).is_ok())
+        });
+    }
+
+    fn keep_alive(&self) {
+        self.retain(|client| {
+            client
+                .sender
+                .try_send((0, Bytes::from_static(b": keep-alive\n\n")))
+                .is_ok()
+        });
+    }
+
+    fn retain<F>(&self, mut f: F)
+    where
+        F: FnMut(&mut Client) -> bool,
+    {
+        if let Ok(mut clients) = self.clients.lock() {
+            let kept = clients
+                .drain(..)
+                .filter_map(|mut client| if f(&mut client) { Some(client) } else { None })
+                .collect();
+            *clients = kept;
+        }
+    }
+}
+
+/// Gives positions to the changes ready to be sent. Returns the number of
+/// changes given one.
+fn publish_changes(conn: &Conn) -> Result<usize, Error> {
+    conn.transaction::<usize, Error, _>(|| {
+        diesel::sql_query(PUBLISH_LOCK).execute(conn)?;
+        diesel::sql_query(PUBLISH_CHANGES)
+            .bind::<BigInt, _>(POLL_LIMIT)
+            .execute(conn)
+            .map_err(Into::into)
+    })
+}
+
+/// Returns up to `limit` changes after the position `last_id`, of
+/// `data_sources` if given.
+fn load_changes_after(
+    conn: &Conn,
+    last_id: i64,
+    data_sources: Option<&HashSet<i32>>,
+    limit: i64,
+) -> Result<Vec<ChangeEvent>, Error> {
+    use change_event::dsl;
+    let mut query = dsl::change_event
+        .select((
+            dsl::position,
+            dsl::data_source_id,
+            dsl::kind,
+            dsl::payload,
+            dsl::created_at,
+        ))
+        .filter(dsl::position.gt(last_id))
+        // The changes of archived data sources are skipped, as they are in the
+        // listings.
+        .filter(
+            dsl::data_source_id.eq_any(
+                data_source::table
+                    .select(data_source::id)
+                    .filter(data_source::archived.eq(false)),
+            ),
+        )
+        .order_by(dsl::position)
+        .limit(limit)
+        .into_boxed();
+    if let Some(data_sources) = data_sources {
+        query = query
+            .filter(dsl::data_source_id.eq_any(data_sources.iter().copied().collect::<Vec<_>>()));
+    }
+    let changes = query.load::<(Option<i64>, i32, String, Value, DateTime<Utc>)>(conn)?;
+    Ok(changes
+        .into_iter()
+        .map(
+            |(position, data_source_id, kind, payload, created_at)| ChangeEvent {
+                id: position.unwrap_or_default(),
+                data_source_id,
+                kind,
+                payload,
+                created_at,
+            },
+        )
+        .collect())
+}
+
+fn purge_changes(conn: &Conn) -> Result<usize, Error> {
+    use change_event::dsl;
+    let retention =
+        chrono::Duration::from_std(CHANGE_RETENTION).unwrap_or_else(|_| chrono::Duration::zero());
+    diesel::delete(dsl::change_event.filter(dsl::created_at.lt(Utc::now() - retention)))
+        .execute(conn)
+        .map_err(Into::into)
+}
+
+/// Gives positions to the changes committed since the last call, and sends the
+/// changes after the position `last_id` in order. Returns the position of the
+/// last change sent.
+fn broadcast_changes(
+    conn: &Conn,
+    broadcaster: &ChangeBroadcaster,
+    mut last_id: i64,
+) -> Result<i64, Error> {
+    loop {
+        let published = publish_changes(conn)?;
+        let changes = load_changes_after(conn, last_id, None, POLL_LIMIT)?;
+        if let Some(change) = changes.last() {
+            last_id = change.id;
+        }
+        broadcaster.send(&changes);
+        let below_limit = |n: usize| i64::try_from(n).map_or(false, |n| n < POLL_LIMIT);
+        if below_limit(published) && below_limit(changes.len()) {
+            return Ok(last_id);
+        }
+    }
+}
+
+/// Sends the changes recorded in `change_event` to the clients of
+/// `GET /api/change` as they are committed, and deletes old changes.
+pub(crate) fn periodically_broadcast_changes(pool: &Pool, broadcaster: &ChangeBroadcaster) {
+    info!(
+        "Starting change broadcast with time interval {} ms",
+        POLL_INTERVAL.as_millis()
+    );
+    let mut last_id = None;
+    let mut last_keepalive = Instant::now();
+    let mut last_purge: Option<Instant> = None;
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let conn = match pool.get() {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to broadcast changes: {}", e);
+                continue;
+            }
+        };
+        let start = match last_id {
+            Some(id) => id,
+            None => {
+                use change_event::dsl;
+                match dsl::change_event
+                    .select(diesel::dsl::max(dsl::position))
+                    .first::<Option<i64>>(&conn)
+                {
+                    Ok(id) => id.unwrap_or(0),
+                    Err(e) => {
+                        error!("Failed to broadcast changes: {}", e);
+                        continue;
+                    }
+                }
+            }
+        };
+        match broadcast_changes(&conn, broadcaster, start) {
+            Ok(id) => last_id = Some(id),
+            Err(e) => {
+                last_id = Some(start);
+                error!("Failed to broadcast changes: {}", e);
+            }
+        }
+        if last_keepalive.elapsed() >= KEEPALIVE_INTERVAL {
+            broadcaster.keep_alive();
+            last_keepalive = Instant::now();
+        }
+        if last_purge.map_or(true, |last_purge| last_purge.elapsed() >= PURGE_INTERVAL) {
+            if let Err(e) = purge_changes(&conn) {
+                error!("Failed to delete old changes: {}", e);
+            }
+            last_purge = Some(Instant::now());
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ChangeStreamQuery {
+    data_source: Option<String>,
+    last_event_id: Option<i64>,
+}
+
+pub(crate) async fn get_change_stream(
+    pool: Data<Database>,
+    broadcaster: Data<ChangeBroadcaster>,
+    request: HttpRequest,
+    query: Query<ChangeStreamQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let last_event_id = request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .or(query.last_event_id);
+
+    let data_sources = if let Some(data_source) = &query.data_source {
+        let mut names = data_source
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        let count = names.len();
+        let ids: Result<Vec<i32>, Error> = pool
+            .run(move |conn| {
+                use data_source::dsl;
+                dsl::data_source
+                    .select(dsl::id)
+                    .filter(dsl::topic_name.eq_any(&names))
+                    .load(conn)
+                    .map_err(Into::into)
+            })
+            .await;
+        match ids {
+            Ok(ids) if ids.len() == count => Some(ids.into_iter().collect::<HashSet<_>>()),
+            Ok(_) => {
+                return Ok(ApiError::not_found("data source")
+                    .field("data_source")
+                    .into())
+            }
+            Err(e) => return Ok(build_error_response(&e)),
+        }
+    } else {
+        None
+    };
+
+    // Subscribes before reading the changes to replay, so that no change is
+    // missed in between. The changes replayed are skipped when they arrive
+    // again.
+    let receiver = broadcaster.subscribe(data_sources.clone());
+    let mut messages = vec![Bytes::from_static(b"retry: 5000\n\n")];
+    let mut last_replayed = 0;
+    if let Some(last_event_id) = last_event_id {
+        let replay: Result<Vec<ChangeEvent>, Error> = pool
+            .run(move |conn| {
+                load_changes_after(conn, last_event_id, data_sources.as_ref(), MAX_REPLAY + 1)
+            })
+            .await;
+        match replay {
+            Ok(changes) if i64::try_from(changes.len()).map_or(true, |len| len > MAX_REPLAY) => {
+                messages.push(Bytes::from_static(b"event: reset\ndata: {}\n\n"));
+            }
+            Ok(changes) => {
+                last_replayed = changes.last().map_or(last_event_id, |change| change.id);
+                messages.extend(changes.iter().map(ChangeEvent::to_message));
+            }
+            Err(e) => return Ok(build_error_response(&e)),
+        }
+    }
+
+    let live = receiver.filter_map(move |(id, message)| {
+        future::ready(if id == 0 || id > last_replayed {
+            Some(Ok::<_, actix_web::Error>(message))
+        } else {
+            None
+        })
+    });
+    Ok(HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "text/event-stream")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .streaming(stream::iter(messages.into_iter().map(Ok)).chain(live)))
+}
@@ -3,16 +3,33 @@ use actix_web::{
     HttpResponse,
 };
 use bigdecimal::{BigDecimal, FromPrimitive};
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
+use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::sync::Mutex;
 
+use super::schema::{category, cluster, data_source, qualifier};
 use crate::database::*;
 
+/// The number of events added to a cluster per hour over the last day. It
+/// takes a query on `cluster_history` per cluster, so it is not selected by
+/// default.
+macro_rules! growth_rate {
+    () => {
+        "(SELECT COALESCE(SUM(cluster_history.size_delta), 0)::FLOAT8 / 24 \
+         FROM cluster_history WHERE cluster_history.cluster_id = cluster.id \
+           AND cluster_history.recorded_at > now() - INTERVAL '24 hours')"
+    };
+}
+const GROWTH_RATE: &str = growth_rate!();
+const GROWTH_RATE_COLUMN: &str = concat!(growth_rate!(), " as growth_rate");
+
 pub(crate) async fn get_clusters(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let default_per_page = 10;
@@ -20,7 +37,8 @@ pub(crate) async fn get_clusters(
     let cluster_schema = "((((cluster INNER JOIN status ON cluster.status_id = status.id) \
                           INNER JOIN qualifier ON cluster.qualifier_id = qualifier.id) \
                           INNER JOIN category ON cluster.category_id = category.id) \
-                          INNER JOIN data_source ON cluster.data_source_id = data_source.id)";
+                          INNER JOIN data_source ON cluster.data_source_id = data_source.id \
+                          AND NOT data_source.archived)";
     let select = query
         .get("select")
         .and_then(Value::as_str)
@@ -40,6 +58,9 @@ pub(crate) async fn get_clusters(
                     "score" => Some("cluster.score"),
                     "event_ids" => Some("cluster.event_ids"),
                     "last_modification_time" => Some("cluster.last_modification_time"),
+                    "first_seen" => Some("cluster.first_seen"),
+                    "growth_rate" => Some(GROWTH_RATE_COLUMN),
+                    "tags" => Some(CLUSTER_TAGS),
                     _ => None,
                 })
                 .collect::<Vec<_>>()
@@ -58,6 +79,8 @@ pub(crate) async fn get_clusters(
                 "cluster.score",
                 "cluster.last_modification_time",
                 "cluster.event_ids",
+                "cluster.first_seen",
+                CLUSTER_TAGS,
             ]
         });
     let where_clause = query
@@ -65,8 +88,7 @@ pub(crate) async fn get_clusters(
         .and_then(Value::as_str)
         .and_then(|f| Filter::get_where_clause(&f).ok())
         .filter(|f| !f.is_empty());
-    let page = GetQuery::get_page(&query);
-    let per_page = GetQuery::get_per_page(&query, max_per_page).unwrap_or_else(|| default_per_page);
+    let paging = Paging::new(&query, default_per_page, max_per_page);
     let orderby = query
         .get("orderby")
         .and_then(Value::as_str)
@@ -82,6 +104,8 @@ pub(crate) async fn get_clusters(
             "score" => Some("cluster.score"),
             "event_ids" => Some("cluster.event_ids"),
             "last_modification_time" => Some("cluster.last_modification_time"),
+            "first_seen" => Some("cluster.first_seen"),
+            "growth_rate" => Some(GROWTH_RATE),
             _ => None,
         });
     let order = if orderby.is_some() {
@@ -90,224 +114,433 @@ pub(crate) async fn get_clusters(
         None
     };
 
-    match pool.get() {
-        Ok(conn) => GetQuery::build_response(
-            select,
-            cluster_schema,
-            where_clause,
-            page,
-            per_page,
-            orderby,
-            order,
-            &conn,
-        ),
-        Err(e) => Ok(build_http_500_response(&e)),
-    }
+    GetQuery::build_response(
+        &pool,
+        select,
+        cluster_schema,
+        "cluster.id",
+        where_clause,
+        paging,
+        orderby,
+        order,
+    )
+    .await
 }
 
 pub(crate) async fn update_cluster(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     cluster_id: Path<String>,
     query: Query<Value>,
     new_cluster: Json<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let data_source = query.get("data_source").and_then(Value::as_str);
+    let data_source = query
+        .get("data_source")
+        .and_then(Value::as_str)
+        .map(str::to_string);
     let new_cluster = new_cluster.into_inner();
     let (new_cluster_id, new_category, new_qualifier) = (
-        new_cluster.get("cluster_id").and_then(Value::as_str),
-        new_cluster.get("category").and_then(Value::as_str),
-        new_cluster.get("qualifier").and_then(Value::as_str),
+        new_cluster
+            .get("cluster_id")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        new_cluster
+            .get("category")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        new_cluster
+            .get("qualifier")
+            .and_then(Value::as_str)
+            .map(str::to_string),
     );
 
-    if let (Some(data_source), Some(_), _, _)
-    | (Some(data_source), _, Some(_), _)
-    | (Some(data_source), _, _, Some(_)) =
-        (data_source, new_cluster_id, new_category, new_qualifier)
-    {
-        let query_result: Result<_, Error> = pool.get().map_err(Into::into).and_then(|conn| {
+    let data_source = match data_source {
+        Some(data_source) => data_source,
+        None => return Ok(ApiError::invalid("data_source", "Missing data_source").into()),
+    };
+    if new_cluster_id.is_none() && new_category.is_none() && new_qualifier.is_none() {
+        return Ok(ApiError::bad_request(
+            "At least one of cluster_id, category, or qualifier must be given",
+        )
+        .into());
+    }
+    let query_result: Result<Result<(), ApiError>, Error> = pool
+        .run(move |conn| {
             let cluster_id = cluster_id.into_inner();
-            diesel::select(attempt_cluster_update(
-                cluster_id,
-                data_source,
-                new_category,
-                new_cluster_id,
-                new_qualifier,
-            ))
-            .get_result::<i32>(&conn)
-            .map_err(Into::into)
-        });
+            conn.transaction::<_, Error, _>(|| {
+                if let Some(e) = check_cluster_update(
+                    conn,
+                    &cluster_id,
+                    &data_source,
+                    new_category.as_deref(),
+                    new_qualifier.as_deref(),
+                )? {
+                    return Ok(Err(e));
+                }
+                diesel::select(attempt_cluster_update(
+                    cluster_id,
+                    data_source,
+                    new_category,
+                    new_cluster_id,
+                    new_qualifier,
+                ))
+                .get_result::<i32>(conn)?;
+                Ok(Ok(()))
+            })
+        })
+        .await;
+
+    match query_result {
+        Ok(Ok(())) => Ok(HttpResponse::Ok().into()),
+        Ok(Err(e)) => Ok(e.into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
 
-        match query_result {
-            Ok(1) => Ok(HttpResponse::Ok().into()),
-            Ok(_) => Ok(HttpResponse::InternalServerError().into()),
-            Err(e) => Ok(build_http_500_response(&e)),
+/// Returns why `attempt_cluster_update` would leave the cluster unchanged, if
+/// it would.
+fn check_cluster_update(
+    conn: &Conn,
+    cluster_id: &str,
+    data_source: &str,
+    category: Option<&str>,
+    qualifier: Option<&str>,
+) -> Result<Option<ApiError>, Error> {
+    use category::dsl as ca_d;
+    use cluster::dsl as c_d;
+    use data_source::dsl as d_d;
+    use qualifier::dsl as q_d;
+
+    let cluster = c_d::cluster
+        .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
+        .select(c_d::id)
+        .filter(
+            c_d::cluster_id
+                .eq(cluster_id)
+                .and(d_d::topic_name.eq(data_source)),
+        )
+        .first::<i32>(conn)
+        .optional()?;
+    if cluster.is_none() {
+        return Ok(Some(ApiError::not_found("cluster")));
+    }
+    if let Some(category) = category {
+        let category = ca_d::category
+            .select(ca_d::id)
+            .filter(ca_d::name.eq(category))
+            .first::<i32>(conn)
+            .optional()?;
+        if category.is_none() {
+            return Ok(Some(ApiError::invalid("category", "Unknown category")));
+        }
+    }
+    if let Some(qualifier) = qualifier {
+        let qualifier = q_d::qualifier
+            .select(q_d::id)
+            .filter(q_d::description.eq(qualifier))
+            .first::<i32>(conn)
+            .optional()?;
+        if qualifier.is_none() {
+            return Ok(Some(ApiError::invalid("qualifier", "Unknown qualifier")));
         }
-    } else {
-        Ok(HttpResponse::BadRequest().into())
     }
+    Ok(None)
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub(crate) struct ClusterUpdate {
-    cluster_id: String,
+table! {
+    cluster_staging (position) {
+        position -> Int4,
+        round -> Int4,
+        cluster_id -> Text,
+        detector_id -> Int4,
+        event_ids -> Nullable<Array<Numeric>>,
+        signature -> Nullable<Text>,
+        score -> Nullable<Float8>,
+        size -> Numeric,
+        data_source -> Text,
+        data_source_type -> Text,
+    }
+}
+
+/// Creates `cluster_staging`, which is dropped after the merge.
+const CLUSTER_STAGING: &str = "CREATE TEMPORARY TABLE cluster_staging (\
+                               position INTEGER NOT NULL, \
+                               round INTEGER NOT NULL, \
+                               cluster_id TEXT NOT NULL, \
+                               detector_id INTEGER NOT NULL, \
+                               event_ids NUMERIC(20, 0)[], \
+                               signature TEXT, \
+                               score FLOAT8, \
+                               size NUMERIC(20, 0) NOT NULL, \
+                               data_source TEXT NOT NULL, \
+                               data_source_type TEXT NOT NULL\
+                               ) ON COMMIT DROP";
+
+#[derive(Debug, Insertable)]
+#[table_name = "cluster_staging"]
+struct StagedCluster<'a> {
+    position: i32,
+    round: i32,
+    cluster_id: &'a str,
     detector_id: i32,
-    signature: Option<String>,
+    event_ids: Option<Vec<BigDecimal>>,
+    signature: Option<&'a str>,
     score: Option<f64>,
-    data_source: String,
-    data_source_type: String,
-    size: Option<usize>,
-    event_ids: Option<Vec<u64>>,
+    size: BigDecimal,
+    data_source: &'a str,
+    data_source_type: &'a str,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ClusterUpdate {
+    pub(crate) cluster_id: String,
+    pub(crate) detector_id: i32,
+    pub(crate) signature: Option<String>,
+    pub(crate) score: Option<f64>,
+    pub(crate) data_source: String,
+    pub(crate) data_source_type: DataType,
+    pub(crate) size: Option<usize>,
+    pub(crate) event_ids: Option<Vec<u64>>,
 }
 
 pub(crate) async fn update_clusters(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     payload: Payload,
+    query: Query<BulkQuery>,
     max_event_id_num: Data<Mutex<usize>>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let bytes = load_payload(payload).await?;
-    let cluster_update: Vec<ClusterUpdate> = serde_json::from_slice(&bytes)?;
-    let max_event_id_num: BigDecimal = match max_event_id_num.lock() {
-        Ok(num) => FromPrimitive::from_usize(*num).expect("should be fine"),
+    let cluster_update: Vec<ClusterUpdate> =
+        serde_json::from_slice(&bytes).map_err(ApiError::unparsable)?;
+    let atomic = query.is_atomic();
+    let max_event_id_num = match max_event_id_num.lock() {
+        Ok(num) => *num,
         Err(e) => {
             error!(
                 "Failed to acquire lock: {}. Use default max_event_id_number 25",
                 e
             );
-            FromPrimitive::from_usize(25).expect("should be fine")
+            25
         }
     };
 
-    let query_result: Result<i32, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        let cluster_update_clone = cluster_update.clone();
-        let result = conn.transaction::<i32, Error, _>(|| {
-            Ok(cluster_update_clone
-                .into_iter()
-                .filter_map(|c| {
-                    let event_ids = c.event_ids.as_ref().map(|e| {
-                        e.iter()
-                            .filter_map(|event_id| FromPrimitive::from_u64(*event_id))
-                            .collect::<Vec<BigDecimal>>()
-                    });
-                    let cluster_size: BigDecimal = c
-                        .size
-                        .and_then(FromPrimitive::from_usize)
-                        .unwrap_or_else(|| FromPrimitive::from_usize(1).unwrap_or_default());
-                    let query_result = diesel::select(attempt_cluster_upsert(
-                        max_event_id_num.clone(),
-                        c.cluster_id,
-                        c.data_source,
-                        c.data_source_type,
-                        c.detector_id,
-                        event_ids,
-                        c.signature,
-                        c.score,
-                        cluster_size,
-                    ))
-                    .get_result::<i32>(&conn);
-
-                    if let Err(e) = &query_result {
-                        log::error!("Failed to insert/update a cluster: {}", e);
-                    }
-                    query_result.ok()
-                })
-                .sum())
-        });
-        update_events(&conn, &cluster_update);
-
-        result
-    });
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            let mut results = BulkResults::new(cluster_update.len(), atomic);
+            results.apply(conn, |indices| {
+                let clusters = indices
+                    .iter()
+                    .map(|&i| &cluster_update[i])
+                    .collect::<Vec<_>>();
+                conn.upsert_clusters(max_event_id_num, &clusters)
+            })?;
+            Ok(results)
+        })
+        .await;
 
     match query_result {
-        Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
-fn update_events(conn: &Conn, cluster_update: &[ClusterUpdate]) {
-    let mut new_events = cluster_update
+/// Upserts `clusters` by staging them in a temporary table and merging them
+/// into `cluster` with `merge_staged_clusters`, which follows the rules of
+/// `attempt_cluster_upsert`. Returns the number of clusters upserted.
+///
+/// A cluster that appears more than once is merged in a later round for each
+/// appearance, so that the result is the same as upserting one by one.
+pub(crate) fn upsert_clusters(
+    conn: &Conn,
+    max_event_id_num: &BigDecimal,
+    clusters: &[&ClusterUpdate],
+) -> Result<i32, Error> {
+    let mut rounds = HashMap::<(&str, &str), i32>::new();
+    let staged = clusters
         .iter()
-        .filter_map(|cluster| {
-            if let Ok(data_source_id) = get_data_source_id(conn, &cluster.data_source) {
-                cluster
-                    .event_ids
-                    .as_ref()
-                    .map(|event_ids| build_events(event_ids, data_source_id))
-            } else {
-                None
+        .enumerate()
+        .map(|(position, c)| {
+            let round = rounds
+                .entry((c.cluster_id.as_str(), c.data_source.as_str()))
+                .or_insert(0);
+            *round += 1;
+            StagedCluster {
+                position: i32::try_from(position).unwrap_or(i32::MAX),
+                round: *round,
+                cluster_id: &c.cluster_id,
+                detector_id: c.detector_id,
+                event_ids: c.event_ids.as_ref().map(|e| {
+                    e.iter()
+                        .filter_map(|event_id| FromPrimitive::from_u64(*event_id))
+                        .collect::<Vec<BigDecimal>>()
+                }),
+                signature: c.signature.as_deref(),
+                score: c.score,
+                size: c
+                    .size
+                    .and_then(FromPrimitive::from_usize)
+                    .unwrap_or_else(|| FromPrimitive::from_usize(1).unwrap_or_default()),
+                data_source: &c.data_source,
+                data_source_type: c.data_source_type.as_str(),
             }
         })
-        .flatten()
         .collect::<Vec<_>>();
+    let last_round = rounds.values().max().copied().unwrap_or(0);
+
+    conn.transaction::<i32, Error, _>(|| {
+        diesel::sql_query(CLUSTER_STAGING).execute(conn)?;
+        for batch in staged.chunks(STAGING_BATCH_SIZE) {
+            diesel::insert_into(cluster_staging::table)
+                .values(batch)
+                .execute(conn)?;
+        }
+        diesel::sql_query("ANALYZE cluster_staging").execute(conn)?;
+        let mut count = 0;
+        for round in 1..=last_round {
+            count += diesel::select(merge_staged_clusters(max_event_id_num, round))
+                .get_result::<i32>(conn)?;
+        }
+        diesel::sql_query("DROP TABLE cluster_staging").execute(conn)?;
+        Ok(count)
+    })
+}
+
+/// Adds the events of `cluster_update` after the clusters are upserted.
+pub(crate) fn add_cluster_events<S: Storage>(
+    storage: &S,
+    cluster_update: &[&ClusterUpdate],
+) -> Result<(), Error> {
+    let mut new_events = Vec::new();
+    for cluster in cluster_update {
+        if let Some(event_ids) = &cluster.event_ids {
+            let data_source_id = storage.data_source_id(&cluster.data_source)?;
+            new_events.extend(build_events(event_ids, data_source_id));
+        }
+    }
 
     if !new_events.is_empty() {
         new_events.sort();
         new_events.dedup();
-        if let Err(e) = add_events(&conn, &new_events) {
-            log::error!("An error occurs while inserting events: {}", e);
-        }
+        storage.add_events(&new_events.iter().collect::<Vec<_>>())?;
     }
+    Ok(())
 }
 
 pub(crate) async fn update_qualifiers(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     payload: Payload,
+    query: Query<BulkQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let bytes = load_payload(payload).await?;
     let qualifier_updates: Vec<Value> = serde_json::from_slice(&bytes)?;
-    let query_result: Result<i32, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        let result: i32 = qualifier_updates
-            .iter()
-            .filter_map(|q| {
-                let cluster_id = q.get("cluster_id").and_then(Value::as_str);
-                let data_source = q.get("data_source").and_then(Value::as_str);
-                let qualifier = q.get("qualifier").and_then(Value::as_str);
-
-                if let (Some(cluster_id), Some(data_source), Some(qualifier)) =
-                    (cluster_id, data_source, qualifier)
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            let mut results = BulkResults::new(qualifier_updates.len(), atomic);
+            let updates = qualifier_updates
+                .iter()
+                .enumerate()
+                .map(|(i, q)| {
+                    let cluster_id = q.get("cluster_id").and_then(Value::as_str);
+                    let data_source = q.get("data_source").and_then(Value::as_str);
+                    let qualifier = q.get("qualifier").and_then(Value::as_str);
+                    if let (Some(cluster_id), Some(data_source), Some(qualifier)) =
+                        (cluster_id, data_source, qualifier)
+                    {
+                        Some((cluster_id, data_source, qualifier))
+                    } else {
+                        results.fail(
+                            i,
+                            &ApiError::bad_request(
+                                "cluster_id, data_source, and qualifier are required",
+                            ),
+                        );
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            results.apply(conn, |indices| {
+                for (cluster_id, data_source, qualifier) in
+                    indices.iter().filter_map(|&i| updates[i])
                 {
-                    diesel::select(attempt_qualifier_id_update(
-                        cluster_id,
-                        data_source,
-                        qualifier,
-                    ))
-                    .get_result::<i32>(&conn)
-                    .ok()
-                } else {
-                    None
+                    conn.update_qualifier(cluster_id, data_source, qualifier)?;
                 }
-            })
-            .sum();
-
-        Ok(result)
-    });
+                Ok(())
+            })?;
+            Ok(results)
+        })
+        .await;
 
     match query_result {
-        Ok(0) => Ok(HttpResponse::BadRequest().into()),
-        Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
+/// Sets the qualifier of a cluster. `attempt_qualifier_id_update` does not
+/// tell whether the cluster exists, so it is looked up first.
+pub(crate) fn update_qualifier(
+    conn: &Conn,
+    cluster_id: &str,
+    data_source: &str,
+    qualifier: &str,
+) -> Result<(), Error> {
+    use cluster::dsl as c_d;
+    use data_source::dsl as d_d;
+    use qualifier::dsl as q_d;
+
+    q_d::qualifier
+        .select(q_d::id)
+        .filter(q_d::description.eq(qualifier))
+        .first::<i32>(conn)
+        .optional()?
+        .ok_or(Error::NotFound("qualifier"))?;
+    c_d::cluster
+        .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
+        .select(c_d::id)
+        .filter(
+            c_d::cluster_id
+                .eq(cluster_id)
+                .and(d_d::topic_name.eq(data_source)),
+        )
+        .first::<i32>(conn)
+        .optional()?
+        .ok_or(Error::NotFound("cluster"))?;
+    diesel::select(attempt_qualifier_id_update(
+        cluster_id,
+        data_source,
+        qualifier,
+    ))
+    .get_result::<i32>(conn)?;
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 struct Filter {
     category: Option<Vec<String>>,
+    include_subcategories: Option<bool>,
     data_source: Option<Vec<String>>,
     status: Option<Vec<String>>,
     qualifier: Option<Vec<String>>,
     cluster_id: Option<Vec<String>>,
     detector_id: Option<Vec<u64>>,
+    tag: Option<Vec<String>>,
+    mode_subnet: Option<Vec<IpNetwork>>,
+    top_n_subnet: Option<Vec<IpNetwork>>,
+    min_growth_rate: Option<f64>,
+    first_seen_after: Option<NaiveDateTime>,
+    first_seen_before: Option<NaiveDateTime>,
 }
 
 impl Filter {
     fn query_builder(&self) -> Vec<String> {
         let mut query = Vec::<String>::new();
         if let Some(category) = &self.category {
-            query.extend(category.iter().map(|c| {
-                format!(
-                    "Cluster.category_id = (SELECT id FROM category WHERE name = '{}')",
-                    c
-                )
-            }));
+            let include_subcategories = self.include_subcategories.unwrap_or(false);
+            query.extend(
+                category
+                    .iter()
+                    .map(|c| category_condition(c, include_subcategories)),
+            );
         }
         let query = match &self.cluster_id {
             Some(cluster_id) => {
@@ -354,12 +587,60 @@ impl Filter {
             }
             None => query,
         };
-        match &self.qualifier {
+        let query = match &self.qualifier {
             Some(qualifier) => {
                 let qualifier = qualifier.iter().map(|q| format!("Cluster.qualifier_id = (SELECT id FROM qualifier WHERE description = '{}')", q)).collect::<Vec<String>>();
                 Self::build_where_clause(&query, &qualifier)
             }
             None => query,
+        };
+        let query = match &self.tag {
+            Some(tag) => {
+                let tag = tag
+                    .iter()
+                    .map(|t| tag_condition("cluster", t))
+                    .collect::<Vec<String>>();
+                Self::build_where_clause(&query, &tag)
+            }
+            None => query,
+        };
+        let query = match &self.mode_subnet {
+            Some(subnet) => {
+                let subnet = subnet
+                    .iter()
+                    .map(|s| subnet_condition("description_ipaddr", "mode", s))
+                    .collect::<Vec<String>>();
+                Self::build_where_clause(&query, &subnet)
+            }
+            None => query,
+        };
+        let query = match &self.top_n_subnet {
+            Some(subnet) => {
+                let subnet = subnet
+                    .iter()
+                    .map(|s| subnet_condition("top_n_ipaddr", "value", s))
+                    .collect::<Vec<String>>();
+                Self::build_where_clause(&query, &subnet)
+            }
+            None => query,
+        };
+        let query = match &self.min_growth_rate {
+            Some(rate) => {
+                Self::build_where_clause(&query, &[format!("{} >= {}", GROWTH_RATE, rate)])
+            }
+            None => query,
+        };
+        let query = match &self.first_seen_after {
+            Some(time) => {
+                Self::build_where_clause(&query, &[format!("cluster.first_seen >= '{}'", time)])
+            }
+            None => query,
+        };
+        match &self.first_seen_before {
+            Some(time) => {
+                Self::build_where_clause(&query, &[format!("cluster.first_seen < '{}'", time)])
+            }
+            None => query,
         }
     }
 
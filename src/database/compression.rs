@@ -0,0 +1,320 @@
+use actix_web::{
+    web::{Data, Path, Payload},
+    HttpResponse,
+};
+use diesel::prelude::*;
+use log::{error, info};
+use ring::digest::{digest, SHA256};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use super::schema::{compression_dictionary, event, outlier};
+use crate::database::{
+    build_error_response, get_data_source_id, load_payload, ApiError, Conn, Database, Error, Pool,
+};
+
+/// The zstd compression level of raw events.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The number of rows compressed at a time when compressing existing rows.
+const MIGRATION_BATCH_SIZE: i64 = 1000;
+
+/// The select clause that tells whether `outlier` in `GET /api/outlier` needs
+/// to be decompressed.
+pub(crate) const OUTLIER_COMPRESSION: &str =
+    "outlier.compressed as outlier_compressed, outlier.dictionary_id as outlier_dictionary_id";
+
+/// Compresses and decompresses raw events, caching the dictionaries loaded
+/// from the database.
+#[derive(Default)]
+pub(crate) struct Codec {
+    dictionaries: HashMap<i32, Vec<u8>>,
+    current: HashMap<i32, Option<i32>>,
+}
+
+impl Codec {
+    /// Compresses `raw` with the latest dictionary of the data source, if any.
+    /// Returns the compressed bytes and the id of the dictionary used.
+    pub(crate) fn compress(
+        &mut self,
+        conn: &Conn,
+        data_source_id: Option<i32>,
+        raw: &[u8],
+    ) -> Result<(Vec<u8>, Option<i32>), Error> {
+        let dictionary_id = match data_source_id {
+            Some(data_source_id) => self.current_dictionary(conn, data_source_id)?,
+            None => None,
+        };
+        let compressed = match dictionary_id {
+            Some(id) => compress_with(raw, self.dictionary(conn, id)?)?,
+            None => compress_with(raw, &[])?,
+        };
+        Ok((compressed, dictionary_id))
+    }
+
+    /// Returns `raw` as it was stored before compression.
+    pub(crate) fn decompress(
+        &mut self,
+        conn: &Conn,
+        raw: &[u8],
+        compressed: bool,
+        dictionary_id: Option<i32>,
+    ) -> Result<Vec<u8>, Error> {
+        if !compressed {
+            return Ok(raw.to_vec());
+        }
+        let dictionary = match dictionary_id {
+            Some(id) => self.dictionary(conn, id)?,
+            None => &[],
+        };
+        let mut decoder = zstd::stream::Decoder::with_dictionary(raw, dictionary)?;
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    /// Decompresses `outlier` of a row in `GET /api/outlier`, which has been
+    /// selected together with `OUTLIER_COMPRESSION`. `outlier` is set to null
+    /// if it fails to be decompressed.
+    pub(crate) fn decompress_outlier(&mut self, conn: &Conn, row: &mut Value) {
+        let row = match row.as_object_mut() {
+            Some(row) => row,
+            None => return,
+        };
+        let compressed = row
+            .remove("outlier_compressed")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let dictionary_id = row
+            .remove("outlier_dictionary_id")
+            .and_then(|v| v.as_i64())
+            .and_then(|v| i32::try_from(v).ok());
+        if !compressed {
+            return;
+        }
+        let decompressed = row
+            .get("outlier")
+            .and_then(Value::as_str)
+            .and_then(decode_hex)
+            .map(|raw| self.decompress(conn, &raw, compressed, dictionary_id));
+        match decompressed {
+            Some(Ok(raw)) => {
+                row.insert("outlier".to_string(), Value::String(encode_hex(&raw)));
+            }
+            // The compressed bytes are not passed off as the raw event.
+            Some(Err(e)) => {
+                error!("Failed to decompress an outlier: {}", e);
+                row.insert("outlier".to_string(), Value::Null);
+            }
+            None => (),
+        }
+    }
+
+    fn current_dictionary(
+        &mut self,
+        conn: &Conn,
+        data_source_id: i32,
+    ) -> Result<Option<i32>, Error> {
+        use compression_dictionary::dsl;
+        if let Some(id) = self.current.get(&data_source_id) {
+            return Ok(*id);
+        }
+        let id = dsl::compression_dictionary
+            .filter(dsl::data_source_id.eq(data_source_id))
+            .select(dsl::id)
+            .order(dsl::id.desc())
+            .first::<i32>(conn)
+            .optional()?;
+        self.current.insert(data_source_id, id);
+        Ok(id)
+    }
+
+    fn dictionary(&mut self, conn: &Conn, id: i32) -> Result<&[u8], Error> {
+        use compression_dictionary::dsl;
+        if !self.dictionaries.contains_key(&id) {
+            let dictionary = dsl::compression_dictionary
+                .filter(dsl::id.eq(id))
+                .select(dsl::dictionary)
+                .first::<Vec<u8>>(conn)?;
+            self.dictionaries.insert(id, dictionary);
+        }
+        Ok(&self.dictionaries[&id])
+    }
+}
+
+/// Returns the hash of an uncompressed raw event, by which an outlier is found
+/// however it is compressed.
+pub(crate) fn hash_raw_event(raw: &[u8]) -> Vec<u8> {
+    digest(&SHA256, raw).as_ref().to_vec()
+}
+
+fn compress_with(raw: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut encoder =
+        zstd::stream::Encoder::with_dictionary(Vec::new(), COMPRESSION_LEVEL, dictionary)?;
+    encoder.write_all(raw)?;
+    encoder.finish().map_err(Into::into)
+}
+
+pub(super) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(raw: &[u8]) -> String {
+    raw.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) async fn add_compression_dictionary(
+    pool: Data<Database>,
+    topic_name: Path<String>,
+    payload: Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    use compression_dictionary::dsl;
+    let dictionary = load_payload(payload).await?.to_vec();
+    if dictionary.is_empty() || compress_with(&[], &dictionary).is_err() {
+        return Ok(ApiError::bad_request("Invalid zstd dictionary").into());
+    }
+    let insert_result: Result<Option<usize>, Error> = pool
+        .run(move |conn| {
+            let data_source_id = match get_data_source_id(conn, &topic_name) {
+                Ok(id) => id,
+                Err(Error::Query(diesel::result::Error::NotFound)) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            diesel::insert_into(dsl::compression_dictionary)
+                .values((
+                    dsl::data_source_id.eq(data_source_id),
+                    dsl::dictionary.eq(&dictionary),
+                ))
+                .execute(conn)
+                .map(Some)
+                .map_err(Into::into)
+        })
+        .await;
+
+    match insert_result {
+        Ok(Some(_)) => Ok(HttpResponse::Created().into()),
+        Ok(None) => Ok(ApiError::not_found("data source").into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+/// Compresses the raw events and outliers stored before compression was
+/// introduced, and hashes the outliers compressed before `raw_event_hash` was
+/// introduced, a batch at a time, until none is left.
+pub(crate) fn compress_existing_raw_events(pool: &Pool) {
+    let mut total = 0;
+    loop {
+        let compress_result = pool.get().map_err(Into::into).and_then(|conn| {
+            let mut codec = Codec::default();
+            let events = compress_event_batch(&conn, &mut codec)?;
+            let outliers = compress_outlier_batch(&conn, &mut codec)?;
+            let hashed = hash_outlier_batch(&conn, &mut codec)?;
+            Ok::<_, Error>(events + outliers + hashed)
+        });
+        match compress_result {
+            Ok(0) => break,
+            Ok(count) => total += count,
+            Err(e) => {
+                error!("Failed to compress existing raw events: {}", e);
+                return;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    if total > 0 {
+        info!("Compressed {} existing raw event(s) and outlier(s)", total);
+    }
+}
+
+fn compress_event_batch(conn: &Conn, codec: &mut Codec) -> Result<usize, Error> {
+    use event::dsl;
+    let rows = dsl::event
+        .filter(dsl::compressed.eq(false).and(dsl::raw_event.is_not_null()))
+        .select((dsl::id, dsl::data_source_id, dsl::raw_event))
+        .order(dsl::id)
+        .limit(MIGRATION_BATCH_SIZE)
+        .load::<(i32, i32, Option<Vec<u8>>)>(conn)?;
+    let count = rows.len();
+    conn.transaction::<(), Error, _>(|| {
+        for (id, data_source_id, raw_event) in rows {
+            let raw_event = raw_event.unwrap_or_default();
+            let (raw_event, dictionary_id) =
+                codec.compress(conn, Some(data_source_id), &raw_event)?;
+            diesel::update(dsl::event.filter(dsl::id.eq(id).and(dsl::compressed.eq(false))))
+                .set((
+                    dsl::raw_event.eq(raw_event),
+                    dsl::compressed.eq(true),
+                    dsl::dictionary_id.eq(dictionary_id),
+                ))
+                .execute(conn)?;
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+fn compress_outlier_batch(conn: &Conn, codec: &mut Codec) -> Result<usize, Error> {
+    use outlier::dsl;
+    let rows = dsl::outlier
+        .filter(dsl::compressed.eq(false))
+        .select((dsl::id, dsl::data_source_id, dsl::raw_event))
+        .order(dsl::id)
+        .limit(MIGRATION_BATCH_SIZE)
+        .load::<(i32, i32, Vec<u8>)>(conn)?;
+    let count = rows.len();
+    conn.transaction::<(), Error, _>(|| {
+        for (id, data_source_id, raw_event) in rows {
+            let (raw_event, dictionary_id) =
+                codec.compress(conn, Some(data_source_id), &raw_event)?;
+            diesel::update(dsl::outlier.filter(dsl::id.eq(id).and(dsl::compressed.eq(false))))
+                .set((
+                    dsl::raw_event.eq(raw_event),
+                    dsl::compressed.eq(true),
+                    dsl::dictionary_id.eq(dictionary_id),
+                ))
+                .execute(conn)?;
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+fn hash_outlier_batch(conn: &Conn, codec: &mut Codec) -> Result<usize, Error> {
+    use outlier::dsl;
+    let rows = dsl::outlier
+        .filter(dsl::raw_event_hash.is_null())
+        .select((dsl::id, dsl::raw_event, dsl::compressed, dsl::dictionary_id))
+        .order(dsl::id)
+        .limit(MIGRATION_BATCH_SIZE)
+        .load::<(i32, Vec<u8>, bool, Option<i32>)>(conn)?;
+    let count = rows.len();
+    conn.transaction::<(), Error, _>(|| {
+        for (id, raw_event, compressed, dictionary_id) in rows {
+            // An outlier that cannot be decompressed gets an empty hash, which
+            // no raw event has, so that it is not tried again.
+            let hash = codec
+                .decompress(conn, &raw_event, compressed, dictionary_id)
+                .map_or_else(
+                    |e| {
+                        error!("Failed to decompress outlier {}: {}", id, e);
+                        Vec::new()
+                    },
+                    |raw| hash_raw_event(&raw),
+                );
+            diesel::update(dsl::outlier.filter(dsl::id.eq(id)))
+                .set(dsl::raw_event_hash.eq(hash))
+                .execute(conn)?;
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
@@ -1,14 +1,20 @@
 use actix_web::{
     http,
-    web::{Data, Query},
+    web::{Data, Json, Path, Query},
     HttpResponse,
 };
 use diesel::prelude::*;
-use serde::{Deserialize, Serialize};
+use diesel::sql_types::Integer;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
 
-use super::schema::data_source;
-use crate::database::{build_http_500_response, Conn, Error, Pool};
+use super::schema::{
+    change_event, cluster, column_description, data_source, event, indicator, kafka_metadata,
+    outlier, template,
+};
+use crate::database::{build_error_response, ApiError, Conn, Database, Error};
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct DataSourceQuery {
@@ -20,40 +26,501 @@ pub(crate) struct DataSource {
     pub(crate) id: i32,
     pub(crate) topic_name: String,
     pub(crate) data_type: String,
+    pub(crate) description: Option<String>,
+    pub(crate) enabled: bool,
+    pub(crate) owner: Option<String>,
+    pub(crate) archived: bool,
+    pub(crate) event_source: String,
+    pub(crate) event_format: String,
+    pub(crate) event_path: Option<String>,
+    pub(crate) record_field: String,
+    pub(crate) id_field: Option<String>,
+    pub(crate) template: Option<String>,
+}
+
+/// The type of events in a data source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DataType {
+    Csv,
+    Email,
+    Log,
+    Packet,
+}
+
+impl DataType {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Email => "email",
+            Self::Log => "log",
+            Self::Packet => "packet",
+        }
+    }
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DataType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "email" => Ok(Self::Email),
+            "log" => Ok(Self::Log),
+            "packet" => Ok(Self::Packet),
+            _ => Err(()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for DataType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data_type = String::deserialize(deserializer)?;
+        data_type.parse().map_err(|()| {
+            de::Error::invalid_value(
+                de::Unexpected::Str(&data_type),
+                &"one of csv, email, log, or packet",
+            )
+        })
+    }
+}
+
+impl Serialize for DataType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Where the raw events of a data source are read from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EventSourceType {
+    /// The Kafka topic named after the data source.
+    Kafka,
+    /// The files in a local directory.
+    Directory,
+}
+
+impl EventSourceType {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Kafka => "kafka",
+            Self::Directory => "directory",
+        }
+    }
+}
+
+impl fmt::Display for EventSourceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EventSourceType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "kafka" => Ok(Self::Kafka),
+            "directory" => Ok(Self::Directory),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How events are encoded in the messages or files of an event source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum EventFormat {
+    /// MessagePack-encoded Fluentd forward messages.
+    Fluentd,
+    /// JSON objects, one per line.
+    Ndjson,
+}
+
+impl EventFormat {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Fluentd => "fluentd",
+            Self::Ndjson => "ndjson",
+        }
+    }
+}
+
+impl fmt::Display for EventFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EventFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fluentd" => Ok(Self::Fluentd),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The event source settings of a data source.
+#[derive(Debug)]
+struct EventSourceSettings {
+    event_source: EventSourceType,
+    event_format: EventFormat,
+    event_path: Option<String>,
+    record_field: String,
+    id_field: Option<String>,
+}
+
+impl EventSourceSettings {
+    /// Returns a message telling what is wrong with the settings, if any.
+    fn check(&self) -> Result<(), ApiError> {
+        if self.event_source == EventSourceType::Directory && self.event_path.is_none() {
+            return Err(ApiError::invalid(
+                "event_path",
+                "event_path is required for a directory event source.",
+            ));
+        }
+        if self.event_format == EventFormat::Ndjson && self.id_field.is_none() {
+            return Err(ApiError::invalid(
+                "id_field",
+                "id_field is required for the ndjson event format.",
+            ));
+        }
+        if self.record_field.is_empty() {
+            return Err(ApiError::invalid(
+                "record_field",
+                "record_field must not be empty.",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DataSourceSelectQuery {
+    archived: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DataSourceUpdate {
+    topic_name: Option<String>,
+    data_type: Option<String>,
+    description: Option<String>,
+    enabled: Option<bool>,
+    owner: Option<String>,
+    event_source: Option<String>,
+    event_format: Option<String>,
+    event_path: Option<String>,
+    record_field: Option<String>,
+    id_field: Option<String>,
+    template: Option<String>,
+}
+
+#[derive(Debug, AsChangeset)]
+#[table_name = "data_source"]
+struct DataSourceChangeset {
+    topic_name: Option<String>,
+    data_type: Option<String>,
+    description: Option<String>,
+    enabled: Option<bool>,
+    owner: Option<String>,
+    event_source: Option<String>,
+    event_format: Option<String>,
+    event_path: Option<Option<String>>,
+    record_field: Option<String>,
+    id_field: Option<Option<String>>,
+    template: Option<Option<String>>,
+}
+
+impl DataSourceChangeset {
+    /// Returns the event source settings of `current` after this change.
+    fn apply_to(&self, current: &DataSource) -> EventSourceSettings {
+        EventSourceSettings {
+            event_source: self
+                .event_source
+                .as_ref()
+                .unwrap_or(&current.event_source)
+                .parse()
+                .unwrap_or(EventSourceType::Kafka),
+            event_format: self
+                .event_format
+                .as_ref()
+                .unwrap_or(&current.event_format)
+                .parse()
+                .unwrap_or(EventFormat::Fluentd),
+            event_path: self
+                .event_path
+                .clone()
+                .unwrap_or_else(|| current.event_path.clone()),
+            record_field: self
+                .record_field
+                .clone()
+                .unwrap_or_else(|| current.record_field.clone()),
+            id_field: self
+                .id_field
+                .clone()
+                .unwrap_or_else(|| current.id_field.clone()),
+        }
+    }
+}
+
+/// What to do with the clusters, outliers, events, Kafka metadata, and
+/// indicators of a data source being deleted.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DeletePolicy {
+    /// Keeps everything, but hides the data source and stops fetching events
+    /// for it.
+    Archive,
+    /// Deletes everything that belongs to the data source.
+    Cascade,
+}
+
+impl Default for DeletePolicy {
+    fn default() -> Self {
+        Self::Archive
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DataSourceDeleteQuery {
+    #[serde(default)]
+    policy: DeletePolicy,
+}
+
+/// The tables that store the descriptions of clusters.
+pub(crate) const DESCRIPTION_TABLES: [&str; 14] = [
+    "description_binary",
+    "description_datetime",
+    "description_enum",
+    "description_float",
+    "description_int",
+    "description_ipaddr",
+    "description_text",
+    "top_n_binary",
+    "top_n_datetime",
+    "top_n_enum",
+    "top_n_float",
+    "top_n_int",
+    "top_n_ipaddr",
+    "top_n_text",
+];
+
+fn invalid_data_type() -> HttpResponse {
+    ApiError::invalid(
+        "data_type",
+        "Invalid data_type. It must be one of csv, email, log, or packet.",
+    )
+    .into()
+}
+
+fn invalid_event_source() -> HttpResponse {
+    ApiError::invalid(
+        "event_source",
+        "Invalid event_source. It must be either kafka or directory.",
+    )
+    .into()
+}
+
+fn invalid_event_format() -> HttpResponse {
+    ApiError::invalid(
+        "event_format",
+        "Invalid event_format. It must be either fluentd or ndjson.",
+    )
+    .into()
+}
+
+fn unknown_template() -> ApiError {
+    ApiError::invalid("template", "Unknown template.")
+}
+
+fn template_exists(conn: &Conn, name: &str) -> Result<bool, Error> {
+    diesel::select(diesel::dsl::exists(
+        template::table.filter(template::name.eq(name)),
+    ))
+    .get_result(conn)
+    .map_err(Into::into)
+}
+
+/// Returns `None` for an empty string, which clears an optional field.
+fn non_empty(value: String) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
 }
 
 pub(crate) async fn add_data_source(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use data_source::dsl;
-    let data_source = query.get("data_source").and_then(Value::as_str);
+    let text = |key: &str| query.get(key).and_then(Value::as_str).map(str::to_string);
+    let data_source = text("data_source");
     let data_type = query.get("data_type").and_then(Value::as_str);
+    let description = text("description");
+    let owner = text("owner");
+    let template = text("template").and_then(non_empty);
+    let event_source = match query.get("event_source").and_then(Value::as_str) {
+        Some(event_source) => match event_source.parse::<EventSourceType>() {
+            Ok(event_source) => event_source,
+            Err(_) => return Ok(invalid_event_source()),
+        },
+        None => EventSourceType::Kafka,
+    };
+    let event_format = match query.get("event_format").and_then(Value::as_str) {
+        Some(event_format) => match event_format.parse::<EventFormat>() {
+            Ok(event_format) => event_format,
+            Err(_) => return Ok(invalid_event_format()),
+        },
+        None => EventFormat::Fluentd,
+    };
+    let settings = EventSourceSettings {
+        event_source,
+        event_format,
+        event_path: text("event_path").and_then(non_empty),
+        record_field: text("record_field").unwrap_or_else(|| "message".to_string()),
+        id_field: text("id_field").and_then(non_empty),
+    };
+    if let Err(e) = settings.check() {
+        return Ok(e.into());
+    }
 
-    let query_result = if let (Some(data_source), Some(data_type)) = (data_source, data_type) {
-        let new_data_source: Result<i32, Error> = pool.get().map_err(Into::into).and_then(|conn| {
+    let data_source = match data_source {
+        Some(data_source) => data_source,
+        None => return Ok(ApiError::invalid("data_source", "Missing data_source").into()),
+    };
+    let data_type = match data_type.map(str::parse::<DataType>) {
+        Some(Ok(data_type)) => data_type,
+        Some(Err(_)) => return Ok(invalid_data_type()),
+        None => return Ok(ApiError::invalid("data_type", "Missing data_type").into()),
+    };
+    let new_data_source: Result<Result<i32, ApiError>, Error> = pool
+        .run(move |conn| {
+            if let Some(template) = &template {
+                if !template_exists(conn, template)? {
+                    return Ok(Err(unknown_template()));
+                }
+            }
             diesel::insert_into(dsl::data_source)
                 .values((
                     dsl::topic_name.eq(data_source),
-                    dsl::data_type.eq(data_type),
+                    dsl::data_type.eq(data_type.as_str()),
+                    dsl::description.eq(description),
+                    dsl::owner.eq(owner),
+                    dsl::event_source.eq(settings.event_source.as_str()),
+                    dsl::event_format.eq(settings.event_format.as_str()),
+                    dsl::event_path.eq(settings.event_path),
+                    dsl::record_field.eq(settings.record_field),
+                    dsl::id_field.eq(settings.id_field),
+                    dsl::template.eq(template),
                 ))
                 .on_conflict(dsl::topic_name)
                 .do_nothing()
                 .returning(dsl::id)
-                .get_result(&conn)
+                .get_result(conn)
+                .map(Ok)
                 .map_err(Into::into)
-        });
-        new_data_source.unwrap_or_default()
-    } else {
-        return Ok(HttpResponse::BadRequest().into());
-    };
+        })
+        .await;
+    match new_data_source {
+        Ok(Ok(_)) => Ok(HttpResponse::Created().into()),
+        Ok(Err(e)) => Ok(e.into()),
+        // `ON CONFLICT DO NOTHING` returns no row.
+        Err(Error::Query(diesel::result::Error::NotFound)) => {
+            Ok(ApiError::conflict("The data source already exists").into())
+        }
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
 
-    match query_result {
-        0 => Ok(HttpResponse::InternalServerError().into()),
-        _ => Ok(HttpResponse::Created().into()),
+pub(crate) async fn delete_data_source(
+    pool: Data<Database>,
+    topic_name: Path<String>,
+    query: Query<DataSourceDeleteQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let delete_result: Result<Option<()>, Error> = pool
+        .run(move |conn| {
+            let data_source_id = match get_data_source_id(conn, &topic_name) {
+                Ok(id) => id,
+                Err(Error::Query(diesel::result::Error::NotFound)) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            match query.policy {
+                DeletePolicy::Archive => archive_data_source(conn, data_source_id)?,
+                DeletePolicy::Cascade => remove_data_source(conn, data_source_id)?,
+            }
+            Ok(Some(()))
+        })
+        .await;
+
+    match delete_result {
+        Ok(Some(_)) => Ok(HttpResponse::Ok().into()),
+        Ok(None) => Ok(ApiError::not_found("data source").into()),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
+fn archive_data_source(conn: &Conn, data_source_id: i32) -> Result<(), Error> {
+    use data_source::dsl;
+    diesel::update(dsl::data_source.filter(dsl::id.eq(data_source_id)))
+        .set((dsl::archived.eq(true), dsl::enabled.eq(false)))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Deletes a data source and everything that belongs to it.
+fn remove_data_source(conn: &Conn, data_source_id: i32) -> Result<(), Error> {
+    conn.transaction::<(), Error, _>(|| {
+        for table in DESCRIPTION_TABLES.iter() {
+            diesel::sql_query(format!(
+                "DELETE FROM {} WHERE description_id IN (\
+                 SELECT column_description.id FROM column_description \
+                 INNER JOIN cluster ON column_description.cluster_id = cluster.id \
+                 WHERE cluster.data_source_id = $1)",
+                table
+            ))
+            .bind::<Integer, _>(data_source_id)
+            .execute(conn)?;
+        }
+        diesel::delete(
+            column_description::table.filter(
+                column_description::cluster_id.eq_any(
+                    cluster::table
+                        .select(cluster::id)
+                        .filter(cluster::data_source_id.eq(data_source_id)),
+                ),
+            ),
+        )
+        .execute(conn)?;
+        diesel::delete(cluster::table.filter(cluster::data_source_id.eq(data_source_id)))
+            .execute(conn)?;
+        diesel::delete(outlier::table.filter(outlier::data_source_id.eq(data_source_id)))
+            .execute(conn)?;
+        diesel::delete(event::table.filter(event::data_source_id.eq(data_source_id)))
+            .execute(conn)?;
+        diesel::delete(
+            kafka_metadata::table.filter(kafka_metadata::data_source_id.eq(data_source_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(indicator::table.filter(indicator::data_source_id.eq(data_source_id)))
+            .execute(conn)?;
+        diesel::delete(change_event::table.filter(change_event::data_source_id.eq(data_source_id)))
+            .execute(conn)?;
+        diesel::delete(data_source::table.filter(data_source::id.eq(data_source_id)))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
 pub(crate) fn get_data_source_id(conn: &Conn, data_source: &str) -> Result<i32, Error> {
     use data_source::dsl;
     dsl::data_source
@@ -64,19 +531,124 @@ pub(crate) fn get_data_source_id(conn: &Conn, data_source: &str) -> Result<i32,
 }
 
 pub(crate) async fn get_data_source_table(
-    pool: Data<Pool>,
+    pool: Data<Database>,
+    query: Query<DataSourceSelectQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let query_result: Result<Vec<DataSource>, Error> =
-        pool.get().map_err(Into::into).and_then(|conn| {
-            data_source::dsl::data_source
-                .load::<DataSource>(&conn)
-                .map_err(Into::into)
-        });
+    use data_source::dsl;
+    let query_result: Result<Vec<DataSource>, Error> = pool
+        .run(move |conn| {
+            let data_source_table = if query.archived.unwrap_or(false) {
+                dsl::data_source.load::<DataSource>(conn)
+            } else {
+                dsl::data_source
+                    .filter(dsl::archived.eq(false))
+                    .load::<DataSource>(conn)
+            };
+            data_source_table.map_err(Into::into)
+        })
+        .await;
 
     match query_result {
         Ok(data_source_table) => Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "application/json")
             .json(data_source_table)),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn update_data_source(
+    pool: Data<Database>,
+    topic_name: Path<String>,
+    new_data_source: Json<DataSourceUpdate>,
+) -> Result<HttpResponse, actix_web::Error> {
+    use data_source::dsl;
+    let new_data_source = new_data_source.into_inner();
+    let data_type = if let Some(data_type) = &new_data_source.data_type {
+        match data_type.parse::<DataType>() {
+            Ok(data_type) => Some(data_type.to_string()),
+            Err(_) => return Ok(invalid_data_type()),
+        }
+    } else {
+        None
+    };
+    let event_source = if let Some(event_source) = &new_data_source.event_source {
+        match event_source.parse::<EventSourceType>() {
+            Ok(event_source) => Some(event_source.to_string()),
+            Err(_) => return Ok(invalid_event_source()),
+        }
+    } else {
+        None
+    };
+    let event_format = if let Some(event_format) = &new_data_source.event_format {
+        match event_format.parse::<EventFormat>() {
+            Ok(event_format) => Some(event_format.to_string()),
+            Err(_) => return Ok(invalid_event_format()),
+        }
+    } else {
+        None
+    };
+    let changeset = DataSourceChangeset {
+        topic_name: new_data_source.topic_name,
+        data_type,
+        description: new_data_source.description,
+        enabled: new_data_source.enabled,
+        owner: new_data_source.owner,
+        event_source,
+        event_format,
+        event_path: new_data_source.event_path.map(non_empty),
+        record_field: new_data_source.record_field,
+        id_field: new_data_source.id_field.map(non_empty),
+        template: new_data_source.template.map(non_empty),
+    };
+    if let DataSourceChangeset {
+        topic_name: None,
+        data_type: None,
+        description: None,
+        enabled: None,
+        owner: None,
+        event_source: None,
+        event_format: None,
+        event_path: None,
+        record_field: None,
+        id_field: None,
+        template: None,
+    } = changeset
+    {
+        return Ok(ApiError::bad_request("No field to update").into());
+    }
+
+    let update_result: Result<Result<usize, ApiError>, Error> = pool
+        .run(move |conn| {
+            conn.transaction::<_, Error, _>(|| {
+                let current = match dsl::data_source
+                    .filter(dsl::topic_name.eq(topic_name.as_str()))
+                    .for_update()
+                    .first::<DataSource>(conn)
+                    .optional()?
+                {
+                    Some(current) => current,
+                    None => return Ok(Ok(0)),
+                };
+                if let Err(e) = changeset.apply_to(&current).check() {
+                    return Ok(Err(e));
+                }
+                if let Some(Some(template)) = &changeset.template {
+                    if !template_exists(conn, template)? {
+                        return Ok(Err(unknown_template()));
+                    }
+                }
+                let updated = diesel::update(dsl::data_source.filter(dsl::id.eq(current.id)))
+                    .set(&changeset)
+                    .execute(conn)?;
+                Ok(Ok(updated))
+            })
+        })
+        .await;
+
+    match update_result {
+        Ok(Ok(0)) => Ok(ApiError::not_found("data source").into()),
+        Ok(Ok(_)) => Ok(HttpResponse::Ok().into()),
+        Ok(Err(e)) => Ok(e.into()),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
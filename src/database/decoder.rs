@@ -0,0 +1,649 @@
+use diesel::prelude::*;
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+use super::compression::decode_hex;
+use super::schema::template;
+use crate::database::{Conn, DataType, Error};
+
+/// The select clause that tells how `outlier` in `GET /api/outlier` is
+/// decoded.
+pub(crate) const OUTLIER_DECODING: &str =
+    "data_source.data_type as outlier_data_type, data_source.template as outlier_template";
+
+/// The number of bytes in a line of a hex dump.
+const HEX_DUMP_WIDTH: usize = 16;
+
+/// The names of the fields of an HTTP access log, in the Common Log Format
+/// followed by the two fields the Combined Log Format adds.
+const HTTP_ACCESS_LOG_FIELDS: [&str; 9] = [
+    "remote_host",
+    "ident",
+    "user",
+    "time",
+    "request",
+    "status",
+    "bytes",
+    "referer",
+    "user_agent",
+];
+
+/// How a raw event was decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DecoderKind {
+    /// The columns of a CSV record.
+    Csv,
+    /// The members of a JSON object.
+    Json,
+    /// An HTTP access log in the Common or Combined Log Format.
+    HttpAccessLog,
+    /// `key=value` pairs, and the words between them.
+    KeyValue,
+    /// UTF-8 text as a whole.
+    Text,
+    /// The lines of a hex dump, for anything else.
+    HexDump,
+}
+
+/// A field of a decoded raw event.
+#[derive(Debug, Serialize)]
+pub(crate) struct DecodedField {
+    /// The position of the field, which is the `column_index` of the column
+    /// description of the field.
+    index: usize,
+    name: Option<String>,
+    value: String,
+    /// The data type of the column in the format of the template of the data
+    /// source.
+    data_type: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DecodedEvent {
+    decoder: DecoderKind,
+    fields: Vec<DecodedField>,
+}
+
+/// The data types of the columns in the format of a template.
+pub(crate) type ColumnLayout = Vec<String>;
+
+#[derive(Deserialize)]
+struct ColumnFormat {
+    data_type: String,
+}
+
+/// Loads the column layout of a template. A template without a format, or
+/// no template, has no columns.
+pub(crate) fn load_column_layout(conn: &Conn, name: Option<&str>) -> Result<ColumnLayout, Error> {
+    use template::dsl;
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(Vec::new()),
+    };
+    let format = dsl::template
+        .filter(dsl::name.eq(name))
+        .select(dsl::format)
+        .first::<Option<Value>>(conn)
+        .optional()?
+        .flatten();
+    Ok(format
+        .and_then(|format| serde_json::from_value::<Vec<ColumnFormat>>(format).ok())
+        .map(|format| format.into_iter().map(|f| f.data_type).collect())
+        .unwrap_or_default())
+}
+
+/// Decodes raw events, caching the column layouts loaded from the database.
+#[derive(Default)]
+pub(crate) struct EventDecoder {
+    layouts: HashMap<String, ColumnLayout>,
+}
+
+impl EventDecoder {
+    /// Adds `decoded` to a row in `GET /api/outlier`, which has been selected
+    /// together with `OUTLIER_DECODING`, after `outlier` is decompressed.
+    pub(crate) fn decode_outlier(&mut self, conn: &Conn, row: &mut Value) {
+        let row = match row.as_object_mut() {
+            Some(row) => row,
+            None => return,
+        };
+        let data_type = row
+            .remove("outlier_data_type")
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default();
+        let template = row
+            .remove("outlier_template")
+            .and_then(|v| v.as_str().map(str::to_string));
+        let raw = match row
+            .get("outlier")
+            .and_then(Value::as_str)
+            .and_then(decode_hex)
+        {
+            Some(raw) => raw,
+            None => return,
+        };
+        let layout = match template {
+            Some(template) => {
+                if !self.layouts.contains_key(&template) {
+                    match load_column_layout(conn, Some(&template)) {
+                        Ok(layout) => {
+                            self.layouts.insert(template.clone(), layout);
+                        }
+                        Err(e) => log::error!("Failed to load the template {}: {}", template, e),
+                    }
+                }
+                self.layouts.get(&template)
+            }
+            None => None,
+        };
+        let decoded = decode(&data_type, layout.map_or(&[], Vec::as_slice), &raw);
+        if let Ok(decoded) = serde_json::to_value(decoded) {
+            row.insert("decoded".to_string(), decoded);
+        }
+    }
+}
+
+/// Decodes a raw event of a data source of `data_type`. The fields are
+/// numbered by their positions, and take the data types of the columns at the
+/// same positions in `layout`.
+pub(crate) fn decode(data_type: &str, layout: &[String], raw: &[u8]) -> DecodedEvent {
+    let text = match std::str::from_utf8(raw) {
+        Ok(text) => text.trim_end_matches(&['\r', '\n'][..]),
+        Err(_) => return with_layout(DecoderKind::HexDump, hex_dump(raw), layout),
+    };
+    let (decoder, fields) = match data_type.parse::<DataType>() {
+        Ok(DataType::Csv) => (DecoderKind::Csv, csv_fields(text)),
+        Ok(DataType::Log) => log_fields(text),
+        Ok(DataType::Packet) => (DecoderKind::HexDump, hex_dump(raw)),
+        Ok(DataType::Email) | Err(()) => (DecoderKind::Text, vec![(None, text.to_string())]),
+    };
+    with_layout(decoder, fields, layout)
+}
+
+fn with_layout(
+    decoder: DecoderKind,
+    fields: Vec<(Option<String>, String)>,
+    layout: &[String],
+) -> DecodedEvent {
+    DecodedEvent {
+        decoder,
+        fields: fields
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, value))| DecodedField {
+                index,
+                name,
+                value,
+                data_type: layout.get(index).cloned(),
+            })
+            .collect(),
+    }
+}
+
+/// Decodes a log as a JSON object, an HTTP access log, or `key=value` pairs,
+/// whichever matches first, or as text.
+fn log_fields(text: &str) -> (DecoderKind, Vec<(Option<String>, String)>) {
+    if let Some(fields) = json_fields(text) {
+        (DecoderKind::Json, fields)
+    } else if let Some(fields) = http_access_log_fields(text) {
+        (DecoderKind::HttpAccessLog, fields)
+    } else if let Some(fields) = key_value_fields(text) {
+        (DecoderKind::KeyValue, fields)
+    } else {
+        (DecoderKind::Text, vec![(None, text.to_string())])
+    }
+}
+
+/// Splits a CSV record into its columns.
+fn csv_fields(text: &str) -> Vec<(Option<String>, String)> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push((None, std::mem::take(&mut field))),
+            c => field.push(c),
+        }
+    }
+    fields.push((None, field));
+    fields
+}
+
+/// The members of a JSON object, in the order they appear.
+struct JsonMembers(Vec<(String, Value)>);
+
+impl<'de> Deserialize<'de> for JsonMembers {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MembersVisitor;
+
+        impl<'de> Visitor<'de> for MembersVisitor {
+            type Value = JsonMembers;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<JsonMembers, A::Error> {
+                let mut members = Vec::new();
+                while let Some(member) = map.next_entry::<String, Value>()? {
+                    members.push(member);
+                }
+                Ok(JsonMembers(members))
+            }
+        }
+
+        deserializer.deserialize_map(MembersVisitor)
+    }
+}
+
+/// Returns the members of a JSON object. Strings are unquoted, and the other
+/// values are in JSON.
+fn json_fields(text: &str) -> Option<Vec<(Option<String>, String)>> {
+    let members = serde_json::from_str::<JsonMembers>(text).ok()?;
+    Some(
+        members
+            .0
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::String(value) => value,
+                    value => value.to_string(),
+                };
+                (Some(name), value)
+            })
+            .collect(),
+    )
+}
+
+/// Returns the length of the double-quoted string at the start of `text`,
+/// including the quotes.
+fn quoted_len(text: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return Some(i + 1),
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Splits `text` into words separated by whitespace. A word in double quotes
+/// or square brackets, or a `key="value"` pair, may have whitespace, and the
+/// quotes and the brackets are kept.
+fn words(text: &str) -> Option<Vec<&str>> {
+    let mut words = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let end = if rest.starts_with('[') {
+            rest.find(']')? + 1
+        } else if rest.starts_with('"') {
+            quoted_len(rest)?
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            match rest[..end].find("=\"") {
+                Some(i) => i + 1 + quoted_len(&rest[i + 1..])?,
+                None => end,
+            }
+        };
+        words.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    Some(words)
+}
+
+fn unquote(word: &str) -> &str {
+    if word.len() >= 2
+        && ((word.starts_with('"') && word.ends_with('"'))
+            || (word.starts_with('[') && word.ends_with(']')))
+    {
+        &word[1..word.len() - 1]
+    } else {
+        word
+    }
+}
+
+/// Returns the fields of an HTTP access log in the Common or Combined Log
+/// Format.
+fn http_access_log_fields(text: &str) -> Option<Vec<(Option<String>, String)>> {
+    let words = words(text)?;
+    if words.len() != 7 && words.len() != 9 {
+        return None;
+    }
+    let is_quoted = |word: &str| word.len() >= 2 && word.starts_with('"') && word.ends_with('"');
+    if !words[3].starts_with('[') || !is_quoted(words[4]) {
+        return None;
+    }
+    if words[5].len() != 3 || !words[5].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    if words[6] != "-" && !words[6].bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    if words.len() == 9 && (!is_quoted(words[7]) || !is_quoted(words[8])) {
+        return None;
+    }
+    Some(
+        HTTP_ACCESS_LOG_FIELDS
+            .iter()
+            .zip(words)
+            .map(|(name, word)| (Some((*name).to_string()), unquote(word).to_string()))
+            .collect(),
+    )
+}
+
+/// Returns the `key=value` pairs in `text`, and the words between them
+/// without names. `None` if there are fewer than two pairs.
+fn key_value_fields(text: &str) -> Option<Vec<(Option<String>, String)>> {
+    let fields = words(text)?
+        .into_iter()
+        .map(|word| match word.find('=') {
+            Some(i)
+                if i > 0
+                    && word[..i]
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b"_.-".contains(&b)) =>
+            {
+                (
+                    Some(word[..i].to_string()),
+                    unquote(&word[i + 1..]).to_string(),
+                )
+            }
+            _ => (None, unquote(word).to_string()),
+        })
+        .collect::<Vec<_>>();
+    if fields.iter().filter(|(name, _)| name.is_some()).count() < 2 {
+        return None;
+    }
+    Some(fields)
+}
+
+/// Returns the lines of a hex dump of `raw`, each named after its offset.
+fn hex_dump(raw: &[u8]) -> Vec<(Option<String>, String)> {
+    raw.chunks(HEX_DUMP_WIDTH)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex = line
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = line
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        char::from(b)
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            (
+                Some(format!("{:08x}", i * HEX_DUMP_WIDTH)),
+                format!(
+                    "{:<width$}  |{}|",
+                    hex,
+                    ascii,
+                    width = HEX_DUMP_WIDTH * 3 - 1
+                ),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the names and the values of the fields of `event`.
+    fn fields(event: &DecodedEvent) -> Vec<(Option<&str>, &str)> {
+        event
+            .fields
+            .iter()
+            .map(|f| (f.name.as_deref(), f.value.as_str()))
+            .collect()
+    }
+
+    fn text(event: &DecodedEvent) -> Vec<&str> {
+        event.fields.iter().map(|f| f.value.as_str()).collect()
+    }
+
+    #[test]
+    fn csv() {
+        let event = decode("csv", &[], b"a,\"b,c\",\"say \"\"hi\"\"\",\r\n");
+        assert_eq!(event.decoder, DecoderKind::Csv);
+        assert_eq!(text(&event), vec!["a", "b,c", "say \"hi\"", ""]);
+        assert!(event.fields.iter().all(|f| f.name.is_none()));
+    }
+
+    #[test]
+    fn csv_with_unterminated_quote() {
+        let event = decode("csv", &[], b"a,\"b,c");
+        assert_eq!(event.decoder, DecoderKind::Csv);
+        assert_eq!(text(&event), vec!["a", "b,c"]);
+    }
+
+    #[test]
+    fn json() {
+        let event = decode(
+            "log",
+            &[],
+            br#"{"status":200,"path":"/index.html","tags":["a","b"],"user":null}"#,
+        );
+        assert_eq!(event.decoder, DecoderKind::Json);
+        assert_eq!(
+            fields(&event),
+            vec![
+                (Some("status"), "200"),
+                (Some("path"), "/index.html"),
+                (Some("tags"), r#"["a","b"]"#),
+                (Some("user"), "null"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_json() {
+        let raw = br#"{"status":200,"path":"/index.html""#;
+        let event = decode("log", &[], raw);
+        assert_eq!(event.decoder, DecoderKind::Text);
+        assert_eq!(
+            fields(&event),
+            vec![(None, std::str::from_utf8(raw).unwrap())]
+        );
+
+        let event = decode("log", &[], b"[1, 2]");
+        assert_eq!(event.decoder, DecoderKind::Text);
+    }
+
+    #[test]
+    fn common_log_format() {
+        let event = decode(
+            "log",
+            &[],
+            b"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] \
+              \"GET /apache_pb.gif HTTP/1.0\" 200 -\n",
+        );
+        assert_eq!(event.decoder, DecoderKind::HttpAccessLog);
+        assert_eq!(
+            fields(&event),
+            vec![
+                (Some("remote_host"), "127.0.0.1"),
+                (Some("ident"), "-"),
+                (Some("user"), "frank"),
+                (Some("time"), "10/Oct/2000:13:55:36 -0700"),
+                (Some("request"), "GET /apache_pb.gif HTTP/1.0"),
+                (Some("status"), "200"),
+                (Some("bytes"), "-"),
+            ]
+        );
+    }
+
+    #[test]
+    fn combined_log_format() {
+        let event = decode(
+            "log",
+            &[],
+            b"10.0.0.1 - - [10/Oct/2000:13:55:36 -0700] \"GET / HTTP/1.1\" 304 0 \
+              \"http://example.com/\" \"Mozilla/5.0 (X11; \\\"Linux\\\")\"",
+        );
+        assert_eq!(event.decoder, DecoderKind::HttpAccessLog);
+        assert_eq!(
+            fields(&event)[7..],
+            [
+                (Some("referer"), "http://example.com/"),
+                (Some("user_agent"), "Mozilla/5.0 (X11; \\\"Linux\\\")"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_access_log() {
+        let event = decode(
+            "log",
+            &[],
+            b"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] \"GET / HTTP/1.0\" 2xx 0",
+        );
+        assert_eq!(event.decoder, DecoderKind::Text);
+
+        let event = decode(
+            "log",
+            &[],
+            b"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700 \"GET / HTTP/1.0\" 200 0",
+        );
+        assert_eq!(event.decoder, DecoderKind::Text);
+    }
+
+    #[test]
+    fn key_value() {
+        let event = decode(
+            "log",
+            &[],
+            b"time=12:00:01 msg=\"connection refused\" from host.name level=warn",
+        );
+        assert_eq!(event.decoder, DecoderKind::KeyValue);
+        assert_eq!(
+            fields(&event),
+            vec![
+                (Some("time"), "12:00:01"),
+                (Some("msg"), "connection refused"),
+                (None, "from"),
+                (None, "host.name"),
+                (Some("level"), "warn"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_key_value() {
+        // A single pair is not enough.
+        let event = decode("log", &[], b"user=admin logged in");
+        assert_eq!(event.decoder, DecoderKind::Text);
+        assert_eq!(fields(&event), vec![(None, "user=admin logged in")]);
+
+        // An unterminated quote makes the words unknown.
+        let event = decode("log", &[], b"a=1 b=2 msg=\"connection");
+        assert_eq!(event.decoder, DecoderKind::Text);
+
+        // A name must consist of letters, digits, `_`, `.`, and `-`.
+        let event = decode("log", &[], b"a=1 1+1=2 =3");
+        assert_eq!(event.decoder, DecoderKind::Text);
+    }
+
+    #[test]
+    fn text_of_email_and_unknown_data_types() {
+        for data_type in &["email", "unknown", ""] {
+            let event = decode(data_type, &[], b"Subject: a=1 b=2\r\n\r\nbody\r\n");
+            assert_eq!(event.decoder, DecoderKind::Text);
+            assert_eq!(fields(&event), vec![(None, "Subject: a=1 b=2\r\n\r\nbody")]);
+        }
+    }
+
+    #[test]
+    fn hex_dump_of_packet() {
+        let event = decode("packet", &[], b"GET / HTTP/1.1\r\nHost");
+        assert_eq!(event.decoder, DecoderKind::HexDump);
+        assert_eq!(
+            fields(&event),
+            vec![
+                (
+                    Some("00000000"),
+                    "47 45 54 20 2f 20 48 54 54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|"
+                ),
+                (
+                    Some("00000010"),
+                    "48 6f 73 74                                      |Host|"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn hex_dump_of_non_utf8() {
+        for data_type in &["csv", "email", "log", "packet"] {
+            let event = decode(data_type, &[], b"\xff\x00a,b");
+            assert_eq!(event.decoder, DecoderKind::HexDump);
+            assert_eq!(
+                fields(&event),
+                vec![(
+                    Some("00000000"),
+                    "ff 00 61 2c 62                                   |..a,b|"
+                )]
+            );
+        }
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(text(&decode("csv", &[], b"")), vec![""]);
+        assert_eq!(text(&decode("log", &[], b"")), vec![""]);
+        assert!(decode("packet", &[], b"").fields.is_empty());
+    }
+
+    #[test]
+    fn layout() {
+        let layout = vec!["int".to_string(), "ipaddr".to_string()];
+        let event = decode("csv", &layout, b"1,10.0.0.1,x");
+        let columns = event
+            .fields
+            .iter()
+            .map(|f| (f.index, f.data_type.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            columns,
+            vec![(0, Some("int")), (1, Some("ipaddr")), (2, None)]
+        );
+
+        let event = decode("log", &layout, br#"{"a":1}"#);
+        assert_eq!(event.fields[0].data_type.as_deref(), Some("int"));
+    }
+
+    #[test]
+    fn serialized() {
+        let event = serde_json::to_value(decode("log", &["int".to_string()], b"a=1 b=2")).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({
+                "decoder": "key_value",
+                "fields": [
+                    {"index": 0, "name": "a", "value": "1", "data_type": "int"},
+                    {"index": 1, "name": "b", "value": "2", "data_type": null},
+                ],
+            })
+        );
+    }
+}
@@ -5,20 +5,22 @@ use actix_web::{
 };
 use chrono::{NaiveDate, NaiveDateTime};
 use diesel::prelude::*;
+use ipnetwork::IpNetwork;
 use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::net::{IpAddr, Ipv4Addr};
-use std::str::FromStr;
 use structured::{Description, DescriptionElement};
 
 use super::schema::{
     self, cluster, column_description, data_source, description_binary, description_datetime,
-    description_enum, description_float, description_int, description_ipaddr, description_text,
-    top_n_binary, top_n_datetime, top_n_enum, top_n_float, top_n_int, top_n_ipaddr, top_n_text,
+    description_element_type, description_enum, description_float, description_int,
+    description_ipaddr, description_text, top_n_binary, top_n_datetime, top_n_enum, top_n_float,
+    top_n_int, top_n_ipaddr, top_n_text,
 };
 use crate::database::data_source::DataSourceQuery;
-use crate::database::{self, build_http_500_response, load_payload};
+use crate::database::{self, build_error_response, load_payload, ApiError, BulkQuery, BulkResults};
 
 #[derive(Clone, Debug, Default, Serialize)]
 struct DescriptionLoad {
@@ -40,19 +42,6 @@ struct DescriptionInsert {
     descriptions: Vec<Description>,
 }
 
-#[derive(Debug, Queryable)]
-struct ColumnDescriptionsTable {
-    // for loading from db
-    pub id: i32,
-    pub cluster_id: i32,
-    pub first_event_id: String,
-    pub last_event_id: String,
-    pub column_index: i32,
-    pub type_id: i32,
-    pub count: i64,
-    pub unique_count: i64,
-}
-
 #[derive(Debug, Insertable)]
 #[table_name = "column_description"]
 struct ColumnDescriptionsInsert {
@@ -66,19 +55,6 @@ struct ColumnDescriptionsInsert {
     unique_count: i64,
 }
 
-#[derive(Debug)]
-struct ColumnDescriptionsWithSerial {
-    // used during processing
-    pub id: i32,
-    pub cluster_id: i32,
-    pub first_event_id: String,
-    pub last_event_id: String,
-    pub column_index: i32,
-    pub type_id: i32,
-    pub count: i64,
-    pub unique_count: i64,
-}
-
 #[derive(Debug, Queryable)]
 struct DescriptionsIntTable {
     // for loading from db
@@ -169,19 +145,18 @@ struct DescriptionsBinaryInsert {
     mode: Option<Vec<u8>>,
 }
 
-// TODO: Instead of String, how about using cidr? But, need to figure out how.
 #[derive(Debug, Queryable)]
 struct DescriptionsIpaddrTable {
     pub id: i32,
     pub description_id: i32,
-    pub mode: Option<String>,
+    pub mode: Option<IpNetwork>,
 }
 
 #[derive(Debug, Insertable)]
 #[table_name = "description_ipaddr"]
 struct DescriptionsIpaddrInsert {
     description_id: i32,
-    mode: Option<String>,
+    mode: Option<IpNetwork>,
 }
 
 #[derive(Debug, Queryable)]
@@ -292,13 +267,12 @@ struct TopNBinaryInsert {
     count: Option<i64>,
 }
 
-// TODO: Instead of String, how about using cidr? But, need to figure out how.
 #[derive(Debug, Queryable)]
 struct TopNIpaddrTable {
     pub id: i32,
     pub description_id: i32,
     pub ranking: Option<i64>,
-    pub value: Option<String>,
+    pub value: Option<IpNetwork>,
     pub count: Option<i64>,
 }
 
@@ -307,7 +281,7 @@ struct TopNIpaddrTable {
 struct TopNIpaddrInsert {
     description_id: i32,
     ranking: Option<i64>,
-    value: Option<String>,
+    value: Option<IpNetwork>,
     count: Option<i64>,
 }
 
@@ -358,56 +332,85 @@ struct ColumnDescription {
     pub unique_count: i64,
 }
 
-macro_rules! push_top_n_others {
-    ($cdsc:expr, $t:path, $func:tt, $id:expr, $d:expr, $dv:ident) => {{
-        if let Some(top_n) = $cdsc.get_top_n() {
-            for (r, (v, c)) in top_n.iter().enumerate() {
-                let insert_v = if let $t(v) = v {
-                    Some((*v).$func())
-                } else {
-                    None
-                };
-                $d.push($dv {
-                    description_id: $id,
-                    ranking: Some(safe_cast_usize_to_i64(r + 1)),
-                    value: insert_v,
-                    count: Some(safe_cast_usize_to_i64(*c)),
-                });
-            }
+/// The types of column descriptions, each stored in its own pair of
+/// description and top-n tables.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum ElementType {
+    Int,
+    Enum,
+    Float,
+    Text,
+    IpAddr,
+    DateTime,
+    Binary,
+}
+
+impl ElementType {
+    /// Returns the type of a column whose mode is `mode`.
+    fn of(mode: &DescriptionElement) -> Option<Self> {
+        match mode {
+            DescriptionElement::Int(_) => Some(Self::Int),
+            DescriptionElement::Enum(_) => Some(Self::Enum),
+            DescriptionElement::FloatRange(_, _) => Some(Self::Float),
+            DescriptionElement::Text(_) => Some(Self::Text),
+            DescriptionElement::IpAddr(_) => Some(Self::IpAddr),
+            DescriptionElement::DateTime(_) => Some(Self::DateTime),
+            DescriptionElement::Binary(_) => Some(Self::Binary),
+            _ => None,
         }
-    }};
+    }
+
+    /// Returns the type with `name` in `description_element_type`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Int" => Some(Self::Int),
+            "Enum" => Some(Self::Enum),
+            "Float" => Some(Self::Float),
+            "Text" => Some(Self::Text),
+            "IpAddr" => Some(Self::IpAddr),
+            "DateTime" => Some(Self::DateTime),
+            "Binary" => Some(Self::Binary),
+            _ => None,
+        }
+    }
 }
 
-macro_rules! insert_rows_to_db {
-    ($r:expr, $conn:expr, $dd:ident, $td:ident, $dv:expr, $tv:expr) => {{
-        $r.push(
-            diesel::insert_into(schema::$dd::dsl::$dd)
-                .values($dv)
-                .execute($conn)
-                .map_err(Into::into),
-        );
-        $r.push(
-            diesel::insert_into(schema::$td::dsl::$td)
-                .values($tv)
-                .execute($conn)
-                .map_err(Into::into),
-        );
-    }};
+/// Loads the id of each type from `description_element_type`.
+fn load_element_type_ids(
+    conn: &database::Conn,
+) -> Result<HashMap<ElementType, i32>, database::Error> {
+    use description_element_type::dsl;
+    Ok(dsl::description_element_type
+        .select((dsl::id, dsl::name))
+        .load::<(i32, String)>(conn)?
+        .into_iter()
+        .filter_map(|(id, name)| ElementType::from_name(&name).map(|t| (t, id)))
+        .collect())
 }
 
-macro_rules! change_to_db_id {
-    ($s:expr, $d:expr, $data:expr) => {{
-        for d in $data.iter_mut() {
-            d.description_id = *$d
-                .get($s.get(&d.description_id).expect("safe"))
-                .expect("safe");
+macro_rules! insert_in_batches {
+    ($conn:expr, $table:ident, $rows:expr) => {{
+        for batch in $rows.chunks(database::STAGING_BATCH_SIZE) {
+            diesel::insert_into(schema::$table::table)
+                .values(batch)
+                .execute($conn)?;
         }
     }};
 }
 
+/// Returns a condition that holds if a cluster has an IpAddr column whose
+/// value in `column` of `table` is within `subnet`.
+pub(crate) fn subnet_condition(table: &str, column: &str, subnet: &IpNetwork) -> String {
+    format!(
+        "EXISTS (SELECT 1 FROM column_description \
+         INNER JOIN {0} ON {0}.description_id = column_description.id \
+         WHERE column_description.cluster_id = cluster.id AND {0}.{1} <<= '{2}')",
+        table, column, subnet
+    )
+}
+
 const MAX_VALUE_OF_I64_I64: i64 = 9_223_372_036_854_775_807_i64;
 const MAX_VALUE_OF_I64_USIZE: usize = 9_223_372_036_854_775_807_usize;
-const MAX_VALUE_OF_I32_USIZE: usize = 2_147_483_647_usize;
 
 pub fn safe_cast_usize_to_i64(value: usize) -> i64 {
     if value > MAX_VALUE_OF_I64_USIZE {
@@ -417,412 +420,393 @@ pub fn safe_cast_usize_to_i64(value: usize) -> i64 {
     }
 }
 
-#[allow(clippy::cognitive_complexity)]
-#[allow(clippy::too_many_lines)]
 pub(crate) async fn add_descriptions(
-    pool: Data<database::Pool>,
+    pool: Data<database::Database>,
     payload: Payload,
     data_source: Query<DataSourceQuery>,
+    query: Query<BulkQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let bytes = load_payload(payload).await?;
     let insert_descriptions: Vec<DescriptionInsert> = serde_json::from_slice(&bytes)?;
     let data_source: String = data_source.into_inner().data_source;
+    let atomic = query.is_atomic();
+
+    let insert_result = pool
+        .run(move |conn| {
+            use schema::cluster::dsl as c_d;
+            use schema::data_source::dsl as d_d;
+
+            let requested = insert_descriptions
+                .iter()
+                .map(|d| d.cluster_id.as_str())
+                .collect::<Vec<_>>();
+            let ids_by_cluster = c_d::cluster
+                .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
+                .filter(
+                    d_d::topic_name
+                        .eq(&data_source)
+                        .and(c_d::cluster_id.eq_any(&requested)),
+                )
+                .select((c_d::cluster_id, c_d::id))
+                .load::<(Option<String>, i32)>(conn)?
+                .into_iter()
+                .filter_map(|(cluster_id, id)| cluster_id.map(|cluster_id| (cluster_id, id)))
+                .collect::<HashMap<_, _>>();
+
+            let mut results = BulkResults::new(insert_descriptions.len(), atomic);
+            let cluster_ids = insert_descriptions
+                .iter()
+                .enumerate()
+                .map(|(i, descriptions_of_cluster)| {
+                    if descriptions_of_cluster.first_event_id.is_none()
+                        || descriptions_of_cluster.last_event_id.is_none()
+                    {
+                        results.fail(
+                            i,
+                            &ApiError::bad_request("first_event_id and last_event_id are required"),
+                        );
+                        return None;
+                    }
+                    let cluster_id = ids_by_cluster.get(&descriptions_of_cluster.cluster_id);
+                    if cluster_id.is_none() {
+                        results.fail(i, &ApiError::not_found("cluster"));
+                    }
+                    cluster_id.copied()
+                })
+                .collect::<Vec<_>>();
+            results.apply(conn, |indices| {
+                let descriptions = indices
+                    .iter()
+                    .filter_map(|&i| cluster_ids[i].map(|id| (id, &insert_descriptions[i])))
+                    .collect::<Vec<_>>();
+                insert_cluster_descriptions(conn, &descriptions)
+            })?;
+            Ok(results)
+        })
+        .await;
 
-    let insert_result = pool.get().map_err(Into::into).and_then(|conn| {
-        let mut result: Vec<Result<usize, database::Error>> = Vec::new();
-
-        let mut desc_data_int = Vec::<DescriptionsIntInsert>::new();
-        let mut top_n_data_int = Vec::<TopNIntInsert>::new();
-        let mut desc_data_float = Vec::<DescriptionsFloatInsert>::new();
-        let mut top_n_data_float = Vec::<TopNFloatInsert>::new();
-        let mut desc_data_enum = Vec::<DescriptionsEnumInsert>::new();
-        let mut top_n_data_enum = Vec::<TopNEnumInsert>::new();
-        let mut desc_data_text = Vec::<DescriptionsTextInsert>::new();
-        let mut top_n_data_text = Vec::<TopNTextInsert>::new();
-        let mut desc_data_binary = Vec::<DescriptionsBinaryInsert>::new();
-        let mut top_n_data_binary = Vec::<TopNBinaryInsert>::new();
-        let mut desc_data_ipaddr = Vec::<DescriptionsIpaddrInsert>::new();
-        let mut top_n_data_ipaddr = Vec::<TopNIpaddrInsert>::new();
-        let mut desc_data_datetime = Vec::<DescriptionsDatetimeInsert>::new();
-        let mut top_n_data_datetime = Vec::<TopNDatetimeInsert>::new();
-        let mut column_description_data = Vec::<ColumnDescriptionsWithSerial>::new();
-
-        let mut tmp_serial_id = 0_i32;
-
-        for descriptions_of_cluster in insert_descriptions {
-            let cluster_id = {
-                use schema::cluster::dsl as c_d;
-                use schema::data_source::dsl as d_d;
-                match c_d::cluster
-                    .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
-                    .filter(
-                        c_d::cluster_id
-                            .eq(&descriptions_of_cluster.cluster_id)
-                            .and(d_d::topic_name.eq(&data_source)),
-                    )
-                    .select(c_d::id)
-                    .first::<i32>(&conn)
-                {
-                    Ok(v) => v,
-                    Err(_) => continue,
-                }
+    match insert_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+/// Inserts the column descriptions of each cluster, given by its id in the
+/// `cluster` table. Each table is written with one statement per batch of
+/// rows, and the ids of the new column descriptions come from `RETURNING`.
+/// A round given more than once is stored once, with the descriptions given
+/// last.
+fn insert_cluster_descriptions(
+    conn: &database::Conn,
+    descriptions: &[(i32, &DescriptionInsert)],
+) -> Result<(), database::Error> {
+    use column_description::dsl as cd_d;
+
+    let type_ids = load_element_type_ids(conn)?;
+    let last_of_round = descriptions
+        .iter()
+        .enumerate()
+        .map(|(position, &(cluster_id, d))| {
+            ((cluster_id, d.first_event_id, d.last_event_id), position)
+        })
+        .collect::<HashMap<_, _>>();
+    let mut rows = Vec::<ColumnDescriptionsInsert>::new();
+    let mut columns = Vec::<(ElementType, &Description)>::new();
+    for (position, &(cluster_id, descriptions_of_cluster)) in descriptions.iter().enumerate() {
+        let round = (
+            cluster_id,
+            descriptions_of_cluster.first_event_id,
+            descriptions_of_cluster.last_event_id,
+        );
+        if last_of_round.get(&round) != Some(&position) {
+            continue;
+        }
+        let (first_event_id, last_event_id) = match (
+            descriptions_of_cluster.first_event_id,
+            descriptions_of_cluster.last_event_id,
+        ) {
+            (Some(first), Some(last)) => (first.to_string(), last.to_string()),
+            _ => continue,
+        };
+        for (i, column) in descriptions_of_cluster.descriptions.iter().enumerate() {
+            let element_type = match column.get_mode().and_then(ElementType::of) {
+                Some(element_type) => element_type,
+                None => continue,
+            };
+            let column_index = match i32::try_from(i) {
+                Ok(column_index) => column_index,
+                Err(_) => continue,
             };
+            let type_id = *type_ids
+                .get(&element_type)
+                .ok_or(database::Error::NotFound("description element type"))?;
+            rows.push(ColumnDescriptionsInsert {
+                cluster_id,
+                first_event_id: first_event_id.clone(),
+                last_event_id: last_event_id.clone(),
+                column_index,
+                type_id,
+                count: safe_cast_usize_to_i64(column.get_count()),
+                unique_count: safe_cast_usize_to_i64(column.get_unique_count()),
+            });
+            columns.push((element_type, column));
+        }
+    }
 
-            for (i, column_description) in descriptions_of_cluster.descriptions.iter().enumerate() {
-                let count = safe_cast_usize_to_i64(column_description.get_count());
-                let unique_count = safe_cast_usize_to_i64(column_description.get_unique_count());
-
-                let type_id: i32 = match column_description.get_mode() {
-                    // TODO: Do I have to read this value from description_element_type table?
-                    Some(DescriptionElement::Int(_)) => 1_i32,
-                    Some(DescriptionElement::Enum(_)) => 2_i32,
-                    Some(DescriptionElement::FloatRange(_, _)) => 3_i32,
-                    Some(DescriptionElement::Text(_)) => 4_i32,
-                    Some(DescriptionElement::IpAddr(_)) => 5_i32,
-                    Some(DescriptionElement::DateTime(_)) => 6_i32,
-                    Some(DescriptionElement::Binary(_)) => 7_i32,
-                    _ => continue,
-                };
+    let mut elements = ElementRows::default();
+    for (rows, columns) in rows
+        .chunks(database::STAGING_BATCH_SIZE)
+        .zip(columns.chunks(database::STAGING_BATCH_SIZE))
+    {
+        let ids = diesel::insert_into(cd_d::column_description)
+            .values(rows)
+            .returning((
+                cd_d::cluster_id,
+                cd_d::first_event_id,
+                cd_d::last_event_id,
+                cd_d::column_index,
+                cd_d::id,
+            ))
+            .get_results::<(i32, String, String, i32, i32)>(conn)?
+            .into_iter()
+            .map(|(cluster_id, first, last, column_index, id)| {
+                ((cluster_id, first, last, column_index), id)
+            })
+            .collect::<HashMap<_, _>>();
+        for (row, &(element_type, column)) in rows.iter().zip(columns) {
+            let key = (
+                row.cluster_id,
+                row.first_event_id.clone(),
+                row.last_event_id.clone(),
+                row.column_index,
+            );
+            let id = *ids
+                .get(&key)
+                .ok_or(database::Error::NotFound("column description"))?;
+            elements.push(id, element_type, column);
+        }
+    }
+    elements.insert(conn)
+}
 
-                tmp_serial_id += 1;
+/// Returns the ranking, the value, and the count of each top-n element of
+/// `column`, with `value` converting the element for its table.
+fn top_n_rows<T, F>(column: &Description, value: F) -> Vec<(i64, Option<T>, i64)>
+where
+    F: Fn(&DescriptionElement) -> Option<T>,
+{
+    column.get_top_n().map_or_else(Vec::new, |top_n| {
+        top_n
+            .iter()
+            .enumerate()
+            .map(|(r, (v, c))| {
+                (
+                    safe_cast_usize_to_i64(r + 1),
+                    value(v),
+                    safe_cast_usize_to_i64(*c),
+                )
+            })
+            .collect()
+    })
+}
 
-                let first_event_id = if let Some(v) = descriptions_of_cluster.first_event_id {
-                    v.to_string()
-                } else {
-                    continue;
+/// The rows of the description and top-n tables of each type.
+#[derive(Default)]
+struct ElementRows {
+    desc_int: Vec<DescriptionsIntInsert>,
+    top_n_int: Vec<TopNIntInsert>,
+    desc_enum: Vec<DescriptionsEnumInsert>,
+    top_n_enum: Vec<TopNEnumInsert>,
+    desc_float: Vec<DescriptionsFloatInsert>,
+    top_n_float: Vec<TopNFloatInsert>,
+    desc_text: Vec<DescriptionsTextInsert>,
+    top_n_text: Vec<TopNTextInsert>,
+    desc_ipaddr: Vec<DescriptionsIpaddrInsert>,
+    top_n_ipaddr: Vec<TopNIpaddrInsert>,
+    desc_datetime: Vec<DescriptionsDatetimeInsert>,
+    top_n_datetime: Vec<TopNDatetimeInsert>,
+    desc_binary: Vec<DescriptionsBinaryInsert>,
+    top_n_binary: Vec<TopNBinaryInsert>,
+}
+
+impl ElementRows {
+    /// Adds the rows of `column`, whose id in `column_description` is
+    /// `description_id`.
+    #[allow(clippy::too_many_lines)]
+    fn push(&mut self, description_id: i32, element_type: ElementType, column: &Description) {
+        match element_type {
+            ElementType::Int => {
+                let int = |v: Option<&DescriptionElement>| match v {
+                    Some(DescriptionElement::Int(v)) => Some(*v),
+                    _ => None,
                 };
-                let last_event_id = if let Some(v) = descriptions_of_cluster.last_event_id {
-                    v.to_string()
-                } else {
-                    continue;
+                self.desc_int.push(DescriptionsIntInsert {
+                    description_id,
+                    min: int(column.get_min()),
+                    max: int(column.get_max()),
+                    mean: column.get_mean(),
+                    s_deviation: column.get_s_deviation(),
+                    mode: int(column.get_mode()),
+                });
+                for (ranking, value, count) in top_n_rows(column, |v| int(Some(v))) {
+                    self.top_n_int.push(TopNIntInsert {
+                        description_id,
+                        ranking: Some(ranking),
+                        value,
+                        count: Some(count),
+                    });
+                }
+            }
+            ElementType::Enum => {
+                let enumeration = |v: &DescriptionElement| match v {
+                    DescriptionElement::Enum(v) => Some(v.clone()),
+                    _ => None,
                 };
-                let column_index = if i <= MAX_VALUE_OF_I32_USIZE {
-                    i.to_i32().expect("Safe cast: 0..=MaxOfi32 -> i32")
-                } else {
-                    continue;
+                self.desc_enum.push(DescriptionsEnumInsert {
+                    description_id,
+                    mode: column.get_mode().and_then(enumeration),
+                });
+                for (ranking, value, count) in top_n_rows(column, enumeration) {
+                    self.top_n_enum.push(TopNEnumInsert {
+                        description_id,
+                        ranking: Some(ranking),
+                        value,
+                        count: Some(count),
+                    });
+                }
+            }
+            ElementType::Float => {
+                let float = |v: Option<&DescriptionElement>| match v {
+                    Some(DescriptionElement::Float(v)) => Some(*v),
+                    _ => None,
+                };
+                let range = |v: &DescriptionElement| match v {
+                    DescriptionElement::FloatRange(x, y) => Some((*x, *y)),
+                    _ => None,
                 };
-                column_description_data.push(ColumnDescriptionsWithSerial {
-                    id: tmp_serial_id,
-                    cluster_id,
-                    first_event_id,
-                    last_event_id,
-                    column_index,
-                    type_id,
-                    count,
-                    unique_count,
+                let mode = column.get_mode().and_then(range);
+                self.desc_float.push(DescriptionsFloatInsert {
+                    description_id,
+                    min: float(column.get_min()),
+                    max: float(column.get_max()),
+                    mean: column.get_mean(),
+                    s_deviation: column.get_s_deviation(),
+                    mode_smallest: mode.map(|(x, _)| x),
+                    mode_largest: mode.map(|(_, y)| y),
                 });
-
-                match column_description.get_mode() {
-                    Some(DescriptionElement::Int(md)) => {
-                        {
-                            let insert_min = if let Some(DescriptionElement::Int(v)) =
-                                column_description.get_min()
-                            {
-                                Some(*v)
-                            } else {
-                                None
-                            };
-                            let insert_max = if let Some(DescriptionElement::Int(v)) =
-                                column_description.get_max()
-                            {
-                                Some(*v)
-                            } else {
-                                None
-                            };
-                            desc_data_int.push(DescriptionsIntInsert {
-                                description_id: tmp_serial_id,
-                                min: insert_min,
-                                max: insert_max,
-                                mean: column_description.get_mean(),
-                                s_deviation: column_description.get_s_deviation(),
-                                mode: Some(*md),
-                            });
-                        }
-                        if let Some(top_n) = column_description.get_top_n() {
-                            for (r, (v, c)) in top_n.iter().enumerate() {
-                                let insert_v = match v {
-                                    DescriptionElement::Int(v) => Some(*v),
-                                    _ => None,
-                                };
-                                top_n_data_int.push(TopNIntInsert {
-                                    description_id: tmp_serial_id,
-                                    ranking: Some(safe_cast_usize_to_i64(r + 1)),
-                                    value: insert_v,
-                                    count: Some(safe_cast_usize_to_i64(*c)),
-                                });
-                            }
-                        }
-                    }
-                    Some(DescriptionElement::Enum(md)) => {
-                        desc_data_enum.push(DescriptionsEnumInsert {
-                            description_id: tmp_serial_id,
-                            mode: Some(md.clone()),
-                        });
-                        push_top_n_others!(
-                            column_description,
-                            DescriptionElement::Enum,
-                            clone,
-                            tmp_serial_id,
-                            top_n_data_enum,
-                            TopNEnumInsert
-                        );
-                    }
-                    Some(DescriptionElement::FloatRange(x, y)) => {
-                        {
-                            let i_min = if let Some(DescriptionElement::Float(x)) =
-                                column_description.get_min()
-                            {
-                                Some(*x)
-                            } else {
-                                None
-                            };
-                            let i_max = if let Some(DescriptionElement::Float(x)) =
-                                column_description.get_max()
-                            {
-                                Some(*x)
-                            } else {
-                                None
-                            };
-                            desc_data_float.push(DescriptionsFloatInsert {
-                                description_id: tmp_serial_id,
-                                min: i_min,
-                                max: i_max,
-                                mean: column_description.get_mean(),
-                                s_deviation: column_description.get_s_deviation(),
-                                mode_smallest: Some(*x),
-                                mode_largest: Some(*y),
-                            });
-                        }
-                        {
-                            if let Some(top_n) = column_description.get_top_n() {
-                                for (r, (v, c)) in top_n.iter().enumerate() {
-                                    let (x, y) = if let DescriptionElement::FloatRange(x, y) = v {
-                                        (Some(*x), Some(*y))
-                                    } else {
-                                        (None, None)
-                                    };
-                                    top_n_data_float.push(TopNFloatInsert {
-                                        description_id: tmp_serial_id,
-                                        ranking: Some(safe_cast_usize_to_i64(r + 1)),
-                                        value_smallest: x,
-                                        value_largest: y,
-                                        count: Some(safe_cast_usize_to_i64(*c)),
-                                    });
-                                }
-                            }
-                        }
-                    }
-                    Some(DescriptionElement::Text(md)) => {
-                        desc_data_text.push(DescriptionsTextInsert {
-                            description_id: tmp_serial_id,
-                            mode: Some(md.clone()),
-                        });
-                        push_top_n_others!(
-                            column_description,
-                            DescriptionElement::Text,
-                            clone,
-                            tmp_serial_id,
-                            top_n_data_text,
-                            TopNTextInsert
-                        );
-                    }
-                    Some(DescriptionElement::Binary(md)) => {
-                        desc_data_binary.push(DescriptionsBinaryInsert {
-                            description_id: tmp_serial_id,
-                            mode: Some(md.to_vec()),
-                        });
-                        push_top_n_others!(
-                            column_description,
-                            DescriptionElement::Binary,
-                            clone,
-                            tmp_serial_id,
-                            top_n_data_binary,
-                            TopNBinaryInsert
-                        );
-                    }
-                    Some(DescriptionElement::IpAddr(md)) => {
-                        desc_data_ipaddr.push(DescriptionsIpaddrInsert {
-                            description_id: tmp_serial_id,
-                            mode: Some(md.to_string()),
-                        });
-                        push_top_n_others!(
-                            column_description,
-                            DescriptionElement::IpAddr,
-                            to_string,
-                            tmp_serial_id,
-                            top_n_data_ipaddr,
-                            TopNIpaddrInsert
-                        );
-                    }
-                    Some(DescriptionElement::DateTime(md)) => {
-                        desc_data_datetime.push(DescriptionsDatetimeInsert {
-                            description_id: tmp_serial_id,
-                            mode: Some(*md),
-                        });
-                        push_top_n_others!(
-                            column_description,
-                            DescriptionElement::DateTime,
-                            clone,
-                            tmp_serial_id,
-                            top_n_data_datetime,
-                            TopNDatetimeInsert
-                        );
-                    }
-                    _ => continue,
+                for (ranking, value, count) in top_n_rows(column, range) {
+                    self.top_n_float.push(TopNFloatInsert {
+                        description_id,
+                        ranking: Some(ranking),
+                        value_smallest: value.map(|(x, _)| x),
+                        value_largest: value.map(|(_, y)| y),
+                        count: Some(count),
+                    });
                 }
             }
-        }
-
-        let mut c_result = 0_usize;
-        let column_description_data_to_db: Vec<ColumnDescriptionsInsert> = column_description_data
-            .iter()
-            .map(|d| ColumnDescriptionsInsert {
-                cluster_id: d.cluster_id,
-                first_event_id: d.first_event_id.clone(),
-                last_event_id: d.last_event_id.clone(),
-                column_index: d.column_index,
-                type_id: d.type_id,
-                count: d.count,
-                unique_count: d.unique_count,
-            })
-            .collect();
-        let serial_id: HashMap<i32, (i32, String, String, i32)> = column_description_data
-            .iter()
-            .map(|d| {
-                (
-                    d.id,
-                    (
-                        d.cluster_id,
-                        d.first_event_id.clone(),
-                        d.last_event_id.clone(),
-                        d.column_index,
-                    ),
-                )
-            })
-            .collect();
-
-        match diesel::insert_into(schema::column_description::dsl::column_description)
-            .values(column_description_data_to_db)
-            .get_results::<ColumnDescriptionsTable>(&conn)
-        {
-            Ok(inserted) => {
-                let db_id: HashMap<(i32, String, String, i32), i32> = inserted
-                    .iter()
-                    .map(|d| {
-                        (
-                            (
-                                d.cluster_id,
-                                d.first_event_id.clone(),
-                                d.last_event_id.clone(),
-                                d.column_index,
-                            ),
-                            d.id,
-                        )
-                    })
-                    .collect();
-
-                change_to_db_id!(serial_id, db_id, desc_data_int);
-                change_to_db_id!(serial_id, db_id, desc_data_enum);
-                change_to_db_id!(serial_id, db_id, desc_data_float);
-                change_to_db_id!(serial_id, db_id, desc_data_text);
-                change_to_db_id!(serial_id, db_id, desc_data_binary);
-                change_to_db_id!(serial_id, db_id, desc_data_ipaddr);
-                change_to_db_id!(serial_id, db_id, desc_data_datetime);
-
-                change_to_db_id!(serial_id, db_id, top_n_data_int);
-                change_to_db_id!(serial_id, db_id, top_n_data_enum);
-                change_to_db_id!(serial_id, db_id, top_n_data_float);
-                change_to_db_id!(serial_id, db_id, top_n_data_text);
-                change_to_db_id!(serial_id, db_id, top_n_data_binary);
-                change_to_db_id!(serial_id, db_id, top_n_data_ipaddr);
-                change_to_db_id!(serial_id, db_id, top_n_data_datetime);
-
-                insert_rows_to_db!(
-                    result,
-                    &conn,
-                    description_int,
-                    top_n_int,
-                    desc_data_int,
-                    top_n_data_int
-                );
-                insert_rows_to_db!(
-                    result,
-                    &conn,
-                    description_enum,
-                    top_n_enum,
-                    desc_data_enum,
-                    top_n_data_enum
-                );
-                insert_rows_to_db!(
-                    result,
-                    &conn,
-                    description_float,
-                    top_n_float,
-                    desc_data_float,
-                    top_n_data_float
-                );
-                insert_rows_to_db!(
-                    result,
-                    &conn,
-                    description_text,
-                    top_n_text,
-                    desc_data_text,
-                    top_n_data_text
-                );
-                insert_rows_to_db!(
-                    result,
-                    &conn,
-                    description_binary,
-                    top_n_binary,
-                    desc_data_binary,
-                    top_n_data_binary
-                );
-                insert_rows_to_db!(
-                    result,
-                    &conn,
-                    description_ipaddr,
-                    top_n_ipaddr,
-                    desc_data_ipaddr,
-                    top_n_data_ipaddr
-                );
-                insert_rows_to_db!(
-                    result,
-                    &conn,
-                    description_datetime,
-                    top_n_datetime,
-                    desc_data_datetime,
-                    top_n_data_datetime
-                );
+            ElementType::Text => {
+                let text = |v: &DescriptionElement| match v {
+                    DescriptionElement::Text(v) => Some(v.clone()),
+                    _ => None,
+                };
+                self.desc_text.push(DescriptionsTextInsert {
+                    description_id,
+                    mode: column.get_mode().and_then(text),
+                });
+                for (ranking, value, count) in top_n_rows(column, text) {
+                    self.top_n_text.push(TopNTextInsert {
+                        description_id,
+                        ranking: Some(ranking),
+                        value,
+                        count: Some(count),
+                    });
+                }
             }
-            Err(_) => c_result = 1_usize,
-        }
-
-        for r in result {
-            match r {
-                Ok(_) => {
-                    c_result += 1;
+            ElementType::IpAddr => {
+                let ipaddr = |v: &DescriptionElement| match v {
+                    DescriptionElement::IpAddr(v) => Some(IpNetwork::from(*v)),
+                    _ => None,
+                };
+                self.desc_ipaddr.push(DescriptionsIpaddrInsert {
+                    description_id,
+                    mode: column.get_mode().and_then(ipaddr),
+                });
+                for (ranking, value, count) in top_n_rows(column, ipaddr) {
+                    self.top_n_ipaddr.push(TopNIpaddrInsert {
+                        description_id,
+                        ranking: Some(ranking),
+                        value,
+                        count: Some(count),
+                    });
+                }
+            }
+            ElementType::DateTime => {
+                let datetime = |v: &DescriptionElement| match v {
+                    DescriptionElement::DateTime(v) => Some(*v),
+                    _ => None,
+                };
+                self.desc_datetime.push(DescriptionsDatetimeInsert {
+                    description_id,
+                    mode: column.get_mode().and_then(datetime),
+                });
+                for (ranking, value, count) in top_n_rows(column, datetime) {
+                    self.top_n_datetime.push(TopNDatetimeInsert {
+                        description_id,
+                        ranking: Some(ranking),
+                        value,
+                        count: Some(count),
+                    });
+                }
+            }
+            ElementType::Binary => {
+                let binary = |v: &DescriptionElement| match v {
+                    DescriptionElement::Binary(v) => Some(v.clone()),
+                    _ => None,
+                };
+                self.desc_binary.push(DescriptionsBinaryInsert {
+                    description_id,
+                    mode: column.get_mode().and_then(binary),
+                });
+                for (ranking, value, count) in top_n_rows(column, binary) {
+                    self.top_n_binary.push(TopNBinaryInsert {
+                        description_id,
+                        ranking: Some(ranking),
+                        value,
+                        count: Some(count),
+                    });
                 }
-                Err(e) => return Err(e),
             }
         }
-        Ok(c_result)
-    });
+    }
 
-    match insert_result {
-        Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+    fn insert(&self, conn: &database::Conn) -> Result<(), database::Error> {
+        insert_in_batches!(conn, description_int, self.desc_int);
+        insert_in_batches!(conn, top_n_int, self.top_n_int);
+        insert_in_batches!(conn, description_enum, self.desc_enum);
+        insert_in_batches!(conn, top_n_enum, self.top_n_enum);
+        insert_in_batches!(conn, description_float, self.desc_float);
+        insert_in_batches!(conn, top_n_float, self.top_n_float);
+        insert_in_batches!(conn, description_text, self.desc_text);
+        insert_in_batches!(conn, top_n_text, self.top_n_text);
+        insert_in_batches!(conn, description_ipaddr, self.desc_ipaddr);
+        insert_in_batches!(conn, top_n_ipaddr, self.top_n_ipaddr);
+        insert_in_batches!(conn, description_datetime, self.desc_datetime);
+        insert_in_batches!(conn, top_n_datetime, self.top_n_datetime);
+        insert_in_batches!(conn, description_binary, self.desc_binary);
+        insert_in_batches!(conn, top_n_binary, self.top_n_binary);
+        Ok(())
     }
 }
 
 pub(crate) async fn get_rounds_by_cluster(
-    pool: Data<database::Pool>,
+    pool: Data<database::Database>,
     query: Query<RoundSelectQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use cluster::dsl as c_d;
     use column_description::dsl as cd_d;
     use data_source::dsl as d_d;
-    let query_result: Result<Vec<RoundResponse>, database::Error> =
-        pool.get().map_err(Into::into).and_then(|conn| {
+    let query_result: Result<Vec<RoundResponse>, database::Error> = pool
+        .run(move |conn| {
             cd_d::column_description
                 .inner_join(c_d::cluster.on(cd_d::cluster_id.eq(c_d::id)))
                 .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
@@ -833,15 +817,16 @@ pub(crate) async fn get_rounds_by_cluster(
                         .and(d_d::topic_name.eq(&query.data_source)),
                 )
                 .distinct_on((cd_d::first_event_id, cd_d::last_event_id))
-                .load::<RoundResponse>(&conn)
+                .load::<RoundResponse>(conn)
                 .map_err(Into::into)
-        });
+        })
+        .await;
 
     match query_result {
         Ok(round_response) => Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "application/json")
             .json(round_response)),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
@@ -869,11 +854,9 @@ impl GetValueByType {
         }
     }
 
-    pub fn top_n_ipaddr(value: &Option<String>) -> DescriptionElement {
+    pub fn top_n_ipaddr(value: &Option<IpNetwork>) -> DescriptionElement {
         match value {
-            Some(value) => DescriptionElement::IpAddr(IpAddr::V4(
-                Ipv4Addr::from_str(value).unwrap_or_else(|_| Ipv4Addr::new(0, 0, 0, 0)),
-            )),
+            Some(value) => DescriptionElement::IpAddr(value.ip()),
             None => DescriptionElement::IpAddr(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0))), // No chance
         }
     }
@@ -906,11 +889,9 @@ impl GetValueByType {
         }
     }
 
-    pub fn mode_ipaddr(value: Option<String>) -> Option<DescriptionElement> {
+    pub fn mode_ipaddr(value: Option<IpNetwork>) -> Option<DescriptionElement> {
         match value {
-            Some(m) => Some(DescriptionElement::IpAddr(IpAddr::V4(
-                Ipv4Addr::from_str(&m).unwrap_or_else(|_| Ipv4Addr::new(0, 0, 0, 0)),
-            ))),
+            Some(m) => Some(DescriptionElement::IpAddr(m.ip())),
             None => None,
         }
     }
@@ -923,342 +904,456 @@ impl GetValueByType {
     }
 }
 
-macro_rules! load_descriptions_others {
-    ($s_desc:ident, $s_top_n:ident, $t_desc:ty, $t_top_n:ty,
-        $conn:expr, $column:expr, $description:expr, $value_top_n:tt, $value_mode:tt) => {{
-        use $s_desc::dsl as d_d;
-        if let Ok(mode) = d_d::$s_desc
-            .select(d_d::mode)
-            .filter(d_d::description_id.eq(&$column.id))
-            .first::<Option<$t_desc>>($conn)
-        {
-            use $s_top_n::dsl as t_d;
-            let top_n = if let Ok(top_n) = t_d::$s_top_n
-                .filter(t_d::description_id.eq(&$column.id))
-                .order_by(t_d::ranking.asc())
-                .load::<$t_top_n>($conn)
-            {
-                let top_n: Vec<(DescriptionElement, usize)> = top_n
-                    .iter()
-                    .map(|t| {
-                        let value = GetValueByType::$value_top_n(&t.value);
-                        let count = match t.count {
-                            Some(count) => count.to_usize().unwrap_or(0_usize),
-                            None => 0_usize, // No chance
-                        };
-                        (value, count)
-                    })
-                    .collect();
-                Some(top_n)
+/// Groups the top-n elements by description. `rows` must be ordered by
+/// description and ranking.
+fn group_top_n<I>(rows: I) -> HashMap<i32, Vec<(DescriptionElement, usize)>>
+where
+    I: IntoIterator<Item = (i32, DescriptionElement, Option<i64>)>,
+{
+    let mut top_n = HashMap::<i32, Vec<_>>::new();
+    for (description_id, value, count) in rows {
+        let count = count.and_then(|c| c.to_usize()).unwrap_or(0_usize);
+        top_n
+            .entry(description_id)
+            .or_default()
+            .push((value, count));
+    }
+    top_n
+}
+
+fn load_int(
+    conn: &database::Conn,
+    ids: &[i32],
+) -> Result<HashMap<i32, DescriptionLoad>, database::Error> {
+    use description_int::dsl as di_d;
+    use top_n_int::dsl as ti_d;
+    let mut top_n = group_top_n(
+        ti_d::top_n_int
+            .filter(ti_d::description_id.eq_any(ids))
+            .order_by((ti_d::description_id, ti_d::ranking.asc()))
+            .load::<TopNIntTable>(conn)?
+            .into_iter()
+            .map(|t| {
+                let value = DescriptionElement::Int(t.value.unwrap_or(0_i64)); // No chance of None
+                (t.description_id, value, t.count)
+            }),
+    );
+    Ok(di_d::description_int
+        .filter(di_d::description_id.eq_any(ids))
+        .load::<DescriptionsIntTable>(conn)?
+        .into_iter()
+        .map(|d| {
+            let description = DescriptionLoad {
+                mean: d.mean,
+                s_deviation: d.s_deviation,
+                min: d.min.map(DescriptionElement::Int),
+                max: d.max.map(DescriptionElement::Int),
+                top_n: Some(top_n.remove(&d.description_id).unwrap_or_default()),
+                mode: d.mode.map(DescriptionElement::Int),
+                ..DescriptionLoad::default()
+            };
+            (d.description_id, description)
+        })
+        .collect())
+}
+
+fn load_float(
+    conn: &database::Conn,
+    ids: &[i32],
+) -> Result<HashMap<i32, DescriptionLoad>, database::Error> {
+    use description_float::dsl as df_d;
+    use top_n_float::dsl as tf_d;
+    let mut top_n = group_top_n(
+        tf_d::top_n_float
+            .filter(tf_d::description_id.eq_any(ids))
+            .order_by((tf_d::description_id, tf_d::ranking.asc()))
+            .load::<TopNFloatTable>(conn)?
+            .into_iter()
+            .map(|t| {
+                let value = DescriptionElement::FloatRange(
+                    t.value_smallest.unwrap_or(0_f64), // No chance of None
+                    t.value_largest.unwrap_or(0_f64),  // No chance of None
+                );
+                (t.description_id, value, t.count)
+            }),
+    );
+    Ok(df_d::description_float
+        .filter(df_d::description_id.eq_any(ids))
+        .load::<DescriptionsFloatTable>(conn)?
+        .into_iter()
+        .map(|d| {
+            let mode = if let (Some(smallest), Some(largest)) = (d.mode_smallest, d.mode_largest) {
+                Some(DescriptionElement::FloatRange(smallest, largest))
             } else {
                 None
             };
-            let mode = GetValueByType::$value_mode(mode);
-            $description = DescriptionLoad {
-                count: $column.count.to_usize().unwrap_or(0_usize),
-                unique_count: $column.unique_count.to_usize().unwrap_or(0_usize),
-                mean: None,
-                s_deviation: None,
-                min: None,
-                max: None,
-                top_n: top_n,
-                mode: mode,
-            }
-        } else {
-            $description = DescriptionLoad::default()
-        }
+            let description = DescriptionLoad {
+                mean: d.mean,
+                s_deviation: d.s_deviation,
+                min: d.min.map(DescriptionElement::Float),
+                max: d.max.map(DescriptionElement::Float),
+                top_n: Some(top_n.remove(&d.description_id).unwrap_or_default()),
+                mode,
+                ..DescriptionLoad::default()
+            };
+            (d.description_id, description)
+        })
+        .collect())
+}
+
+macro_rules! load_others {
+    ($conn:expr, $ids:expr, $s_desc:ident, $s_top_n:ident, $t_desc:ty, $t_top_n:ty,
+        $value_top_n:ident, $value_mode:ident) => {{
+        use $s_desc::dsl as d_d;
+        use $s_top_n::dsl as t_d;
+        let mut top_n = group_top_n(
+            t_d::$s_top_n
+                .filter(t_d::description_id.eq_any($ids))
+                .order_by((t_d::description_id, t_d::ranking.asc()))
+                .load::<$t_top_n>($conn)?
+                .into_iter()
+                .map(|t| {
+                    (
+                        t.description_id,
+                        GetValueByType::$value_top_n(&t.value),
+                        t.count,
+                    )
+                }),
+        );
+        d_d::$s_desc
+            .filter(d_d::description_id.eq_any($ids))
+            .load::<$t_desc>($conn)?
+            .into_iter()
+            .map(|d| {
+                let description = DescriptionLoad {
+                    top_n: Some(top_n.remove(&d.description_id).unwrap_or_default()),
+                    mode: GetValueByType::$value_mode(d.mode),
+                    ..DescriptionLoad::default()
+                };
+                (d.description_id, description)
+            })
+            .collect::<HashMap<_, _>>()
     }};
 }
 
-#[allow(clippy::too_many_lines)]
-pub(crate) async fn get_description(
-    pool: Data<database::Pool>,
-    query: Query<DescriptionSelectQuery>,
-) -> Result<HttpResponse, actix_web::Error> {
+/// Loads the descriptions of all columns in a round, in the order of the
+/// columns. Each type of description present in the round takes two queries.
+fn load_round(
+    conn: &database::Conn,
+    query: &DescriptionSelectQuery,
+) -> Result<Vec<DescriptionLoad>, database::Error> {
     use cluster::dsl as c_d;
     use column_description::dsl as cd_d;
     use data_source::dsl as d_d;
-    let query_result: Result<Vec<DescriptionLoad>, database::Error> =
-        pool.get().map_err(Into::into).and_then(|conn| {
-            cd_d::column_description
-                .inner_join(c_d::cluster.on(cd_d::cluster_id.eq(c_d::id)))
-                .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
-                .select((
-                    cd_d::id,
-                    cd_d::column_index,
-                    cd_d::type_id,
-                    cd_d::count,
-                    cd_d::unique_count,
-                ))
-                .filter(
-                    c_d::cluster_id
-                        .eq(&query.cluster_id)
-                        .and(d_d::topic_name.eq(&query.data_source))
-                        .and(cd_d::first_event_id.eq(&query.first_event_id))
-                        .and(cd_d::last_event_id.eq(&query.last_event_id)),
-                )
-                .order_by(cd_d::column_index.asc())
-                .load::<ColumnDescription>(&conn)
-                .and_then(|columns| {
-                    Ok(columns
-                        .iter()
-                        .map(|column| match column.type_id {
-                            1 => {
-                                use description_int::dsl as di_d;
-                                if let Ok((min, max, mean, s_deviation, mode)) =
-                                    di_d::description_int
-                                        .select((
-                                            di_d::min,
-                                            di_d::max,
-                                            di_d::mean,
-                                            di_d::s_deviation,
-                                            di_d::mode,
-                                        ))
-                                        .filter(di_d::description_id.eq(&column.id))
-                                        .first::<(
-                                            Option<i64>,
-                                            Option<i64>,
-                                            Option<f64>,
-                                            Option<f64>,
-                                            Option<i64>,
-                                        )>(&conn)
-                                {
-                                    use top_n_int::dsl as ti_d;
-                                    let top_n = if let Ok(top_n) = ti_d::top_n_int
-                                        .filter(ti_d::description_id.eq(&column.id))
-                                        .order_by(ti_d::ranking.asc())
-                                        .load::<TopNIntTable>(&conn)
-                                    {
-                                        let top_n: Vec<(DescriptionElement, usize)> = top_n
-                                            .iter()
-                                            .map(|t| {
-                                                let value = match t.value {
-                                                    Some(value) => DescriptionElement::Int(value),
-                                                    None => DescriptionElement::Int(0_i64), // No chance
-                                                };
-                                                let count = match t.count {
-                                                    Some(count) => {
-                                                        count.to_usize().unwrap_or(0_usize)
-                                                    }
-                                                    None => 0_usize, // No chance
-                                                };
-                                                (value, count)
-                                            })
-                                            .collect();
-                                        Some(top_n)
-                                    } else {
-                                        None
-                                    };
-                                    let min = match min {
-                                        Some(m) => Some(DescriptionElement::Int(m)),
-                                        None => None,
-                                    };
-                                    let max = match max {
-                                        Some(m) => Some(DescriptionElement::Int(m)),
-                                        None => None,
-                                    };
-                                    let mode = match mode {
-                                        Some(m) => Some(DescriptionElement::Int(m)),
-                                        None => None,
-                                    };
-                                    DescriptionLoad {
-                                        count: column.count.to_usize().unwrap_or(0_usize),
-                                        unique_count: column
-                                            .unique_count
-                                            .to_usize()
-                                            .unwrap_or(0_usize),
-                                        mean,
-                                        s_deviation,
-                                        min,
-                                        max,
-                                        top_n,
-                                        mode,
-                                    }
-                                } else {
-                                    DescriptionLoad::default()
-                                }
-                            }
-                            2 => {
-                                let description;
-                                load_descriptions_others!(
-                                    description_enum,
-                                    top_n_enum,
-                                    String,
-                                    TopNEnumTable,
-                                    &conn,
-                                    column,
-                                    description,
-                                    top_n_enum,
-                                    mode_enum
-                                );
-                                description
-                            }
-                            3 => {
-                                use description_float::dsl as df_d;
-                                if let Ok((
-                                    min,
-                                    max,
-                                    mean,
-                                    s_deviation,
-                                    mode_smallest,
-                                    mode_largest,
-                                )) = df_d::description_float
-                                    .select((
-                                        df_d::min,
-                                        df_d::max,
-                                        df_d::mean,
-                                        df_d::s_deviation,
-                                        df_d::mode_smallest,
-                                        df_d::mode_largest,
-                                    ))
-                                    .filter(df_d::description_id.eq(&column.id))
-                                    .first::<(
-                                        Option<f64>,
-                                        Option<f64>,
-                                        Option<f64>,
-                                        Option<f64>,
-                                        Option<f64>,
-                                        Option<f64>,
-                                    )>(&conn)
-                                {
-                                    use top_n_float::dsl as tf_d;
-                                    let top_n = if let Ok(top_n) = tf_d::top_n_float
-                                        .filter(tf_d::description_id.eq(&column.id))
-                                        .order_by(tf_d::ranking.asc())
-                                        .load::<TopNFloatTable>(&conn)
-                                    {
-                                        let top_n: Vec<(DescriptionElement, usize)> = top_n
-                                            .iter()
-                                            .map(|t| {
-                                                let smallest = match t.value_smallest {
-                                                    Some(value) => value,
-                                                    None => 0_f64, // No chance
-                                                };
-                                                let largest = match t.value_largest {
-                                                    Some(value) => value,
-                                                    None => 0_f64, // No chance
-                                                };
-                                                let count = match t.count {
-                                                    Some(count) => {
-                                                        count.to_usize().unwrap_or(0_usize)
-                                                    }
-                                                    None => 0_usize, // No chance
-                                                };
-                                                (
-                                                    DescriptionElement::FloatRange(
-                                                        smallest, largest,
-                                                    ),
-                                                    count,
-                                                )
-                                            })
-                                            .collect();
-                                        Some(top_n)
-                                    } else {
-                                        None
-                                    };
-                                    let min = match min {
-                                        Some(m) => Some(DescriptionElement::Float(m)),
-                                        None => None,
-                                    };
-                                    let max = match max {
-                                        Some(m) => Some(DescriptionElement::Float(m)),
-                                        None => None,
-                                    };
-                                    let mode = if let (Some(smallest), Some(largest)) =
-                                        (mode_smallest, mode_largest)
-                                    {
-                                        Some(DescriptionElement::FloatRange(smallest, largest))
-                                    } else {
-                                        None
-                                    };
-                                    DescriptionLoad {
-                                        count: column.count.to_usize().unwrap_or(0_usize),
-                                        unique_count: column
-                                            .unique_count
-                                            .to_usize()
-                                            .unwrap_or(0_usize),
-                                        mean,
-                                        s_deviation,
-                                        min,
-                                        max,
-                                        top_n,
-                                        mode,
-                                    }
-                                } else {
-                                    DescriptionLoad::default()
-                                }
-                            }
-                            4 => {
-                                let description;
-                                load_descriptions_others!(
-                                    description_text,
-                                    top_n_text,
-                                    String,
-                                    TopNTextTable,
-                                    &conn,
-                                    column,
-                                    description,
-                                    top_n_text,
-                                    mode_text
-                                );
-                                description
-                            }
-                            5 => {
-                                let description;
-                                load_descriptions_others!(
-                                    description_ipaddr,
-                                    top_n_ipaddr,
-                                    String,
-                                    TopNIpaddrTable,
-                                    &conn,
-                                    column,
-                                    description,
-                                    top_n_ipaddr,
-                                    mode_ipaddr
-                                );
-                                description
-                            }
-                            6 => {
-                                let description;
-                                load_descriptions_others!(
-                                    description_datetime,
-                                    top_n_datetime,
-                                    NaiveDateTime,
-                                    TopNDatetimeTable,
-                                    &conn,
-                                    column,
-                                    description,
-                                    top_n_datetime,
-                                    mode_datetime
-                                );
-                                description
-                            }
-                            7 => {
-                                let description;
-                                load_descriptions_others!(
-                                    description_binary,
-                                    top_n_binary,
-                                    Vec<u8>,
-                                    TopNBinaryTable,
-                                    &conn,
-                                    column,
-                                    description,
-                                    top_n_binary,
-                                    mode_binary
-                                );
-                                description
-                            }
-                            _ => DescriptionLoad::default(),
-                        })
-                        .collect::<Vec<DescriptionLoad>>())
-                })
-                .map_err(Into::into)
+    use description_element_type::dsl as det_d;
+
+    let columns = cd_d::column_description
+        .inner_join(c_d::cluster.on(cd_d::cluster_id.eq(c_d::id)))
+        .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
+        .inner_join(det_d::description_element_type.on(cd_d::type_id.eq(det_d::id)))
+        .select((
+            (
+                cd_d::id,
+                cd_d::column_index,
+                cd_d::type_id,
+                cd_d::count,
+                cd_d::unique_count,
+            ),
+            det_d::name,
+        ))
+        .filter(
+            c_d::cluster_id
+                .eq(&query.cluster_id)
+                .and(d_d::topic_name.eq(&query.data_source))
+                .and(cd_d::first_event_id.eq(&query.first_event_id))
+                .and(cd_d::last_event_id.eq(&query.last_event_id)),
+        )
+        .order_by(cd_d::column_index.asc())
+        .load::<(ColumnDescription, String)>(conn)?;
+
+    let mut ids_by_type = HashMap::<ElementType, Vec<i32>>::new();
+    for (column, name) in &columns {
+        if let Some(element_type) = ElementType::from_name(name) {
+            ids_by_type.entry(element_type).or_default().push(column.id);
+        }
+    }
+    let mut descriptions = HashMap::<i32, DescriptionLoad>::new();
+    for (element_type, ids) in &ids_by_type {
+        let ids = ids.as_slice();
+        descriptions.extend(match element_type {
+            ElementType::Int => load_int(conn, ids)?,
+            ElementType::Float => load_float(conn, ids)?,
+            ElementType::Enum => load_others!(
+                conn,
+                ids,
+                description_enum,
+                top_n_enum,
+                DescriptionsEnumTable,
+                TopNEnumTable,
+                top_n_enum,
+                mode_enum
+            ),
+            ElementType::Text => load_others!(
+                conn,
+                ids,
+                description_text,
+                top_n_text,
+                DescriptionsTextTable,
+                TopNTextTable,
+                top_n_text,
+                mode_text
+            ),
+            ElementType::IpAddr => load_others!(
+                conn,
+                ids,
+                description_ipaddr,
+                top_n_ipaddr,
+                DescriptionsIpaddrTable,
+                TopNIpaddrTable,
+                top_n_ipaddr,
+                mode_ipaddr
+            ),
+            ElementType::DateTime => load_others!(
+                conn,
+                ids,
+                description_datetime,
+                top_n_datetime,
+                DescriptionsDatetimeTable,
+                TopNDatetimeTable,
+                top_n_datetime,
+                mode_datetime
+            ),
+            ElementType::Binary => load_others!(
+                conn,
+                ids,
+                description_binary,
+                top_n_binary,
+                DescriptionsBinaryTable,
+                TopNBinaryTable,
+                top_n_binary,
+                mode_binary
+            ),
         });
+    }
+
+    Ok(columns
+        .into_iter()
+        .map(|(column, _)| match descriptions.remove(&column.id) {
+            Some(description) => DescriptionLoad {
+                count: column.count.to_usize().unwrap_or(0_usize),
+                unique_count: column.unique_count.to_usize().unwrap_or(0_usize),
+                ..description
+            },
+            None => DescriptionLoad::default(),
+        })
+        .collect())
+}
+
+pub(crate) async fn get_description(
+    pool: Data<database::Database>,
+    query: Query<DescriptionSelectQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query_result: Result<Vec<DescriptionLoad>, database::Error> =
+        pool.run(move |conn| load_round(conn, &query)).await;
 
     match query_result {
         Ok(response) => Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "application/json")
             .json(response)),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bigdecimal::BigDecimal;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::database::test_conn;
+
+    const DATA_SOURCE: &str = "description-test";
+    const CLUSTER_ID: &str = "description-test";
+
+    /// Adds a cluster and its data source, and returns its id in `cluster`.
+    fn add_cluster(conn: &database::Conn) -> i32 {
+        use cluster::dsl as c_d;
+        use data_source::dsl as d_d;
+        let data_source_id = diesel::insert_into(d_d::data_source)
+            .values((d_d::topic_name.eq(DATA_SOURCE), d_d::data_type.eq("log")))
+            .returning(d_d::id)
+            .get_result::<i32>(conn)
+            .expect("cannot add a data source");
+        diesel::insert_into(c_d::cluster)
+            .values((
+                c_d::cluster_id.eq(CLUSTER_ID),
+                c_d::detector_id.eq(1),
+                c_d::signature.eq("signature"),
+                c_d::size.eq(BigDecimal::from(1)),
+                c_d::data_source_id.eq(data_source_id),
+            ))
+            .returning(c_d::id)
+            .get_result::<i32>(conn)
+            .expect("cannot add a cluster")
+    }
+
+    /// Stores `descriptions`, the rounds of the test cluster in the request
+    /// body of `PUT /api/description`, and loads the round from event 1 to
+    /// event 2 as in the response of `GET /api/description`.
+    fn store_and_load(descriptions: Value) -> Value {
+        let conn = test_conn();
+        let cluster_id = add_cluster(&conn);
+        let rounds: Vec<DescriptionInsert> =
+            serde_json::from_value(descriptions).expect("invalid descriptions");
+        let rounds = rounds.iter().map(|r| (cluster_id, r)).collect::<Vec<_>>();
+        insert_cluster_descriptions(&conn, &rounds).expect("cannot store descriptions");
+        let query = DescriptionSelectQuery {
+            cluster_id: CLUSTER_ID.to_string(),
+            data_source: DATA_SOURCE.to_string(),
+            first_event_id: "1".to_string(),
+            last_event_id: "2".to_string(),
+        };
+        let round = load_round(&conn, &query).expect("cannot load descriptions");
+        serde_json::to_value(round).expect("cannot serialize descriptions")
+    }
+
+    /// Returns the round from event 1 to event 2 with `columns`.
+    fn round(columns: &Value) -> Value {
+        json!({
+            "cluster_id": CLUSTER_ID,
+            "first_event_id": 1,
+            "last_event_id": 2,
+            "descriptions": columns,
+        })
+    }
+
+    /// Returns the description of a column with the values in `top_n`, the
+    /// first of which is the mode. Only numbers have a mean, a minimum, and a
+    /// maximum.
+    fn column(top_n: &Value) -> Value {
+        json!({
+            "count": 6,
+            "unique_count": 2,
+            "mean": null,
+            "s_deviation": null,
+            "min": null,
+            "max": null,
+            "top_n": top_n,
+            "mode": top_n[0][0],
+        })
+    }
+
+    fn assert_round_trip(columns: &Value) {
+        assert_eq!(&store_and_load(json!([round(columns)])), columns);
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn int() {
+        assert_round_trip(&json!([{
+            "count": 6,
+            "unique_count": 2,
+            "mean": 1.5,
+            "s_deviation": 0.5,
+            "min": {"Int": 1},
+            "max": {"Int": 2},
+            "top_n": [[{"Int": 1}, 3], [{"Int": 2}, 3]],
+            "mode": {"Int": 1},
+        }]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn enumeration() {
+        assert_round_trip(&json!([column(&json!([
+            [{"Enum": "GET"}, 4],
+            [{"Enum": "POST"}, 2],
+        ]))]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn float_range() {
+        assert_round_trip(&json!([{
+            "count": 6,
+            "unique_count": 6,
+            "mean": 1.25,
+            "s_deviation": 0.75,
+            "min": {"Float": 0.5},
+            "max": {"Float": 2.5},
+            "top_n": [
+                [{"FloatRange": [0.5, 1.5]}, 4],
+                [{"FloatRange": [1.5, 2.5]}, 2],
+            ],
+            "mode": {"FloatRange": [0.5, 1.5]},
+        }]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn text() {
+        assert_round_trip(&json!([column(&json!([
+            [{"Text": "connection refused"}, 4],
+            [{"Text": "it's \"quoted\""}, 2],
+        ]))]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn ipv4_addr() {
+        assert_round_trip(&json!([column(&json!([
+            [{"IpAddr": "192.168.0.1"}, 4],
+            [{"IpAddr": "10.0.0.255"}, 2],
+        ]))]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn ipv6_addr() {
+        assert_round_trip(&json!([column(&json!([
+            [{"IpAddr": "2001:db8::1"}, 4],
+            [{"IpAddr": "::ffff:10.0.0.1"}, 2],
+        ]))]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn datetime() {
+        assert_round_trip(&json!([column(&json!([
+            [{"DateTime": "2020-03-01T12:34:56"}, 4],
+            [{"DateTime": "1999-12-31T23:59:59.500"}, 2],
+        ]))]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn binary() {
+        assert_round_trip(&json!([column(&json!([
+            [{"Binary": [0, 1, 255]}, 4],
+            [{"Binary": []}, 2],
+        ]))]));
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn columns_of_several_types() {
+        let columns = json!([
+            column(&json!([[{"Binary": [1]}, 4], [{"Binary": [2]}, 2]])),
+            column(&json!([[{"Enum": "a"}, 4], [{"Enum": "b"}, 2]])),
+            column(&json!([[{"IpAddr": "10.0.0.1"}, 4], [{"IpAddr": "::1"}, 2]])),
+            column(&json!([[{"Text": "a"}, 4], [{"Text": "b"}, 2]])),
+        ]);
+        assert_round_trip(&columns);
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn repeated_round() {
+        let first = json!([column(&json!([[{"Text": "first"}, 4], [{"Text": "a"}, 2]]))]);
+        let last = json!([
+            column(&json!([[{"Text": "last"}, 4], [{"Text": "b"}, 2]])),
+            column(&json!([[{"Enum": "last"}, 4], [{"Enum": "b"}, 2]])),
+        ]);
+        assert_eq!(store_and_load(json!([round(&first), round(&last)])), last);
     }
 }
@@ -1,18 +1,23 @@
 use actix_web::{
     http,
-    web::{Data, Payload, Query},
+    web::{self, Data, Payload, Query},
     HttpResponse,
 };
 use bigdecimal::{BigDecimal, FromPrimitive};
 use diesel::{pg::upsert::excluded, prelude::*};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
 
-use super::schema::event;
+use super::schema::{data_source, event};
 use crate::database::{
-    build_http_500_response, get_data_source_id, kafka_metadata_lookup, load_payload,
-    lookup_events_with_no_raw_event, Conn, Error, Pool,
+    build_error_response, decode, load_column_layout, load_payload,
+    lookup_events_with_no_raw_event, ApiError, BulkQuery, BulkResults, Codec, Conn, DataSource,
+    Database, DecodedEvent, Error, Storage,
 };
+use crate::kafka_consumer::{BrokerConfig, SourceSettings};
 
 #[derive(Debug, Clone, Insertable, Serialize, Deserialize)]
 #[table_name = "event"]
@@ -42,13 +47,50 @@ impl PartialEq for Event {
 
 impl Eq for Event {}
 
-pub(crate) fn add_events(conn: &Conn, events: &[Event]) -> Result<usize, Error> {
+#[derive(Debug, Insertable)]
+#[table_name = "event"]
+struct NewEvent<'a> {
+    message_id: &'a BigDecimal,
+    data_source_id: i32,
+    raw_event: Option<Vec<u8>>,
+    compressed: bool,
+    dictionary_id: Option<i32>,
+}
+
+pub(crate) fn add_events<'a, I>(conn: &Conn, events: I) -> Result<usize, Error>
+where
+    I: IntoIterator<Item = &'a Event>,
+{
     use event::dsl;
+    let mut codec = Codec::default();
+    let events = events
+        .into_iter()
+        .map(|event| {
+            let (raw_event, dictionary_id) = if let Some(raw_event) = &event.raw_event {
+                let (raw_event, dictionary_id) =
+                    codec.compress(conn, Some(event.data_source_id), raw_event)?;
+                (Some(raw_event), dictionary_id)
+            } else {
+                (None, None)
+            };
+            Ok(NewEvent {
+                message_id: &event.message_id,
+                data_source_id: event.data_source_id,
+                compressed: raw_event.is_some(),
+                raw_event,
+                dictionary_id,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
     diesel::insert_into(dsl::event)
-        .values(events)
+        .values(&events)
         .on_conflict((dsl::message_id, dsl::data_source_id))
         .do_update()
-        .set(dsl::raw_event.eq(excluded(dsl::raw_event)))
+        .set((
+            dsl::raw_event.eq(excluded(dsl::raw_event)),
+            dsl::compressed.eq(excluded(dsl::compressed)),
+            dsl::dictionary_id.eq(excluded(dsl::dictionary_id)),
+        ))
         .execute(conn)
         .map_err(Into::into)
 }
@@ -69,35 +111,129 @@ pub(crate) fn build_events(event_ids: &[u64], data_source_id: i32) -> Vec<Event>
 pub(crate) fn delete_events(conn: &Conn, events: &[Event]) -> Result<usize, Error> {
     use event::dsl;
     conn.transaction::<usize, Error, _>(|| {
-        Ok(events
-            .iter()
-            .filter_map(|event| {
-                diesel::delete(
-                    dsl::event.filter(
-                        dsl::data_source_id
-                            .eq(event.data_source_id)
-                            .and(dsl::message_id.eq(&event.message_id)),
-                    ),
-                )
-                .execute(conn)
-                .ok()
-            })
-            .sum())
+        events.iter().try_fold(0, |deleted, event| {
+            diesel::delete(
+                dsl::event.filter(
+                    dsl::data_source_id
+                        .eq(event.data_source_id)
+                        .and(dsl::message_id.eq(&event.message_id)),
+                ),
+            )
+            .execute(conn)
+            .map(|n| deleted + n)
+            .map_err(Into::into)
+        })
     })
 }
 
+/// The time limit of `GET /api/event/search`, in milliseconds, unless the
+/// request gives one.
+const DEFAULT_SEARCH_TIMEOUT: u64 = 5_000;
+
+/// The longest time limit a request to `GET /api/event/search` can give, in
+/// milliseconds.
+const MAX_SEARCH_TIMEOUT: u64 = 30_000;
+
+/// Where the raw event of an event found by `GET /api/event/search` came from.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EventStatus {
+    /// Stored in the database.
+    Cached,
+    /// Read from Kafka for this request, and stored in the database.
+    Fetched,
+    /// Neither stored nor read in time.
+    NotFound,
+    /// Stored, but failed to be decompressed.
+    Corrupted,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct GetEvent {
     message_id: u64,
     raw_event: Option<Vec<u8>>,
+    status: EventStatus,
+    #[serde(skip_deserializing)]
+    decoded: Option<DecodedEvent>,
+}
+
+/// Returns the events of `message_ids` stored in the database, and the
+/// partitions and the offsets of the Kafka messages with the raw events
+/// missing.
+fn load_events(
+    conn: &Conn,
+    data_source_id: i32,
+    message_ids: &[u64],
+) -> Result<(Vec<GetEvent>, Vec<(i32, i64)>), Error> {
+    use event::dsl;
+    let mut codec = Codec::default();
+    let events = conn.transaction::<Vec<GetEvent>, Error, _>(|| {
+        Ok(message_ids
+            .iter()
+            .filter_map(|&id| {
+                let message_id: BigDecimal = FromPrimitive::from_u64(id)?;
+                let result = dsl::event
+                    .filter(
+                        dsl::data_source_id
+                            .eq(data_source_id)
+                            .and(dsl::message_id.eq(message_id)),
+                    )
+                    .select((dsl::raw_event, dsl::compressed, dsl::dictionary_id))
+                    .get_result::<(Option<Vec<u8>>, bool, Option<i32>)>(conn);
+
+                let (raw_event, status) = match result {
+                    Ok((Some(raw_event), compressed, dictionary_id)) => {
+                        match codec.decompress(conn, &raw_event, compressed, dictionary_id) {
+                            Ok(raw_event) => (Some(raw_event), EventStatus::Cached),
+                            Err(e) => {
+                                log::error!("Failed to decompress an event: {}", e);
+                                (None, EventStatus::Corrupted)
+                            }
+                        }
+                    }
+                    _ => (None, EventStatus::NotFound),
+                };
+                Some(GetEvent {
+                    message_id: id,
+                    raw_event,
+                    status,
+                    decoded: None,
+                })
+            })
+            .collect::<Vec<_>>())
+    })?;
+
+    let mut locations = Vec::<(i32, i64)>::new();
+    for event in events
+        .iter()
+        .filter(|event| event.status == EventStatus::NotFound)
+    {
+        let message_id = match FromPrimitive::from_u64(event.message_id) {
+            Some(message_id) => message_id,
+            None => continue,
+        };
+        if let Some((_, partition, offset)) =
+            conn.kafka_metadata_lookup(data_source_id, &message_id)
+        {
+            if let (Ok(partition), Ok(offset)) = (i32::try_from(partition), i64::try_from(offset)) {
+                if !locations.contains(&(partition, offset)) {
+                    locations.push((partition, offset));
+                }
+            }
+        }
+    }
+    Ok((events, locations))
 }
+
 pub(crate) async fn get_events(
-    pool: Data<Pool>,
+    pool: Data<Database>,
+    broker: Data<BrokerConfig>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    use event::dsl;
-
-    let data_source = query.get("data_source").and_then(Value::as_str);
+    let data_source = query
+        .get("data_source")
+        .and_then(Value::as_str)
+        .map(str::to_string);
     let filter = query
         .get("filter")
         .and_then(Value::as_str)
@@ -106,80 +242,118 @@ pub(crate) async fn get_events(
         filter
             .get("message_ids")
             .and_then(Value::as_array)
-            .map(|f| {
-                f.iter()
-                    .filter_map(|f| {
-                        let message_id = f.as_u64()?;
-                        FromPrimitive::from_u64(message_id)
-                    })
-                    .collect::<Vec<_>>()
-            })
+            .map(|f| f.iter().filter_map(Value::as_u64).collect::<Vec<_>>())
     } else {
         None
     };
-    if let (Some(data_source), Some(message_ids)) = (data_source, message_ids) {
-        if message_ids.len() <= 100 {
-            let query_result: Result<Vec<_>, Error> =
-                pool.get().map_err(Into::into).and_then(|conn| {
-                    if let Ok(data_source_id) = get_data_source_id(&conn, data_source) {
-                        conn.transaction::<Vec<GetEvent>, Error, _>(|| {
-                            Ok(message_ids
-                                .into_iter()
-                                .filter_map(|id| {
-                                    let message_id: BigDecimal = FromPrimitive::from_u64(id)?;
-                                    let result = dsl::event
-                                        .filter(
-                                            dsl::data_source_id
-                                                .eq(data_source_id)
-                                                .and(dsl::message_id.eq(message_id)),
-                                        )
-                                        .select(dsl::raw_event)
-                                        .get_result::<Option<Vec<u8>>>(&conn);
-
-                                    match result {
-                                        Ok(Some(raw_event)) => Some(GetEvent {
-                                            message_id: id,
-                                            raw_event: Some(raw_event),
-                                        }),
-                                        _ => None,
-                                    }
-                                })
-                                .collect::<Vec<_>>())
-                        })
-                    } else {
-                        // if data_source is not found, return a response with empty body
-                        Ok(Vec::new())
-                    }
-                });
+    let timeout = match query.get("timeout").and_then(Value::as_str) {
+        Some(timeout) => match timeout.parse::<u64>() {
+            Ok(timeout) => timeout.min(MAX_SEARCH_TIMEOUT),
+            Err(_) => return Ok(ApiError::invalid("timeout", "Invalid timeout.").into()),
+        },
+        None => DEFAULT_SEARCH_TIMEOUT,
+    };
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+    let (data_source, message_ids) =
+        if let (Some(data_source), Some(message_ids)) = (data_source, message_ids) {
+            (data_source, message_ids)
+        } else {
+            return Ok(ApiError::bad_request("Missing required query parameters.").into());
+        };
+    if message_ids.len() > 100 {
+        return Ok(ApiError::invalid(
+            "filter",
+            "The number of message_id must be less than or equal to 100.",
+        )
+        .into());
+    }
+
+    let query_result: Result<_, Error> = pool
+        .run(move |conn| {
+            use data_source::dsl;
+            let data_source = match dsl::data_source
+                .filter(dsl::topic_name.eq(&data_source))
+                .first::<DataSource>(conn)
+                .optional()?
+            {
+                Some(data_source) => data_source,
+                None => return Ok(None),
+            };
+            let layout = load_column_layout(conn, data_source.template.as_deref())?;
+            let (events, locations) = load_events(conn, data_source.id, &message_ids)?;
+            Ok(Some((data_source, layout, events, locations)))
+        })
+        .await;
+    let (data_source, layout, mut events, locations) = match query_result {
+        Ok(Some(found)) => found,
+        // if data_source is not found, return a response with empty body
+        Ok(None) => return Ok(json_response(&Vec::<GetEvent>::new())),
+        Err(e) => return Ok(build_error_response(&e)),
+    };
 
-            match query_result {
-                Ok(data) => Ok(HttpResponse::Ok()
-                    .header(http::header::CONTENT_TYPE, "application/json")
-                    .json(data)),
-                Err(e) => Ok(build_http_500_response(&e)),
+    if !locations.is_empty() {
+        let missing = events
+            .iter()
+            .filter(|event| event.status == EventStatus::NotFound)
+            .map(|event| event.message_id)
+            .collect::<Vec<_>>();
+        let fetched = match SourceSettings::new(&data_source) {
+            Ok(settings) => {
+                let broker = broker.get_ref().clone();
+                web::block(move || settings.fetch_until(&broker, &missing, &locations, deadline))
+                    .await
+                    .unwrap_or_else(|e| {
+                        log::error!("Failed to fetch events from Kafka: {}", e);
+                        HashMap::new()
+                    })
+            }
+            Err(e) => {
+                log::error!("{}: {}", data_source.topic_name, e);
+                HashMap::new()
+            }
+        };
+
+        let mut new_events = Vec::new();
+        for event in &mut events {
+            if let Some(raw_event) = fetched.get(&event.message_id) {
+                if let Some(message_id) = FromPrimitive::from_u64(event.message_id) {
+                    new_events.push(Event {
+                        message_id,
+                        data_source_id: data_source.id,
+                        raw_event: Some(raw_event.clone()),
+                    });
+                }
+                event.raw_event = Some(raw_event.clone());
+                event.status = EventStatus::Fetched;
             }
-        } else {
-            let message = json!({
-                "message": "The number of message_id must be less than or equal to 100.",
-            })
-            .to_string();
-            Ok(HttpResponse::BadRequest()
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(message))
         }
-    } else {
-        let message = json!({
-            "message": "Missing required query parameters.",
-        })
-        .to_string();
-        Ok(HttpResponse::BadRequest()
-            .header(http::header::CONTENT_TYPE, "application/json")
-            .body(message))
+        if !new_events.is_empty() {
+            let add_result: Result<usize, Error> = pool
+                .run(move |conn| conn.add_events(&new_events.iter().collect::<Vec<_>>()))
+                .await;
+            if let Err(e) = add_result {
+                log::error!("Failed to store events fetched from Kafka: {}", e);
+            }
+        }
+    }
+
+    for event in &mut events {
+        event.decoded = event
+            .raw_event
+            .as_ref()
+            .map(|raw_event| decode(&data_source.data_type, &layout, raw_event));
     }
+    Ok(json_response(&events))
+}
+
+fn json_response<T: Serialize>(data: &T) -> HttpResponse {
+    HttpResponse::Ok()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .json(data)
 }
 
 pub(crate) async fn get_events_with_no_raw_event(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let data_source_id = query
@@ -188,64 +362,74 @@ pub(crate) async fn get_events_with_no_raw_event(
         .and_then(|data_source_id| data_source_id.parse::<i32>().ok());
 
     if let Some(data_source_id) = data_source_id {
-        let query_result: Result<_, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-            let message_ids = diesel::select(lookup_events_with_no_raw_event(data_source_id))
-                .get_results::<BigDecimal>(&conn)?;
-            let mut kafka_metadata = Vec::<(u64, u64)>::new();
-            if !message_ids.is_empty() {
-                let mut message_ids_cloned = message_ids.clone();
-                message_ids_cloned.sort();
-                while let Some(metadata) =
-                    kafka_metadata_lookup(&conn, data_source_id, &message_ids_cloned[0])
-                {
-                    if let Some(upper_value) =
-                        bigdecimal::FromPrimitive::from_u64(metadata.0) as Option<BigDecimal>
+        let query_result: Result<_, Error> = pool
+            .run(move |conn| {
+                let message_ids = diesel::select(lookup_events_with_no_raw_event(data_source_id))
+                    .get_results::<BigDecimal>(conn)?;
+                let mut kafka_metadata = Vec::<(u64, u64)>::new();
+                if !message_ids.is_empty() {
+                    let mut message_ids_cloned = message_ids.clone();
+                    message_ids_cloned.sort();
+                    while let Some(metadata) =
+                        conn.kafka_metadata_lookup(data_source_id, &message_ids_cloned[0])
                     {
-                        message_ids_cloned.retain(|v| upper_value < *v);
-                    } else {
-                        break;
-                    }
+                        if let Some(upper_value) =
+                            bigdecimal::FromPrimitive::from_u64(metadata.0) as Option<BigDecimal>
+                        {
+                            message_ids_cloned.retain(|v| upper_value < *v);
+                        } else {
+                            break;
+                        }
 
-                    kafka_metadata.push((metadata.1, metadata.2));
-                    if message_ids_cloned.is_empty() {
-                        break;
+                        kafka_metadata.push((metadata.1, metadata.2));
+                        if message_ids_cloned.is_empty() {
+                            break;
+                        }
                     }
                 }
-            }
 
-            let data = json!({
-                "message_ids": message_ids,
-                "metadata": kafka_metadata
-            })
-            .to_string();
+                let data = json!({
+                    "message_ids": message_ids,
+                    "metadata": kafka_metadata
+                })
+                .to_string();
 
-            Ok(data)
-        });
+                Ok(data)
+            })
+            .await;
 
         match query_result {
             Ok(data) => Ok(HttpResponse::Ok()
                 .header(http::header::CONTENT_TYPE, "application/json")
                 .body(data)),
-            Err(e) => Ok(build_http_500_response(&e)),
+            Err(e) => Ok(build_error_response(&e)),
         }
     } else {
-        Ok(HttpResponse::BadRequest().into())
+        Ok(ApiError::invalid("data_source_id", "Missing or invalid data_source_id").into())
     }
 }
 
 pub(crate) async fn update_events(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     payload: Payload,
+    query: Query<BulkQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let bytes = load_payload(payload).await?;
     let events: Vec<Event> = serde_json::from_slice(&bytes)?;
-    let query_result: Result<usize, Error> = pool
-        .get()
-        .map_err(Into::into)
-        .and_then(|conn| add_events(&conn, &events));
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            let mut results = BulkResults::new(events.len(), atomic);
+            results.apply(conn, |indices| {
+                conn.add_events(&indices.iter().map(|&i| &events[i]).collect::<Vec<_>>())
+                    .map(|_| ())
+            })?;
+            Ok(results)
+        })
+        .await;
 
     match query_result {
-        Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
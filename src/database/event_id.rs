@@ -3,12 +3,10 @@ use actix_web::{
     web::{Data, Query},
     HttpResponse,
 };
-use bigdecimal::{BigDecimal, FromPrimitive};
-use diesel::prelude::*;
 use serde_json::Value;
 use std::sync::Mutex;
 
-use crate::database::{attempt_event_ids_update, build_http_500_response, Conn, Pool};
+use crate::database::{build_error_response, ApiError, Database, Storage};
 
 pub(crate) async fn get_max_event_id_num(max_event_id_num: Data<Mutex<usize>>) -> HttpResponse {
     let max_event_id_num = max_event_id_num.lock().unwrap();
@@ -19,37 +17,54 @@ pub(crate) async fn get_max_event_id_num(max_event_id_num: Data<Mutex<usize>>) -
         .body(message)
 }
 
-pub(crate) fn update_event_ids(conn: &Conn, max_event_id_num: BigDecimal) -> Result<(), String> {
-    diesel::select(attempt_event_ids_update(max_event_id_num))
-        .execute(conn)
-        .map_err(|e| format!("Failed to run attempt_event_ids_update: {}", e))
-        .map(|_| ())
+pub(crate) fn update_event_ids<S: Storage>(
+    storage: &S,
+    max_event_id_num: usize,
+) -> Result<(), String> {
+    storage
+        .cap_event_ids(max_event_id_num)
+        .map_err(|e| format!("Failed to cap event_ids: {}", e))
 }
 
 pub(crate) async fn update_max_event_id_num(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     max_event_id_num: Data<Mutex<usize>>,
     query: Query<Value>,
 ) -> HttpResponse {
-    match query
+    let new_max_event_id_num = match query
         .get("max_event_id_num")
         .and_then(Value::as_str)
         .and_then(|v| v.parse::<usize>().ok())
     {
-        Some(new_max_event_id_num) => match max_event_id_num.lock() {
-            Ok(mut max_event_id_num) => {
-                if *max_event_id_num > new_max_event_id_num {
-                    if let (Ok(conn), Some(num)) =
-                        (pool.get(), FromPrimitive::from_usize(new_max_event_id_num))
-                    {
-                        let _ = update_event_ids(&conn, num);
-                    }
+        Some(new_max_event_id_num) => new_max_event_id_num,
+        None => {
+            return ApiError::invalid("max_event_id_num", "Missing or invalid max_event_id_num")
+                .into()
+        }
+    };
+    let decreased = match max_event_id_num.lock() {
+        Ok(mut max_event_id_num) => {
+            let decreased = *max_event_id_num > new_max_event_id_num;
+            *max_event_id_num = new_max_event_id_num;
+            decreased
+        }
+        Err(e) => {
+            log::error!("{}", e);
+            return build_error_response(&e);
+        }
+    };
+    if decreased {
+        let update_result = pool
+            .run(move |conn| {
+                if let Err(e) = update_event_ids(conn, new_max_event_id_num) {
+                    log::error!("{}", e);
                 }
-                *max_event_id_num = new_max_event_id_num;
-                HttpResponse::Ok().into()
-            }
-            Err(e) => build_http_500_response(&e),
-        },
-        None => HttpResponse::BadRequest().into(),
+                Ok(())
+            })
+            .await;
+        if let Err(e) = update_result {
+            return build_error_response(&e);
+        }
     }
+    HttpResponse::Ok().into()
 }
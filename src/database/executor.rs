@@ -0,0 +1,121 @@
+use actix_web::error::BlockingError;
+use actix_web::web;
+use diesel::prelude::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::database::{Conn, Error, Pool};
+
+/// A connection pool that runs database work on actix's blocking thread pool
+/// rather than on the async workers serving requests.
+///
+/// At most `max_pending` jobs may be running or queued at a time; any more are
+/// rejected with `Error::Saturated` instead of waiting. A job started with
+/// `run` runs in a transaction. If it does not finish within `timeout`,
+/// counted from when it is queued, it fails with `Error::Timeout` and its
+/// transaction is rolled back; PostgreSQL cancels a statement still running
+/// at the deadline.
+#[derive(Clone)]
+pub(crate) struct Database {
+    pool: Pool,
+    pending: Arc<AtomicUsize>,
+    max_pending: usize,
+    timeout: Duration,
+}
+
+/// Counts a job as pending until dropped.
+struct Pending(Arc<AtomicUsize>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Database {
+    pub(crate) fn new(pool: Pool, max_pending: usize, timeout: Duration) -> Self {
+        Self {
+            pool,
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending,
+            timeout,
+        }
+    }
+
+    /// Runs `f` in a transaction with a connection from the pool on the
+    /// blocking thread pool.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if too many jobs are pending, if `f` does not finish in
+    /// time, if no connection is available, or if `f` fails. Nothing `f` did is
+    /// committed in any of these cases.
+    pub(crate) async fn run<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Conn) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let deadline = Instant::now() + self.timeout;
+        self.spawn(move |conn| {
+            conn.transaction(|| {
+                let remaining = match deadline.checked_duration_since(Instant::now()) {
+                    Some(remaining) if remaining.as_millis() > 0 => remaining,
+                    _ => return Err(Error::Timeout),
+                };
+                diesel::sql_query(format!(
+                    "SET LOCAL statement_timeout = {}",
+                    remaining.as_millis()
+                ))
+                .execute(conn)?;
+                let result = f(conn);
+                // A statement canceled at the deadline fails `f`, which is
+                // reported as the timeout it is.
+                if Instant::now() >= deadline {
+                    return Err(Error::Timeout);
+                }
+                result
+            })
+        })
+        .await
+    }
+
+    /// Runs `f` with a connection from the pool on the blocking thread pool,
+    /// outside a transaction and without a time limit. Each statement of `f`
+    /// is committed on its own, as in a background job.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if too many jobs are pending, if no connection is
+    /// available, or if `f` fails.
+    pub(crate) async fn run_autocommit<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Conn) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.spawn(f).await
+    }
+
+    async fn spawn<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Conn) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.pending.fetch_add(1, Ordering::SeqCst) >= self.max_pending {
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            return Err(Error::Saturated);
+        }
+        let pending = Pending(self.pending.clone());
+        let pool = self.pool.clone();
+        let job = web::block(move || {
+            let _pending = pending;
+            let conn = pool.get()?;
+            f(&conn)
+        });
+        match job.await {
+            Ok(result) => Ok(result),
+            Err(BlockingError::Error(e)) => Err(e),
+            Err(BlockingError::Canceled) => Err(Error::Canceled),
+        }
+    }
+}
@@ -10,27 +10,6 @@ sql_function! {
     ) -> Integer;
 }
 
-sql_function! {
-    #[allow(clippy::too_many_arguments)]
-    fn attempt_cluster_upsert (
-        max_event_id_num: Numeric,
-        cluster_id: Varchar,
-        topic_name: Varchar,
-        data_type: Varchar,
-        detector_id: Integer,
-        event_ids: Nullable<Array<Numeric>>,
-        signature: Nullable<Varchar>,
-        score: Nullable<Float8>,
-        size: Nullable<Numeric>
-    ) -> Integer;
-}
-
-sql_function! {
-    fn attempt_event_ids_update (
-        max_event_id_num: Numeric
-    );
-}
-
 sql_function! {
     fn attempt_indicator_update (
         indicator_name: Varchar,
@@ -41,18 +20,6 @@ sql_function! {
     ) -> Integer;
 }
 
-sql_function! {
-    fn attempt_outlier_upsert (
-        max_event_id_num: Numeric,
-        id: Integer,
-        raw_event: Bytea,
-        topic_name: Varchar,
-        data_type: Varchar,
-        event_ids: Array<Numeric>,
-        size: Numeric
-    ) -> Integer;
-}
-
 sql_function! {
     fn attempt_qualifier_id_update (
         cluster_id: Varchar,
@@ -68,8 +35,15 @@ sql_function! {
 }
 
 sql_function! {
-    fn lookup_kafka_metadata (
-        data_source_id: Integer,
-        messae_id: Numeric
-    ) -> Nullable<Jsonb>;
+    fn merge_staged_clusters (
+        max_event_id_num: Numeric,
+        merge_round: Integer
+    ) -> Integer;
+}
+
+sql_function! {
+    fn merge_staged_outliers (
+        max_event_id_num: Numeric,
+        merge_round: Integer
+    ) -> Integer;
 }
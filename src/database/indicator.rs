@@ -1,59 +1,71 @@
 use actix_web::{
-    http,
     web::{Data, Json, Path, Query},
     HttpResponse,
 };
 use diesel::prelude::*;
-use serde_json::{json, Value};
-use std::collections::HashSet;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
 
 use super::schema::indicator;
 use crate::database::*;
 
 pub(crate) async fn add_indicator(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     indicators: Json<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use indicator::dsl;
 
-    let name = indicators.get("name").and_then(Value::as_str);
-    let token: Option<&Value> = indicators.get("token");
-    let description = indicators.get("description").and_then(Value::as_str);
-    let data_source = indicators.get("data_source").and_then(Value::as_str);
+    let mut indicators = indicators.into_inner();
+    let name = indicators
+        .get("name")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let token: Option<Value> = indicators.get_mut("token").map(Value::take);
+    let description = indicators
+        .get("description")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    let data_source = indicators
+        .get("data_source")
+        .and_then(Value::as_str)
+        .map(str::to_string);
 
     if let (Some(name), Some(token), Some(data_source)) = (name, token, data_source) {
         if serde_json::from_value::<HashSet<Vec<String>>>(token.clone()).is_err() {
-            let message = json!({
-                "message": "Invalid indicator",
-            });
-            return Ok(HttpResponse::BadRequest()
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(message));
+            return Ok(ApiError::invalid("token", "Invalid indicator").into());
         }
-        let insert_result: Result<_, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-            let data_source_id = get_data_source_id(&conn, &data_source)?;
-            diesel::insert_into(dsl::indicator)
-                .values((
-                    dsl::name.eq(name),
-                    dsl::token.eq(token),
-                    dsl::description.eq(description),
-                    dsl::data_source_id.eq(data_source_id),
-                ))
-                .execute(&conn)
-                .map_err(Into::into)
-        });
+        let insert_result: Result<_, Error> = pool
+            .run(move |conn| {
+                let data_source_id =
+                    get_data_source_id(conn, &data_source).map_err(|e| match e {
+                        Error::Query(diesel::result::Error::NotFound) => {
+                            Error::NotFound("data source")
+                        }
+                        e => e,
+                    })?;
+                diesel::insert_into(dsl::indicator)
+                    .values((
+                        dsl::name.eq(name),
+                        dsl::token.eq(token),
+                        dsl::description.eq(description),
+                        dsl::data_source_id.eq(data_source_id),
+                    ))
+                    .execute(conn)
+                    .map_err(Into::into)
+            })
+            .await;
 
         match insert_result {
             Ok(_) => Ok(HttpResponse::Created().into()),
-            Err(e) => Ok(build_http_500_response(&e)),
+            Err(e) => Ok(build_error_response(&e)),
         }
     } else {
-        Ok(HttpResponse::BadRequest().into())
+        Ok(ApiError::bad_request("name, token, and data_source are required").into())
     }
 }
 
 pub(crate) async fn delete_indicator(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use indicator::dsl;
@@ -65,50 +77,77 @@ pub(crate) async fn delete_indicator(
             _ => None,
         })
         .unwrap_or_else(|| false);
-    let name = query.get("name").and_then(Value::as_str);
+    let name = query
+        .get("name")
+        .and_then(Value::as_str)
+        .map(str::to_string);
 
-    if let (false, None) | (true, Some(_)) = (is_all, name) {
-        return Ok(HttpResponse::BadRequest().into());
+    if let (false, None) | (true, Some(_)) = (is_all, &name) {
+        return Ok(ApiError::bad_request("Either name or all=true must be given").into());
     }
 
-    let delete_result: Result<_, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        if let Some(name) = name {
-            diesel::delete(dsl::indicator.filter(dsl::name.eq(name)))
-                .execute(&conn)
-                .map_err(Into::into)
-        } else {
-            diesel::delete(dsl::indicator)
-                .execute(&conn)
-                .map_err(Into::into)
-        }
-    });
+    let delete_result: Result<_, Error> = pool
+        .run(move |conn| {
+            if let Some(name) = name {
+                diesel::delete(dsl::indicator.filter(dsl::name.eq(name)))
+                    .execute(conn)
+                    .map_err(Into::into)
+            } else {
+                diesel::delete(dsl::indicator)
+                    .execute(conn)
+                    .map_err(Into::into)
+            }
+        })
+        .await;
 
     match delete_result {
-        Ok(0) => Ok(HttpResponse::BadRequest().into()),
+        Ok(0) if !is_all => Ok(ApiError::not_found("indicator").into()),
         Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
 pub(crate) async fn get_indicators(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let indicator_schema =
-        "(indicator INNER JOIN data_source ON indicator.data_source_id = data_source.id)";
-    let select = vec![
-        "indicator.name",
-        "indicator.token",
-        "data_source.topic_name as data_source",
-        "indicator.description",
-        "indicator.last_modification_time",
-    ];
+    let indicator_schema = "(indicator INNER JOIN data_source \
+                            ON indicator.data_source_id = data_source.id AND NOT data_source.archived)";
+    let select = query
+        .get("select")
+        .and_then(Value::as_str)
+        .and_then(|s| serde_json::from_str::<HashMap<String, bool>>(s).ok())
+        .map(|s| {
+            s.iter()
+                .filter(|s| *s.1)
+                .filter_map(|s| match s.0.to_lowercase().as_str() {
+                    "name" => Some("indicator.name"),
+                    "token" => Some("indicator.token"),
+                    "data_source" => Some("data_source.topic_name as data_source"),
+                    "description" => Some("indicator.description"),
+                    "last_modification_time" => Some("indicator.last_modification_time"),
+                    "tags" => Some(INDICATOR_TAGS),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| {
+            vec![
+                "indicator.name",
+                "indicator.token",
+                "data_source.topic_name as data_source",
+                "indicator.description",
+                "indicator.last_modification_time",
+                INDICATOR_TAGS,
+            ]
+        });
     let filter = query
         .get("filter")
         .and_then(Value::as_str)
         .and_then(|f| serde_json::from_str::<Value>(f).ok());
     let where_clause = if let Some(filter) = filter {
-        filter.get("name").and_then(Value::as_array).map(|f| {
+        let name = filter.get("name").and_then(Value::as_array).map(|f| {
             let mut where_clause = String::new();
             for (index, f) in f.iter().enumerate() {
                 if let Some(f) = f.as_str() {
@@ -121,14 +160,31 @@ pub(crate) async fn get_indicators(
                 }
             }
             where_clause
-        })
+        });
+        let tag = filter.get("tag").and_then(Value::as_array).map(|f| {
+            f.iter()
+                .filter_map(Value::as_str)
+                .map(|t| tag_condition("indicator", t))
+                .collect::<Vec<_>>()
+                .join(" or ")
+        });
+        let where_clause = vec![name, tag]
+            .into_iter()
+            .flatten()
+            .filter(|f| !f.is_empty())
+            .map(|f| format!("({})", f))
+            .collect::<Vec<_>>();
+        if where_clause.is_empty() {
+            None
+        } else {
+            Some(where_clause.join(" and "))
+        }
     } else {
         None
     };
     let default_per_page = 10;
     let max_per_page = 100;
-    let page = GetQuery::get_page(&query);
-    let per_page = GetQuery::get_per_page(&query, max_per_page).unwrap_or_else(|| default_per_page);
+    let paging = Paging::new(&query, default_per_page, max_per_page);
     let orderby = query
         .get("orderby")
         .and_then(Value::as_str)
@@ -143,66 +199,95 @@ pub(crate) async fn get_indicators(
         None
     };
 
-    match pool.get() {
-        Ok(conn) => GetQuery::build_response(
-            select,
-            indicator_schema,
-            where_clause,
-            page,
-            per_page,
-            orderby,
-            order,
-            &conn,
-        ),
-        Err(e) => Ok(build_http_500_response(&e)),
-    }
+    GetQuery::build_response(
+        &pool,
+        select,
+        indicator_schema,
+        "indicator.id",
+        where_clause,
+        paging,
+        orderby,
+        order,
+    )
+    .await
 }
 
 pub(crate) async fn update_indicator(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     name: Path<String>,
     new_indicator: Json<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let new_indicator = new_indicator.into_inner();
+    let mut new_indicator = new_indicator.into_inner();
     let (new_name, new_data_source, new_description, new_token) = (
-        new_indicator.get("name").and_then(Value::as_str),
-        new_indicator.get("data_source").and_then(Value::as_str),
-        new_indicator.get("description").and_then(Value::as_str),
-        new_indicator.get("token"),
+        new_indicator
+            .get("name")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        new_indicator
+            .get("data_source")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        new_indicator
+            .get("description")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        new_indicator.get_mut("token").map(Value::take),
     );
-    if let Some(token) = new_token {
+    if let Some(token) = &new_token {
         if serde_json::from_value::<HashSet<Vec<String>>>(token.clone()).is_err() {
-            let message = json!({
-                "message": "Invalid indicator",
-            });
-            return Ok(HttpResponse::BadRequest()
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(message));
+            return Ok(ApiError::invalid("token", "Invalid indicator").into());
         }
     }
     if let (Some(_), _, _, _) | (_, Some(_), _, _) | (_, _, Some(_), _) | (_, _, _, Some(_)) =
-        (new_name, new_data_source, new_description, new_token)
+        (&new_name, &new_data_source, &new_description, &new_token)
     {
-        let query_result: Result<_, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-            let name = name.into_inner();
-            diesel::select(attempt_indicator_update(
-                name,
-                new_name,
-                new_token,
-                new_data_source,
-                new_description,
-            ))
-            .get_result::<i32>(&conn)
-            .map_err(Into::into)
-        });
+        let query_result: Result<Result<(), ApiError>, Error> = pool
+            .run(move |conn| {
+                use indicator::dsl;
+                let name = name.into_inner();
+                conn.transaction::<_, Error, _>(|| {
+                    let current = dsl::indicator
+                        .select(dsl::id)
+                        .filter(dsl::name.eq(&name))
+                        .for_update()
+                        .first::<i32>(conn)
+                        .optional()?;
+                    if current.is_none() {
+                        return Ok(Err(ApiError::not_found("indicator")));
+                    }
+                    if let Some(data_source) = &new_data_source {
+                        match get_data_source_id(conn, data_source) {
+                            Ok(_) => {}
+                            Err(Error::Query(diesel::result::Error::NotFound)) => {
+                                return Ok(Err(ApiError::invalid(
+                                    "data_source",
+                                    "Unknown data source",
+                                )))
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    diesel::select(attempt_indicator_update(
+                        name,
+                        new_name,
+                        new_token,
+                        new_data_source,
+                        new_description,
+                    ))
+                    .get_result::<i32>(conn)?;
+                    Ok(Ok(()))
+                })
+            })
+            .await;
         match query_result {
-            Ok(1) => Ok(HttpResponse::Ok().into()),
-            Ok(_) => Ok(HttpResponse::InternalServerError()
-                .header(http::header::CONTENT_TYPE, "application/json")
-                .body(json!({"message": "Something went wrong"}).to_string())),
-            Err(e) => Ok(build_http_500_response(&e)),
+            Ok(Ok(())) => Ok(HttpResponse::Ok().into()),
+            Ok(Err(e)) => Ok(e.into()),
+            Err(e) => Ok(build_error_response(&e)),
         }
     } else {
-        Ok(HttpResponse::BadRequest().into())
+        Ok(ApiError::bad_request(
+            "At least one of name, data_source, description, or token must be given",
+        )
+        .into())
     }
 }
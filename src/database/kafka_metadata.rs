@@ -5,12 +5,13 @@ use actix_web::{
 };
 use bigdecimal::BigDecimal;
 use diesel::prelude::*;
+use diesel::sql_types::{Integer, Jsonb, Nullable, Numeric};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::ops::Bound;
 
 use super::schema::kafka_metadata;
-use crate::database::{build_http_500_response, lookup_kafka_metadata, Conn, Error, Pool};
+use crate::database::{build_error_response, ApiError, Conn, Database, Error, Storage};
 
 #[derive(Debug, Clone, Insertable, Queryable, Serialize, Deserialize)]
 #[table_name = "kafka_metadata"]
@@ -21,28 +22,43 @@ pub(crate) struct KafkaMetadata {
     pub(crate) partition: i32,
 }
 
+/// The result of `lookup_kafka_metadata`, which is called with `sql_query`
+/// because `sql_function!` cannot declare a function returning `jsonb` when
+/// diesel is built with the `sqlite` feature.
+#[derive(Debug, QueryableByName)]
+struct KafkaMetadataLookup {
+    #[sql_type = "Nullable<Jsonb>"]
+    metadata: Option<Value>,
+}
+
 pub(crate) async fn add_kafka_metadata(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     metadata: Json<Vec<KafkaMetadata>>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    use kafka_metadata::dsl;
-    let query_result: Result<usize, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        let metadata = metadata.into_inner();
-        diesel::insert_into(dsl::kafka_metadata)
-            .values(&metadata)
-            .on_conflict((dsl::data_source_id, dsl::partition, dsl::offsets))
-            .do_nothing()
-            .execute(&conn)
-            .map_err(Into::into)
-    });
+    let query_result: Result<usize, Error> = pool
+        .run(move |conn| conn.add_kafka_metadata(&metadata.into_inner()))
+        .await;
     match query_result {
         Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
+pub(crate) fn insert_kafka_metadata(
+    conn: &Conn,
+    metadata: &[KafkaMetadata],
+) -> Result<usize, Error> {
+    use kafka_metadata::dsl;
+    diesel::insert_into(dsl::kafka_metadata)
+        .values(metadata)
+        .on_conflict((dsl::data_source_id, dsl::partition, dsl::offsets))
+        .do_nothing()
+        .execute(conn)
+        .map_err(Into::into)
+}
+
 pub(crate) async fn get_kafka_metadata(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use kafka_metadata::dsl;
@@ -52,8 +68,8 @@ pub(crate) async fn get_kafka_metadata(
         .and_then(|data_source_id| data_source_id.parse::<i32>().ok());
 
     if let Some(data_source_id) = data_source_id {
-        let query_result: Result<Vec<KafkaMetadata>, Error> =
-            pool.get().map_err(Into::into).and_then(|conn| {
+        let query_result: Result<Vec<KafkaMetadata>, Error> = pool
+            .run(move |conn| {
                 dsl::kafka_metadata
                     .filter(dsl::data_source_id.eq(data_source_id))
                     .select((
@@ -62,17 +78,18 @@ pub(crate) async fn get_kafka_metadata(
                         dsl::offsets,
                         dsl::partition,
                     ))
-                    .load::<KafkaMetadata>(&conn)
+                    .load::<KafkaMetadata>(conn)
                     .map_err(Into::into)
-            });
+            })
+            .await;
         match query_result {
             Ok(metadata) => Ok(HttpResponse::Ok()
                 .header(http::header::CONTENT_TYPE, "application/json")
                 .json(metadata)),
-            Err(e) => Ok(build_http_500_response(&e)),
+            Err(e) => Ok(build_error_response(&e)),
         }
     } else {
-        Ok(HttpResponse::BadRequest().into())
+        Ok(ApiError::invalid("data_source_id", "Missing or invalid data_source_id").into())
     }
 }
 
@@ -81,10 +98,12 @@ pub(crate) fn kafka_metadata_lookup(
     data_source_id: i32,
     message_id: &BigDecimal,
 ) -> Option<(u64, u64, u64)> {
-    diesel::select(lookup_kafka_metadata(data_source_id, message_id))
-        .get_result::<Option<Value>>(conn)
+    diesel::sql_query("SELECT lookup_kafka_metadata($1, $2) AS metadata")
+        .bind::<Integer, _>(data_source_id)
+        .bind::<Numeric, _>(message_id)
+        .get_result::<KafkaMetadataLookup>(conn)
         .ok()
-        .and_then(|v| v)
+        .and_then(|lookup| lookup.metadata)
         .and_then(|v| {
             if let (Some(message_id), Some(offsets), Some(partition)) = (
                 v.get("message_id").and_then(Value::as_u64),
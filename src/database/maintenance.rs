@@ -0,0 +1,41 @@
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+use crate::database::{Conn, Error};
+
+#[derive(QueryableByName)]
+struct TableName {
+    #[sql_type = "Text"]
+    tablename: String,
+}
+
+/// Rebuilds the indexes of `tables`, or of all tables if empty, one table at
+/// a time. Returns the names of the tables reindexed.
+///
+/// # Errors
+///
+/// Returns an error if any of `tables` does not exist, or if the database
+/// fails. The tables reindexed before the failure are kept.
+pub(crate) fn reindex_tables(conn: &Conn, tables: &[String]) -> Result<Vec<String>, Error> {
+    let existing = diesel::sql_query(
+        "SELECT tablename::TEXT AS tablename FROM pg_tables \
+         WHERE schemaname = current_schema() ORDER BY tablename",
+    )
+    .load::<TableName>(conn)?
+    .into_iter()
+    .map(|t| t.tablename)
+    .collect::<Vec<_>>();
+    let tables = if tables.is_empty() {
+        existing
+    } else {
+        if tables.iter().any(|t| !existing.contains(t)) {
+            return Err(Error::NotFound("table"));
+        }
+        tables.to_vec()
+    };
+    for table in &tables {
+        conn.batch_execute(&format!("REINDEX TABLE \"{}\"", table.replace('"', "\"\"")))?;
+    }
+    Ok(tables)
+}
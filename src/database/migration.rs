@@ -0,0 +1,166 @@
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+
+use crate::database::Error;
+
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+/// A migration in `migrations`, built into the binary by the build script.
+#[derive(Debug)]
+pub(crate) struct EmbeddedMigration {
+    version: &'static str,
+    name: &'static str,
+    up: &'static str,
+    down: &'static str,
+}
+
+impl EmbeddedMigration {
+    /// The name of the directory of the migration.
+    pub(crate) fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// The version the database is migrated to.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum MigrationTarget<'a> {
+    /// Applies all pending migrations.
+    Latest,
+    /// Applies or reverts migrations until `version` is the last one applied.
+    Version(&'a str),
+    /// Reverts the last migration applied.
+    RevertLast,
+}
+
+/// A migration applied or reverted by `migrate`.
+#[derive(Debug)]
+pub(crate) enum MigrationStep {
+    Applied(&'static EmbeddedMigration),
+    Reverted(&'static EmbeddedMigration),
+}
+
+#[derive(QueryableByName)]
+struct AppliedVersion {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+/// Applies all pending migrations.
+///
+/// # Errors
+///
+/// Returns an error if a migration fails. The migrations applied before it
+/// are kept.
+pub(crate) fn run_migrations(conn: &PgConnection) -> Result<(), Error> {
+    migrate(conn, MigrationTarget::Latest).map(|_| ())
+}
+
+/// Returns the migrations not applied yet.
+///
+/// # Errors
+///
+/// Returns an error if the applied migrations cannot be read.
+pub(crate) fn pending_migrations(
+    conn: &PgConnection,
+) -> Result<Vec<&'static EmbeddedMigration>, Error> {
+    let applied = applied_versions(conn)?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|v| v == m.version))
+        .collect())
+}
+
+/// Migrates the database to `target`, one migration per transaction, and
+/// returns the migrations applied or reverted in order.
+///
+/// # Errors
+///
+/// Returns an error if `target` is not a known version, if a migration to
+/// revert is not built into the binary, or if a migration fails. The
+/// migrations done before the failure are kept.
+pub(crate) fn migrate(
+    conn: &PgConnection,
+    target: MigrationTarget,
+) -> Result<Vec<MigrationStep>, Error> {
+    let applied = applied_versions(conn)?;
+    let is_applied = |m: &EmbeddedMigration| applied.iter().any(|v| v == m.version);
+    let mut steps = Vec::new();
+    match target {
+        MigrationTarget::Latest => {
+            for m in MIGRATIONS.iter().filter(|m| !is_applied(m)) {
+                apply(conn, m)?;
+                steps.push(MigrationStep::Applied(m));
+            }
+        }
+        MigrationTarget::Version(version) => {
+            if !MIGRATIONS.iter().any(|m| m.version == version) {
+                return Err(Error::NotFound("migration"));
+            }
+            for m in MIGRATIONS
+                .iter()
+                .filter(|m| m.version <= version && !is_applied(m))
+            {
+                apply(conn, m)?;
+                steps.push(MigrationStep::Applied(m));
+            }
+            for applied in applied.iter().rev().filter(|v| v.as_str() > version) {
+                let m = find(applied)?;
+                revert(conn, m)?;
+                steps.push(MigrationStep::Reverted(m));
+            }
+        }
+        MigrationTarget::RevertLast => {
+            if let Some(last) = applied.last() {
+                let m = find(last)?;
+                revert(conn, m)?;
+                steps.push(MigrationStep::Reverted(m));
+            }
+        }
+    }
+    Ok(steps)
+}
+
+/// Returns the versions of the applied migrations in ascending order. The
+/// table is the one `diesel_migrations` keeps, so that databases migrated by
+/// earlier versions are recognized.
+fn applied_versions(conn: &PgConnection) -> Result<Vec<String>, Error> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+           version VARCHAR(50) PRIMARY KEY NOT NULL,
+           run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+         )",
+    )?;
+    let versions =
+        diesel::sql_query("SELECT version FROM __diesel_schema_migrations ORDER BY version")
+            .load::<AppliedVersion>(conn)?;
+    Ok(versions.into_iter().map(|v| v.version).collect())
+}
+
+fn find(version: &str) -> Result<&'static EmbeddedMigration, Error> {
+    MIGRATIONS
+        .iter()
+        .find(|m| m.version == version)
+        .ok_or(Error::NotFound("migration"))
+}
+
+fn apply(conn: &PgConnection, migration: &EmbeddedMigration) -> Result<(), Error> {
+    conn.transaction(|| {
+        conn.batch_execute(migration.up)?;
+        diesel::sql_query("INSERT INTO __diesel_schema_migrations (version) VALUES ($1)")
+            .bind::<Text, _>(migration.version)
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+fn revert(conn: &PgConnection, migration: &EmbeddedMigration) -> Result<(), Error> {
+    conn.transaction(|| {
+        conn.batch_execute(migration.down)?;
+        diesel::sql_query("DELETE FROM __diesel_schema_migrations WHERE version = $1")
+            .bind::<Text, _>(migration.version)
+            .execute(conn)?;
+        Ok(())
+    })
+}
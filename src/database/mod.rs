@@ -1,72 +1,115 @@
 use actix_web::{
-    http,
     web::{BytesMut, Payload},
     HttpResponse,
 };
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, PooledConnection};
 use futures::StreamExt;
-use log::error;
-use serde_json::json;
 use thiserror::Error;
 
+mod api_error;
+mod api_token;
+mod bulk;
 mod category;
+mod change_event;
 mod cluster;
+mod compression;
 mod data_source;
+mod decoder;
 mod description;
 mod event;
 mod event_id;
+mod executor;
 mod function;
 mod indicator;
 mod kafka_metadata;
+mod maintenance;
+mod migration;
 mod outlier;
 mod qualifier;
 mod query;
+mod related_event;
+mod retention;
 mod schema;
+mod search;
+mod similarity;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 mod status;
+mod storage;
+mod tag;
 mod template;
+mod timeline;
 
+pub(crate) use self::api_error::*;
+pub(crate) use self::api_token::*;
+pub(crate) use self::bulk::*;
 pub(crate) use self::category::*;
+pub(crate) use self::change_event::*;
 pub(crate) use self::cluster::*;
+pub(crate) use self::compression::*;
 pub(crate) use self::data_source::*;
+pub(crate) use self::decoder::*;
 pub(crate) use self::description::*;
 pub(crate) use self::event::*;
 pub(crate) use self::event_id::*;
+pub(crate) use self::executor::*;
 pub(crate) use self::function::*;
 pub(crate) use self::indicator::*;
 pub(crate) use self::kafka_metadata::*;
+pub(crate) use self::maintenance::*;
+pub(crate) use self::migration::*;
 pub(crate) use self::outlier::*;
 pub(crate) use self::qualifier::*;
 pub(crate) use self::query::*;
+pub(crate) use self::related_event::*;
+pub(crate) use self::retention::*;
+pub(crate) use self::search::*;
+pub(crate) use self::similarity::*;
 pub(crate) use self::status::*;
+pub(crate) use self::storage::*;
+pub(crate) use self::tag::*;
 pub(crate) use self::template::*;
+pub(crate) use self::timeline::*;
 
 pub(crate) type Conn = PooledConnection<ConnectionManager<PgConnection>>;
 pub(crate) type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
+/// The number of rows inserted into a staging table at a time, which keeps the
+/// bind parameters of a statement below the PostgreSQL limit of 65535.
+pub(crate) const STAGING_BATCH_SIZE: usize = 5000;
+
 #[derive(Debug, Error)]
 pub enum Error {
+    #[error("database job canceled")]
+    Canceled,
+    #[error("compression error: {0}")]
+    Compression(#[from] std::io::Error),
     #[error("diesel connection error: {0}")]
     Connection(#[from] diesel::ConnectionError),
     #[error("migration error: {0}")]
     Migration(#[from] diesel_migrations::RunMigrationsError),
+    #[error("{0} not found")]
+    NotFound(&'static str),
     #[error("query error: {0}")]
     Query(#[from] diesel::result::Error),
     #[error("connection error: {0}")]
     R2D2(#[from] r2d2::Error),
+    #[error("failed to generate random bytes")]
+    Random,
+    #[error("too many pending database jobs")]
+    Saturated,
     #[error("JSON deserialization error: {0}")]
     SerdeJson(#[from] serde_json::Error),
+    #[error("database job timed out")]
+    Timeout,
 }
 
-pub(crate) fn build_http_500_response(e: &dyn std::error::Error) -> HttpResponse {
-    error!("{}", e);
-    let err_msg = json!({
-        "message": e.to_string(),
-    })
-    .to_string();
-    HttpResponse::InternalServerError()
-        .header(http::header::CONTENT_TYPE, "application/json")
-        .body(err_msg)
+/// Builds the response for an error from the database. The status is 404 for a
+/// missing record, 409 for a conflict with existing records, 503 if the
+/// database is too busy to serve the request, and 500 otherwise.
+pub(crate) fn build_error_response(e: &(dyn std::error::Error + 'static)) -> HttpResponse {
+    ApiError::from_error(e).into()
 }
 
 pub(crate) async fn load_payload(mut payload: Payload) -> Result<BytesMut, actix_web::Error> {
@@ -76,3 +119,24 @@ pub(crate) async fn load_payload(mut payload: Payload) -> Result<BytesMut, actix
     }
     Ok(bytes)
 }
+
+/// Returns a connection to the database at `DATABASE_URL`, in a transaction that
+/// is never committed.
+#[cfg(test)]
+pub(crate) fn test_conn() -> Conn {
+    use std::sync::Once;
+
+    static MIGRATIONS: Once = Once::new();
+
+    dotenv::dotenv().ok();
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL is not set");
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::<PgConnection>::new(url))
+        .expect("cannot connect to the database");
+    let conn = pool.get().expect("cannot connect to the database");
+    MIGRATIONS.call_once(|| run_migrations(&conn).expect("cannot migrate the database"));
+    conn.begin_test_transaction()
+        .expect("cannot begin a transaction");
+    conn
+}
@@ -6,7 +6,8 @@ use bigdecimal::{BigDecimal, FromPrimitive};
 use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::sync::Mutex;
 
 use super::schema::outlier;
@@ -22,88 +23,139 @@ struct Outlier {
     size: BigDecimal,
 }
 
+table! {
+    outlier_staging (position) {
+        position -> Int4,
+        round -> Int4,
+        id -> Int4,
+        raw_event -> Bytea,
+        raw_event_hash -> Bytea,
+        data_source -> Text,
+        data_source_type -> Text,
+        event_ids -> Array<Numeric>,
+        size -> Numeric,
+        compressed -> Bool,
+        dictionary_id -> Nullable<Int4>,
+    }
+}
+
+/// Creates `outlier_staging`, which is dropped after the merge.
+const OUTLIER_STAGING: &str = "CREATE TEMPORARY TABLE outlier_staging (\
+                               position INTEGER NOT NULL, \
+                               round INTEGER NOT NULL, \
+                               id INTEGER NOT NULL, \
+                               raw_event BYTEA NOT NULL, \
+                               raw_event_hash BYTEA NOT NULL, \
+                               data_source TEXT NOT NULL, \
+                               data_source_type TEXT NOT NULL, \
+                               event_ids NUMERIC(20, 0)[] NOT NULL, \
+                               size NUMERIC(20, 0) NOT NULL, \
+                               compressed BOOLEAN NOT NULL, \
+                               dictionary_id INTEGER\
+                               ) ON COMMIT DROP";
+
+#[derive(Debug, Insertable)]
+#[table_name = "outlier_staging"]
+struct StagedOutlier<'a> {
+    position: i32,
+    round: i32,
+    id: i32,
+    raw_event: Vec<u8>,
+    raw_event_hash: Vec<u8>,
+    data_source: &'a str,
+    data_source_type: &'a str,
+    event_ids: Vec<BigDecimal>,
+    size: BigDecimal,
+    compressed: bool,
+    dictionary_id: Option<i32>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct OutlierUpdate {
-    id: i32,
-    outlier: Vec<u8>,
-    data_source: String,
-    data_source_type: String,
-    event_ids: Vec<u64>,
-    size: usize,
+    pub(crate) id: i32,
+    pub(crate) outlier: Vec<u8>,
+    pub(crate) data_source: String,
+    pub(crate) data_source_type: DataType,
+    pub(crate) event_ids: Vec<u64>,
+    pub(crate) size: usize,
 }
 
 pub(crate) async fn delete_outliers(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     payload: Payload,
     data_source: Query<DataSourceQuery>,
+    query: Query<BulkQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
     use outlier::dsl;
 
     let bytes = load_payload(payload).await?;
     let outliers: Vec<Vec<u8>> = serde_json::from_slice(&bytes)?;
     let data_source = data_source.into_inner();
-    let conn = match pool.get() {
-        Ok(conn) => conn,
-        Err(e) => return Ok(build_http_500_response(&e)),
-    };
-    if let Ok(data_source_id) = get_data_source_id(&conn, &data_source.data_source) {
-        let mut query = dsl::outlier.into_boxed();
-        for outlier in &outliers {
-            query = query.or_filter(dsl::raw_event.eq(outlier));
-        }
-        query = query.filter(dsl::data_source_id.eq(data_source_id));
-        let _ = query
-            .select(dsl::event_ids)
-            .load::<Vec<BigDecimal>>(&conn)
-            .map_err(Into::into)
-            .and_then(|event_ids| {
-                let events = event_ids
-                    .into_iter()
-                    .flatten()
-                    .map(|message_id| Event {
-                        message_id,
-                        data_source_id,
-                        raw_event: None,
-                    })
-                    .collect::<Vec<_>>();
-                delete_events(&conn, &events)
-            });
-
-        let query_result: Result<usize, Error> = conn.build_transaction().read_write().run(|| {
-            Ok(outliers
+    let atomic = query.is_atomic();
+    let delete_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            let mut results = BulkResults::new(outliers.len(), atomic);
+            let data_source_id = match conn.data_source_id(&data_source.data_source) {
+                Ok(id) => id,
+                Err(_) => {
+                    for i in 0..outliers.len() {
+                        results.fail(i, &ApiError::not_found("data source"));
+                    }
+                    return Ok(results);
+                }
+            };
+            // An outlier is found by the hash of its uncompressed raw event,
+            // however it is compressed.
+            let hashes = outliers
                 .iter()
-                .filter_map(|outlier| {
-                    diesel::delete(
+                .map(|outlier| hash_raw_event(outlier))
+                .collect::<Vec<_>>();
+            results.apply(conn, |indices| {
+                for &i in indices {
+                    let event_ids = diesel::delete(
                         dsl::outlier.filter(
                             dsl::data_source_id
                                 .eq(data_source_id)
-                                .and(dsl::raw_event.eq(outlier)),
+                                .and(dsl::raw_event_hash.eq(&hashes[i])),
                         ),
                     )
-                    .execute(&conn)
-                    .ok()
-                })
-                .sum())
-        });
+                    .returning(dsl::event_ids)
+                    .get_results::<Vec<BigDecimal>>(conn)?;
+                    if event_ids.is_empty() {
+                        return Err(Error::NotFound("outlier"));
+                    }
+                    let events = event_ids
+                        .into_iter()
+                        .flatten()
+                        .map(|message_id| Event {
+                            message_id,
+                            data_source_id,
+                            raw_event: None,
+                        })
+                        .collect::<Vec<_>>();
+                    conn.delete_events(&events)?;
+                }
+                Ok(())
+            })?;
+            Ok(results)
+        })
+        .await;
 
-        match query_result {
-            Ok(_) => Ok(HttpResponse::Ok().into()),
-            Err(e) => Ok(build_http_500_response(&e)),
-        }
-    } else {
-        Ok(HttpResponse::InternalServerError().into())
+    match delete_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
 pub(crate) async fn get_outliers(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<Value>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let default_per_page = 10;
     let max_per_page = 100;
-    let outlier_schema =
-        "(outlier INNER JOIN data_source ON outlier.data_source_id = data_source.id)";
-    let select = query
+    let outlier_schema = "(outlier INNER JOIN data_source \
+                          ON outlier.data_source_id = data_source.id AND NOT data_source.archived)";
+    let mut select = query
         .get("select")
         .and_then(Value::as_str)
         .and_then(|s| serde_json::from_str::<HashMap<String, bool>>(s).ok())
@@ -116,6 +168,7 @@ pub(crate) async fn get_outliers(
                     "data_source" => Some("data_source.topic_name as data_source"),
                     "size" => Some("outlier.size"),
                     "event_ids" => Some("outlier.event_ids"),
+                    "tags" => Some(OUTLIER_TAGS),
                     _ => None,
                 })
                 .collect::<Vec<_>>()
@@ -127,14 +180,20 @@ pub(crate) async fn get_outliers(
                 "data_source.topic_name as data_source",
                 "outlier.size",
                 "outlier.event_ids",
+                OUTLIER_TAGS,
             ]
         });
+    let decompress = select.contains(&"right((outlier.raw_event)::TEXT, -2) as outlier");
+    if decompress {
+        select.push(OUTLIER_COMPRESSION);
+        select.push(OUTLIER_DECODING);
+    }
     let filter = query
         .get("filter")
         .and_then(Value::as_str)
         .and_then(|f| serde_json::from_str::<Value>(f).ok());
     let where_clause = if let Some(filter) = filter {
-        filter.get("data_source")
+        let data_source = filter.get("data_source")
         .and_then(Value::as_array)
         .map(|f| {
             let mut where_clause = String::new();
@@ -149,12 +208,29 @@ pub(crate) async fn get_outliers(
                 }
             }
             where_clause
-        })
+        });
+        let tag = filter.get("tag").and_then(Value::as_array).map(|f| {
+            f.iter()
+                .filter_map(Value::as_str)
+                .map(|t| tag_condition("outlier", t))
+                .collect::<Vec<_>>()
+                .join(" or ")
+        });
+        let where_clause = vec![data_source, tag]
+            .into_iter()
+            .flatten()
+            .filter(|f| !f.is_empty())
+            .map(|f| format!("({})", f))
+            .collect::<Vec<_>>();
+        if where_clause.is_empty() {
+            None
+        } else {
+            Some(where_clause.join(" and "))
+        }
     } else {
         None
     };
-    let page = GetQuery::get_page(&query);
-    let per_page = GetQuery::get_per_page(&query, max_per_page).unwrap_or_else(|| default_per_page);
+    let paging = Paging::new(&query, default_per_page, max_per_page);
     let orderby = query
         .get("orderby")
         .and_then(Value::as_str)
@@ -171,99 +247,172 @@ pub(crate) async fn get_outliers(
         None
     };
 
-    match pool.get() {
-        Ok(conn) => GetQuery::build_response(
-            select,
-            outlier_schema,
-            where_clause,
-            page,
-            per_page,
-            orderby,
-            order,
-            &conn,
-        ),
-        Err(e) => Ok(build_http_500_response(&e)),
-    }
+    let mut codec = Codec::default();
+    let mut decoder = EventDecoder::default();
+    GetQuery::build_response_with(
+        &pool,
+        select,
+        outlier_schema,
+        "outlier.id",
+        where_clause,
+        paging,
+        orderby,
+        order,
+        move |conn, row| {
+            if decompress {
+                codec.decompress_outlier(conn, row);
+                decoder.decode_outlier(conn, row);
+            }
+        },
+    )
+    .await
 }
 
 pub(crate) async fn update_outliers(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     payload: Payload,
+    query: Query<BulkQuery>,
     max_event_id_num: Data<Mutex<usize>>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    use outlier::dsl;
+
     let bytes = load_payload(payload).await?;
-    let outlier_update: Vec<OutlierUpdate> = serde_json::from_slice(&bytes)?;
-    let max_event_id_num: BigDecimal = match max_event_id_num.lock() {
-        Ok(num) => FromPrimitive::from_usize(*num).expect("should be fine"),
+    let outlier_update: Vec<OutlierUpdate> =
+        serde_json::from_slice(&bytes).map_err(ApiError::unparsable)?;
+    let atomic = query.is_atomic();
+    let max_event_id_num = match max_event_id_num.lock() {
+        Ok(num) => *num,
         Err(e) => {
             error!(
                 "Failed to acquire lock: {}. Use default max_event_id_number 25",
                 e
             );
-            FromPrimitive::from_usize(25).expect("should be fine")
+            25
         }
     };
 
-    let query_result: Result<i32, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        let outlier_update_clone = outlier_update.clone();
-        let result = conn.transaction::<i32, Error, _>(|| {
-            Ok(outlier_update_clone
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            let mut results = BulkResults::new(outlier_update.len(), atomic);
+            let ids = outlier_update
+                .iter()
+                .filter(|o| o.id != 0)
+                .map(|o| o.id)
+                .collect::<Vec<_>>();
+            let existing = dsl::outlier
+                .select(dsl::id)
+                .filter(dsl::id.eq_any(&ids))
+                .load::<i32>(conn)?
                 .into_iter()
-                .filter_map(|o| {
-                    let event_ids = o
-                        .event_ids
-                        .iter()
-                        .filter_map(|event_id| FromPrimitive::from_u64(*event_id))
-                        .collect::<Vec<BigDecimal>>();
-                    if event_ids.is_empty() {
-                        return None;
-                    }
-                    let size: BigDecimal = FromPrimitive::from_usize(o.size)?;
-                    let query_result = diesel::select(attempt_outlier_upsert(
-                        max_event_id_num.clone(),
-                        o.id,
-                        o.outlier,
-                        o.data_source,
-                        o.data_source_type,
-                        event_ids,
-                        size,
-                    ))
-                    .get_result::<i32>(&conn);
-                    if let Err(e) = &query_result {
-                        log::error!("Failed to insert/update an outlier: {}", e);
-                    }
-                    query_result.ok()
-                })
-                .sum())
-        });
-        update_events(&conn, &outlier_update);
-        result
-    });
+                .collect::<HashSet<_>>();
+            for (i, o) in outlier_update.iter().enumerate() {
+                if o.event_ids.is_empty() {
+                    results.fail(i, &ApiError::invalid("event_ids", "event_ids is empty"));
+                } else if o.id != 0 && !existing.contains(&o.id) {
+                    results.fail(i, &ApiError::not_found("outlier"));
+                }
+            }
+            results.apply(conn, |indices| {
+                let outliers = indices
+                    .iter()
+                    .map(|&i| &outlier_update[i])
+                    .collect::<Vec<_>>();
+                conn.upsert_outliers(max_event_id_num, &outliers)
+            })?;
+            Ok(results)
+        })
+        .await;
 
     match query_result {
-        Ok(_) => Ok(HttpResponse::Ok().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
-fn update_events(conn: &Conn, outlier_update: &[OutlierUpdate]) {
-    let mut new_events = outlier_update
-        .iter()
-        .filter_map(|outlier| {
-            if let Ok(data_source_id) = get_data_source_id(conn, &outlier.data_source) {
-                Some(build_events(&outlier.event_ids, data_source_id))
-            } else {
-                None
-            }
-        })
-        .flatten()
-        .collect::<Vec<_>>();
+/// Inserts or updates `outliers` by staging them in a temporary table and
+/// merging them into `outlier` with `merge_staged_outliers`, which follows the
+/// rules of `attempt_outlier_upsert`. Returns the number of outliers inserted
+/// or updated.
+///
+/// An existing outlier that appears more than once is merged in a later round
+/// for each appearance, so that the result is the same as updating one by
+/// one.
+pub(crate) fn upsert_outliers(
+    conn: &Conn,
+    max_event_id_num: &BigDecimal,
+    outliers: &[&OutlierUpdate],
+) -> Result<i32, Error> {
+    let mut codec = Codec::default();
+    let mut data_source_ids = HashMap::<&str, Option<i32>>::new();
+    let mut rounds = HashMap::<i32, i32>::new();
+    let mut staged = Vec::with_capacity(outliers.len());
+    for (position, o) in outliers.iter().enumerate() {
+        let event_ids = o
+            .event_ids
+            .iter()
+            .filter_map(|event_id| FromPrimitive::from_u64(*event_id))
+            .collect::<Vec<BigDecimal>>();
+        let size: BigDecimal = FromPrimitive::from_usize(o.size).unwrap_or_default();
+        let data_source_id = *data_source_ids
+            .entry(o.data_source.as_str())
+            .or_insert_with(|| get_data_source_id(conn, &o.data_source).ok());
+        let (raw_event, dictionary_id) = codec.compress(conn, data_source_id, &o.outlier)?;
+        let round = if o.id == 0 {
+            1
+        } else {
+            let round = rounds.entry(o.id).or_insert(0);
+            *round += 1;
+            *round
+        };
+        staged.push(StagedOutlier {
+            position: i32::try_from(position).unwrap_or(i32::MAX),
+            round,
+            id: o.id,
+            raw_event,
+            raw_event_hash: hash_raw_event(&o.outlier),
+            data_source: &o.data_source,
+            data_source_type: o.data_source_type.as_str(),
+            event_ids,
+            size,
+            compressed: true,
+            dictionary_id,
+        });
+    }
+    let last_round = rounds.values().max().copied().unwrap_or(1);
+
+    conn.transaction::<i32, Error, _>(|| {
+        diesel::sql_query(OUTLIER_STAGING).execute(conn)?;
+        for batch in staged.chunks(STAGING_BATCH_SIZE) {
+            diesel::insert_into(outlier_staging::table)
+                .values(batch)
+                .execute(conn)?;
+        }
+        diesel::sql_query("ANALYZE outlier_staging").execute(conn)?;
+        let mut count = 0;
+        for round in 1..=last_round {
+            count += diesel::select(merge_staged_outliers(max_event_id_num, round))
+                .get_result::<i32>(conn)?;
+        }
+        diesel::sql_query("DROP TABLE outlier_staging").execute(conn)?;
+        Ok(count)
+    })
+}
+
+/// Adds the events of `outlier_update` after the outliers are upserted.
+pub(crate) fn add_outlier_events<S: Storage>(
+    storage: &S,
+    outlier_update: &[&OutlierUpdate],
+) -> Result<(), Error> {
+    let mut new_events = Vec::new();
+    for outlier in outlier_update {
+        let data_source_id = storage.data_source_id(&outlier.data_source)?;
+        new_events.extend(build_events(&outlier.event_ids, data_source_id));
+    }
 
     if !new_events.is_empty() {
         new_events.sort();
         new_events.dedup();
-        if let Err(e) = add_events(&conn, &new_events) {
-            log::error!("An error occurs while inserting events: {}", e);
-        }
+        storage.add_events(&new_events.iter().collect::<Vec<_>>())?;
     }
+    Ok(())
 }
@@ -3,7 +3,7 @@ use diesel::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use super::schema::qualifier;
-use crate::database::{build_http_500_response, Error, Pool};
+use crate::database::{build_error_response, Database, Error};
 
 #[derive(Debug, Deserialize, Identifiable, Queryable, Serialize)]
 #[table_name = "qualifier"]
@@ -13,19 +13,20 @@ struct QualifierTable {
 }
 
 pub(crate) async fn get_qualifier_table(
-    pool: Data<Pool>,
+    pool: Data<Database>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let query_result: Result<Vec<QualifierTable>, Error> =
-        pool.get().map_err(Into::into).and_then(|conn| {
+    let query_result: Result<Vec<QualifierTable>, Error> = pool
+        .run(move |conn| {
             qualifier::dsl::qualifier
-                .load::<QualifierTable>(&conn)
+                .load::<QualifierTable>(conn)
                 .map_err(Into::into)
-        });
+        })
+        .await;
 
     match query_result {
         Ok(qualifier_table) => Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "application/json")
             .json(qualifier_table)),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
@@ -3,11 +3,12 @@ use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::query_builder::*;
 use diesel::query_dsl::methods::LoadQuery;
-use diesel::sql_types::{BigInt, Jsonb};
-use serde::Deserialize;
+use diesel::sql_types::{BigInt, Jsonb, Text};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::convert::TryFrom;
 
-use crate::database::{build_http_500_response, Conn, Error};
+use crate::database::{build_error_response, ApiError, Conn, Database, Error};
 
 #[derive(Debug, Deserialize, QueryableByName)]
 pub(crate) struct GetQueryData {
@@ -21,103 +22,363 @@ struct Count {
     count: i64,
 }
 
+/// The columns added to each row to build the cursor of the next page. They
+/// are removed before responding.
+const CURSOR_VALUE: &str = "_cursor_value";
+const CURSOR_ID: &str = "_cursor_id";
+
+/// How the total number of rows is reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Total {
+    Exact,
+    Estimate,
+    Omit,
+}
+
+/// The page to return, given either by a page number or by a cursor.
+#[derive(Debug)]
+pub(crate) struct Paging {
+    page: Option<i64>,
+    per_page: i64,
+    cursor: Option<String>,
+    total: Option<String>,
+}
+
+impl Paging {
+    pub(crate) fn new(query: &Query<Value>, default_per_page: i64, max_per_page: i64) -> Self {
+        Self {
+            page: Self::get_page(query),
+            per_page: Self::get_per_page(query, max_per_page).unwrap_or(default_per_page),
+            cursor: query
+                .get("cursor")
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            total: query
+                .get("total")
+                .and_then(Value::as_str)
+                .map(str::to_lowercase),
+        }
+    }
+
+    fn get_page(query: &Query<Value>) -> Option<i64> {
+        query
+            .get("page")
+            .and_then(Value::as_str)
+            .and_then(|p| p.parse::<i64>().ok())
+            .filter(|p| *p > 0)
+    }
+
+    fn get_per_page(query: &Query<Value>, max_per_page: i64) -> Option<i64> {
+        query
+            .get("per_page")
+            .and_then(Value::as_str)
+            .and_then(|p| p.parse::<i64>().ok())
+            .filter(|p| *p > 0)
+            .map(|p| if p > max_per_page { max_per_page } else { p })
+    }
+
+    pub(crate) fn per_page(&self) -> i64 {
+        self.per_page
+    }
+
+    /// Decodes the cursor of the request. A cursor must come from a page in
+    /// the same order, and cannot be used with a page number.
+    pub(crate) fn cursor(
+        &self,
+        orderby: Option<&str>,
+        order: Option<&str>,
+    ) -> Result<Option<Cursor>, ApiError> {
+        let token = match &self.cursor {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        match Cursor::decode(token) {
+            Some(cursor)
+                if self.page.is_none()
+                    && cursor.orderby.as_deref() == orderby
+                    && cursor.order.as_deref() == order =>
+            {
+                Ok(Some(cursor))
+            }
+            _ => Err(ApiError::invalid(
+                "cursor",
+                "Invalid cursor, or cursor used with page or a different order",
+            )),
+        }
+    }
+
+    /// The total is exact by default, to keep page-based clients working, and
+    /// omitted by default when paging with a cursor.
+    fn total(&self) -> Total {
+        match self.total.as_deref() {
+            Some("exact") => Total::Exact,
+            Some("estimate") => Total::Estimate,
+            Some("none") => Total::Omit,
+            _ if self.cursor.is_some() => Total::Omit,
+            _ => Total::Exact,
+        }
+    }
+}
+
+/// The position of the last row of a page: the value of the `orderby` column
+/// as text, and the id of the row. It is passed to clients as an opaque token.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct Cursor {
+    orderby: Option<String>,
+    order: Option<String>,
+    value: Option<String>,
+    id: i64,
+}
+
+impl Cursor {
+    fn after(row: &Value, orderby: Option<&str>, order: Option<&str>) -> Option<Self> {
+        Some(Self {
+            orderby: orderby.map(str::to_string),
+            order: order.map(str::to_string),
+            value: row
+                .get(CURSOR_VALUE)
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            id: row.get(CURSOR_ID).and_then(Value::as_i64)?,
+        })
+    }
+
+    /// Returns the cursor after the row whose `orderby` column has `value`.
+    /// The column must be unique, as no id breaks ties.
+    pub(crate) fn after_value(orderby: &str, order: Option<&str>, value: String) -> Self {
+        Self {
+            orderby: Some(orderby.to_string()),
+            order: order.map(str::to_string),
+            value: Some(value),
+            id: 0,
+        }
+    }
+
+    pub(crate) fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    fn decode(token: &str) -> Option<Self> {
+        base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|cursor| serde_json::from_slice(&cursor).ok())
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        base64::encode_config(
+            &serde_json::to_vec(self).unwrap_or_default(),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+
+    /// Builds the condition for the rows that come after this cursor when
+    /// ordered by `orderby` and then `id`. NULL values of `orderby` come last
+    /// in ascending order and first in descending order, as PostgreSQL sorts
+    /// them by default.
+    fn condition(&self, orderby: Option<&str>, id: &str, desc: bool) -> String {
+        let op = if desc { "<" } else { ">" };
+        let after_id = format!("{} {} {}", id, op, self.id);
+        match (orderby, &self.value) {
+            (None, _) => after_id,
+            (Some(column), Some(value)) => {
+                // An untyped literal is coerced to the type of the column.
+                let value = format!("'{}'", value.replace('\'', "''"));
+                let after = format!(
+                    "{c} {op} {v} OR ({c} = {v} AND {after_id})",
+                    c = column,
+                    op = op,
+                    v = value,
+                    after_id = after_id
+                );
+                if desc {
+                    format!("({})", after)
+                } else {
+                    format!("({} OR {} IS NULL)", after, column)
+                }
+            }
+            (Some(column), None) => {
+                if desc {
+                    format!("({} IS NOT NULL OR {})", column, after_id)
+                } else {
+                    format!("({} IS NULL AND {})", column, after_id)
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct GetQuery<'a> {
     pub(crate) select: Vec<&'a str>,
     pub(crate) schema: &'a str,
+    pub(crate) id: &'a str,
     pub(crate) where_clause: &'a Option<String>,
-    pub(crate) page: Option<i64>,
-    pub(crate) per_page: i64,
+    pub(crate) limit: i64,
+    pub(crate) offset: Option<i64>,
     pub(crate) orderby: Option<&'a str>,
     pub(crate) order: Option<&'a str>,
 }
 
 impl<'a> GetQuery<'a> {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         select: Vec<&'a str>,
         schema: &'a str,
+        id: &'a str,
         where_clause: &'a Option<String>,
-        page: Option<i64>,
-        per_page: i64,
+        limit: i64,
+        offset: Option<i64>,
         orderby: Option<&'a str>,
         order: Option<&'a str>,
     ) -> Self {
         Self {
             select,
             schema,
+            id,
             where_clause,
-            page,
-            per_page,
+            limit,
+            offset,
             orderby,
             order,
         }
     }
 
+    /// Responds with a page of rows from `schema`, ordered by `orderby` and
+    /// then by `id`, the primary key of the listed table.
+    ///
+    /// Unless a page number is given, the response has an
+    /// `X-REviewd-NextCursor` header if more rows follow. Passing its value as
+    /// `cursor` returns the next page.
     #[allow(clippy::too_many_arguments)]
-    pub(crate) fn build_response(
-        select: Vec<&'a str>,
-        schema: &'a str,
+    pub(crate) async fn build_response(
+        pool: &Database,
+        select: Vec<&'static str>,
+        schema: &'static str,
+        id: &'static str,
         where_clause: Option<String>,
-        page: Option<i64>,
-        per_page: i64,
-        orderby: Option<&'a str>,
-        order: Option<&'a str>,
-        conn: &Conn,
+        paging: Paging,
+        orderby: Option<&'static str>,
+        order: Option<&'static str>,
     ) -> Result<HttpResponse, actix_web::Error> {
-        let query_result: Result<Vec<GetQueryData>, Error> = GetQuery::new(
+        Self::build_response_with(
+            pool,
             select,
             schema,
-            &where_clause,
-            page,
-            per_page,
+            id,
+            where_clause,
+            paging,
             orderby,
             order,
+            |_, _| (),
         )
-        .get_results::<GetQueryData>(&conn)
-        .map_err(Into::into);
+        .await
+    }
 
-        match query_result {
-            Ok(data) => {
-                let mut query = format!("SELECT COUNT(*) FROM ({})", schema);
-                if let Some(where_clause) = where_clause {
-                    let q = format!(" WHERE ({})", where_clause);
-                    query.push_str(&q);
-                }
-                query.push_str(" LIMIT 1");
-                let total: Result<Option<Count>, Error> = diesel::sql_query(query)
-                    .get_result::<Count>(conn)
-                    .optional()
-                    .map_err(Into::into);
-                let pagination = match total {
-                    Ok(Some(total)) => {
-                        if total.count == 0 {
-                            None
-                        } else {
-                            let total_pages = (total.count + per_page - 1) / per_page;
-                            Some((total.count, total_pages))
+    /// Same as `build_response`, but calls `f` on each row before responding.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn build_response_with<F>(
+        pool: &Database,
+        select: Vec<&'static str>,
+        schema: &'static str,
+        id: &'static str,
+        where_clause: Option<String>,
+        paging: Paging,
+        orderby: Option<&'static str>,
+        order: Option<&'static str>,
+        mut f: F,
+    ) -> Result<HttpResponse, actix_web::Error>
+    where
+        F: FnMut(&Conn, &mut Value) + Send + 'static,
+    {
+        let cursor = match paging.cursor(orderby, order) {
+            Ok(cursor) => cursor,
+            Err(e) => return Ok(e.into()),
+        };
+        let total = paging.total();
+        let per_page = paging.per_page;
+        // Without a page number, one more row is fetched to tell whether a
+        // next page exists.
+        let (limit, offset) = match paging.page {
+            Some(page) => (per_page, Some((page - 1) * per_page)),
+            None => (per_page + 1, None),
+        };
+        let rows_where = match &cursor {
+            Some(cursor) => {
+                let after = cursor.condition(orderby, id, order == Some("desc"));
+                Some(match &where_clause {
+                    Some(where_clause) => format!("({}) AND {}", where_clause, after),
+                    None => after,
+                })
+            }
+            None => where_clause.clone(),
+        };
+
+        let query_result = pool
+            .run(move |conn| {
+                let mut rows = GetQuery::new(
+                    select,
+                    schema,
+                    id,
+                    &rows_where,
+                    limit,
+                    offset,
+                    orderby,
+                    order,
+                )
+                .get_results::<GetQueryData>(conn)?;
+                let per_page_rows = usize::try_from(per_page).unwrap_or(usize::MAX);
+                let next = if paging.page.is_none() && rows.len() > per_page_rows {
+                    rows.truncate(per_page_rows);
+                    rows.last()
+                        .and_then(|row| Cursor::after(&row.data, orderby, order))
+                        .map(|cursor| cursor.encode())
+                } else {
+                    None
+                };
+
+                let count = count_rows(conn, schema, &where_clause, total).unwrap_or_else(|e| {
+                    log::error!("{}", e);
+                    None
+                });
+                let data = rows
+                    .into_iter()
+                    .map(|d| {
+                        let mut data = d.data;
+                        if let Some(row) = data.as_object_mut() {
+                            row.remove(CURSOR_VALUE);
+                            row.remove(CURSOR_ID);
                         }
+                        f(conn, &mut data);
+                        data
+                    })
+                    .collect::<Vec<Value>>();
+                Ok((data, next, count))
+            })
+            .await;
+
+        match query_result {
+            Ok((data, next, count)) => {
+                let mut response = HttpResponse::Ok();
+                match (total, count) {
+                    (Total::Exact, Some(count)) if count > 0 => {
+                        let total_pages = (count + per_page - 1) / per_page;
+                        response
+                            .header("X-REviewd-Total", count.to_string())
+                            .header("X-REviewd-TotalPages", total_pages.to_string());
                     }
-                    Err(e) => {
-                        log::error!("{}", e);
-                        None
+                    (Total::Estimate, Some(count)) => {
+                        response.header("X-REviewd-EstimatedTotal", count.to_string());
                     }
-                    _ => None,
-                };
-                let data = data.into_iter().map(|d| d.data).collect::<Vec<Value>>();
-
-                if let Some(pagination) = pagination {
-                    Ok(HttpResponse::Ok()
-                        .header("X-REviewd-Total", pagination.0.to_string())
-                        .header("X-REviewd-TotalPages", pagination.1.to_string())
-                        .header(http::header::CONTENT_TYPE, "application/json")
-                        .json(data))
-                } else {
-                    Ok(HttpResponse::Ok()
-                        .header(http::header::CONTENT_TYPE, "application/json")
-                        .json(data))
+                    _ => {}
                 }
+                if let Some(next) = next {
+                    response.header("X-REviewd-NextCursor", next);
+                }
+                Ok(response
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .json(data))
             }
-            Err(e) => Ok(build_http_500_response(&e)),
+            Err(e) => Ok(build_error_response(&e)),
         }
     }
 
@@ -130,56 +391,77 @@ impl<'a> GetQuery<'a> {
                 _ => None,
             })
     }
+}
 
-    pub(crate) fn get_page(query: &Query<Value>) -> Option<i64> {
-        query
-            .get("page")
-            .and_then(Value::as_str)
-            .and_then(|p| p.parse::<i64>().ok())
-            .filter(|p| *p > 0)
-    }
-
-    pub(crate) fn get_per_page(query: &Query<Value>, max_per_page: i64) -> Option<i64> {
-        query
-            .get("per_page")
-            .and_then(Value::as_str)
-            .and_then(|p| p.parse::<i64>().ok())
-            .filter(|p| *p > 0)
-            .map(|p| if p > max_per_page { max_per_page } else { p })
+/// Counts the rows of `schema` that match `where_clause`, or estimates the
+/// count from the query plan.
+fn count_rows(
+    conn: &Conn,
+    schema: &str,
+    where_clause: &Option<String>,
+    total: Total,
+) -> Result<Option<i64>, Error> {
+    let mut from = format!("FROM ({})", schema);
+    if let Some(where_clause) = where_clause {
+        from.push_str(&format!(" WHERE ({})", where_clause));
     }
+    let count = match total {
+        Total::Exact => diesel::sql_query(format!("SELECT COUNT(*) AS count {}", from))
+            .get_result::<Count>(conn)?,
+        Total::Estimate => diesel::sql_query("SELECT count_estimate($1) AS count")
+            .bind::<Text, _>(format!("SELECT 1 {}", from))
+            .get_result::<Count>(conn)?,
+        Total::Omit => return Ok(None),
+    };
+    Ok(Some(count.count))
 }
 
 impl<'a> QueryFragment<Pg> for GetQuery<'a> {
     fn walk_ast(&self, mut out: AstPass<Pg>) -> QueryResult<()> {
         out.unsafe_to_cache_prepared();
         out.push_sql("SELECT to_jsonb(a) as data FROM ( SELECT ");
-        for (i, column) in self.select.iter().enumerate() {
+        for column in &self.select {
             out.push_sql(column);
-            if i < self.select.len() - 1 {
-                out.push_sql(", ");
-            } else {
-                out.push_sql(" FROM ");
+            out.push_sql(", ");
+        }
+        match &self.orderby {
+            Some(orderby) => {
+                out.push_sql(orderby);
+                out.push_sql("::text");
             }
+            None => out.push_sql("NULL"),
         }
+        out.push_sql(" AS ");
+        out.push_sql(CURSOR_VALUE);
+        out.push_sql(", ");
+        out.push_sql(self.id);
+        out.push_sql(" AS ");
+        out.push_sql(CURSOR_ID);
+        out.push_sql(" FROM ");
         out.push_sql(self.schema);
         if let Some(where_clause) = &self.where_clause {
             out.push_sql(" WHERE ");
             out.push_sql(&where_clause);
         }
+        out.push_sql(" ORDER BY ");
         if let Some(orderby) = &self.orderby {
-            out.push_sql(" ORDER BY ");
             out.push_sql(orderby);
+            if let Some(order) = &self.order {
+                out.push_sql(" ");
+                out.push_sql(order);
+            }
+            out.push_sql(", ");
         }
+        out.push_sql(self.id);
         if let Some(order) = &self.order {
             out.push_sql(" ");
             out.push_sql(order);
         }
         out.push_sql(" LIMIT ");
-        out.push_bind_param::<BigInt, _>(&self.per_page)?;
-        if let Some(page) = &self.page {
-            let offset = (page - 1) * self.per_page;
+        out.push_bind_param::<BigInt, _>(&self.limit)?;
+        if let Some(offset) = &self.offset {
             out.push_sql(" OFFSET ");
-            out.push_bind_param::<BigInt, _>(&offset)?;
+            out.push_bind_param::<BigInt, _>(offset)?;
         }
         out.push_sql(") as a");
         Ok(())
@@ -199,3 +481,184 @@ impl<'a> LoadQuery<PgConnection, GetQueryData> for GetQuery<'a> {
 }
 
 impl<'a, Conn> RunQueryDsl<Conn> for GetQuery<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::database::test_conn;
+
+    fn cursor(value: Option<&str>, id: i64) -> Cursor {
+        Cursor {
+            orderby: Some("name".to_string()),
+            order: None,
+            value: value.map(str::to_string),
+            id,
+        }
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        let row = json!({"name": "b", CURSOR_VALUE: "b", CURSOR_ID: 7});
+        let token = Cursor::after(&row, Some("name"), Some("desc"))
+            .unwrap()
+            .encode();
+        let decoded = Cursor::decode(&token).unwrap();
+        assert_eq!(decoded.orderby.as_deref(), Some("name"));
+        assert_eq!(decoded.order.as_deref(), Some("desc"));
+        assert_eq!(decoded.value.as_deref(), Some("b"));
+        assert_eq!(decoded.id, 7);
+
+        let row = json!({CURSOR_VALUE: null, CURSOR_ID: 8});
+        let decoded = Cursor::decode(&Cursor::after(&row, None, None).unwrap().encode()).unwrap();
+        assert_eq!(decoded.orderby, None);
+        assert_eq!(decoded.value, None);
+        assert_eq!(decoded.id, 8);
+    }
+
+    #[test]
+    fn cursor_needs_id() {
+        assert!(Cursor::after(&json!({CURSOR_VALUE: "b"}), Some("name"), None).is_none());
+    }
+
+    #[test]
+    fn malformed_tokens() {
+        let encode = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        assert!(Cursor::decode("").is_none());
+        assert!(Cursor::decode("not a cursor!").is_none());
+        assert!(Cursor::decode(&encode(b"\xff\xfe")).is_none());
+        assert!(Cursor::decode(&encode(br#"{"value": "b"}"#)).is_none());
+        assert!(Cursor::decode(&encode(br#"{"value": "b", "id": "7"}"#)).is_none());
+
+        let mut token = cursor(Some("b"), 7).encode();
+        token.truncate(token.len() / 2);
+        assert!(Cursor::decode(&token).is_none());
+    }
+
+    #[test]
+    fn tampered_value_stays_literal() {
+        let token = base64::encode_config(
+            br#"{"orderby": "name", "value": "b' OR '1'='1", "id": 7}"#,
+            base64::URL_SAFE_NO_PAD,
+        );
+        let condition = Cursor::decode(&token)
+            .unwrap()
+            .condition(Some("name"), "id", true);
+        assert_eq!(
+            condition,
+            "(name < 'b'' OR ''1''=''1' OR (name = 'b'' OR ''1''=''1' AND id < 7))"
+        );
+    }
+
+    #[test]
+    fn conditions() {
+        let after_b = cursor(Some("b"), 7);
+        assert_eq!(after_b.condition(None, "id", false), "id > 7");
+        assert_eq!(after_b.condition(None, "id", true), "id < 7");
+        assert_eq!(
+            after_b.condition(Some("name"), "id", false),
+            "(name > 'b' OR (name = 'b' AND id > 7) OR name IS NULL)"
+        );
+        assert_eq!(
+            after_b.condition(Some("name"), "id", true),
+            "(name < 'b' OR (name = 'b' AND id < 7))"
+        );
+
+        let after_null = cursor(None, 7);
+        assert_eq!(
+            after_null.condition(Some("name"), "id", false),
+            "(name IS NULL AND id > 7)"
+        );
+        assert_eq!(
+            after_null.condition(Some("name"), "id", true),
+            "(name IS NOT NULL OR id < 7)"
+        );
+    }
+
+    #[test]
+    fn paging() {
+        let paging = Paging::new(&Query(json!({"page": "2", "per_page": "500"})), 10, 100);
+        assert_eq!(paging.page, Some(2));
+        assert_eq!(paging.per_page, 100);
+        assert_eq!(paging.total(), Total::Exact);
+
+        let paging = Paging::new(&Query(json!({"page": "0", "per_page": "x"})), 10, 100);
+        assert_eq!(paging.page, None);
+        assert_eq!(paging.per_page, 10);
+
+        let paging = Paging::new(&Query(json!({"cursor": "abc"})), 10, 100);
+        assert_eq!(paging.cursor.as_deref(), Some("abc"));
+        assert_eq!(paging.total(), Total::Omit);
+
+        let paging = Paging::new(
+            &Query(json!({"cursor": "abc", "total": "Estimate"})),
+            10,
+            100,
+        );
+        assert_eq!(paging.total(), Total::Estimate);
+        let paging = Paging::new(&Query(json!({"total": "none"})), 10, 100);
+        assert_eq!(paging.total(), Total::Omit);
+    }
+
+    /// Returns the ids of all rows of `cursor_test`, fetched `per_page` at a
+    /// time with a cursor.
+    fn ids_by_cursor(conn: &Conn, order: Option<&'static str>, per_page: i64) -> Vec<i64> {
+        let mut ids = Vec::new();
+        let mut cursor: Option<Cursor> = None;
+        loop {
+            let where_clause = cursor
+                .as_ref()
+                .map(|cursor| cursor.condition(Some("score"), "id", order == Some("desc")));
+            let rows = GetQuery::new(
+                vec!["id"],
+                "cursor_test",
+                "id",
+                &where_clause,
+                per_page,
+                None,
+                Some("score"),
+                order,
+            )
+            .get_results::<GetQueryData>(conn)
+            .unwrap();
+            let last = match rows.last() {
+                Some(last) => last,
+                None => return ids,
+            };
+            ids.extend(rows.iter().filter_map(|row| row.data["id"].as_i64()));
+            let token = Cursor::after(&last.data, Some("score"), order)
+                .unwrap()
+                .encode();
+            cursor = Cursor::decode(&token);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn cursor_visits_each_row_once() {
+        let conn = test_conn();
+        diesel::sql_query(
+            "CREATE TEMPORARY TABLE cursor_test (id INTEGER PRIMARY KEY, score INTEGER)",
+        )
+        .execute(&conn)
+        .unwrap();
+        diesel::sql_query(
+            "INSERT INTO cursor_test VALUES \
+             (1, 3), (2, 1), (3, 3), (4, NULL), (5, 1), (6, 3), (7, NULL), (8, 2)",
+        )
+        .execute(&conn)
+        .unwrap();
+
+        assert_eq!(ids_by_cursor(&conn, None, 2), vec![2, 5, 8, 1, 3, 6, 4, 7]);
+        assert_eq!(
+            ids_by_cursor(&conn, Some("desc"), 2),
+            vec![7, 4, 6, 3, 1, 8, 5, 2]
+        );
+        for per_page in 1..=8 {
+            let mut ids = ids_by_cursor(&conn, Some("desc"), per_page);
+            ids.sort_unstable();
+            assert_eq!(ids, (1..=8).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use actix_web::{
+    http,
+    web::{Data, Path, Query},
+    HttpResponse,
+};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Bytea, Int4, Int8, Nullable, Numeric, Text};
+use serde::Serialize;
+use serde_json::Value;
+use std::convert::TryFrom;
+
+use crate::database::{
+    build_error_response, decode, load_column_layout, ApiError, Codec, Conn, Cursor, Database,
+    DecodedEvent, Error, Paging,
+};
+
+const DEFAULT_PER_PAGE: i64 = 25;
+const MAX_PER_PAGE: i64 = 100;
+
+/// The order of the events, which is also that of their cursors.
+const ORDERBY: &str = "message_id";
+const ORDER: &str = "desc";
+
+/// Whether the raw event of an event is available.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum EventState {
+    /// Stored in the database.
+    Stored,
+    /// Not stored yet, but the Kafka message with it is known.
+    Pending,
+    /// Neither stored nor in a known Kafka message.
+    Missing,
+    /// Stored, but failed to be decompressed.
+    Corrupted,
+}
+
+#[derive(Debug, Serialize)]
+struct RelatedEvent {
+    message_id: u64,
+    state: EventState,
+    raw_event: Option<Vec<u8>>,
+    decoded: Option<DecodedEvent>,
+    partition: Option<i32>,
+    offset: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct EventRow {
+    #[sql_type = "Numeric"]
+    message_id: BigDecimal,
+    #[sql_type = "Nullable<Bytea>"]
+    raw_event: Option<Vec<u8>>,
+    #[sql_type = "Nullable<Bool>"]
+    compressed: Option<bool>,
+    #[sql_type = "Nullable<Int4>"]
+    dictionary_id: Option<i32>,
+    #[sql_type = "Nullable<Int4>"]
+    partition: Option<i32>,
+    #[sql_type = "Nullable<Int8>"]
+    offsets: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct Owner {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Int4"]
+    data_source_id: i32,
+    #[sql_type = "Text"]
+    data_type: String,
+    #[sql_type = "Nullable<Text>"]
+    template: Option<String>,
+    #[sql_type = "Int8"]
+    total: i64,
+}
+
+/// Returns a page of the events in the `event_ids` of a cluster, from the
+/// latest message id.
+pub(crate) async fn get_cluster_events(
+    pool: Data<Database>,
+    cluster_id: Path<String>,
+    query: Query<Value>,
+) -> Result<HttpResponse, actix_web::Error> {
+    related_events(
+        pool,
+        "cluster",
+        "cluster.cluster_id = $1",
+        cluster_id.into_inner(),
+        query,
+    )
+    .await
+}
+
+/// Returns a page of the events in the `event_ids` of an outlier, from the
+/// latest message id.
+pub(crate) async fn get_outlier_events(
+    pool: Data<Database>,
+    id: Path<i32>,
+    query: Query<Value>,
+) -> Result<HttpResponse, actix_web::Error> {
+    related_events(
+        pool,
+        "outlier",
+        "outlier.id = $1::INT4",
+        id.into_inner().to_string(),
+        query,
+    )
+    .await
+}
+
+/// Responds with the events of the row of `table`, `cluster` or `outlier`,
+/// that matches `condition` with `key` as `$1`. The response has an
+/// `X-REviewd-NextCursor` header if more events follow. Passing its value as
+/// `cursor` returns the next page.
+async fn related_events(
+    pool: Data<Database>,
+    table: &'static str,
+    condition: &'static str,
+    key: String,
+    query: Query<Value>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let data_source = match query.get("data_source").and_then(Value::as_str) {
+        Some(data_source) => data_source.to_string(),
+        None => return Ok(ApiError::invalid("data_source", "Missing data_source").into()),
+    };
+    if query.get("page").is_some() {
+        return Ok(ApiError::invalid("page", "Use cursor to get the next page").into());
+    }
+    let paging = Paging::new(&query, DEFAULT_PER_PAGE, MAX_PER_PAGE);
+    let before = match paging.cursor(Some(ORDERBY), Some(ORDER)) {
+        Ok(Some(cursor)) => match cursor.value().and_then(|v| v.parse::<BigDecimal>().ok()) {
+            Some(message_id) => Some(message_id),
+            None => return Ok(ApiError::invalid("cursor", "Invalid cursor").into()),
+        },
+        Ok(None) => None,
+        Err(e) => return Ok(e.into()),
+    };
+    let per_page = paging.per_page();
+    let per_page_events = usize::try_from(per_page).unwrap_or(usize::MAX);
+
+    let query_result: Result<Option<(Vec<RelatedEvent>, i64)>, Error> = pool
+        .run(move |conn| {
+            let owner = diesel::sql_query(format!(
+                "SELECT {table}.id, {table}.data_source_id, \
+                   data_source.data_type, data_source.template, \
+                   cardinality(ARRAY(SELECT DISTINCT unnest({table}.event_ids)))::INT8 AS total \
+                 FROM {table} \
+                 INNER JOIN data_source ON {table}.data_source_id = data_source.id \
+                 WHERE {condition} AND data_source.topic_name = $2",
+                table = table,
+                condition = condition,
+            ))
+            .bind::<Text, _>(&key)
+            .bind::<Text, _>(&data_source)
+            .get_result::<Owner>(conn)
+            .optional()?;
+            match owner {
+                Some(owner) => {
+                    // One more event is loaded to tell whether a next page exists.
+                    let events = load_related_events(
+                        conn,
+                        table,
+                        &owner,
+                        before.as_ref(),
+                        per_page.saturating_add(1),
+                    )?;
+                    Ok(Some((events, owner.total)))
+                }
+                None => Ok(None),
+            }
+        })
+        .await;
+
+    match query_result {
+        Ok(Some((mut events, total))) => {
+            let mut response = HttpResponse::Ok();
+            if total > 0 {
+                response.header("X-REviewd-Total", total.to_string());
+            }
+            if events.len() > per_page_events {
+                events.truncate(per_page_events);
+                if let Some(last) = events.last() {
+                    let cursor =
+                        Cursor::after_value(ORDERBY, Some(ORDER), last.message_id.to_string());
+                    response.header("X-REviewd-NextCursor", cursor.encode());
+                }
+            }
+            Ok(response
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .json(events))
+        }
+        Ok(None) => Ok(ApiError::not_found(table).into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+/// Loads up to `limit` events of `owner`, a row of `table`, before the message
+/// id `before`, with their raw events and the Kafka messages they are in. The
+/// message ids without an event are reported as well.
+fn load_related_events(
+    conn: &Conn,
+    table: &str,
+    owner: &Owner,
+    before: Option<&BigDecimal>,
+    limit: i64,
+) -> Result<Vec<RelatedEvent>, Error> {
+    let rows = diesel::sql_query(format!(
+        "SELECT ids.message_id, event.raw_event, event.compressed, event.dictionary_id, \
+           metadata.partition, metadata.offsets \
+         FROM (SELECT DISTINCT unnest(event_ids) AS message_id FROM {table} WHERE id = $1) ids \
+         LEFT JOIN event \
+           ON event.data_source_id = $2 AND event.message_id = ids.message_id \
+         LEFT JOIN LATERAL (\
+           SELECT kafka_metadata.partition, kafka_metadata.offsets FROM kafka_metadata \
+           WHERE kafka_metadata.data_source_id = $2 \
+             AND LOWER(kafka_metadata.message_ids) <= ids.message_id \
+             AND UPPER(kafka_metadata.message_ids) >= ids.message_id \
+           LIMIT 1) metadata ON TRUE \
+         WHERE $4::NUMERIC IS NULL OR ids.message_id < $4 \
+         ORDER BY ids.message_id DESC \
+         LIMIT $3",
+        table = table
+    ))
+    .bind::<Int4, _>(owner.id)
+    .bind::<Int4, _>(owner.data_source_id)
+    .bind::<Int8, _>(limit)
+    .bind::<Nullable<Numeric>, _>(before)
+    .load::<EventRow>(conn)?;
+
+    let layout = load_column_layout(conn, owner.template.as_deref())?;
+    let mut codec = Codec::default();
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let (raw_event, state) = match (&row.raw_event, row.compressed) {
+                (Some(raw_event), Some(compressed)) => {
+                    match codec.decompress(conn, raw_event, compressed, row.dictionary_id) {
+                        Ok(raw_event) => (Some(raw_event), EventState::Stored),
+                        Err(e) => {
+                            log::error!("Failed to decompress an event: {}", e);
+                            (None, EventState::Corrupted)
+                        }
+                    }
+                }
+                _ if row.partition.is_some() => (None, EventState::Pending),
+                _ => (None, EventState::Missing),
+            };
+            let decoded = raw_event
+                .as_ref()
+                .map(|raw_event| decode(&owner.data_type, &layout, raw_event));
+            Some(RelatedEvent {
+                message_id: row.message_id.to_u64()?,
+                state,
+                raw_event,
+                decoded,
+                partition: row.partition,
+                offset: row.offsets,
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::database::test_conn;
+
+    #[test]
+    fn cursor_of_message_id() {
+        let token = Cursor::after_value(ORDERBY, Some(ORDER), u64::MAX.to_string()).encode();
+        let paging = Paging::new(&Query(json!({ "cursor": token })), 25, 100);
+        let cursor = paging.cursor(Some(ORDERBY), Some(ORDER)).unwrap().unwrap();
+        assert_eq!(cursor.value(), Some("18446744073709551615"));
+        assert!(paging.cursor(Some(ORDERBY), None).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn events_before_message_id() {
+        use EventState::{Missing, Stored};
+
+        let conn = test_conn();
+        let owner = diesel::sql_query(
+            "WITH source AS (\
+               INSERT INTO data_source (topic_name, data_type) \
+               VALUES ('related_event_test', 'log') RETURNING id), \
+             stored AS (\
+               INSERT INTO event (message_id, data_source_id, raw_event) \
+               SELECT 18446744073709551615, id, 'latest' FROM source) \
+             INSERT INTO cluster \
+               (cluster_id, detector_id, signature, size, data_source_id, event_ids) \
+             SELECT 'c', 1, 's', 4, id, '{3, 1, 18446744073709551615, 2, 3}' FROM source \
+             RETURNING cluster.id, cluster.data_source_id, 'log'::TEXT AS data_type, \
+               NULL::TEXT AS template, 4::INT8 AS total",
+        )
+        .get_result::<Owner>(&conn)
+        .unwrap();
+        let events = |before: Option<&str>, limit| {
+            let before = before.map(|b| b.parse::<BigDecimal>().unwrap());
+            load_related_events(&conn, "cluster", &owner, before.as_ref(), limit)
+                .unwrap()
+                .into_iter()
+                .map(|e| (e.message_id, e.state))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            events(None, 10),
+            vec![(u64::MAX, Stored), (3, Missing), (2, Missing), (1, Missing)]
+        );
+        assert_eq!(events(None, 2), vec![(u64::MAX, Stored), (3, Missing)]);
+        assert_eq!(events(Some("3"), 10), vec![(2, Missing), (1, Missing)]);
+        assert_eq!(
+            events(Some("18446744073709551615"), 2),
+            vec![(3, Missing), (2, Missing)]
+        );
+        assert_eq!(events(Some("1"), 10), vec![]);
+    }
+}
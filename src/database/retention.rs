@@ -0,0 +1,422 @@
+use actix_web::{
+    http,
+    web::{Data, Json, Path, Query},
+    HttpResponse,
+};
+use diesel::prelude::*;
+use diesel::sql_types::BigInt;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use super::schema::{data_source, retention_policy};
+use crate::database::{
+    build_error_response, get_data_source_id, ApiError, Conn, Database, Error, Pool,
+    DESCRIPTION_TABLES,
+};
+
+/// The number of rows deleted by a statement. Each batch is committed on its
+/// own, so that a large purge does not hold its locks until the end.
+const PURGE_BATCH_SIZE: usize = 10_000;
+
+/// Retention rules of a data source. A rule set to `None` is not enforced.
+#[derive(Debug, AsChangeset, Deserialize, Insertable, Queryable, Serialize)]
+#[table_name = "retention_policy"]
+#[primary_key(data_source_id)]
+#[changeset_options(treat_none_as_null = "true")]
+pub(crate) struct RetentionPolicy {
+    #[serde(skip)]
+    data_source_id: i32,
+    /// Events, Kafka metadata, outliers, and cluster history older than this
+    /// are purged.
+    max_age_days: Option<i32>,
+    /// Only the latest `max_rows` events, Kafka metadata, outliers, and
+    /// cluster history snapshots are kept.
+    max_rows: Option<i64>,
+    /// Only the latest `description_rounds` rounds of descriptions are kept
+    /// for each cluster.
+    description_rounds: Option<i32>,
+    /// Whether events in the `event_ids` of a cluster may be purged.
+    #[serde(default)]
+    purge_referenced_events: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PurgeQuery {
+    data_source: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// The number of rows purged, or to be purged in a dry run, from a data
+/// source.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct PurgeReport {
+    data_source: String,
+    events: i64,
+    kafka_metadata: i64,
+    outliers: i64,
+    cluster_history: i64,
+    description_rounds: i64,
+}
+
+#[derive(Debug, QueryableByName)]
+struct Count {
+    #[sql_type = "BigInt"]
+    count: i64,
+}
+
+pub(crate) async fn get_retention_policy(
+    pool: Data<Database>,
+    topic_name: Path<String>,
+) -> Result<HttpResponse, actix_web::Error> {
+    use retention_policy::dsl;
+    let query_result: Result<Option<RetentionPolicy>, Error> = pool
+        .run(move |conn| {
+            let data_source_id = match get_data_source_id(conn, &topic_name) {
+                Ok(id) => id,
+                Err(Error::Query(diesel::result::Error::NotFound)) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let policy = dsl::retention_policy
+                .filter(dsl::data_source_id.eq(data_source_id))
+                .first::<RetentionPolicy>(conn)
+                .optional()?;
+            Ok(Some(policy.unwrap_or(RetentionPolicy {
+                data_source_id,
+                max_age_days: None,
+                max_rows: None,
+                description_rounds: None,
+                purge_referenced_events: false,
+            })))
+        })
+        .await;
+
+    match query_result {
+        Ok(Some(policy)) => Ok(HttpResponse::Ok()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .json(policy)),
+        Ok(None) => Ok(ApiError::not_found("data source").into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn update_retention_policy(
+    pool: Data<Database>,
+    topic_name: Path<String>,
+    policy: Json<RetentionPolicy>,
+) -> Result<HttpResponse, actix_web::Error> {
+    use retention_policy::dsl;
+    let mut policy = policy.into_inner();
+    if policy.max_age_days.map_or(false, |v| v < 0)
+        || policy.max_rows.map_or(false, |v| v < 0)
+        || policy.description_rounds.map_or(false, |v| v < 0)
+    {
+        return Ok(ApiError::bad_request(
+            "max_age_days, max_rows, and description_rounds must not be negative",
+        )
+        .into());
+    }
+    let update_result: Result<Option<()>, Error> = pool
+        .run(move |conn| {
+            policy.data_source_id = match get_data_source_id(conn, &topic_name) {
+                Ok(id) => id,
+                Err(Error::Query(diesel::result::Error::NotFound)) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            diesel::insert_into(dsl::retention_policy)
+                .values(&policy)
+                .on_conflict(dsl::data_source_id)
+                .do_update()
+                .set(&policy)
+                .execute(conn)?;
+            Ok(Some(()))
+        })
+        .await;
+
+    match update_result {
+        Ok(Some(_)) => Ok(HttpResponse::Ok().into()),
+        Ok(None) => Ok(ApiError::not_found("data source").into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn purge_data(
+    pool: Data<Database>,
+    query: Query<PurgeQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Purged in batches committed one by one, like the periodic purge, rather
+    // than in one transaction bounded by the time limit of a request.
+    let purge_result: Result<Option<Vec<PurgeReport>>, Error> = pool
+        .run_autocommit(move |conn| {
+            if let Some(data_source) = &query.data_source {
+                if let Err(e) = get_data_source_id(conn, data_source) {
+                    return match e {
+                        Error::Query(diesel::result::Error::NotFound) => Ok(None),
+                        e => Err(e),
+                    };
+                }
+            }
+            apply_retention_policies(conn, query.data_source.as_deref(), query.dry_run).map(Some)
+        })
+        .await;
+
+    match purge_result {
+        Ok(Some(report)) => Ok(HttpResponse::Ok()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .json(report)),
+        Ok(None) => Ok(ApiError::not_found("data source").into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+/// Purges the data exceeding the retention policies of all data sources, or
+/// only `data_source` if given. In a dry run, nothing is deleted and the
+/// report shows what would have been purged.
+pub(crate) fn apply_retention_policies(
+    conn: &Conn,
+    data_source: Option<&str>,
+    dry_run: bool,
+) -> Result<Vec<PurgeReport>, Error> {
+    use data_source::dsl as ds_d;
+    use retention_policy::dsl as rp_d;
+    let mut query = rp_d::retention_policy
+        .inner_join(ds_d::data_source)
+        .select((
+            ds_d::topic_name,
+            (
+                rp_d::data_source_id,
+                rp_d::max_age_days,
+                rp_d::max_rows,
+                rp_d::description_rounds,
+                rp_d::purge_referenced_events,
+            ),
+        ))
+        .into_boxed();
+    if let Some(data_source) = data_source {
+        query = query.filter(ds_d::topic_name.eq(data_source));
+    }
+    let policies = query.load::<(String, RetentionPolicy)>(conn)?;
+
+    let mut reports = Vec::with_capacity(policies.len());
+    for (topic_name, policy) in policies {
+        let mut report = PurgeReport {
+            data_source: topic_name,
+            ..PurgeReport::default()
+        };
+        if let Some(condition) = expiration_condition("event", &policy) {
+            let condition = if policy.purge_referenced_events {
+                condition
+            } else {
+                // The referenced ids are collected once per statement and
+                // joined, instead of searching the clusters for each event.
+                format!(
+                    "{} AND NOT EXISTS (SELECT 1 FROM (\
+                     SELECT unnest(event_ids) AS message_id FROM cluster \
+                     WHERE data_source_id = {}) referenced \
+                     WHERE referenced.message_id = event.message_id)",
+                    condition, policy.data_source_id
+                )
+            };
+            report.events = purge(conn, "event", &condition, dry_run)?;
+        }
+        if let Some(condition) = expiration_condition("kafka_metadata", &policy) {
+            // The Kafka messages of events whose raw events are not stored
+            // yet are still needed to fetch them.
+            let condition = format!(
+                "{} AND NOT EXISTS (SELECT 1 FROM event \
+                 WHERE event.data_source_id = kafka_metadata.data_source_id \
+                 AND event.raw_event IS NULL \
+                 AND event.message_id >= LOWER(kafka_metadata.message_ids) \
+                 AND event.message_id <= UPPER(kafka_metadata.message_ids))",
+                condition
+            );
+            report.kafka_metadata = purge(conn, "kafka_metadata", &condition, dry_run)?;
+        }
+        if let Some(condition) = expiration_condition("outlier", &policy) {
+            report.outliers = purge(conn, "outlier", &condition, dry_run)?;
+        }
+        if let Some(condition) = history_expiration_condition(&policy) {
+            report.cluster_history = purge(conn, "cluster_history", &condition, dry_run)?;
+        }
+        if let Some(rounds) = policy.description_rounds {
+            report.description_rounds = conn.transaction(|| {
+                purge_description_rounds(conn, policy.data_source_id, rounds, dry_run)
+            })?;
+        }
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+/// Builds a condition that is true for the rows of `table` that exceed the
+/// maximum age or the maximum number of rows of `policy`.
+fn expiration_condition(table: &str, policy: &RetentionPolicy) -> Option<String> {
+    let mut conditions = Vec::new();
+    if let Some(days) = policy.max_age_days {
+        conditions.push(format!(
+            "{}.creation_time < NOW() - INTERVAL '{} days'",
+            table, days
+        ));
+    }
+    if let Some(rows) = policy.max_rows {
+        conditions.push(format!(
+            "{table}.id NOT IN (SELECT id FROM {table} WHERE data_source_id = {} \
+             ORDER BY id DESC LIMIT {})",
+            policy.data_source_id,
+            rows,
+            table = table
+        ));
+    }
+    if conditions.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "{}.data_source_id = {} AND ({})",
+            table,
+            policy.data_source_id,
+            conditions.join(" OR ")
+        ))
+    }
+}
+
+/// Builds a condition that is true for the snapshots in `cluster_history` that
+/// exceed the maximum age or the maximum number of rows of `policy`. The
+/// history has no data source of its own, so it is found through its clusters.
+fn history_expiration_condition(policy: &RetentionPolicy) -> Option<String> {
+    let clusters = format!(
+        "SELECT id FROM cluster WHERE data_source_id = {}",
+        policy.data_source_id
+    );
+    let mut conditions = Vec::new();
+    if let Some(days) = policy.max_age_days {
+        conditions.push(format!(
+            "cluster_history.recorded_at < NOW() - INTERVAL '{} days'",
+            days
+        ));
+    }
+    if let Some(rows) = policy.max_rows {
+        conditions.push(format!(
+            "cluster_history.id NOT IN (SELECT id FROM cluster_history \
+             WHERE cluster_id IN ({}) ORDER BY id DESC LIMIT {})",
+            clusters, rows
+        ));
+    }
+    if conditions.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "cluster_history.cluster_id IN ({}) AND ({})",
+            clusters,
+            conditions.join(" OR ")
+        ))
+    }
+}
+
+/// Deletes the rows of `table` that match `condition`, in batches of
+/// `PURGE_BATCH_SIZE`. Returns the number of rows deleted, or to be deleted
+/// in a dry run.
+fn purge(conn: &Conn, table: &str, condition: &str, dry_run: bool) -> Result<i64, Error> {
+    if dry_run {
+        let count = diesel::sql_query(format!(
+            "SELECT COUNT(*) as count FROM {} WHERE {}",
+            table, condition
+        ))
+        .get_result::<Count>(conn)?;
+        return Ok(count.count);
+    }
+    let mut total = 0_usize;
+    loop {
+        let count = diesel::sql_query(format!(
+            "DELETE FROM {table} WHERE id IN (SELECT id FROM {table} WHERE {} LIMIT {})",
+            condition,
+            PURGE_BATCH_SIZE,
+            table = table
+        ))
+        .execute(conn)?;
+        total += count;
+        if count < PURGE_BATCH_SIZE {
+            break;
+        }
+    }
+    Ok(i64::try_from(total).unwrap_or(i64::MAX))
+}
+
+/// Purges all but the latest `rounds` rounds of descriptions of each cluster
+/// in a data source. Returns the number of rounds purged.
+fn purge_description_rounds(
+    conn: &Conn,
+    data_source_id: i32,
+    rounds: i32,
+    dry_run: bool,
+) -> Result<i64, Error> {
+    let expired_rounds = format!(
+        "SELECT cluster_id, first_event_id, last_event_id FROM (\
+         SELECT cluster_id, first_event_id, last_event_id, ROW_NUMBER() OVER (\
+         PARTITION BY cluster_id ORDER BY MAX(id) DESC) AS rank \
+         FROM column_description WHERE cluster_id IN (\
+         SELECT id FROM cluster WHERE data_source_id = {}) \
+         GROUP BY cluster_id, first_event_id, last_event_id) rounds \
+         WHERE rank > {}",
+        data_source_id, rounds
+    );
+    let count = diesel::sql_query(format!(
+        "SELECT COUNT(*) as count FROM ({}) expired",
+        expired_rounds
+    ))
+    .get_result::<Count>(conn)?;
+    if dry_run || count.count == 0 {
+        return Ok(count.count);
+    }
+
+    let expired_descriptions = format!(
+        "SELECT id FROM column_description \
+         WHERE (cluster_id, first_event_id, last_event_id) IN ({})",
+        expired_rounds
+    );
+    for table in DESCRIPTION_TABLES.iter() {
+        diesel::sql_query(format!(
+            "DELETE FROM {} WHERE description_id IN ({})",
+            table, expired_descriptions
+        ))
+        .execute(conn)?;
+    }
+    diesel::sql_query(format!(
+        "DELETE FROM column_description WHERE id IN ({})",
+        expired_descriptions
+    ))
+    .execute(conn)?;
+    Ok(count.count)
+}
+
+/// Applies the retention policies every `interval`.
+pub(crate) fn periodically_apply_retention_policies(pool: &Pool, interval: Duration) {
+    info!(
+        "Starting retention task with time interval {} second(s)",
+        interval.as_secs()
+    );
+    loop {
+        std::thread::sleep(interval);
+        let purge_result = pool
+            .get()
+            .map_err(Into::into)
+            .and_then(|conn| apply_retention_policies(&conn, None, false));
+        match purge_result {
+            Ok(reports) => {
+                for report in reports {
+                    info!(
+                        "Purged {} event(s), {} Kafka metadata, {} outlier(s), {} cluster history snapshot(s), and {} description round(s) from {}",
+                        report.events,
+                        report.kafka_metadata,
+                        report.outliers,
+                        report.cluster_history,
+                        report.description_rounds,
+                        report.data_source
+                    );
+                }
+            }
+            Err(e) => error!("Failed to apply retention policies: {}", e),
+        }
+    }
+}
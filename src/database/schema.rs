@@ -1,7 +1,29 @@
+table! {
+    api_token (id) {
+        id -> Int4,
+        name -> Text,
+        token_hash -> Bytea,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     category (id) {
         id -> Int4,
         name -> Text,
+        parent_id -> Nullable<Int4>,
+    }
+}
+
+table! {
+    change_event (id) {
+        id -> Int8,
+        data_source_id -> Int4,
+        kind -> Text,
+        payload -> Jsonb,
+        created_at -> Timestamptz,
+        tx_id -> Int8,
+        position -> Nullable<Int8>,
     }
 }
 
@@ -19,6 +41,26 @@ table! {
         score -> Nullable<Float8>,
         data_source_id -> Int4,
         last_modification_time -> Nullable<Timestamp>,
+        first_seen -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    cluster_history (id) {
+        id -> Int8,
+        cluster_id -> Int4,
+        recorded_at -> Timestamptz,
+        size -> Numeric,
+        size_delta -> Numeric,
+        score -> Nullable<Float8>,
+        new_event_count -> Int4,
+    }
+}
+
+table! {
+    cluster_tag (cluster_id, tag_id) {
+        cluster_id -> Int4,
+        tag_id -> Int4,
     }
 }
 
@@ -35,11 +77,29 @@ table! {
     }
 }
 
+table! {
+    compression_dictionary (id) {
+        id -> Int4,
+        data_source_id -> Int4,
+        dictionary -> Bytea,
+    }
+}
+
 table! {
     data_source (id) {
         id -> Int4,
         topic_name -> Text,
         data_type -> Text,
+        description -> Nullable<Text>,
+        enabled -> Bool,
+        owner -> Nullable<Text>,
+        archived -> Bool,
+        event_source -> Text,
+        event_format -> Text,
+        event_path -> Nullable<Text>,
+        record_field -> Text,
+        id_field -> Nullable<Text>,
+        template -> Nullable<Text>,
     }
 }
 
@@ -103,7 +163,7 @@ table! {
     description_ipaddr (id) {
         id -> Int4,
         description_id -> Int4,
-        mode -> Nullable<Text>,
+        mode -> Nullable<Inet>,
     }
 }
 
@@ -121,6 +181,9 @@ table! {
         message_id -> Numeric,
         data_source_id -> Int4,
         raw_event -> Nullable<Bytea>,
+        creation_time -> Timestamp,
+        compressed -> Bool,
+        dictionary_id -> Nullable<Int4>,
     }
 }
 
@@ -135,6 +198,13 @@ table! {
     }
 }
 
+table! {
+    indicator_tag (indicator_id, tag_id) {
+        indicator_id -> Int4,
+        tag_id -> Int4,
+    }
+}
+
 table! {
     kafka_metadata (id) {
         id -> Int4,
@@ -142,6 +212,7 @@ table! {
         partition -> Int4,
         offsets -> Int8,
         message_ids -> Numrange,
+        creation_time -> Timestamp,
     }
 }
 
@@ -152,6 +223,17 @@ table! {
         data_source_id -> Int4,
         event_ids -> Array<Numeric>,
         size -> Numeric,
+        creation_time -> Timestamp,
+        compressed -> Bool,
+        dictionary_id -> Nullable<Int4>,
+        raw_event_hash -> Nullable<Bytea>,
+    }
+}
+
+table! {
+    outlier_tag (outlier_id, tag_id) {
+        outlier_id -> Int4,
+        tag_id -> Int4,
     }
 }
 
@@ -162,6 +244,16 @@ table! {
     }
 }
 
+table! {
+    retention_policy (data_source_id) {
+        data_source_id -> Int4,
+        max_age_days -> Nullable<Int4>,
+        max_rows -> Nullable<Int8>,
+        description_rounds -> Nullable<Int4>,
+        purge_referenced_events -> Bool,
+    }
+}
+
 table! {
     status (id) {
         id -> Int4,
@@ -169,6 +261,13 @@ table! {
     }
 }
 
+table! {
+    tag (id) {
+        id -> Int4,
+        name -> Text,
+    }
+}
+
 table! {
     template (id) {
         id -> Int4,
@@ -240,7 +339,7 @@ table! {
         id -> Int4,
         description_id -> Int4,
         ranking -> Nullable<Int8>,
-        value -> Nullable<Text>,
+        value -> Nullable<Inet>,
         count -> Nullable<Int8>,
     }
 }
@@ -255,6 +354,9 @@ table! {
     }
 }
 
+joinable!(cluster_history -> cluster (cluster_id));
+joinable!(cluster_tag -> cluster (cluster_id));
+joinable!(cluster_tag -> tag (tag_id));
 joinable!(column_description -> cluster (cluster_id));
 joinable!(column_description -> description_element_type (type_id));
 joinable!(description_binary -> column_description (description_id));
@@ -264,6 +366,14 @@ joinable!(description_float -> column_description (description_id));
 joinable!(description_int -> column_description (description_id));
 joinable!(description_ipaddr -> column_description (description_id));
 joinable!(description_text -> column_description (description_id));
+joinable!(compression_dictionary -> data_source (data_source_id));
+joinable!(event -> compression_dictionary (dictionary_id));
+joinable!(indicator_tag -> indicator (indicator_id));
+joinable!(indicator_tag -> tag (tag_id));
+joinable!(outlier -> compression_dictionary (dictionary_id));
+joinable!(outlier_tag -> outlier (outlier_id));
+joinable!(outlier_tag -> tag (tag_id));
+joinable!(retention_policy -> data_source (data_source_id));
 joinable!(top_n_binary -> column_description (description_id));
 joinable!(top_n_datetime -> column_description (description_id));
 joinable!(top_n_enum -> column_description (description_id));
@@ -273,9 +383,14 @@ joinable!(top_n_ipaddr -> column_description (description_id));
 joinable!(top_n_text -> column_description (description_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_token,
     category,
+    change_event,
     cluster,
+    cluster_history,
+    cluster_tag,
     column_description,
+    compression_dictionary,
     data_source,
     description_binary,
     description_datetime,
@@ -287,10 +402,14 @@ allow_tables_to_appear_in_same_query!(
     description_text,
     event,
     indicator,
+    indicator_tag,
     kafka_metadata,
     outlier,
+    outlier_tag,
     qualifier,
+    retention_policy,
     status,
+    tag,
     template,
     top_n_binary,
     top_n_datetime,
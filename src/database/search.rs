@@ -0,0 +1,546 @@
+use actix_web::{
+    web::{Data, Query},
+    HttpResponse,
+};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Bool, Bytea, Float4, Int4, Int8, Nullable, Numeric, Text};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use super::schema::data_source;
+use crate::database::{build_error_response, ApiError, Codec, Conn, Database, Error, Pool};
+
+/// How often the raw events and outliers not indexed yet are indexed.
+const INDEX_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The number of rows indexed at a time.
+const INDEX_BATCH_SIZE: i64 = 1000;
+
+/// The number of bytes of a raw event that are indexed. The rest is not
+/// searchable.
+const MAX_INDEXED_LEN: usize = 65_536;
+
+/// The shortest query, which is the shortest string a trigram index can find.
+const MIN_QUERY_LEN: usize = 3;
+
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// The number of characters in a snippet, and how many of them come before
+/// the first match.
+const SNIPPET_LEN: usize = 200;
+const SNIPPET_CONTEXT: usize = 60;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SearchQuery {
+    q: String,
+    /// Comma-separated names of data sources.
+    data_source: Option<String>,
+    /// Comma-separated qualifiers, which only apply to clusters.
+    qualifier: Option<String>,
+    /// Comma-separated statuses, which only apply to clusters.
+    status: Option<String>,
+    /// Comma-separated kinds of hits to return: `cluster`, `outlier`, `event`,
+    /// and `indicator`. All kinds if not given.
+    kind: Option<String>,
+    /// The maximum number of hits of each kind.
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct SearchResults {
+    clusters: Vec<ClusterHit>,
+    outliers: Vec<OutlierHit>,
+    events: Vec<EventHit>,
+    indicators: Vec<IndicatorHit>,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct ClusterHit {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Nullable<Text>"]
+    cluster_id: Option<String>,
+    #[sql_type = "Text"]
+    data_source: String,
+    #[sql_type = "Text"]
+    qualifier: String,
+    #[sql_type = "Text"]
+    status: String,
+    #[sql_type = "Float4"]
+    rank: f32,
+    #[sql_type = "Text"]
+    #[serde(rename = "snippet")]
+    signature: String,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct IndicatorHit {
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "Text"]
+    data_source: String,
+    #[sql_type = "Float4"]
+    rank: f32,
+    #[sql_type = "Text"]
+    #[serde(rename = "snippet")]
+    description: String,
+}
+
+#[derive(Debug, Serialize)]
+struct EventHit {
+    message_id: u64,
+    data_source: String,
+    rank: f32,
+    snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+struct OutlierHit {
+    id: i32,
+    data_source: String,
+    rank: f32,
+    snippet: String,
+}
+
+/// A raw event or an outlier matching a query, before decompression.
+#[derive(QueryableByName)]
+struct RawHit {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Nullable<Numeric>"]
+    message_id: Option<BigDecimal>,
+    #[sql_type = "Text"]
+    data_source: String,
+    #[sql_type = "Nullable<Bytea>"]
+    raw_event: Option<Vec<u8>>,
+    #[sql_type = "Bool"]
+    compressed: bool,
+    #[sql_type = "Nullable<Int4>"]
+    dictionary_id: Option<i32>,
+    #[sql_type = "Float4"]
+    rank: f32,
+}
+
+#[derive(QueryableByName)]
+struct Unindexed {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Bytea"]
+    raw_event: Vec<u8>,
+    #[sql_type = "Bool"]
+    compressed: bool,
+    #[sql_type = "Nullable<Int4>"]
+    dictionary_id: Option<i32>,
+}
+
+/// The filters of a search, checked and resolved.
+struct SearchFilter {
+    text: String,
+    pattern: String,
+    words: Vec<String>,
+    data_sources: Option<Vec<i32>>,
+    qualifiers: Option<Vec<String>>,
+    statuses: Option<Vec<String>>,
+    limit: i64,
+}
+
+pub(crate) async fn search(
+    pool: Data<Database>,
+    query: Query<SearchQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query = query.into_inner();
+    let text = query.q.trim().to_string();
+    if text.chars().count() < MIN_QUERY_LEN {
+        return Ok(ApiError::invalid(
+            "q",
+            format!("q must have at least {} characters", MIN_QUERY_LEN),
+        )
+        .into());
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Ok(ApiError::invalid(
+            "limit",
+            format!("limit must be between 1 and {}", MAX_SEARCH_LIMIT),
+        )
+        .into());
+    }
+    let kinds = match &query.kind {
+        Some(kind) => split_list(kind),
+        None => vec![
+            "cluster".to_string(),
+            "outlier".to_string(),
+            "event".to_string(),
+            "indicator".to_string(),
+        ],
+    };
+    if let Some(kind) = kinds
+        .iter()
+        .find(|k| !["cluster", "outlier", "event", "indicator"].contains(&k.as_str()))
+    {
+        return Ok(ApiError::invalid("kind", format!("Unknown kind: {}", kind)).into());
+    }
+    let data_source_names = query.data_source.as_deref().map(split_list);
+
+    let results: Result<Option<SearchResults>, Error> = pool
+        .run(move |conn| {
+            let data_sources = match data_source_names {
+                Some(names) => {
+                    use data_source::dsl;
+                    let ids = dsl::data_source
+                        .select(dsl::id)
+                        .filter(dsl::topic_name.eq_any(&names))
+                        .load::<i32>(conn)?;
+                    if ids.len() != names.len() {
+                        return Ok(None);
+                    }
+                    Some(ids)
+                }
+                None => None,
+            };
+            let filter = SearchFilter {
+                pattern: format!("%{}%", escape_like(&text)),
+                words: words(&text),
+                text,
+                data_sources,
+                qualifiers: query.qualifier.as_deref().map(split_list),
+                statuses: query.status.as_deref().map(split_list),
+                limit,
+            };
+            let has = |kind: &str| kinds.iter().any(|k| k == kind);
+            let mut results = SearchResults::default();
+            if has("cluster") {
+                results.clusters = search_clusters(conn, &filter)?;
+            }
+            if has("outlier") {
+                results.outliers = search_raw_events(conn, "outlier", &filter)?
+                    .into_iter()
+                    .map(|(hit, snippet)| OutlierHit {
+                        id: hit.id,
+                        data_source: hit.data_source,
+                        rank: hit.rank,
+                        snippet,
+                    })
+                    .collect();
+            }
+            if has("event") {
+                results.events = search_raw_events(conn, "event", &filter)?
+                    .into_iter()
+                    .filter_map(|(hit, snippet)| {
+                        Some(EventHit {
+                            message_id: hit.message_id.as_ref()?.to_u64()?,
+                            data_source: hit.data_source,
+                            rank: hit.rank,
+                            snippet,
+                        })
+                    })
+                    .collect();
+            }
+            if has("indicator") {
+                results.indicators = search_indicators(conn, &filter)?;
+            }
+            Ok(Some(results))
+        })
+        .await;
+
+    match results {
+        Ok(Some(results)) => Ok(HttpResponse::Ok().json(results)),
+        Ok(None) => Ok(ApiError::not_found("data source")
+            .field("data_source")
+            .into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+/// Finds the clusters whose signature contains the query, ranked by how
+/// closely a part of the signature matches it.
+fn search_clusters(conn: &Conn, filter: &SearchFilter) -> Result<Vec<ClusterHit>, Error> {
+    let mut hits = diesel::sql_query(
+        "SELECT cluster.id, cluster.cluster_id, data_source.topic_name AS data_source, \
+           qualifier.description AS qualifier, status.description AS status, \
+           word_similarity($1, cluster.signature) AS rank, cluster.signature \
+         FROM cluster \
+         INNER JOIN data_source \
+           ON cluster.data_source_id = data_source.id AND NOT data_source.archived \
+         INNER JOIN qualifier ON cluster.qualifier_id = qualifier.id \
+         INNER JOIN status ON cluster.status_id = status.id \
+         WHERE cluster.signature ILIKE $2 \
+           AND ($3::INT4[] IS NULL OR cluster.data_source_id = ANY($3)) \
+           AND ($4::TEXT[] IS NULL OR qualifier.description = ANY($4)) \
+           AND ($5::TEXT[] IS NULL OR status.description = ANY($5)) \
+         ORDER BY rank DESC, cluster.id \
+         LIMIT $6",
+    )
+    .bind::<Text, _>(&filter.text)
+    .bind::<Text, _>(&filter.pattern)
+    .bind::<Nullable<Array<Int4>>, _>(&filter.data_sources)
+    .bind::<Nullable<Array<Text>>, _>(&filter.qualifiers)
+    .bind::<Nullable<Array<Text>>, _>(&filter.statuses)
+    .bind::<Int8, _>(filter.limit)
+    .load::<ClusterHit>(conn)?;
+    let phrase = [filter.text.clone()];
+    for hit in &mut hits {
+        hit.signature = snippet(&hit.signature, &phrase);
+    }
+    Ok(hits)
+}
+
+/// Finds the indicators whose description contains the query.
+fn search_indicators(conn: &Conn, filter: &SearchFilter) -> Result<Vec<IndicatorHit>, Error> {
+    let mut hits = diesel::sql_query(
+        "SELECT indicator.name, data_source.topic_name AS data_source, \
+           word_similarity($1, indicator.description) AS rank, indicator.description \
+         FROM indicator \
+         INNER JOIN data_source \
+           ON indicator.data_source_id = data_source.id AND NOT data_source.archived \
+         WHERE indicator.description ILIKE $2 \
+           AND ($3::INT4[] IS NULL OR indicator.data_source_id = ANY($3)) \
+         ORDER BY rank DESC, indicator.id \
+         LIMIT $4",
+    )
+    .bind::<Text, _>(&filter.text)
+    .bind::<Text, _>(&filter.pattern)
+    .bind::<Nullable<Array<Int4>>, _>(&filter.data_sources)
+    .bind::<Int8, _>(filter.limit)
+    .load::<IndicatorHit>(conn)?;
+    let phrase = [filter.text.clone()];
+    for hit in &mut hits {
+        hit.description = snippet(&hit.description, &phrase);
+    }
+    Ok(hits)
+}
+
+/// Finds the raw events in `table`, `event` or `outlier`, that have every word
+/// of the query, ranked by how close the words are to each other. Returns the
+/// hits with their snippets.
+fn search_raw_events(
+    conn: &Conn,
+    table: &str,
+    filter: &SearchFilter,
+) -> Result<Vec<(RawHit, String)>, Error> {
+    if filter.words.is_empty() {
+        return Ok(Vec::new());
+    }
+    let message_id = if table == "event" {
+        "event.message_id"
+    } else {
+        "NULL::NUMERIC"
+    };
+    let hits = diesel::sql_query(format!(
+        "SELECT {table}.id, {message_id} AS message_id, data_source.topic_name AS data_source, \
+           {table}.raw_event, {table}.compressed, {table}.dictionary_id, \
+           ts_rank_cd({table}.search_vector, query) AS rank \
+         FROM {table} \
+         INNER JOIN data_source \
+           ON {table}.data_source_id = data_source.id AND NOT data_source.archived, \
+           plainto_tsquery('simple', $1) AS query \
+         WHERE {table}.search_vector @@ query \
+           AND ($2::INT4[] IS NULL OR {table}.data_source_id = ANY($2)) \
+         ORDER BY rank DESC, {table}.id \
+         LIMIT $3",
+        table = table,
+        message_id = message_id,
+    ))
+    .bind::<Text, _>(filter.words.join(" "))
+    .bind::<Nullable<Array<Int4>>, _>(&filter.data_sources)
+    .bind::<Int8, _>(filter.limit)
+    .load::<RawHit>(conn)?;
+
+    let mut codec = Codec::default();
+    hits.into_iter()
+        .map(|hit| {
+            let raw = match &hit.raw_event {
+                Some(raw) => codec.decompress(conn, raw, hit.compressed, hit.dictionary_id)?,
+                None => Vec::new(),
+            };
+            let snippet = snippet(&String::from_utf8_lossy(&raw), &filter.words);
+            Ok((hit, snippet))
+        })
+        .collect()
+}
+
+/// Indexes the raw events and outliers added or changed since the last call,
+/// a batch at a time.
+pub(crate) fn periodically_index_raw_events(pool: &Pool) {
+    info!(
+        "Starting raw event indexing with time interval {} second(s)",
+        INDEX_INTERVAL.as_secs()
+    );
+    loop {
+        let index_result = pool.get().map_err(Into::into).and_then(|conn| {
+            let mut codec = Codec::default();
+            let mut total = 0;
+            loop {
+                let count = index_batch(&conn, &mut codec, "event")?
+                    + index_batch(&conn, &mut codec, "outlier")?;
+                if count == 0 {
+                    return Ok::<_, Error>(total);
+                }
+                total += count;
+            }
+        });
+        match index_result {
+            Ok(0) => {}
+            Ok(count) => info!("Indexed {} raw event(s) and outlier(s)", count),
+            Err(e) => error!("Failed to index raw events: {}", e),
+        }
+        std::thread::sleep(INDEX_INTERVAL);
+    }
+}
+
+/// Sets the search vectors of up to `INDEX_BATCH_SIZE` rows of `table` that
+/// have none. Returns the number of rows indexed.
+fn index_batch(conn: &Conn, codec: &mut Codec, table: &str) -> Result<usize, Error> {
+    let rows = diesel::sql_query(format!(
+        "SELECT id, raw_event, compressed, dictionary_id FROM {table} \
+         WHERE search_vector IS NULL AND raw_event IS NOT NULL \
+         ORDER BY id LIMIT $1",
+        table = table
+    ))
+    .bind::<Int8, _>(INDEX_BATCH_SIZE)
+    .load::<Unindexed>(conn)?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let mut ids = Vec::with_capacity(rows.len());
+    let mut texts = Vec::with_capacity(rows.len());
+    for row in rows {
+        // A row that cannot be decompressed is indexed as empty, so that it is
+        // not tried again.
+        let raw = codec
+            .decompress(conn, &row.raw_event, row.compressed, row.dictionary_id)
+            .unwrap_or_else(|e| {
+                error!("Failed to decompress {} {}: {}", table, row.id, e);
+                Vec::new()
+            });
+        ids.push(row.id);
+        texts.push(searchable_text(&raw));
+    }
+    diesel::sql_query(format!(
+        "UPDATE {table} SET search_vector = to_tsvector('simple', indexed.text) \
+         FROM unnest($1::INT4[], $2::TEXT[]) AS indexed(id, text) \
+         WHERE {table}.id = indexed.id",
+        table = table
+    ))
+    .bind::<Array<Int4>, _>(&ids)
+    .bind::<Array<Text>, _>(&texts)
+    .execute(conn)?;
+    Ok(ids.len())
+}
+
+/// Returns the text indexed for a raw event: its first `MAX_INDEXED_LEN`
+/// bytes, with every character other than letters, digits, and `_` replaced
+/// by a space, so that the parts of paths, options, and file names are
+/// separate words.
+fn searchable_text(raw: &[u8]) -> String {
+    let raw = &raw[..raw.len().min(MAX_INDEXED_LEN)];
+    String::from_utf8_lossy(raw)
+        .chars()
+        .map(|c| if is_word_char(c) { c } else { ' ' })
+        .collect()
+}
+
+/// Returns the words of a query, as they are indexed.
+pub(super) fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !is_word_char(c))
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    let mut items = list
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    items.sort();
+    items.dedup();
+    items
+}
+
+/// Escapes the wildcards of `LIKE`.
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Returns up to `SNIPPET_LEN` characters of `text` from a little before the
+/// first match of `terms`, as HTML, with every match in `<mark>`. The matches
+/// are case-insensitive.
+fn snippet(text: &str, terms: &[String]) -> String {
+    let chars = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<Vec<_>>();
+    let lower = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect::<Vec<_>>();
+    let terms = terms
+        .iter()
+        .map(|t| t.chars().flat_map(char::to_lowercase).collect::<Vec<_>>())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+
+    // Whether each character is part of a match.
+    let mut marked = vec![false; chars.len()];
+    let mut first = None;
+    for term in &terms {
+        let mut start = 0;
+        while start + term.len() <= lower.len() {
+            if lower[start..start + term.len()] == term[..] {
+                for m in &mut marked[start..start + term.len()] {
+                    *m = true;
+                }
+                first = Some(first.map_or(start, |f: usize| f.min(start)));
+                start += term.len();
+            } else {
+                start += 1;
+            }
+        }
+    }
+
+    let begin = first.map_or(0, |f| f.saturating_sub(SNIPPET_CONTEXT));
+    let end = chars.len().min(begin + SNIPPET_LEN);
+    let mut html = String::new();
+    if begin > 0 {
+        html.push('…');
+    }
+    let mut in_mark = false;
+    for (&c, &m) in chars[begin..end].iter().zip(&marked[begin..end]) {
+        if m != in_mark {
+            html.push_str(if m { "<mark>" } else { "</mark>" });
+            in_mark = m;
+        }
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    if in_mark {
+        html.push_str("</mark>");
+    }
+    if end < chars.len() {
+        html.push('…');
+    }
+    html
+}
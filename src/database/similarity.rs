@@ -0,0 +1,505 @@
+use actix_web::{
+    web::{Data, Path, Query},
+    HttpResponse,
+};
+use bigdecimal::ToPrimitive;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, Bool, Int4, Int8, Nullable, Text};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use super::search::words;
+use crate::database::{build_error_response, ApiError, Conn, Database, Error, Pool};
+
+/// How often the minhashes of new or changed signatures are computed.
+const MINHASH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The number of clusters whose minhashes are computed at a time.
+const MINHASH_BATCH_SIZE: i64 = 1000;
+
+/// The number of hash functions of a minhash, and the number of them in a
+/// band. Clusters whose signatures share at least one band are compared; with
+/// 20 bands of 3, a pair with a Jaccard similarity of 0.5 shares a band with a
+/// probability of 93%, and a pair with 0.2 with a probability of 15%.
+const MINHASH_LEN: usize = 60;
+const BAND_LEN: usize = 3;
+
+/// The number of clusters sharing the most bands with a cluster that are
+/// ranked.
+const MAX_CANDIDATES: i64 = 200;
+
+const DEFAULT_SIMILAR_LIMIT: i64 = 10;
+const MAX_SIMILAR_LIMIT: i64 = 100;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SimilarClusterQuery {
+    data_source: String,
+    /// If `true`, only the clusters pending review are returned.
+    unreviewed_only: Option<bool>,
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct SimilarCluster {
+    cluster_id: Option<String>,
+    data_source: String,
+    qualifier: String,
+    status: String,
+    signature: String,
+    score: f64,
+    token_similarity: f64,
+    /// `None` unless both clusters have descriptions.
+    description_similarity: Option<f64>,
+}
+
+#[derive(QueryableByName)]
+struct Signature {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Text"]
+    signature: String,
+}
+
+#[derive(QueryableByName)]
+struct Candidate {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Array<Int8>"]
+    minhash: Vec<i64>,
+}
+
+#[derive(QueryableByName)]
+struct ColumnStats {
+    #[sql_type = "Int4"]
+    cluster_id: i32,
+    #[sql_type = "Int4"]
+    column_index: i32,
+    #[sql_type = "Int4"]
+    type_id: i32,
+    #[sql_type = "Int8"]
+    count: i64,
+    #[sql_type = "Int8"]
+    unique_count: i64,
+}
+
+#[derive(QueryableByName)]
+struct ClusterSummary {
+    #[sql_type = "Int4"]
+    id: i32,
+    #[sql_type = "Nullable<Text>"]
+    cluster_id: Option<String>,
+    #[sql_type = "Text"]
+    data_source: String,
+    #[sql_type = "Text"]
+    qualifier: String,
+    #[sql_type = "Text"]
+    status: String,
+    #[sql_type = "Text"]
+    signature: String,
+}
+
+/// Returns the clusters, in any data source, whose signatures have tokens in
+/// common with that of a cluster, ranked by the Jaccard similarity of the
+/// tokens estimated from their minhashes. If both clusters have descriptions,
+/// the similarity of the column statistics in their latest rounds counts for
+/// half of the score.
+pub(crate) async fn get_similar_clusters(
+    pool: Data<Database>,
+    cluster_id: Path<String>,
+    query: Query<SimilarClusterQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query = query.into_inner();
+    let limit = query.limit.unwrap_or(DEFAULT_SIMILAR_LIMIT);
+    if !(1..=MAX_SIMILAR_LIMIT).contains(&limit) {
+        return Ok(ApiError::invalid(
+            "limit",
+            format!("limit must be between 1 and {}", MAX_SIMILAR_LIMIT),
+        )
+        .into());
+    }
+    let unreviewed_only = query.unreviewed_only.unwrap_or(false);
+
+    let query_result: Result<Option<Vec<SimilarCluster>>, Error> = pool
+        .run(move |conn| {
+            let target = diesel::sql_query(
+                "SELECT cluster.id, cluster.signature FROM cluster \
+                 INNER JOIN data_source ON cluster.data_source_id = data_source.id \
+                 WHERE cluster.cluster_id = $1 AND data_source.topic_name = $2",
+            )
+            .bind::<Text, _>(cluster_id.as_str())
+            .bind::<Text, _>(&query.data_source)
+            .get_result::<Signature>(conn)
+            .optional()?;
+            match target {
+                Some(target) => similar_clusters(conn, &target, unreviewed_only, limit).map(Some),
+                None => Ok(None),
+            }
+        })
+        .await;
+
+    match query_result {
+        Ok(Some(clusters)) => Ok(HttpResponse::Ok().json(clusters)),
+        Ok(None) => Ok(ApiError::not_found("cluster").into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+fn similar_clusters(
+    conn: &Conn,
+    target: &Signature,
+    unreviewed_only: bool,
+    limit: i64,
+) -> Result<Vec<SimilarCluster>, Error> {
+    let minhash = minhash(&target.signature);
+    if minhash.is_empty() {
+        return Ok(Vec::new());
+    }
+    let candidates = diesel::sql_query(
+        "SELECT id, minhash FROM cluster \
+         WHERE minhash_bands && $1 AND id <> $2 \
+           AND (NOT $3 OR status_id = (SELECT id FROM status WHERE description = 'pending review')) \
+         ORDER BY cardinality(ARRAY(SELECT unnest(minhash_bands) INTERSECT SELECT unnest($1))) DESC, id \
+         LIMIT $4",
+    )
+    .bind::<Array<Int8>, _>(bands(&minhash))
+    .bind::<Int4, _>(target.id)
+    .bind::<Bool, _>(unreviewed_only)
+    .bind::<Int8, _>(MAX_CANDIDATES)
+    .load::<Candidate>(conn)?;
+    if candidates.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut ids = candidates.iter().map(|c| c.id).collect::<Vec<_>>();
+    ids.push(target.id);
+    let mut columns = HashMap::<i32, Vec<ColumnStats>>::new();
+    for column in diesel::sql_query(
+        "SELECT column_description.cluster_id, column_index, type_id, count, unique_count \
+         FROM column_description \
+         INNER JOIN (\
+           SELECT DISTINCT ON (cluster_id) cluster_id, first_event_id, last_event_id \
+           FROM column_description WHERE cluster_id = ANY($1) \
+           ORDER BY cluster_id, id DESC) latest \
+         USING (cluster_id, first_event_id, last_event_id)",
+    )
+    .bind::<Array<Int4>, _>(&ids)
+    .load::<ColumnStats>(conn)?
+    {
+        columns.entry(column.cluster_id).or_default().push(column);
+    }
+    let target_columns = columns.remove(&target.id);
+
+    let mut ranked = candidates
+        .iter()
+        .map(|c| {
+            let token_similarity = minhash_similarity(&minhash, &c.minhash);
+            let description_similarity = match (&target_columns, columns.get(&c.id)) {
+                (Some(a), Some(b)) => Some(description_similarity(a, b)),
+                _ => None,
+            };
+            let score = description_similarity
+                .map_or(token_similarity, |d| 0.5 * token_similarity + 0.5 * d);
+            (c.id, score, token_similarity, description_similarity)
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    ranked.truncate(usize::try_from(limit).unwrap_or_default());
+
+    let ids = ranked.iter().map(|r| r.0).collect::<Vec<_>>();
+    let mut summaries = diesel::sql_query(
+        "SELECT cluster.id, cluster.cluster_id, data_source.topic_name AS data_source, \
+           qualifier.description AS qualifier, status.description AS status, cluster.signature \
+         FROM cluster \
+         INNER JOIN data_source ON cluster.data_source_id = data_source.id \
+         INNER JOIN qualifier ON cluster.qualifier_id = qualifier.id \
+         INNER JOIN status ON cluster.status_id = status.id \
+         WHERE cluster.id = ANY($1)",
+    )
+    .bind::<Array<Int4>, _>(&ids)
+    .load::<ClusterSummary>(conn)?
+    .into_iter()
+    .map(|s| (s.id, s))
+    .collect::<HashMap<_, _>>();
+    Ok(ranked
+        .into_iter()
+        .filter_map(|(id, score, token_similarity, description_similarity)| {
+            let summary = summaries.remove(&id)?;
+            Some(SimilarCluster {
+                cluster_id: summary.cluster_id,
+                data_source: summary.data_source,
+                qualifier: summary.qualifier,
+                status: summary.status,
+                signature: summary.signature,
+                score,
+                token_similarity,
+                description_similarity,
+            })
+        })
+        .collect())
+}
+
+/// Computes the minhashes of the clusters added or changed since the last
+/// call, a batch at a time.
+pub(crate) fn periodically_compute_minhashes(pool: &Pool) {
+    info!(
+        "Starting signature minhash computation with time interval {} second(s)",
+        MINHASH_INTERVAL.as_secs()
+    );
+    loop {
+        let minhash_result = pool.get().map_err(Into::into).and_then(|conn| {
+            let mut total = 0;
+            loop {
+                let count = compute_minhash_batch(&conn)?;
+                if count == 0 {
+                    return Ok::<_, Error>(total);
+                }
+                total += count;
+            }
+        });
+        match minhash_result {
+            Ok(0) => {}
+            Ok(count) => info!("Computed the minhashes of {} cluster(s)", count),
+            Err(e) => error!("Failed to compute minhashes: {}", e),
+        }
+        std::thread::sleep(MINHASH_INTERVAL);
+    }
+}
+
+/// Sets the minhashes of up to `MINHASH_BATCH_SIZE` clusters that have none.
+/// Returns the number of clusters updated.
+fn compute_minhash_batch(conn: &Conn) -> Result<usize, Error> {
+    let rows = diesel::sql_query(
+        "SELECT id, signature FROM cluster WHERE minhash IS NULL ORDER BY id LIMIT $1",
+    )
+    .bind::<Int8, _>(MINHASH_BATCH_SIZE)
+    .load::<Signature>(conn)?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    // A signature without tokens gets empty arrays, which match nothing.
+    let mut empty = Vec::new();
+    let mut ids = Vec::with_capacity(rows.len());
+    let mut minhashes = Vec::with_capacity(rows.len() * MINHASH_LEN);
+    let mut band_hashes = Vec::with_capacity(rows.len() * MINHASH_LEN / BAND_LEN);
+    for row in &rows {
+        let minhash = minhash(&row.signature);
+        if minhash.is_empty() {
+            empty.push(row.id);
+        } else {
+            ids.push(row.id);
+            band_hashes.extend(bands(&minhash));
+            minhashes.extend(minhash);
+        }
+    }
+    conn.transaction::<_, Error, _>(|| {
+        diesel::sql_query(format!(
+            "UPDATE cluster \
+             SET minhash = ($2::INT8[])[(computed.i - 1) * {0} + 1 : computed.i * {0}], \
+               minhash_bands = ($3::INT8[])[(computed.i - 1) * {1} + 1 : computed.i * {1}] \
+             FROM unnest($1::INT4[]) WITH ORDINALITY AS computed(id, i) \
+             WHERE cluster.id = computed.id",
+            MINHASH_LEN,
+            MINHASH_LEN / BAND_LEN
+        ))
+        .bind::<Array<Int4>, _>(&ids)
+        .bind::<Array<Int8>, _>(&minhashes)
+        .bind::<Array<Int8>, _>(&band_hashes)
+        .execute(conn)?;
+        diesel::sql_query(
+            "UPDATE cluster SET minhash = '{}', minhash_bands = '{}' WHERE id = ANY($1)",
+        )
+        .bind::<Array<Int4>, _>(&empty)
+        .execute(conn)?;
+        Ok(())
+    })?;
+    Ok(rows.len())
+}
+
+/// Returns the minhash of the tokens of `signature`, which are its words as
+/// in search, or an empty `Vec` if it has no tokens.
+fn minhash(signature: &str) -> Vec<i64> {
+    let tokens = words(signature)
+        .into_iter()
+        .map(|t| fnv1a(t.as_bytes()))
+        .collect::<HashSet<_>>();
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    let mut seed = FNV_OFFSET;
+    (0..MINHASH_LEN)
+        .map(|_| {
+            seed = mix(seed);
+            let min = tokens
+                .iter()
+                .map(|t| mix(t ^ seed))
+                .min()
+                .unwrap_or_default();
+            to_i64(min)
+        })
+        .collect()
+}
+
+/// Returns the hash of each band of `minhash`, which also depends on the
+/// position of the band.
+fn bands(minhash: &[i64]) -> Vec<i64> {
+    minhash
+        .chunks(BAND_LEN)
+        .enumerate()
+        .map(|(i, band)| {
+            let position = u64::try_from(i).unwrap_or_default();
+            to_i64(band.iter().fold(mix(FNV_OFFSET ^ position), |h, &v| {
+                mix(h ^ u64::try_from(v).unwrap_or_default())
+            }))
+        })
+        .collect()
+}
+
+/// Estimates the Jaccard similarity of two sets of tokens from their
+/// minhashes.
+fn minhash_similarity(a: &[i64], b: &[i64]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let same = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    same.to_f64().unwrap_or_default() / a.len().to_f64().unwrap_or(1.0)
+}
+
+/// Returns how similar the columns of two descriptions are, from 0 to 1. Two
+/// columns at the same index are similar if they have the same type and close
+/// ratios of unique values. A column present in only one description counts
+/// as dissimilar.
+fn description_similarity(a: &[ColumnStats], b: &[ColumnStats]) -> f64 {
+    let columns = a.len().max(b.len());
+    if columns == 0 {
+        return 0.0;
+    }
+    let b = b
+        .iter()
+        .map(|c| (c.column_index, c))
+        .collect::<HashMap<_, _>>();
+    let total = a
+        .iter()
+        .filter_map(|x| {
+            let y = b.get(&x.column_index)?;
+            if x.type_id == y.type_id {
+                Some(1.0 - (unique_ratio(x) - unique_ratio(y)).abs())
+            } else {
+                None
+            }
+        })
+        .sum::<f64>();
+    total / columns.to_f64().unwrap_or(1.0)
+}
+
+fn unique_ratio(column: &ColumnStats) -> f64 {
+    if column.count <= 0 {
+        return 0.0;
+    }
+    let ratio =
+        column.unique_count.to_f64().unwrap_or_default() / column.count.to_f64().unwrap_or(1.0);
+    ratio.min(1.0)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |h, &b| {
+        (h ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Drops the lowest bit of `hash` to store it as a non-negative `BIGINT`.
+fn to_i64(hash: u64) -> i64 {
+    i64::try_from(hash >> 1).unwrap_or_default()
+}
+
+/// The finalizer of splitmix64, which spreads the bits of `x`.
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a signature of the tokens `prefix0` to `prefix{n - 1}`.
+    fn tokens(prefix: &str, n: usize) -> String {
+        (0..n)
+            .map(|i| format!("{}{}", prefix, i))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn minhash_of_tokens() {
+        let a = minhash("GET /index.html HTTP/1.1");
+        assert_eq!(a.len(), MINHASH_LEN);
+        assert!(a.iter().all(|&h| h >= 0));
+        assert_eq!(a, minhash("get index html http 1 1"));
+        assert_eq!(a, minhash("HTTP/1.1 index.html GET"));
+        assert_ne!(a, minhash("POST /index.html HTTP/1.1"));
+        assert!(minhash("").is_empty());
+        assert!(minhash("/// - ...").is_empty());
+    }
+
+    #[test]
+    fn identical_signatures() {
+        let a = minhash("user admin logged in from 10.0.0.1");
+        let b = minhash("user admin logged in from 10.0.0.1");
+        assert!((minhash_similarity(&a, &b) - 1.0).abs() < f64::EPSILON);
+        assert_eq!(bands(&a), bands(&b));
+    }
+
+    #[test]
+    fn disjoint_signatures() {
+        let a = minhash(&tokens("a", 30));
+        let b = minhash(&tokens("b", 30));
+        assert!(minhash_similarity(&a, &b) < 0.05);
+        let a = bands(&a);
+        assert!(bands(&b).iter().all(|band| !a.contains(band)));
+    }
+
+    #[test]
+    fn overlapping_signatures() {
+        // 20 shared tokens of 40 in total: a Jaccard similarity of 0.5.
+        let shared = tokens("s", 20);
+        let a = minhash(&format!("{} {}", shared, tokens("a", 10)));
+        let b = minhash(&format!("{} {}", shared, tokens("b", 10)));
+        let similarity = minhash_similarity(&a, &b);
+        assert!(similarity > 0.3 && similarity < 0.7, "{}", similarity);
+    }
+
+    #[test]
+    fn similarity_of_incomparable_minhashes() {
+        let a = minhash("a b c");
+        assert!(minhash_similarity(&a, &a[..MINHASH_LEN - 1]).abs() < f64::EPSILON);
+        assert!(minhash_similarity(&[], &[]).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn bands_of_minhash() {
+        let minhash = minhash("a b c");
+        let bands = super::bands(&minhash);
+        assert_eq!(bands.len(), MINHASH_LEN / BAND_LEN);
+        assert!(bands.iter().all(|&b| b >= 0));
+
+        // A band depends on its position as well as its values.
+        let same = super::bands(&[7; MINHASH_LEN]);
+        assert_eq!(same.iter().collect::<HashSet<_>>().len(), same.len());
+
+        // Changing a value changes only the band it is in.
+        let mut changed = minhash.clone();
+        changed[BAND_LEN + 1] += 1;
+        let changed = super::bands(&changed);
+        for (i, (x, y)) in bands.iter().zip(&changed).enumerate() {
+            assert_eq!(x == y, i != 1, "band {}", i);
+        }
+    }
+}
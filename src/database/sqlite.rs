@@ -0,0 +1,571 @@
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use diesel::dsl::now;
+use diesel::prelude::*;
+use diesel::sql_types::{Binary, Integer, Nullable, Text};
+use diesel::sqlite::SqliteConnection;
+use std::convert::TryFrom;
+use std::ops::Bound;
+
+use crate::database::{
+    add_cluster_events, add_outlier_events, ClusterUpdate, Error, Event, KafkaMetadata,
+    OutlierUpdate, Storage,
+};
+
+embed_migrations!("migrations_sqlite");
+
+mod schema {
+    table! {
+        cluster (id) {
+            id -> Integer,
+            cluster_id -> Nullable<Text>,
+            category_id -> Integer,
+            detector_id -> Integer,
+            event_ids -> Nullable<Text>,
+            qualifier_id -> Integer,
+            status_id -> Integer,
+            signature -> Text,
+            size -> BigInt,
+            score -> Nullable<Double>,
+            data_source_id -> Integer,
+            last_modification_time -> Nullable<Timestamp>,
+        }
+    }
+
+    table! {
+        data_source (id) {
+            id -> Integer,
+            topic_name -> Text,
+            data_type -> Text,
+        }
+    }
+
+    table! {
+        event (id) {
+            id -> Integer,
+            message_id -> Text,
+            data_source_id -> Integer,
+            raw_event -> Nullable<Binary>,
+            compressed -> Bool,
+            dictionary_id -> Nullable<Integer>,
+        }
+    }
+
+    table! {
+        kafka_metadata (id) {
+            id -> Integer,
+            data_source_id -> Integer,
+            partition -> Integer,
+            offsets -> BigInt,
+            lower_message_id -> Nullable<Text>,
+            upper_message_id -> Nullable<Text>,
+        }
+    }
+
+    table! {
+        outlier (id) {
+            id -> Integer,
+            raw_event -> Binary,
+            data_source_id -> Integer,
+            event_ids -> Text,
+            size -> BigInt,
+            compressed -> Bool,
+            dictionary_id -> Nullable<Integer>,
+        }
+    }
+
+    table! {
+        qualifier (id) {
+            id -> Integer,
+            description -> Text,
+        }
+    }
+}
+
+use self::schema::{cluster, data_source, event, kafka_metadata, outlier, qualifier};
+
+/// The number of message ids deleted at a time, which keeps the bind
+/// parameters of a statement below the default limit of 999.
+const DELETE_BATCH_SIZE: usize = 500;
+
+/// Inserts an event, or replaces the raw event of an existing one as the
+/// `add_events` of the server does. Raw events are not compressed.
+const UPSERT_EVENT: &str = "INSERT INTO event (message_id, data_source_id, raw_event) \
+                            VALUES (?, ?, ?) \
+                            ON CONFLICT (message_id, data_source_id) DO UPDATE SET \
+                            raw_event = excluded.raw_event, \
+                            compressed = 0, \
+                            dictionary_id = NULL";
+
+/// Opens a database file, or an in-memory database for `:memory:`, and
+/// applies the migrations in `migrations_sqlite`.
+///
+/// # Errors
+///
+/// Returns an error if the database cannot be opened or migrated.
+#[allow(dead_code)] // The server runs on PostgreSQL only.
+pub(crate) fn establish_sqlite(database_url: &str) -> Result<SqliteConnection, Error> {
+    let conn = SqliteConnection::establish(database_url)?;
+    embedded_migrations::run(&conn)?;
+    Ok(conn)
+}
+
+impl Storage for SqliteConnection {
+    fn data_source_id(&self, data_source: &str) -> Result<i32, Error> {
+        use data_source::dsl;
+        dsl::data_source
+            .select(dsl::id)
+            .filter(dsl::topic_name.eq(data_source))
+            .first::<i32>(self)
+            .map_err(Into::into)
+    }
+
+    fn upsert_clusters(
+        &self,
+        max_event_id_num: usize,
+        clusters: &[&ClusterUpdate],
+    ) -> Result<(), Error> {
+        self.transaction::<(), Error, _>(|| {
+            for c in clusters {
+                upsert_cluster(self, max_event_id_num, c)?;
+            }
+            add_cluster_events(self, clusters)
+        })
+    }
+
+    fn upsert_outliers(
+        &self,
+        max_event_id_num: usize,
+        outliers: &[&OutlierUpdate],
+    ) -> Result<(), Error> {
+        self.transaction::<(), Error, _>(|| {
+            for o in outliers {
+                upsert_outlier(self, max_event_id_num, o)?;
+            }
+            add_outlier_events(self, outliers)
+        })
+    }
+
+    fn add_events(&self, events: &[&Event]) -> Result<usize, Error> {
+        self.transaction::<usize, Error, _>(|| {
+            events.iter().try_fold(0, |added, event| {
+                diesel::sql_query(UPSERT_EVENT)
+                    .bind::<Text, _>(message_id_key(&event.message_id))
+                    .bind::<Integer, _>(event.data_source_id)
+                    .bind::<Nullable<Binary>, _>(event.raw_event.as_deref())
+                    .execute(self)
+                    .map(|n| added + n)
+                    .map_err(Into::into)
+            })
+        })
+    }
+
+    fn delete_events(&self, events: &[Event]) -> Result<usize, Error> {
+        use event::dsl;
+        self.transaction::<usize, Error, _>(|| {
+            events.iter().try_fold(0, |deleted, event| {
+                diesel::delete(
+                    dsl::event.filter(
+                        dsl::data_source_id
+                            .eq(event.data_source_id)
+                            .and(dsl::message_id.eq(message_id_key(&event.message_id))),
+                    ),
+                )
+                .execute(self)
+                .map(|n| deleted + n)
+                .map_err(Into::into)
+            })
+        })
+    }
+
+    fn update_qualifier(
+        &self,
+        cluster_id: &str,
+        data_source: &str,
+        qualifier: &str,
+    ) -> Result<(), Error> {
+        use cluster::dsl as c_d;
+        use data_source::dsl as d_d;
+        use qualifier::dsl as q_d;
+        let qualifier_id = q_d::qualifier
+            .select(q_d::id)
+            .filter(q_d::description.eq(qualifier))
+            .first::<i32>(self)
+            .optional()?
+            .ok_or(Error::NotFound("qualifier"))?;
+        let data_source_id = d_d::data_source
+            .select(d_d::id)
+            .filter(d_d::topic_name.eq(data_source))
+            .first::<i32>(self)
+            .optional()?
+            .ok_or(Error::NotFound("cluster"))?;
+        let updated = diesel::update(
+            c_d::cluster.filter(
+                c_d::cluster_id
+                    .eq(cluster_id)
+                    .and(c_d::data_source_id.eq(data_source_id)),
+            ),
+        )
+        .set((
+            c_d::qualifier_id.eq(qualifier_id),
+            c_d::last_modification_time.eq(now.nullable()),
+        ))
+        .execute(self)?;
+        if updated == 0 {
+            return Err(Error::NotFound("cluster"));
+        }
+        Ok(())
+    }
+
+    fn cap_event_ids(&self, max_event_id_num: usize) -> Result<(), Error> {
+        use cluster::dsl as c_d;
+        use outlier::dsl as o_d;
+        self.transaction::<(), Error, _>(|| {
+            let clusters = c_d::cluster
+                .select((c_d::id, c_d::data_source_id, c_d::event_ids))
+                .filter(c_d::event_ids.is_not_null())
+                .load::<(i32, i32, Option<String>)>(self)?;
+            for (id, data_source_id, event_ids) in clusters {
+                let event_ids = decode_event_ids(event_ids.as_deref().unwrap_or("[]"))?;
+                if event_ids.len() > max_event_id_num {
+                    let (kept, expired) = split_event_ids(&event_ids, max_event_id_num);
+                    diesel::update(c_d::cluster.find(id))
+                        .set(c_d::event_ids.eq(encode_event_ids(&kept)?))
+                        .execute(self)?;
+                    delete_message_ids(self, data_source_id, &expired)?;
+                }
+            }
+
+            let outliers = o_d::outlier
+                .select((o_d::id, o_d::data_source_id, o_d::event_ids))
+                .load::<(i32, i32, String)>(self)?;
+            for (id, data_source_id, event_ids) in outliers {
+                let event_ids = decode_event_ids(&event_ids)?;
+                if event_ids.len() > max_event_id_num {
+                    let (kept, expired) = split_event_ids(&event_ids, max_event_id_num);
+                    diesel::update(o_d::outlier.find(id))
+                        .set(o_d::event_ids.eq(encode_event_ids(&kept)?))
+                        .execute(self)?;
+                    delete_message_ids(self, data_source_id, &expired)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn add_kafka_metadata(&self, metadata: &[KafkaMetadata]) -> Result<usize, Error> {
+        use kafka_metadata::dsl;
+        self.transaction::<usize, Error, _>(|| {
+            metadata.iter().try_fold(0, |added, m| {
+                diesel::insert_or_ignore_into(dsl::kafka_metadata)
+                    .values((
+                        dsl::data_source_id.eq(m.data_source_id),
+                        dsl::partition.eq(m.partition),
+                        dsl::offsets.eq(m.offsets),
+                        dsl::lower_message_id.eq(bound_key(&m.message_ids.0)),
+                        dsl::upper_message_id.eq(bound_key(&m.message_ids.1)),
+                    ))
+                    .execute(self)
+                    .map(|n| added + n)
+                    .map_err(Into::into)
+            })
+        })
+    }
+
+    fn kafka_metadata_lookup(
+        &self,
+        data_source_id: i32,
+        message_id: &BigDecimal,
+    ) -> Option<(u64, u64, u64)> {
+        use kafka_metadata::dsl;
+        let key = message_id_key(message_id);
+        let (upper, partition, offsets) = dsl::kafka_metadata
+            .select((dsl::upper_message_id, dsl::partition, dsl::offsets))
+            .filter(
+                dsl::data_source_id
+                    .eq(data_source_id)
+                    .and(dsl::lower_message_id.le(&key))
+                    .and(dsl::upper_message_id.ge(&key)),
+            )
+            .first::<(Option<String>, i32, i64)>(self)
+            .ok()?;
+        Some((
+            upper?.parse::<u64>().ok()?,
+            u64::try_from(partition).ok()?,
+            u64::try_from(offsets).ok()?,
+        ))
+    }
+}
+
+/// Upserts a cluster with the rules of `merge_staged_clusters`.
+fn upsert_cluster(
+    conn: &SqliteConnection,
+    max_event_id_num: usize,
+    c: &ClusterUpdate,
+) -> Result<(), Error> {
+    use cluster::dsl;
+    let data_source_id = insert_data_source(conn, &c.data_source, c.data_source_type.as_str())?;
+    let size = c.size.unwrap_or(1);
+    let current = dsl::cluster
+        .select((dsl::id, dsl::signature, dsl::event_ids, dsl::size))
+        .filter(
+            dsl::cluster_id
+                .eq(&c.cluster_id)
+                .and(dsl::data_source_id.eq(data_source_id)),
+        )
+        .first::<(i32, String, Option<String>, i64)>(conn)
+        .optional()?;
+    let (id, signature, current_event_ids, current_size) = match current {
+        Some(current) => current,
+        None => {
+            let event_ids = match &c.event_ids {
+                Some(event_ids) => Some(encode_event_ids(event_ids)?),
+                None => None,
+            };
+            diesel::insert_into(dsl::cluster)
+                .values((
+                    dsl::cluster_id.eq(&c.cluster_id),
+                    dsl::detector_id.eq(c.detector_id),
+                    dsl::event_ids.eq(event_ids),
+                    dsl::signature.eq(c.signature.as_deref().unwrap_or("-")),
+                    dsl::size.eq(i64::try_from(size).unwrap_or(i64::MAX)),
+                    dsl::score.eq(c.score),
+                    dsl::data_source_id.eq(data_source_id),
+                    dsl::last_modification_time.eq(None::<NaiveDateTime>),
+                ))
+                .execute(conn)?;
+            return Ok(());
+        }
+    };
+    let event_ids = match &c.event_ids {
+        Some(event_ids) => event_ids,
+        None => return Ok(()),
+    };
+
+    let (new_event_ids, removed_event_ids) = match current_event_ids {
+        None => (event_ids.clone(), Vec::new()),
+        Some(current_event_ids) => {
+            let current_event_ids = decode_event_ids(&current_event_ids)?;
+            if size >= max_event_id_num {
+                (event_ids.clone(), current_event_ids)
+            } else {
+                let mut merged = event_ids.clone();
+                merged.extend(current_event_ids);
+                split_event_ids(&merged, max_event_id_num)
+            }
+        }
+    };
+    delete_message_ids(conn, data_source_id, &removed_event_ids)?;
+    diesel::update(dsl::cluster.find(id))
+        .set((
+            dsl::signature.eq(c.signature.as_deref().unwrap_or(&signature)),
+            dsl::event_ids.eq(encode_event_ids(&new_event_ids)?),
+            dsl::size.eq(current_size.saturating_add(i64::try_from(size).unwrap_or(i64::MAX))),
+            dsl::last_modification_time.eq(now.nullable()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Inserts or updates an outlier with the rules of `merge_staged_outliers`.
+fn upsert_outlier(
+    conn: &SqliteConnection,
+    max_event_id_num: usize,
+    o: &OutlierUpdate,
+) -> Result<(), Error> {
+    use outlier::dsl;
+    let data_source_id = insert_data_source(conn, &o.data_source, o.data_source_type.as_str())?;
+    let size = i64::try_from(o.size).unwrap_or(i64::MAX);
+    let expired = if o.id == 0 {
+        let (kept, expired) = split_event_ids(&o.event_ids, max_event_id_num);
+        diesel::insert_into(dsl::outlier)
+            .values((
+                dsl::raw_event.eq(&o.outlier),
+                dsl::data_source_id.eq(data_source_id),
+                dsl::event_ids.eq(encode_event_ids(&kept)?),
+                dsl::size.eq(size),
+            ))
+            .execute(conn)?;
+        expired
+    } else {
+        let current_event_ids = match dsl::outlier
+            .select(dsl::event_ids)
+            .find(o.id)
+            .first::<String>(conn)
+            .optional()?
+        {
+            Some(current_event_ids) => decode_event_ids(&current_event_ids)?,
+            None => return Ok(()),
+        };
+        let mut merged = o.event_ids.clone();
+        merged.extend(current_event_ids);
+        let (kept, expired) = split_event_ids(&merged, max_event_id_num);
+        diesel::update(dsl::outlier.find(o.id))
+            .set((
+                dsl::event_ids.eq(encode_event_ids(&kept)?),
+                dsl::size.eq(size),
+            ))
+            .execute(conn)?;
+        expired
+    };
+    delete_message_ids(conn, data_source_id, &expired)
+}
+
+/// Returns the id of a data source, inserting it if it does not exist.
+fn insert_data_source(
+    conn: &SqliteConnection,
+    topic_name: &str,
+    data_type: &str,
+) -> Result<i32, Error> {
+    use data_source::dsl;
+    diesel::insert_or_ignore_into(dsl::data_source)
+        .values((dsl::topic_name.eq(topic_name), dsl::data_type.eq(data_type)))
+        .execute(conn)?;
+    conn.data_source_id(topic_name)
+}
+
+fn delete_message_ids(
+    conn: &SqliteConnection,
+    data_source_id: i32,
+    message_ids: &[u64],
+) -> Result<(), Error> {
+    use event::dsl;
+    let keys = message_ids
+        .iter()
+        .map(|message_id| format!("{:020}", message_id))
+        .collect::<Vec<_>>();
+    for batch in keys.chunks(DELETE_BATCH_SIZE) {
+        diesel::delete(
+            dsl::event.filter(
+                dsl::data_source_id
+                    .eq(data_source_id)
+                    .and(dsl::message_id.eq_any(batch)),
+            ),
+        )
+        .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Splits event ids as the `cap_event_ids` procedure does: the ids among the
+/// largest `max_event_id_num` ones, in their original order, and the rest,
+/// sorted and deduplicated. Duplicates of an id are kept or expired together.
+fn split_event_ids(event_ids: &[u64], max_event_id_num: usize) -> (Vec<u64>, Vec<u64>) {
+    let mut sorted = event_ids.to_vec();
+    sorted.sort_unstable_by(|a, b| b.cmp(a));
+    let boundary = match sorted.get(max_event_id_num) {
+        Some(boundary) => *boundary,
+        None => return (event_ids.to_vec(), Vec::new()),
+    };
+    let kept = event_ids
+        .iter()
+        .filter(|&&id| id > boundary)
+        .copied()
+        .collect();
+    let mut expired = sorted
+        .into_iter()
+        .rev()
+        .filter(|&id| id <= boundary)
+        .collect::<Vec<_>>();
+    expired.dedup();
+    (kept, expired)
+}
+
+fn encode_event_ids(event_ids: &[u64]) -> Result<String, Error> {
+    serde_json::to_string(event_ids).map_err(Into::into)
+}
+
+fn decode_event_ids(event_ids: &str) -> Result<Vec<u64>, Error> {
+    serde_json::from_str(event_ids).map_err(Into::into)
+}
+
+/// Formats a message id with 20 digits so that message ids compare in numeric
+/// order as text.
+fn message_id_key(message_id: &BigDecimal) -> String {
+    format!("{:0>20}", message_id.with_scale(0))
+}
+
+fn bound_key(bound: &Bound<BigDecimal>) -> Option<String> {
+    match bound {
+        Bound::Included(message_id) | Bound::Excluded(message_id) => {
+            Some(message_id_key(message_id))
+        }
+        Bound::Unbounded => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+    use diesel::sqlite::SqliteConnection;
+    use std::convert::TryFrom;
+
+    use super::schema::{cluster, event, outlier};
+    use super::{decode_event_ids, Storage};
+    use crate::database::storage::tests::Inspect;
+
+    impl Inspect for SqliteConnection {
+        fn cluster(&self, cluster_id: &str, data_source: &str) -> Option<(Vec<u64>, u64)> {
+            use cluster::dsl;
+            let data_source_id = self.data_source_id(data_source).ok()?;
+            let (event_ids, size) = dsl::cluster
+                .select((dsl::event_ids, dsl::size))
+                .filter(
+                    dsl::cluster_id
+                        .eq(cluster_id)
+                        .and(dsl::data_source_id.eq(data_source_id)),
+                )
+                .first::<(Option<String>, i64)>(self)
+                .optional()
+                .expect("cannot read a cluster")?;
+            let event_ids = decode_event_ids(event_ids.as_deref().unwrap_or("[]")).ok()?;
+            Some((sorted(event_ids), u64::try_from(size).ok()?))
+        }
+
+        fn outliers(&self, data_source: &str) -> Vec<(i32, Vec<u64>, u64)> {
+            use outlier::dsl;
+            let data_source_id = match self.data_source_id(data_source) {
+                Ok(id) => id,
+                Err(_) => return Vec::new(),
+            };
+            dsl::outlier
+                .select((dsl::id, dsl::event_ids, dsl::size))
+                .filter(dsl::data_source_id.eq(data_source_id))
+                .order_by(dsl::id)
+                .load::<(i32, String, i64)>(self)
+                .expect("cannot read outliers")
+                .into_iter()
+                .map(|(id, event_ids, size)| {
+                    let event_ids = decode_event_ids(&event_ids).expect("invalid event ids");
+                    (
+                        id,
+                        sorted(event_ids),
+                        u64::try_from(size).unwrap_or_default(),
+                    )
+                })
+                .collect()
+        }
+
+        fn events(&self, data_source: &str) -> Vec<u64> {
+            use event::dsl;
+            let data_source_id = match self.data_source_id(data_source) {
+                Ok(id) => id,
+                Err(_) => return Vec::new(),
+            };
+            let message_ids = dsl::event
+                .select(dsl::message_id)
+                .filter(dsl::data_source_id.eq(data_source_id))
+                .load::<String>(self)
+                .expect("cannot read events")
+                .iter()
+                .filter_map(|message_id| message_id.parse().ok())
+                .collect();
+            sorted(message_ids)
+        }
+    }
+
+    fn sorted(mut ids: Vec<u64>) -> Vec<u64> {
+        ids.sort_unstable();
+        ids
+    }
+}
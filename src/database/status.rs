@@ -3,7 +3,7 @@ use diesel::prelude::*;
 use serde::Serialize;
 
 use super::schema::status;
-use crate::database::{build_http_500_response, Error, Pool};
+use crate::database::{build_error_response, Database, Error};
 
 #[derive(Debug, Identifiable, Queryable, Serialize)]
 #[table_name = "status"]
@@ -12,18 +12,21 @@ struct StatusTable {
     description: String,
 }
 
-pub(crate) async fn get_status_table(pool: Data<Pool>) -> Result<HttpResponse, actix_web::Error> {
-    let query_result: Result<Vec<StatusTable>, Error> =
-        pool.get().map_err(Into::into).and_then(|conn| {
+pub(crate) async fn get_status_table(
+    pool: Data<Database>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let query_result: Result<Vec<StatusTable>, Error> = pool
+        .run(move |conn| {
             status::dsl::status
-                .load::<StatusTable>(&conn)
+                .load::<StatusTable>(conn)
                 .map_err(Into::into)
-        });
+        })
+        .await;
 
     match query_result {
         Ok(status_table) => Ok(HttpResponse::Ok()
             .header(http::header::CONTENT_TYPE, "application/json")
             .json(status_table)),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
@@ -0,0 +1,483 @@
+use bigdecimal::{BigDecimal, FromPrimitive};
+use diesel::prelude::*;
+use diesel::sql_types::Numeric;
+
+use crate::database::{
+    add_cluster_events, add_events, add_outlier_events, delete_events, get_data_source_id,
+    insert_kafka_metadata, kafka_metadata_lookup, update_qualifier, upsert_clusters,
+    upsert_outliers, ClusterUpdate, Conn, Error, Event, KafkaMetadata, OutlierUpdate,
+};
+
+/// The operations of the handlers whose rules are implemented by the database
+/// backend.
+pub(crate) trait Storage {
+    /// Returns the id of a data source.
+    fn data_source_id(&self, data_source: &str) -> Result<i32, Error>;
+
+    /// Inserts or updates clusters and adds their events. A cluster keeps at
+    /// most `max_event_id_num` of the largest event ids, and the events of the
+    /// other ids are deleted.
+    fn upsert_clusters(
+        &self,
+        max_event_id_num: usize,
+        clusters: &[&ClusterUpdate],
+    ) -> Result<(), Error>;
+
+    /// Inserts the outliers whose id is 0, updates the existing ones, and adds
+    /// their events, with the same event id limit as `upsert_clusters`.
+    fn upsert_outliers(
+        &self,
+        max_event_id_num: usize,
+        outliers: &[&OutlierUpdate],
+    ) -> Result<(), Error>;
+
+    /// Adds events, replacing the raw events of the existing ones. Returns the
+    /// number of events added or replaced.
+    fn add_events(&self, events: &[&Event]) -> Result<usize, Error>;
+
+    /// Deletes events. Returns the number of events deleted.
+    fn delete_events(&self, events: &[Event]) -> Result<usize, Error>;
+
+    /// Sets the qualifier of a cluster, which marks it as reviewed.
+    fn update_qualifier(
+        &self,
+        cluster_id: &str,
+        data_source: &str,
+        qualifier: &str,
+    ) -> Result<(), Error>;
+
+    /// Removes the smallest event ids of the clusters and outliers with more
+    /// than `max_event_id_num` of them, and deletes their events.
+    fn cap_event_ids(&self, max_event_id_num: usize) -> Result<(), Error>;
+
+    /// Adds Kafka metadata, ignoring the messages already known. Returns the
+    /// number of rows added.
+    fn add_kafka_metadata(&self, metadata: &[KafkaMetadata]) -> Result<usize, Error>;
+
+    /// Returns the last message id, partition, and offset of the Kafka message
+    /// that contains `message_id`.
+    fn kafka_metadata_lookup(
+        &self,
+        data_source_id: i32,
+        message_id: &BigDecimal,
+    ) -> Option<(u64, u64, u64)>;
+}
+
+impl Storage for Conn {
+    fn data_source_id(&self, data_source: &str) -> Result<i32, Error> {
+        get_data_source_id(self, data_source)
+    }
+
+    fn upsert_clusters(
+        &self,
+        max_event_id_num: usize,
+        clusters: &[&ClusterUpdate],
+    ) -> Result<(), Error> {
+        let max_event_id_num = BigDecimal::from_usize(max_event_id_num).unwrap_or_default();
+        upsert_clusters(self, &max_event_id_num, clusters)?;
+        add_cluster_events(self, clusters)
+    }
+
+    fn upsert_outliers(
+        &self,
+        max_event_id_num: usize,
+        outliers: &[&OutlierUpdate],
+    ) -> Result<(), Error> {
+        let max_event_id_num = BigDecimal::from_usize(max_event_id_num).unwrap_or_default();
+        upsert_outliers(self, &max_event_id_num, outliers)?;
+        add_outlier_events(self, outliers)
+    }
+
+    fn add_events(&self, events: &[&Event]) -> Result<usize, Error> {
+        add_events(self, events.iter().copied())
+    }
+
+    fn delete_events(&self, events: &[Event]) -> Result<usize, Error> {
+        delete_events(self, events)
+    }
+
+    fn update_qualifier(
+        &self,
+        cluster_id: &str,
+        data_source: &str,
+        qualifier: &str,
+    ) -> Result<(), Error> {
+        update_qualifier(self, cluster_id, data_source, qualifier)
+    }
+
+    fn cap_event_ids(&self, max_event_id_num: usize) -> Result<(), Error> {
+        let max_event_id_num = BigDecimal::from_usize(max_event_id_num).unwrap_or_default();
+        // `sql_function!` cannot declare a function returning `void` when diesel
+        // is built with the `sqlite` feature.
+        diesel::sql_query("SELECT attempt_event_ids_update($1)")
+            .bind::<Numeric, _>(max_event_id_num)
+            .execute(self)?;
+        Ok(())
+    }
+
+    fn add_kafka_metadata(&self, metadata: &[KafkaMetadata]) -> Result<usize, Error> {
+        insert_kafka_metadata(self, metadata)
+    }
+
+    fn kafka_metadata_lookup(
+        &self,
+        data_source_id: i32,
+        message_id: &BigDecimal,
+    ) -> Option<(u64, u64, u64)> {
+        kafka_metadata_lookup(self, data_source_id, message_id)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use bigdecimal::BigDecimal;
+    use diesel::prelude::*;
+    use num_traits::ToPrimitive;
+    use std::ops::Bound;
+
+    use super::Storage;
+    use crate::database::schema::{cluster, event, outlier};
+    use crate::database::{
+        build_events, ClusterUpdate, Conn, DataType, Error, KafkaMetadata, OutlierUpdate,
+    };
+
+    const DATA_SOURCE: &str = "storage-test";
+
+    /// Reads back what `Storage` writes, so that one suite checks every backend.
+    pub(crate) trait Inspect: Storage {
+        /// Returns the sorted event ids and the size of a cluster.
+        fn cluster(&self, cluster_id: &str, data_source: &str) -> Option<(Vec<u64>, u64)>;
+
+        /// Returns the id, the sorted event ids, and the size of the outliers of
+        /// a data source, ordered by id.
+        fn outliers(&self, data_source: &str) -> Vec<(i32, Vec<u64>, u64)>;
+
+        /// Returns the sorted message ids of the events of a data source.
+        fn events(&self, data_source: &str) -> Vec<u64>;
+    }
+
+    impl Inspect for Conn {
+        fn cluster(&self, cluster_id: &str, data_source: &str) -> Option<(Vec<u64>, u64)> {
+            use cluster::dsl;
+            let data_source_id = self.data_source_id(data_source).ok()?;
+            let (event_ids, size) = dsl::cluster
+                .select((dsl::event_ids, dsl::size))
+                .filter(
+                    dsl::cluster_id
+                        .eq(cluster_id)
+                        .and(dsl::data_source_id.eq(data_source_id)),
+                )
+                .first::<(Option<Vec<BigDecimal>>, BigDecimal)>(self)
+                .optional()
+                .expect("cannot read a cluster")?;
+            Some((sorted(&event_ids.unwrap_or_default()), size.to_u64()?))
+        }
+
+        fn outliers(&self, data_source: &str) -> Vec<(i32, Vec<u64>, u64)> {
+            use outlier::dsl;
+            let data_source_id = match self.data_source_id(data_source) {
+                Ok(id) => id,
+                Err(_) => return Vec::new(),
+            };
+            dsl::outlier
+                .select((dsl::id, dsl::event_ids, dsl::size))
+                .filter(dsl::data_source_id.eq(data_source_id))
+                .order_by(dsl::id)
+                .load::<(i32, Vec<BigDecimal>, BigDecimal)>(self)
+                .expect("cannot read outliers")
+                .into_iter()
+                .map(|(id, event_ids, size)| {
+                    (id, sorted(&event_ids), size.to_u64().unwrap_or_default())
+                })
+                .collect()
+        }
+
+        fn events(&self, data_source: &str) -> Vec<u64> {
+            use event::dsl;
+            let data_source_id = match self.data_source_id(data_source) {
+                Ok(id) => id,
+                Err(_) => return Vec::new(),
+            };
+            let message_ids = dsl::event
+                .select(dsl::message_id)
+                .filter(dsl::data_source_id.eq(data_source_id))
+                .load::<BigDecimal>(self)
+                .expect("cannot read events");
+            sorted(&message_ids)
+        }
+    }
+
+    fn sorted(ids: &[BigDecimal]) -> Vec<u64> {
+        let mut ids = ids
+            .iter()
+            .filter_map(ToPrimitive::to_u64)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn cluster_update(
+        cluster_id: &str,
+        size: Option<usize>,
+        event_ids: Option<Vec<u64>>,
+    ) -> ClusterUpdate {
+        ClusterUpdate {
+            cluster_id: cluster_id.to_string(),
+            detector_id: 1,
+            signature: Some(format!("signature of {}", cluster_id)),
+            score: Some(0.5),
+            data_source: DATA_SOURCE.to_string(),
+            data_source_type: DataType::Log,
+            size,
+            event_ids,
+        }
+    }
+
+    fn outlier_update(id: i32, size: usize, event_ids: Vec<u64>) -> OutlierUpdate {
+        OutlierUpdate {
+            id,
+            outlier: b"outlier".to_vec(),
+            data_source: DATA_SOURCE.to_string(),
+            data_source_type: DataType::Log,
+            event_ids,
+            size,
+        }
+    }
+
+    /// Runs the suite on the storage `$storage` returns, with `$attr` on each
+    /// test.
+    macro_rules! storage_tests {
+        ($storage:expr $(, #[$attr:meta])*) => {
+            #[test]
+            $(#[$attr])*
+            fn new_cluster() {
+                super::new_cluster(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn cluster_update_merges_event_ids() {
+                super::cluster_update_merges_event_ids(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn cluster_update_at_limit_replaces_event_ids() {
+                super::cluster_update_at_limit_replaces_event_ids(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn cluster_update_without_event_ids() {
+                super::cluster_update_without_event_ids(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn outlier_insert_and_update() {
+                super::outlier_insert_and_update(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn cap_event_ids() {
+                super::cap_event_ids(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn events() {
+                super::events(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn qualifier() {
+                super::qualifier(&$storage);
+            }
+
+            #[test]
+            $(#[$attr])*
+            fn kafka_metadata() {
+                super::kafka_metadata(&$storage);
+            }
+        };
+    }
+
+    mod postgres {
+        use crate::database::test_conn;
+
+        storage_tests!(test_conn(), #[ignore = "needs a database at DATABASE_URL"]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    mod sqlite {
+        use crate::database::sqlite::establish_sqlite;
+
+        storage_tests!(establish_sqlite(":memory:").expect("cannot open an SQLite database"));
+    }
+
+    /// A new cluster keeps all its event ids, and its data source is added.
+    fn new_cluster<S: Inspect>(storage: &S) {
+        let c1 = cluster_update("c1", Some(3), Some(vec![3, 1, 2]));
+        let c2 = cluster_update("c2", None, None);
+        storage.upsert_clusters(2, &[&c1, &c2]).unwrap();
+
+        assert!(storage.data_source_id(DATA_SOURCE).is_ok());
+        assert_eq!(storage.cluster("c1", DATA_SOURCE), Some((vec![1, 2, 3], 3)));
+        assert_eq!(storage.cluster("c2", DATA_SOURCE), Some((vec![], 1)));
+        assert_eq!(storage.events(DATA_SOURCE), vec![1, 2, 3]);
+    }
+
+    /// An update keeps the largest event ids, deletes the events of the others,
+    /// and adds to the size.
+    fn cluster_update_merges_event_ids<S: Inspect>(storage: &S) {
+        let insert = cluster_update("c1", Some(3), Some(vec![1, 2, 3]));
+        storage.upsert_clusters(4, &[&insert]).unwrap();
+        let update = cluster_update("c1", Some(2), Some(vec![5, 4]));
+        storage.upsert_clusters(4, &[&update]).unwrap();
+
+        assert_eq!(
+            storage.cluster("c1", DATA_SOURCE),
+            Some((vec![2, 3, 4, 5], 5))
+        );
+        assert_eq!(storage.events(DATA_SOURCE), vec![2, 3, 4, 5]);
+    }
+
+    /// An update whose size reaches the limit replaces all the event ids.
+    fn cluster_update_at_limit_replaces_event_ids<S: Inspect>(storage: &S) {
+        let insert = cluster_update("c1", Some(3), Some(vec![1, 2, 3]));
+        storage.upsert_clusters(4, &[&insert]).unwrap();
+        let update = cluster_update("c1", Some(4), Some(vec![7, 8]));
+        storage.upsert_clusters(4, &[&update]).unwrap();
+
+        assert_eq!(storage.cluster("c1", DATA_SOURCE), Some((vec![7, 8], 7)));
+        assert_eq!(storage.events(DATA_SOURCE), vec![7, 8]);
+    }
+
+    /// An update without event ids leaves the cluster as it is.
+    fn cluster_update_without_event_ids<S: Inspect>(storage: &S) {
+        let insert = cluster_update("c1", Some(1), Some(vec![1]));
+        storage.upsert_clusters(4, &[&insert]).unwrap();
+        let update = cluster_update("c1", Some(5), None);
+        storage.upsert_clusters(4, &[&update]).unwrap();
+
+        assert_eq!(storage.cluster("c1", DATA_SOURCE), Some((vec![1], 1)));
+    }
+
+    /// Outliers with id 0 are inserted with capped event ids, the existing ones
+    /// are merged, and unknown ids are skipped.
+    fn outlier_insert_and_update<S: Inspect>(storage: &S) {
+        let insert = outlier_update(0, 4, vec![1, 2, 3, 4]);
+        storage.upsert_outliers(3, &[&insert]).unwrap();
+        let outliers = storage.outliers(DATA_SOURCE);
+        assert_eq!(outliers.len(), 1);
+        let (id, event_ids, size) = outliers[0].clone();
+        assert_eq!((event_ids, size), (vec![2, 3, 4], 4));
+
+        let update = outlier_update(id, 9, vec![5]);
+        let unknown = outlier_update(id + 1, 1, vec![6]);
+        storage.upsert_outliers(3, &[&update, &unknown]).unwrap();
+        assert_eq!(storage.outliers(DATA_SOURCE), vec![(id, vec![3, 4, 5], 9)]);
+        assert!(!storage.events(DATA_SOURCE).contains(&2));
+    }
+
+    /// Capping trims the clusters and outliers over the limit, and duplicates
+    /// of an event id expire together.
+    fn cap_event_ids<S: Inspect>(storage: &S) {
+        let c1 = cluster_update("c1", Some(4), Some(vec![3, 1, 1, 2]));
+        let c2 = cluster_update("c2", Some(2), Some(vec![8, 9]));
+        storage.upsert_clusters(10, &[&c1, &c2]).unwrap();
+        let o1 = outlier_update(0, 4, vec![4, 5, 6, 7]);
+        storage.upsert_outliers(10, &[&o1]).unwrap();
+
+        storage.cap_event_ids(3).unwrap();
+        assert_eq!(storage.cluster("c1", DATA_SOURCE), Some((vec![2, 3], 4)));
+        assert_eq!(storage.cluster("c2", DATA_SOURCE), Some((vec![8, 9], 2)));
+        let outliers = storage.outliers(DATA_SOURCE);
+        assert_eq!(outliers.len(), 1);
+        assert_eq!(outliers[0].1, vec![5, 6, 7]);
+        assert_eq!(storage.events(DATA_SOURCE), vec![2, 3, 5, 6, 7, 8, 9]);
+    }
+
+    /// Adding an event again replaces it, and deleting counts the events
+    /// deleted.
+    fn events<S: Inspect>(storage: &S) {
+        let c1 = cluster_update("c1", Some(1), Some(vec![1]));
+        storage.upsert_clusters(4, &[&c1]).unwrap();
+        let data_source_id = storage.data_source_id(DATA_SOURCE).unwrap();
+
+        let mut events = build_events(&[1, 2], data_source_id);
+        for event in &mut events {
+            event.raw_event = Some(b"raw event".to_vec());
+        }
+        assert_eq!(
+            storage
+                .add_events(&events.iter().collect::<Vec<_>>())
+                .unwrap(),
+            2
+        );
+        assert_eq!(storage.events(DATA_SOURCE), vec![1, 2]);
+
+        let unknown = build_events(&[3], data_source_id);
+        assert_eq!(storage.delete_events(&events[..1]).unwrap(), 1);
+        assert_eq!(storage.delete_events(&unknown).unwrap(), 0);
+        assert_eq!(storage.events(DATA_SOURCE), vec![2]);
+    }
+
+    /// The qualifier of a cluster can be set, and an unknown cluster or
+    /// qualifier is not found.
+    fn qualifier<S: Inspect>(storage: &S) {
+        let c1 = cluster_update("c1", Some(1), Some(vec![1]));
+        storage.upsert_clusters(4, &[&c1]).unwrap();
+
+        assert!(storage
+            .update_qualifier("c1", DATA_SOURCE, "benign")
+            .is_ok());
+        assert!(matches!(
+            storage.update_qualifier("c2", DATA_SOURCE, "benign"),
+            Err(Error::NotFound("cluster"))
+        ));
+        assert!(matches!(
+            storage.update_qualifier("c1", "unknown-source", "benign"),
+            Err(Error::NotFound("cluster"))
+        ));
+        assert!(matches!(
+            storage.update_qualifier("c1", DATA_SOURCE, "no such qualifier"),
+            Err(Error::NotFound("qualifier"))
+        ));
+    }
+
+    /// Metadata already known is ignored, and a lookup finds the message that
+    /// contains a message id.
+    fn kafka_metadata<S: Inspect>(storage: &S) {
+        let c1 = cluster_update("c1", Some(1), Some(vec![1]));
+        storage.upsert_clusters(4, &[&c1]).unwrap();
+        let data_source_id = storage.data_source_id(DATA_SOURCE).unwrap();
+        let metadata = |lower: u32, upper: u32, offsets| KafkaMetadata {
+            data_source_id,
+            message_ids: (
+                Bound::Included(BigDecimal::from(lower)),
+                Bound::Included(BigDecimal::from(upper)),
+            ),
+            offsets,
+            partition: 1,
+        };
+
+        let added = storage
+            .add_kafka_metadata(&[metadata(10, 20, 5), metadata(21, 30, 6)])
+            .unwrap();
+        assert_eq!(added, 2);
+        assert_eq!(
+            storage.add_kafka_metadata(&[metadata(10, 20, 5)]).unwrap(),
+            0
+        );
+
+        let lookup = |message_id: u32| {
+            storage.kafka_metadata_lookup(data_source_id, &BigDecimal::from(message_id))
+        };
+        assert_eq!(lookup(15), Some((20, 1, 5)));
+        assert_eq!(lookup(30), Some((30, 1, 6)));
+        assert_eq!(lookup(31), None);
+    }
+}
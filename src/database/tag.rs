@@ -0,0 +1,481 @@
+use actix_web::{
+    http,
+    web::{Data, Json, Path, Payload, Query},
+    HttpResponse,
+};
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Integer, Text};
+use serde::{Deserialize, Serialize};
+
+use super::schema::{
+    cluster, cluster_tag, data_source, indicator, indicator_tag, outlier, outlier_tag, tag,
+};
+use crate::database::{
+    build_error_response, load_payload, ApiError, BulkQuery, BulkResults, Conn, Database, Error,
+};
+
+pub(crate) const CLUSTER_TAGS: &str = "ARRAY(SELECT tag.name FROM cluster_tag \
+                                       INNER JOIN tag ON cluster_tag.tag_id = tag.id \
+                                       WHERE cluster_tag.cluster_id = cluster.id \
+                                       ORDER BY tag.name) as tags";
+pub(crate) const INDICATOR_TAGS: &str = "ARRAY(SELECT tag.name FROM indicator_tag \
+                                         INNER JOIN tag ON indicator_tag.tag_id = tag.id \
+                                         WHERE indicator_tag.indicator_id = indicator.id \
+                                         ORDER BY tag.name) as tags";
+pub(crate) const OUTLIER_TAGS: &str = "ARRAY(SELECT tag.name FROM outlier_tag \
+                                       INNER JOIN tag ON outlier_tag.tag_id = tag.id \
+                                       WHERE outlier_tag.outlier_id = outlier.id \
+                                       ORDER BY tag.name) as tags";
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct NewTag {
+    tag: String,
+}
+
+#[derive(Debug, QueryableByName, Serialize)]
+struct TagUsage {
+    #[sql_type = "Integer"]
+    id: i32,
+    #[sql_type = "Text"]
+    name: String,
+    #[sql_type = "BigInt"]
+    clusters: i64,
+    #[sql_type = "BigInt"]
+    outliers: i64,
+    #[sql_type = "BigInt"]
+    indicators: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClusterTags {
+    cluster_id: String,
+    data_source: String,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OutlierTags {
+    id: i32,
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndicatorTags {
+    name: String,
+    tags: Vec<String>,
+}
+
+/// Builds a condition that is true when `entity` (one of `cluster`, `outlier`,
+/// or `indicator`) has the tag `name`. The quotes in `name` are doubled so
+/// that it stays a single string literal.
+pub(crate) fn tag_condition(entity: &str, name: &str) -> String {
+    format!(
+        "EXISTS (SELECT 1 FROM {0}_tag INNER JOIN tag ON {0}_tag.tag_id = tag.id \
+         WHERE {0}_tag.{0}_id = {0}.id AND tag.name = '{1}')",
+        entity,
+        name.replace('\'', "''")
+    )
+}
+
+fn find_or_create_tags(conn: &Conn, names: &[String]) -> Result<Vec<i32>, Error> {
+    use tag::dsl;
+    let new_tags = names.iter().map(|n| dsl::name.eq(n)).collect::<Vec<_>>();
+    diesel::insert_into(dsl::tag)
+        .values(&new_tags)
+        .on_conflict(dsl::name)
+        .do_nothing()
+        .execute(conn)?;
+    find_tags(conn, names)
+}
+
+fn find_tags(conn: &Conn, names: &[String]) -> Result<Vec<i32>, Error> {
+    use tag::dsl;
+    dsl::tag
+        .filter(dsl::name.eq_any(names))
+        .select(dsl::id)
+        .load::<i32>(conn)
+        .map_err(Into::into)
+}
+
+fn find_cluster(conn: &Conn, cluster_id: &str, data_source: &str) -> Result<Option<i32>, Error> {
+    use cluster::dsl as c_d;
+    use data_source::dsl as d_d;
+    c_d::cluster
+        .inner_join(d_d::data_source.on(c_d::data_source_id.eq(d_d::id)))
+        .filter(
+            c_d::cluster_id
+                .eq(cluster_id)
+                .and(d_d::topic_name.eq(data_source)),
+        )
+        .select(c_d::id)
+        .first::<i32>(conn)
+        .optional()
+        .map_err(Into::into)
+}
+
+fn find_outlier(conn: &Conn, id: i32) -> Result<Option<i32>, Error> {
+    use outlier::dsl;
+    dsl::outlier
+        .filter(dsl::id.eq(id))
+        .select(dsl::id)
+        .first::<i32>(conn)
+        .optional()
+        .map_err(Into::into)
+}
+
+fn find_indicator(conn: &Conn, name: &str) -> Result<Option<i32>, Error> {
+    use indicator::dsl;
+    dsl::indicator
+        .filter(dsl::name.eq(name))
+        .select(dsl::id)
+        .first::<i32>(conn)
+        .optional()
+        .map_err(Into::into)
+}
+
+macro_rules! attach_tags {
+    ($conn:expr, $link:ident, $column:ident, $id:expr, $tags:expr) => {{
+        use $link::dsl;
+        let links = find_or_create_tags($conn, $tags)?
+            .into_iter()
+            .map(|tag_id| (dsl::$column.eq($id), dsl::tag_id.eq(tag_id)))
+            .collect::<Vec<_>>();
+        diesel::insert_into(dsl::$link)
+            .values(&links)
+            .on_conflict_do_nothing()
+            .execute($conn)
+            .map_err(Error::from)
+    }};
+}
+
+macro_rules! detach_tags {
+    ($conn:expr, $link:ident, $column:ident, $id:expr, $tags:expr) => {{
+        use $link::dsl;
+        let tag_ids = find_tags($conn, $tags)?;
+        diesel::delete(dsl::$link.filter(dsl::$column.eq($id).and(dsl::tag_id.eq_any(tag_ids))))
+            .execute($conn)
+            .map_err(Error::from)
+    }};
+}
+
+/// Attaches or detaches the tags of each item with `apply`, given the id of
+/// the item found by `find`. An item that is not found fails as `entity` not
+/// found.
+fn update_tags<T, F, A>(
+    conn: &Conn,
+    items: &[T],
+    atomic: bool,
+    entity: &'static str,
+    find: F,
+    mut apply: A,
+) -> Result<BulkResults, Error>
+where
+    F: Fn(&T) -> Result<Option<i32>, Error>,
+    A: FnMut(i32, &T) -> Result<usize, Error>,
+{
+    let mut results = BulkResults::new(items.len(), atomic);
+    let mut ids = Vec::with_capacity(items.len());
+    for (i, item) in items.iter().enumerate() {
+        let id = find(item)?;
+        if id.is_none() {
+            results.fail(i, &ApiError::not_found(entity));
+        }
+        ids.push(id);
+    }
+    results.apply(conn, |indices| {
+        for &i in indices {
+            if let Some(id) = ids[i] {
+                apply(id, &items[i])?;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(results)
+}
+
+pub(crate) async fn add_cluster_tags(
+    pool: Data<Database>,
+    payload: Payload,
+    query: Query<BulkQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let bytes = load_payload(payload).await?;
+    let cluster_tags: Vec<ClusterTags> = serde_json::from_slice(&bytes)?;
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            update_tags(
+                conn,
+                &cluster_tags,
+                atomic,
+                "cluster",
+                |item| find_cluster(conn, &item.cluster_id, &item.data_source),
+                |id, item| attach_tags!(conn, cluster_tag, cluster_id, id, &item.tags),
+            )
+        })
+        .await;
+
+    match query_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn delete_cluster_tags(
+    pool: Data<Database>,
+    payload: Payload,
+    query: Query<BulkQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let bytes = load_payload(payload).await?;
+    let cluster_tags: Vec<ClusterTags> = serde_json::from_slice(&bytes)?;
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            update_tags(
+                conn,
+                &cluster_tags,
+                atomic,
+                "cluster",
+                |item| find_cluster(conn, &item.cluster_id, &item.data_source),
+                |id, item| detach_tags!(conn, cluster_tag, cluster_id, id, &item.tags),
+            )
+        })
+        .await;
+
+    match query_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn add_outlier_tags(
+    pool: Data<Database>,
+    payload: Payload,
+    query: Query<BulkQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let bytes = load_payload(payload).await?;
+    let outlier_tags: Vec<OutlierTags> = serde_json::from_slice(&bytes)?;
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            update_tags(
+                conn,
+                &outlier_tags,
+                atomic,
+                "outlier",
+                |item| find_outlier(conn, item.id),
+                |id, item| attach_tags!(conn, outlier_tag, outlier_id, id, &item.tags),
+            )
+        })
+        .await;
+
+    match query_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn delete_outlier_tags(
+    pool: Data<Database>,
+    payload: Payload,
+    query: Query<BulkQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let bytes = load_payload(payload).await?;
+    let outlier_tags: Vec<OutlierTags> = serde_json::from_slice(&bytes)?;
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            update_tags(
+                conn,
+                &outlier_tags,
+                atomic,
+                "outlier",
+                |item| find_outlier(conn, item.id),
+                |id, item| detach_tags!(conn, outlier_tag, outlier_id, id, &item.tags),
+            )
+        })
+        .await;
+
+    match query_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn add_indicator_tags(
+    pool: Data<Database>,
+    payload: Payload,
+    query: Query<BulkQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let bytes = load_payload(payload).await?;
+    let indicator_tags: Vec<IndicatorTags> = serde_json::from_slice(&bytes)?;
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            update_tags(
+                conn,
+                &indicator_tags,
+                atomic,
+                "indicator",
+                |item| find_indicator(conn, &item.name),
+                |id, item| attach_tags!(conn, indicator_tag, indicator_id, id, &item.tags),
+            )
+        })
+        .await;
+
+    match query_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn delete_indicator_tags(
+    pool: Data<Database>,
+    payload: Payload,
+    query: Query<BulkQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let bytes = load_payload(payload).await?;
+    let indicator_tags: Vec<IndicatorTags> = serde_json::from_slice(&bytes)?;
+    let atomic = query.is_atomic();
+    let query_result: Result<BulkResults, Error> = pool
+        .run(move |conn| {
+            update_tags(
+                conn,
+                &indicator_tags,
+                atomic,
+                "indicator",
+                |item| find_indicator(conn, &item.name),
+                |id, item| detach_tags!(conn, indicator_tag, indicator_id, id, &item.tags),
+            )
+        })
+        .await;
+
+    match query_result {
+        Ok(results) => Ok(results.into_response()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn get_tags(pool: Data<Database>) -> Result<HttpResponse, actix_web::Error> {
+    let query_result: Result<Vec<TagUsage>, Error> =
+        pool.run(move |conn| {
+            diesel::sql_query(
+                "SELECT tag.id, tag.name, \
+                 (SELECT COUNT(*) FROM cluster_tag WHERE cluster_tag.tag_id = tag.id) AS clusters, \
+                 (SELECT COUNT(*) FROM outlier_tag WHERE outlier_tag.tag_id = tag.id) AS outliers, \
+                 (SELECT COUNT(*) FROM indicator_tag WHERE indicator_tag.tag_id = tag.id) AS indicators \
+                 FROM tag ORDER BY tag.name",
+            )
+            .load::<TagUsage>(conn)
+            .map_err(Into::into)
+        }).await;
+
+    match query_result {
+        Ok(tags) => Ok(HttpResponse::Ok()
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .json(tags)),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+pub(crate) async fn update_tag(
+    pool: Data<Database>,
+    current_tag: Path<String>,
+    new_tag: Json<NewTag>,
+) -> Result<HttpResponse, actix_web::Error> {
+    use tag::dsl;
+    let update_result: Result<usize, Error> = pool
+        .run(move |conn| {
+            diesel::update(dsl::tag)
+                .filter(dsl::name.eq(&current_tag.into_inner()))
+                .set(dsl::name.eq(new_tag.into_inner().tag))
+                .execute(conn)
+                .map_err(Into::into)
+        })
+        .await;
+
+    match update_result {
+        Ok(0) => Ok(ApiError::not_found("tag").into()),
+        Ok(_) => Ok(HttpResponse::Ok().into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use diesel::sql_types::Integer;
+
+    use super::*;
+    use crate::database::test_conn;
+
+    #[derive(QueryableByName)]
+    struct Id {
+        #[sql_type = "Integer"]
+        id: i32,
+    }
+
+    #[test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    fn outlier_tags_of_missing_outliers_fail() {
+        let conn = test_conn();
+        let outlier = diesel::sql_query(
+            "WITH source AS (\
+             INSERT INTO data_source (topic_name, data_type) VALUES ('tag_test', 'log') \
+             RETURNING id) \
+             INSERT INTO outlier (raw_event, data_source_id, event_ids, size) \
+             SELECT 'outlier', id, '{}', 1 FROM source RETURNING id",
+        )
+        .get_result::<Id>(&conn)
+        .unwrap()
+        .id;
+        let items = vec![
+            OutlierTags {
+                id: outlier,
+                tags: vec!["tagged".to_string()],
+            },
+            OutlierTags {
+                id: outlier + 1,
+                tags: vec!["tagged".to_string()],
+            },
+        ];
+        let tag = |conn: &Conn, atomic| {
+            update_tags(
+                conn,
+                &items,
+                atomic,
+                "outlier",
+                |item| find_outlier(conn, item.id),
+                |id, item| attach_tags!(conn, outlier_tag, outlier_id, id, &item.tags),
+            )
+            .unwrap()
+            .into_response()
+            .status()
+        };
+        let tagged = |conn: &Conn| {
+            outlier_tag::table
+                .filter(outlier_tag::outlier_id.eq(outlier))
+                .count()
+                .get_result::<i64>(conn)
+                .unwrap()
+        };
+
+        assert_eq!(tag(&conn, true), StatusCode::BAD_REQUEST);
+        assert_eq!(tagged(&conn), 0);
+        assert_eq!(tag(&conn, false), StatusCode::MULTI_STATUS);
+        assert_eq!(tagged(&conn), 1);
+
+        let detached = update_tags(
+            &conn,
+            &items[1..],
+            false,
+            "outlier",
+            |item| find_outlier(&conn, item.id),
+            |id, item| detach_tags!(&conn, outlier_tag, outlier_id, id, &item.tags),
+        )
+        .unwrap()
+        .into_response()
+        .status();
+        assert_eq!(detached, StatusCode::MULTI_STATUS);
+        assert_eq!(tagged(&conn), 1);
+    }
+}
@@ -8,7 +8,7 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use super::schema::template;
-use crate::database::{build_http_500_response, load_payload, Error, Pool};
+use crate::database::{build_error_response, load_payload, Database, Error};
 
 #[derive(Debug, Insertable, Queryable, Serialize, Deserialize)]
 #[table_name = "template"]
@@ -50,7 +50,7 @@ struct TemplateHttpTransfer {
 }
 
 pub(crate) async fn add_template(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     payload: Payload,
 ) -> Result<HttpResponse, actix_web::Error> {
     use template::dsl;
@@ -72,57 +72,58 @@ pub(crate) async fn add_template(
         dimensions: new_template.dimensions,
     };
 
-    let insert_result: Result<usize, Error> = pool.get().map_err(Into::into).and_then(|conn| {
-        diesel::insert_into(dsl::template)
-            .values(new_template)
-            .execute(&conn)
-            .map_err(Into::into)
-    });
+    let insert_result: Result<usize, Error> = pool
+        .run(move |conn| {
+            diesel::insert_into(dsl::template)
+                .values(new_template)
+                .execute(conn)
+                .map_err(Into::into)
+        })
+        .await;
 
     match insert_result {
         Ok(_) => Ok(HttpResponse::Created().into()),
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
 
 pub(crate) async fn get_template(
-    pool: Data<Pool>,
+    pool: Data<Database>,
     query: Query<TemplateSelectQuery>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let query_result: Result<Vec<Template>, Error> =
-        pool.get()
-            .map_err(Into::into)
-            .and_then(|conn| match &query.name {
-                Some(name) => template::dsl::template
-                    .select((
-                        template::dsl::name,
-                        template::dsl::event_type,
-                        template::dsl::method,
-                        template::dsl::algorithm,
-                        template::dsl::min_token_length,
-                        template::dsl::eps,
-                        template::dsl::format,
-                        template::dsl::dimension_default,
-                        template::dsl::dimensions,
-                    ))
-                    .filter(template::dsl::name.eq(name))
-                    .load::<Template>(&conn)
-                    .map_err(Into::into),
-                None => template::dsl::template
-                    .select((
-                        template::dsl::name,
-                        template::dsl::event_type,
-                        template::dsl::method,
-                        template::dsl::algorithm,
-                        template::dsl::min_token_length,
-                        template::dsl::eps,
-                        template::dsl::format,
-                        template::dsl::dimension_default,
-                        template::dsl::dimensions,
-                    ))
-                    .load::<Template>(&conn)
-                    .map_err(Into::into),
-            });
+    let query_result: Result<Vec<Template>, Error> = pool
+        .run(move |conn| match &query.name {
+            Some(name) => template::dsl::template
+                .select((
+                    template::dsl::name,
+                    template::dsl::event_type,
+                    template::dsl::method,
+                    template::dsl::algorithm,
+                    template::dsl::min_token_length,
+                    template::dsl::eps,
+                    template::dsl::format,
+                    template::dsl::dimension_default,
+                    template::dsl::dimensions,
+                ))
+                .filter(template::dsl::name.eq(name))
+                .load::<Template>(conn)
+                .map_err(Into::into),
+            None => template::dsl::template
+                .select((
+                    template::dsl::name,
+                    template::dsl::event_type,
+                    template::dsl::method,
+                    template::dsl::algorithm,
+                    template::dsl::min_token_length,
+                    template::dsl::eps,
+                    template::dsl::format,
+                    template::dsl::dimension_default,
+                    template::dsl::dimensions,
+                ))
+                .load::<Template>(conn)
+                .map_err(Into::into),
+        })
+        .await;
 
     match query_result {
         Ok(template) => {
@@ -149,6 +150,6 @@ pub(crate) async fn get_template(
                 .header(http::header::CONTENT_TYPE, "application/json")
                 .json(template))
         }
-        Err(e) => Ok(build_http_500_response(&e)),
+        Err(e) => Ok(build_error_response(&e)),
     }
 }
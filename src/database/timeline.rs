@@ -0,0 +1,141 @@
+use actix_web::{
+    web::{Data, Path, Query},
+    HttpResponse,
+};
+use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use diesel::sql_types::{Float8, Int4, Int8, Nullable, Numeric, Text, Timestamptz};
+use serde::{Deserialize, Serialize};
+
+use crate::database::{build_error_response, ApiError, Database, Error};
+
+const DEFAULT_TIMELINE_LIMIT: i64 = 100;
+const MAX_TIMELINE_LIMIT: i64 = 1000;
+
+/// The intervals a timeline can be bucketed by, as `date_trunc` takes them.
+const INTERVALS: [&str; 4] = ["minute", "hour", "day", "week"];
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TimelineQuery {
+    data_source: String,
+    /// `minute`, `hour`, `day`, or `week`. Defaults to `hour`.
+    interval: Option<String>,
+    /// The number of the latest buckets to return.
+    limit: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct Bucket {
+    #[sql_type = "Timestamptz"]
+    time: DateTime<Utc>,
+    #[sql_type = "Int8"]
+    snapshots: i64,
+    #[sql_type = "Numeric"]
+    size: BigDecimal,
+    #[sql_type = "Numeric"]
+    size_delta: BigDecimal,
+    #[sql_type = "Nullable<Float8>"]
+    score: Option<f64>,
+    #[sql_type = "Int8"]
+    new_event_count: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct TimelineBucket {
+    time: DateTime<Utc>,
+    snapshots: i64,
+    size: u64,
+    size_delta: i64,
+    score: Option<f64>,
+    new_event_count: i64,
+}
+
+#[derive(QueryableByName)]
+struct ClusterKey {
+    #[sql_type = "Int4"]
+    id: i32,
+}
+
+/// Returns the history of a cluster, in buckets of `interval` in UTC from the
+/// oldest to the latest. Each bucket has the size and score of the cluster at
+/// its end, and the sums of the changes recorded in it.
+pub(crate) async fn get_cluster_timeline(
+    pool: Data<Database>,
+    cluster_id: Path<String>,
+    query: Query<TimelineQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let TimelineQuery {
+        data_source,
+        interval,
+        limit,
+    } = query.into_inner();
+    let interval = interval.unwrap_or_else(|| "hour".to_string());
+    if !INTERVALS.contains(&interval.as_str()) {
+        return Ok(ApiError::invalid(
+            "interval",
+            format!("interval must be one of {}", INTERVALS.join(", ")),
+        )
+        .into());
+    }
+    let limit = limit.unwrap_or(DEFAULT_TIMELINE_LIMIT);
+    if !(1..=MAX_TIMELINE_LIMIT).contains(&limit) {
+        return Ok(ApiError::invalid(
+            "limit",
+            format!("limit must be between 1 and {}", MAX_TIMELINE_LIMIT),
+        )
+        .into());
+    }
+
+    let query_result: Result<Option<Vec<Bucket>>, Error> = pool
+        .run(move |conn| {
+            let cluster = diesel::sql_query(
+                "SELECT cluster.id FROM cluster \
+                 INNER JOIN data_source ON cluster.data_source_id = data_source.id \
+                 WHERE cluster.cluster_id = $1 AND data_source.topic_name = $2",
+            )
+            .bind::<Text, _>(cluster_id.as_str())
+            .bind::<Text, _>(&data_source)
+            .get_result::<ClusterKey>(conn)
+            .optional()?;
+            let cluster = match cluster {
+                Some(cluster) => cluster,
+                None => return Ok(None),
+            };
+            let mut buckets = diesel::sql_query(
+                "SELECT date_trunc($2, recorded_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS time, \
+                   COUNT(*) AS snapshots, \
+                   (ARRAY_AGG(size ORDER BY recorded_at DESC, id DESC))[1] AS size, \
+                   SUM(size_delta) AS size_delta, \
+                   (ARRAY_AGG(score ORDER BY recorded_at DESC, id DESC))[1] AS score, \
+                   SUM(new_event_count)::INT8 AS new_event_count \
+                 FROM cluster_history WHERE cluster_id = $1 \
+                 GROUP BY 1 ORDER BY 1 DESC LIMIT $3",
+            )
+            .bind::<Int4, _>(cluster.id)
+            .bind::<Text, _>(&interval)
+            .bind::<Int8, _>(limit)
+            .load::<Bucket>(conn)?;
+            buckets.reverse();
+            Ok(Some(buckets))
+        })
+        .await;
+
+    match query_result {
+        Ok(Some(buckets)) => Ok(HttpResponse::Ok().json(
+            buckets
+                .into_iter()
+                .map(|b| TimelineBucket {
+                    time: b.time,
+                    snapshots: b.snapshots,
+                    size: b.size.to_u64().unwrap_or_default(),
+                    size_delta: b.size_delta.to_i64().unwrap_or_default(),
+                    score: b.score,
+                    new_event_count: b.new_event_count,
+                })
+                .collect::<Vec<_>>(),
+        )),
+        Ok(None) => Ok(ApiError::not_found("cluster").into()),
+        Err(e) => Ok(build_error_response(&e)),
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::{anyhow, Context, Result};
+use rdkafka::consumer::BaseConsumer;
+use rdkafka::error::KafkaResult;
+use rdkafka::{ClientConfig, Offset};
+use std::env;
+use std::fmt;
+
+/// How to connect to the Kafka brokers and consume their messages.
+#[derive(Clone, Debug)]
+pub(crate) struct BrokerConfig {
+    brokers: Vec<String>,
+    group_id: String,
+    client_id: String,
+    fetch_max_bytes: usize,
+    start_offset: StartOffset,
+    tls: Option<TlsConfig>,
+    sasl: Option<SaslConfig>,
+}
+
+/// Where to start consuming a partition that has no offset committed by the
+/// consumer group.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum StartOffset {
+    Earliest,
+    Latest,
+}
+
+#[derive(Clone, Debug)]
+struct TlsConfig {
+    ca: Option<String>,
+    cert_and_key: Option<(String, String)>,
+}
+
+#[derive(Clone)]
+struct SaslConfig {
+    mechanism: String,
+    username: String,
+    password: String,
+}
+
+impl BrokerConfig {
+    /// Reads the configuration from the environment variables.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if neither `KAFKA_BROKERS` nor `KAFKA_URL` is set, or if
+    /// any of the other `KAFKA_*` variables is invalid.
+    pub(crate) fn from_env() -> Result<Self> {
+        let brokers = env::var("KAFKA_BROKERS")
+            .or_else(|_| env::var("KAFKA_URL"))
+            .context("KAFKA_BROKERS is not set")?
+            .split(',')
+            .map(str::trim)
+            .filter(|broker| !broker.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if brokers.is_empty() {
+            return Err(anyhow!("KAFKA_BROKERS is empty"));
+        }
+        let fetch_max_bytes = match env::var("KAFKA_FETCH_MAX_BYTES") {
+            Ok(v) => v
+                .parse::<usize>()
+                .with_context(|| format!("invalid KAFKA_FETCH_MAX_BYTES: {}", v))?,
+            Err(_) => 10_000_000,
+        };
+        let start_offset = match env::var("KAFKA_START_OFFSET") {
+            Ok(v) => match v.to_lowercase().as_str() {
+                "earliest" => StartOffset::Earliest,
+                "latest" => StartOffset::Latest,
+                _ => return Err(anyhow!("invalid KAFKA_START_OFFSET: {}", v)),
+            },
+            Err(_) => StartOffset::Earliest,
+        };
+        let ca = env::var("KAFKA_TLS_CA").ok();
+        let cert_and_key = match (env::var("KAFKA_TLS_CERT"), env::var("KAFKA_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => Some((cert, key)),
+            (Err(_), Err(_)) => None,
+            _ => {
+                return Err(anyhow!(
+                    "KAFKA_TLS_CERT and KAFKA_TLS_KEY must be set together"
+                ))
+            }
+        };
+        let tls = if ca.is_some() || cert_and_key.is_some() {
+            Some(TlsConfig { ca, cert_and_key })
+        } else {
+            None
+        };
+        let sasl = match env::var("KAFKA_SASL_USERNAME") {
+            Ok(username) => {
+                let mechanism = env::var("KAFKA_SASL_MECHANISM")
+                    .unwrap_or_else(|_| "PLAIN".to_string())
+                    .to_uppercase();
+                if !["PLAIN", "SCRAM-SHA-256", "SCRAM-SHA-512"].contains(&mechanism.as_str()) {
+                    return Err(anyhow!("invalid KAFKA_SASL_MECHANISM: {}", mechanism));
+                }
+                let password =
+                    env::var("KAFKA_SASL_PASSWORD").context("KAFKA_SASL_PASSWORD is not set")?;
+                Some(SaslConfig {
+                    mechanism,
+                    username,
+                    password,
+                })
+            }
+            Err(_) => None,
+        };
+
+        Ok(Self {
+            brokers,
+            group_id: env::var("KAFKA_GROUP_ID").unwrap_or_else(|_| "REviewd".to_string()),
+            client_id: env::var("KAFKA_CLIENT_ID").unwrap_or_else(|_| "REview".to_string()),
+            fetch_max_bytes,
+            start_offset,
+            tls,
+            sasl,
+        })
+    }
+
+    pub(crate) fn start_offset(&self) -> StartOffset {
+        self.start_offset
+    }
+
+    /// Creates a consumer that commits offsets only when asked to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configuration is rejected by the Kafka client.
+    pub(crate) fn consumer(&self) -> KafkaResult<BaseConsumer> {
+        let protocol = match (&self.tls, &self.sasl) {
+            (None, None) => "plaintext",
+            (Some(_), None) => "ssl",
+            (None, Some(_)) => "sasl_plaintext",
+            (Some(_), Some(_)) => "sasl_ssl",
+        };
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", self.brokers.join(","))
+            .set("group.id", &self.group_id)
+            .set("client.id", &self.client_id)
+            .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "true")
+            .set(
+                "max.partition.fetch.bytes",
+                self.fetch_max_bytes.to_string(),
+            )
+            .set("auto.offset.reset", self.start_offset.as_str())
+            .set("security.protocol", protocol);
+        if let Some(tls) = &self.tls {
+            if let Some(ca) = &tls.ca {
+                config.set("ssl.ca.location", ca);
+            }
+            if let Some((cert, key)) = &tls.cert_and_key {
+                config
+                    .set("ssl.certificate.location", cert)
+                    .set("ssl.key.location", key);
+            }
+        }
+        if let Some(sasl) = &self.sasl {
+            config
+                .set("sasl.mechanisms", &sasl.mechanism)
+                .set("sasl.username", &sasl.username)
+                .set("sasl.password", &sasl.password);
+        }
+        config.create()
+    }
+}
+
+impl fmt::Debug for SaslConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SaslConfig")
+            .field("mechanism", &self.mechanism)
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+impl StartOffset {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Earliest => "earliest",
+            Self::Latest => "latest",
+        }
+    }
+}
+
+impl From<StartOffset> for Offset {
+    fn from(start: StartOffset) -> Self {
+        match start {
+            StartOffset::Earliest => Offset::Beginning,
+            StartOffset::Latest => Offset::End,
+        }
+    }
+}
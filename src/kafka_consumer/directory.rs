@@ -0,0 +1,132 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::source::{Decoder, EventSource, MessageLocation};
+
+/// Reads the events of a data source from the files in a local directory, such
+/// as Fluentd forward dumps or logs converted from pcap files.
+///
+/// The files are indexed in memory by the range of the message ids in them,
+/// so no Kafka metadata is kept for them.
+pub(crate) struct DirectorySource {
+    path: PathBuf,
+    decoder: Decoder,
+    files: HashMap<PathBuf, FileIndex>,
+}
+
+/// The state of a file when it was indexed, and the smallest and the largest
+/// message ids in it.
+struct FileIndex {
+    len: u64,
+    modified: Option<SystemTime>,
+    ids: Option<(u64, u64)>,
+}
+
+impl DirectorySource {
+    pub(crate) fn new(path: &str, decoder: Decoder) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            decoder,
+            files: HashMap::new(),
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        self.decoder
+            .decode_stream(BufReader::new(File::open(path)?))
+    }
+}
+
+impl EventSource for DirectorySource {
+    /// Indexes up to `max_count` files that are new or have changed since they
+    /// were indexed.
+    fn index(&mut self, max_count: usize) -> Result<Vec<MessageLocation>, String> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.path)
+            .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?
+        {
+            let entry =
+                entry.map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?;
+            let metadata = match entry.metadata() {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            paths.push((entry.path(), metadata.len(), metadata.modified().ok()));
+        }
+        paths.sort();
+
+        let present = paths.iter().map(|(path, ..)| path).collect::<HashSet<_>>();
+        self.files.retain(|path, _| present.contains(path));
+        let mut count = 0;
+        for (path, len, modified) in paths {
+            if count >= max_count {
+                break;
+            }
+            if let Some(file) = self.files.get(&path) {
+                if file.len == len && file.modified == modified {
+                    continue;
+                }
+            }
+            let events = match self.read(&path) {
+                Ok(events) => events,
+                Err(e) => {
+                    log::error!("Failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let first = events.iter().map(|(id, _)| *id).min();
+            let last = events.iter().map(|(id, _)| *id).max();
+            self.files.insert(
+                path,
+                FileIndex {
+                    len,
+                    modified,
+                    ids: first.and_then(|first| last.map(|last| (first, last))),
+                },
+            );
+            count += 1;
+        }
+        Ok(Vec::new())
+    }
+
+    fn commit(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn raw_events(
+        &mut self,
+        message_ids: &[u64],
+        _locations: &[(i32, i64)],
+    ) -> Result<HashMap<u64, Vec<u8>>, String> {
+        let mut wanted = message_ids.iter().copied().collect::<HashSet<_>>();
+        let mut events = HashMap::new();
+        for (path, file) in &self.files {
+            if wanted.is_empty() {
+                break;
+            }
+            let (first, last) = match file.ids {
+                Some(ids) => ids,
+                None => continue,
+            };
+            if !wanted.iter().any(|id| first <= *id && *id <= last) {
+                continue;
+            }
+            let records = match self.read(path) {
+                Ok(records) => records,
+                Err(e) => {
+                    log::error!("Failed to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            for (id, raw) in records {
+                if wanted.remove(&id) {
+                    events.insert(id, raw);
+                }
+            }
+        }
+        Ok(events)
+    }
+}
@@ -1,92 +1,57 @@
 use bigdecimal::{BigDecimal, FromPrimitive, ToPrimitive};
-use eventio::fluentd::ForwardMode;
-use kafka::client::{FetchPartition, KafkaClient};
-use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
 use serde::Serialize;
 use serde_json::Value;
-use std::collections::HashMap;
 use std::ops::Bound::Included;
 
+use super::source::EventSource;
 use crate::database::{Event, KafkaMetadata};
 
+/// Reads the messages not read before from `source`, and adds their locations
+/// to the Kafka metadata. Returns `source` to be used again.
 pub(crate) async fn fetch_kafka_metadata(
-    kafka_url: String,
-    topic_name: String,
+    mut source: Box<dyn EventSource>,
     data_source_id: i32,
     reviewd_addr: String,
     max_offset_count: usize,
-) -> Result<(), String> {
-    let mut consumer = Consumer::from_hosts(vec![kafka_url])
-        .with_group("REviewd".into())
-        .with_fallback_offset(FetchOffset::Earliest)
-        .with_fetch_max_bytes_per_partition(10_000_000)
-        .with_offset_storage(GroupOffsetStorage::Kafka)
-        .with_client_id("REview".into())
-        .with_topic(topic_name)
-        .create()
-        .map_err(|e| format!("Failed to create Kafka consumer: {}", e))?;
-
-    let mut offset_count = 0;
-    let mut metadata = Vec::<KafkaMetadata>::new();
-    loop {
-        let messagesets = consumer
-            .poll()
-            .map_err(|e| format!("Failed to consume kafka messages: {}", e))?;
-        if messagesets.is_empty() {
-            break;
-        }
-        for msgset in messagesets.iter() {
-            let partition = msgset.partition();
-            let result = (0..msgset.messages().len())
-                .filter_map(|index| {
-                    let msg = msgset.messages().get(index)?;
-                    let fwd_msg: ForwardMode = rmp_serde::from_slice(msg.value).ok()?;
-                    let first: BigDecimal = FromPrimitive::from_u64(fwd_msg.entries[0].time)?;
-                    let last: BigDecimal =
-                        FromPrimitive::from_u64(fwd_msg.entries[fwd_msg.entries.len() - 1].time)?;
-                    offset_count += 1;
-
-                    Some(KafkaMetadata {
-                        data_source_id,
-                        partition,
-                        offsets: msg.offset,
-                        message_ids: (Included(first), Included(last)),
-                    })
-                })
-                .collect::<Vec<_>>();
-
-            if !result.is_empty() {
-                metadata.extend(result);
-                if let Err(e) = consumer.consume_messageset(msgset) {
-                    log::error!("Failed to mark messages consumed: {}", e);
-                }
-            }
-        }
-        if offset_count >= max_offset_count {
-            break;
-        }
-    }
+) -> (Box<dyn EventSource>, Result<(), String>) {
+    let locations = match source.index(max_offset_count) {
+        Ok(locations) => locations,
+        Err(e) => return (source, Err(e)),
+    };
+    let metadata = locations
+        .into_iter()
+        .filter_map(|location| {
+            Some(KafkaMetadata {
+                data_source_id,
+                partition: location.partition,
+                offsets: location.offset,
+                message_ids: (
+                    Included(FromPrimitive::from_u64(location.first)?),
+                    Included(FromPrimitive::from_u64(location.last)?),
+                ),
+            })
+        })
+        .collect::<Vec<_>>();
     if !metadata.is_empty() {
-        let api_url = format!("http://{}/api/kafka_metadata", reviewd_addr);
-        if send_http_put_request(&metadata, &api_url).await.is_ok() {
-            consumer
-                .commit_consumed()
-                .map_err(|e| format!("Failed to send commit message(s): {}", e))?;
+        let api_url = format!("http://{}/api/v1/kafka_metadata", reviewd_addr);
+        if send_http_put_request(&metadata, &api_url).await.is_err() {
+            return (source, Ok(()));
         }
     }
-
-    Ok(())
+    let result = source.commit();
+    (source, result)
 }
 
+/// Fills in the raw events of the events that have none, reading them from
+/// `source`. Returns `source` to be used again.
 pub(crate) async fn fetch_raw_events(
-    kafka_url: String,
-    topic_name: String,
+    mut source: Box<dyn EventSource>,
     data_source_id: i32,
     reviewd_addr: String,
     max_offset_count: usize,
-) -> Result<(), String> {
+) -> (Box<dyn EventSource>, Result<(), String>) {
     let api_url = format!(
-        "http://{}/api/event/no_raw_events?data_source_id={}",
+        "http://{}/api/v1/event/no_raw_events?data_source_id={}",
         reviewd_addr, data_source_id
     );
     match send_http_get_request(&api_url).await {
@@ -104,53 +69,12 @@ pub(crate) async fn fetch_raw_events(
                 let metadata = data
                     .get("metadata")
                     .and_then(|v| serde_json::from_value::<Vec<(i32, i64)>>(v.clone()).ok());
-                if let (Some(message_ids), Some(metadata)) = (message_ids, metadata) {
-                    let metadata = if metadata.len() > max_offset_count {
-                        let (metadata, _) = metadata.split_at(max_offset_count);
-                        metadata.to_vec()
-                    } else {
-                        metadata
+                if let (Some(message_ids), Some(mut metadata)) = (message_ids, metadata) {
+                    metadata.truncate(max_offset_count);
+                    let entries = match source.raw_events(&message_ids, &metadata) {
+                        Ok(entries) => entries,
+                        Err(e) => return (source, Err(e)),
                     };
-                    let mut client = KafkaClient::new(vec![kafka_url]);
-                    let entries = metadata
-                        .iter()
-                        .filter_map(|(partition, offsets)| {
-                            let _ = client.load_metadata(&[&topic_name]);
-                            let req = &FetchPartition::new(&topic_name, *partition, *offsets)
-                                .with_max_bytes(1_000_000);
-                            if let Ok(resps) = client.fetch_messages_for_partition(req) {
-                                let mut fwd_msgs = Vec::<ForwardMode>::new();
-                                resps.iter().for_each(|resp| {
-                                    for t in resp.topics() {
-                                        for p in t.partitions() {
-                                            if let Ok(data) = p.data() {
-                                                let msg = (0..data.messages().len())
-                                                    .filter_map(|index| {
-                                                        let msg = data.messages().get(index)?;
-                                                        rmp_serde::from_slice::<ForwardMode>(
-                                                            msg.value,
-                                                        )
-                                                        .ok()
-                                                    })
-                                                    .collect::<Vec<_>>();
-                                                fwd_msgs.extend(msg);
-                                            }
-                                        }
-                                    }
-                                });
-                                Some(fwd_msgs)
-                            } else {
-                                None
-                            }
-                        })
-                        .flatten()
-                        .flat_map(|msg| {
-                            msg.entries.into_iter().filter_map(|entry| {
-                                let raw = entry.record.get("message")?;
-                                Some((entry.time, raw.clone()))
-                            })
-                        })
-                        .collect::<HashMap<_, _>>();
                     let events = message_ids
                         .iter()
                         .filter_map(|message_id| {
@@ -165,7 +89,7 @@ pub(crate) async fn fetch_raw_events(
                         })
                         .collect::<Vec<_>>();
 
-                    let api_url = format!("http://{}/api/event", reviewd_addr);
+                    let api_url = format!("http://{}/api/v1/event", reviewd_addr);
                     let _ = send_http_put_request(&events, &api_url).await;
                 }
             }
@@ -173,7 +97,7 @@ pub(crate) async fn fetch_raw_events(
         Err(e) => log::error!("Failed to fetch events with no raw_event: {}", e),
     }
 
-    Ok(())
+    (source, Ok(()))
 }
 
 pub(crate) async fn send_http_get_request(api_url: &str) -> Result<String, reqwest::Error> {
@@ -0,0 +1,194 @@
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+use rdkafka::error::{KafkaError, KafkaResult};
+use rdkafka::{Message, Offset, TopicPartitionList};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use super::source::{Decoder, EventSource, MessageLocation};
+use super::{BrokerConfig, StartOffset};
+
+/// How long to wait for the Kafka brokers to respond.
+const KAFKA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads the events of a data source from its Kafka topic.
+pub(crate) struct KafkaSource {
+    consumer: BaseConsumer,
+    topic: String,
+    start: StartOffset,
+    decoder: Decoder,
+    /// The next offset to read in each partition, after the last `index`.
+    consumed: HashMap<i32, i64>,
+}
+
+impl KafkaSource {
+    pub(crate) fn new(
+        broker: &BrokerConfig,
+        topic: &str,
+        decoder: Decoder,
+    ) -> Result<Self, String> {
+        let consumer = broker
+            .consumer()
+            .map_err(|e| format!("Failed to create Kafka consumer: {}", e))?;
+        Ok(Self {
+            consumer,
+            topic: topic.to_string(),
+            start: broker.start_offset(),
+            decoder,
+            consumed: HashMap::new(),
+        })
+    }
+
+    /// Assigns all partitions of the topic, starting from the offsets
+    /// committed by the consumer group, or from the start offset where there
+    /// are none. Returns the number of partitions.
+    fn assign_partitions(&self) -> KafkaResult<usize> {
+        let metadata = self
+            .consumer
+            .fetch_metadata(Some(&self.topic), KAFKA_TIMEOUT)?;
+        let mut partitions = TopicPartitionList::new();
+        for t in metadata.topics() {
+            for p in t.partitions() {
+                partitions.add_partition(&self.topic, p.id());
+            }
+        }
+        let committed = self.consumer.committed_offsets(partitions, KAFKA_TIMEOUT)?;
+        let mut assignment = TopicPartitionList::new();
+        for elem in committed.elements() {
+            let offset = match elem.offset() {
+                Offset::Offset(offset) => Offset::Offset(offset),
+                _ => self.start.into(),
+            };
+            assignment.add_partition_offset(&self.topic, elem.partition(), offset)?;
+        }
+        self.consumer.assign(&assignment)?;
+        Ok(assignment.count())
+    }
+
+    /// Reads the message at `offset` in `partition`, waiting up to `timeout`.
+    /// Returns `None` if there is no such message.
+    fn fetch_message(
+        &self,
+        partition: i32,
+        offset: i64,
+        timeout: Duration,
+    ) -> KafkaResult<Option<Vec<u8>>> {
+        let mut assignment = TopicPartitionList::new();
+        assignment.add_partition_offset(&self.topic, partition, Offset::Offset(offset))?;
+        self.consumer.assign(&assignment)?;
+        match self.consumer.poll(timeout) {
+            Some(Ok(msg)) if msg.partition() == partition && msg.offset() == offset => {
+                Ok(msg.payload().map(<[u8]>::to_vec))
+            }
+            Some(Err(KafkaError::PartitionEOF(_))) | Some(Ok(_)) | None => Ok(None),
+            Some(Err(e)) => Err(e),
+        }
+    }
+
+    /// Returns the raw events of `message_ids` in the messages at
+    /// `locations`. No more messages are read after `deadline`.
+    pub(crate) fn read_messages(
+        &self,
+        message_ids: &[u64],
+        locations: &[(i32, i64)],
+        deadline: Option<Instant>,
+    ) -> HashMap<u64, Vec<u8>> {
+        let wanted = message_ids.iter().collect::<HashSet<_>>();
+        let mut events = HashMap::new();
+        for &(partition, offset) in locations {
+            let timeout = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) => left.min(KAFKA_TIMEOUT),
+                    None => break,
+                },
+                None => KAFKA_TIMEOUT,
+            };
+            let payload = match self.fetch_message(partition, offset, timeout) {
+                Ok(Some(payload)) => payload,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!(
+                        "Failed to fetch a message at {} in partition {} of {}: {}",
+                        offset,
+                        partition,
+                        self.topic,
+                        e
+                    );
+                    continue;
+                }
+            };
+            events.extend(
+                self.decoder
+                    .decode(&payload)
+                    .into_iter()
+                    .filter(|(id, _)| wanted.contains(id)),
+            );
+        }
+        events
+    }
+}
+
+impl EventSource for KafkaSource {
+    fn index(&mut self, max_count: usize) -> Result<Vec<MessageLocation>, String> {
+        self.consumed.clear();
+        let partitions = self
+            .assign_partitions()
+            .map_err(|e| format!("Failed to assign partitions of {}: {}", self.topic, e))?;
+
+        let mut locations = Vec::new();
+        let mut at_end = HashSet::<i32>::new();
+        while at_end.len() < partitions && locations.len() < max_count {
+            let msg = match self.consumer.poll(KAFKA_TIMEOUT) {
+                Some(Ok(msg)) => msg,
+                Some(Err(KafkaError::PartitionEOF(partition))) => {
+                    at_end.insert(partition);
+                    continue;
+                }
+                Some(Err(e)) => return Err(format!("Failed to consume kafka messages: {}", e)),
+                None => break,
+            };
+            let partition = msg.partition();
+            at_end.remove(&partition);
+            self.consumed.insert(partition, msg.offset() + 1);
+            let ids = msg
+                .payload()
+                .map(|payload| self.decoder.decode(payload))
+                .unwrap_or_default();
+            let first = ids.iter().map(|(id, _)| *id).min();
+            let last = ids.iter().map(|(id, _)| *id).max();
+            if let (Some(first), Some(last)) = (first, last) {
+                locations.push(MessageLocation {
+                    partition,
+                    offset: msg.offset(),
+                    first,
+                    last,
+                });
+            }
+        }
+        Ok(locations)
+    }
+
+    fn commit(&mut self) -> Result<(), String> {
+        if self.consumed.is_empty() {
+            return Ok(());
+        }
+        let mut offsets = TopicPartitionList::new();
+        for (&partition, &offset) in &self.consumed {
+            offsets
+                .add_partition_offset(&self.topic, partition, Offset::Offset(offset))
+                .map_err(|e| format!("Failed to send commit message(s): {}", e))?;
+        }
+        self.consumer
+            .commit(&offsets, CommitMode::Sync)
+            .map_err(|e| format!("Failed to send commit message(s): {}", e))?;
+        self.consumed.clear();
+        Ok(())
+    }
+
+    fn raw_events(
+        &mut self,
+        message_ids: &[u64],
+        locations: &[(i32, i64)],
+    ) -> Result<HashMap<u64, Vec<u8>>, String> {
+        Ok(self.read_messages(message_ids, locations, None))
+    }
+}
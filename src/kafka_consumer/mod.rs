@@ -1,15 +1,28 @@
+mod config;
+mod directory;
 mod fetch;
+mod kafka;
+mod source;
 use futures::future;
 use log::error;
-use serde::Deserialize;
+use std::collections::HashMap;
 use std::time::Duration;
 use tokio::{task, time};
 
+pub(crate) use self::config::{BrokerConfig, StartOffset};
+use self::source::EventSource;
+pub(crate) use self::source::SourceSettings;
 use crate::database::DataSource;
 
-#[derive(Debug, Deserialize)]
+/// The event source of each data source, and the settings it was opened with.
+type Sources = HashMap<i32, (SourceSettings, Box<dyn EventSource>)>;
+
+/// What a task returns: the event source it used and its result.
+type TaskResult = Result<(Box<dyn EventSource>, Result<(), String>), task::JoinError>;
+
+#[derive(Debug)]
 pub(crate) struct KafkaConfig {
-    kafka_url: String,
+    broker: BrokerConfig,
     reviewd_addr: String,
     interval: Option<u64>,
     max_offset_count: Option<usize>,
@@ -17,13 +30,13 @@ pub(crate) struct KafkaConfig {
 impl KafkaConfig {
     #[allow(clippy::must_use_candidate)]
     pub(crate) fn new(
-        kafka_url: String,
+        broker: BrokerConfig,
         reviewd_addr: String,
         interval: Option<u64>,
         max_offset_count: Option<usize>,
     ) -> Self {
         Self {
-            kafka_url,
+            broker,
             reviewd_addr,
             interval,
             max_offset_count,
@@ -38,12 +51,15 @@ impl KafkaConfig {
         let mut interval =
             time::interval(Duration::from_secs(self.interval.unwrap_or_else(|| 900)));
         let max_offset_count: usize = self.max_offset_count.unwrap_or_else(|| 1000);
-        let api_url = format!("http://{}/api/data_source", self.reviewd_addr);
+        let api_url = format!("http://{}/api/v1/data_source", self.reviewd_addr);
         log::info!(
             "Starting periodic tasks with time interval {} second(s) and max_offset_count {}",
             self.interval.unwrap_or_else(|| 900),
             max_offset_count
         );
+        // The event sources of the data sources, kept between the periodic
+        // tasks to reuse the Kafka consumers and the directory indices.
+        let mut sources = Sources::new();
         interval.tick().await;
         loop {
             interval.tick().await;
@@ -51,43 +67,52 @@ impl KafkaConfig {
                 Ok(data_sources) => {
                     if let Ok(data_sources) = serde_json::from_str::<Vec<DataSource>>(&data_sources)
                     {
-                        // Fetch unread kafka messages and insert metadata to the database
-                        let tasks = data_sources.iter().map(|data_source| {
-                            task::spawn(fetch::fetch_kafka_metadata(
-                                self.kafka_url.clone(),
-                                data_source.topic_name.clone(),
-                                data_source.id,
+                        let mut opened = HashMap::new();
+                        for data_source in data_sources.iter().filter(|d| d.enabled) {
+                            let settings = match SourceSettings::new(data_source) {
+                                Ok(settings) => settings,
+                                Err(e) => {
+                                    error!("{}: {}", data_source.topic_name, e);
+                                    continue;
+                                }
+                            };
+                            let source = match sources.remove(&data_source.id) {
+                                Some((old, source)) if old == settings => source,
+                                _ => match settings.open(&self.broker) {
+                                    Ok(source) => source,
+                                    Err(e) => {
+                                        error!("{}: {}", data_source.topic_name, e);
+                                        continue;
+                                    }
+                                },
+                            };
+                            opened.insert(data_source.id, (settings, source));
+                        }
+                        sources = opened;
+
+                        // Fetch unread messages and insert metadata to the database
+                        let tasks = sources.drain().map(|(id, (settings, source))| {
+                            let task = task::spawn(fetch::fetch_kafka_metadata(
+                                source,
+                                id,
                                 self.reviewd_addr.clone(),
                                 max_offset_count,
-                            ))
+                            ));
+                            async move { (id, settings, task.await) }
                         });
-                        future::join_all(tasks)
-                            .await
-                            .iter()
-                            .for_each(|task| match task {
-                                Err(e) => error!("{}", e),
-                                Ok(Err(e)) => error!("{}", e),
-                                _ => (),
-                            });
+                        sources = collect_sources(future::join_all(tasks).await);
 
-                        // Fetch specific kafka messages using partition and offset
-                        let tasks = data_sources.iter().map(|data_source| {
-                            task::spawn(fetch::fetch_raw_events(
-                                self.kafka_url.clone(),
-                                data_source.topic_name.clone(),
-                                data_source.id,
+                        // Fetch specific messages using partition and offset
+                        let tasks = sources.drain().map(|(id, (settings, source))| {
+                            let task = task::spawn(fetch::fetch_raw_events(
+                                source,
+                                id,
                                 self.reviewd_addr.clone(),
                                 max_offset_count,
-                            ))
+                            ));
+                            async move { (id, settings, task.await) }
                         });
-                        future::join_all(tasks)
-                            .await
-                            .iter()
-                            .for_each(|task| match task {
-                                Err(e) => error!("{}", e),
-                                Ok(Err(e)) => error!("{}", e),
-                                _ => (),
-                            });
+                        sources = collect_sources(future::join_all(tasks).await);
                     }
                 }
                 Err(e) => error!("Failed to fetch kafka topic names: {}", e),
@@ -95,3 +120,22 @@ impl KafkaConfig {
         }
     }
 }
+
+/// Logs the errors of the tasks, and returns the event sources they used.
+fn collect_sources(results: Vec<(i32, SourceSettings, TaskResult)>) -> Sources {
+    results
+        .into_iter()
+        .filter_map(|(id, settings, result)| match result {
+            Ok((source, result)) => {
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+                Some((id, (settings, source)))
+            }
+            Err(e) => {
+                error!("{}", e);
+                None
+            }
+        })
+        .collect()
+}
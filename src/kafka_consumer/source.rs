@@ -0,0 +1,342 @@
+use eventio::fluentd::ForwardMode;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::time::Instant;
+
+use super::directory::DirectorySource;
+use super::kafka::KafkaSource;
+use super::BrokerConfig;
+use crate::database::{DataSource, EventFormat, EventSourceType};
+
+/// A Kafka message read by an event source, and the range of the message ids
+/// in it.
+pub(crate) struct MessageLocation {
+    pub(crate) partition: i32,
+    pub(crate) offset: i64,
+    pub(crate) first: u64,
+    pub(crate) last: u64,
+}
+
+/// A source of the raw events of a data source.
+pub(crate) trait EventSource: Send {
+    /// Reads up to `max_count` messages not read before, and returns their
+    /// locations to be kept in the Kafka metadata. A source that finds its
+    /// events without the metadata returns nothing.
+    fn index(&mut self, max_count: usize) -> Result<Vec<MessageLocation>, String>;
+
+    /// Marks the messages returned by the last call to `index` as read.
+    fn commit(&mut self) -> Result<(), String>;
+
+    /// Returns the raw events of `message_ids`, given the partitions and the
+    /// offsets of the messages found in the Kafka metadata.
+    fn raw_events(
+        &mut self,
+        message_ids: &[u64],
+        locations: &[(i32, i64)],
+    ) -> Result<HashMap<u64, Vec<u8>>, String>;
+}
+
+/// The settings of a data source that decide where and how its events are
+/// read.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct SourceSettings {
+    source: EventSourceType,
+    topic: String,
+    path: Option<String>,
+    decoder: Decoder,
+}
+
+impl SourceSettings {
+    /// Reads the settings of `data_source`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the settings are invalid.
+    pub(crate) fn new(data_source: &DataSource) -> Result<Self, String> {
+        let source = data_source
+            .event_source
+            .parse::<EventSourceType>()
+            .map_err(|_| format!("invalid event_source: {}", data_source.event_source))?;
+        let format = data_source
+            .event_format
+            .parse::<EventFormat>()
+            .map_err(|_| format!("invalid event_format: {}", data_source.event_format))?;
+        Ok(Self {
+            source,
+            topic: data_source.topic_name.clone(),
+            path: data_source.event_path.clone(),
+            decoder: Decoder {
+                format,
+                record_field: data_source.record_field.clone(),
+                id_field: data_source.id_field.clone(),
+            },
+        })
+    }
+
+    /// Opens the event source.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the Kafka consumer cannot be created, or if a
+    /// directory source has no path.
+    pub(crate) fn open(&self, broker: &BrokerConfig) -> Result<Box<dyn EventSource>, String> {
+        match self.source {
+            EventSourceType::Kafka => Ok(Box::new(KafkaSource::new(
+                broker,
+                &self.topic,
+                self.decoder.clone(),
+            )?)),
+            EventSourceType::Directory => {
+                let path = self
+                    .path
+                    .as_ref()
+                    .ok_or_else(|| format!("no event_path for {}", self.topic))?;
+                Ok(Box::new(DirectorySource::new(path, self.decoder.clone())))
+            }
+        }
+    }
+
+    /// Reads the raw events of `message_ids` from the Kafka messages at
+    /// `locations` right away, returning those read by `deadline`. A
+    /// directory source returns nothing, as its files are read only by the
+    /// periodic task.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the Kafka consumer cannot be created.
+    pub(crate) fn fetch_until(
+        &self,
+        broker: &BrokerConfig,
+        message_ids: &[u64],
+        locations: &[(i32, i64)],
+        deadline: Instant,
+    ) -> Result<HashMap<u64, Vec<u8>>, String> {
+        match self.source {
+            EventSourceType::Kafka => Ok(KafkaSource::new(
+                broker,
+                &self.topic,
+                self.decoder.clone(),
+            )?
+            .read_messages(message_ids, locations, Some(deadline))),
+            EventSourceType::Directory => Ok(HashMap::new()),
+        }
+    }
+}
+
+/// Reads the message ids and the raw events from the records of messages or
+/// files.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Decoder {
+    format: EventFormat,
+    record_field: String,
+    /// The field with the message id. If `None`, the time of a Fluentd entry
+    /// is used.
+    id_field: Option<String>,
+}
+
+impl Decoder {
+    /// Returns the message id and the raw event of each record in a message.
+    pub(crate) fn decode(&self, payload: &[u8]) -> Vec<(u64, Vec<u8>)> {
+        self.decode_stream(payload).unwrap_or_default()
+    }
+
+    /// Returns the message id and the raw event of each record read from
+    /// `reader`, stopping at the first message that cannot be decoded.
+    /// Records without the record field or a valid id are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading fails.
+    pub(crate) fn decode_stream<R: BufRead>(
+        &self,
+        mut reader: R,
+    ) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let mut events = Vec::new();
+        match self.format {
+            EventFormat::Fluentd => {
+                while !reader.fill_buf()?.is_empty() {
+                    let msg = match ForwardMode::deserialize(&mut rmp_serde::Deserializer::new(
+                        &mut reader,
+                    )) {
+                        Ok(msg) => msg,
+                        Err(_) => break,
+                    };
+                    for entry in msg.entries {
+                        let raw: &[u8] = match entry.record.get(&self.record_field) {
+                            Some(raw) => raw.as_ref(),
+                            None => continue,
+                        };
+                        let id = match &self.id_field {
+                            Some(field) => match entry.record.get(field).and_then(|id| {
+                                let id: &[u8] = id.as_ref();
+                                parse_id(std::str::from_utf8(id).ok()?)
+                            }) {
+                                Some(id) => id,
+                                None => continue,
+                            },
+                            None => entry.time,
+                        };
+                        events.push((id, raw.to_vec()));
+                    }
+                }
+            }
+            EventFormat::Ndjson => {
+                for line in reader.split(b'\n') {
+                    if let Some(event) = self.json_record(&line?) {
+                        events.push(event);
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    /// Returns the message id and the raw event in a JSON object. A string
+    /// record field is the raw event itself; any other value is serialized.
+    fn json_record(&self, line: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let record = serde_json::from_slice::<Value>(line).ok()?;
+        let id = match record.get(self.id_field.as_ref()?)? {
+            Value::Number(id) => id.as_u64()?,
+            Value::String(id) => parse_id(id)?,
+            _ => return None,
+        };
+        let raw = match record.get(&self.record_field)? {
+            Value::String(raw) => raw.as_bytes().to_vec(),
+            raw => raw.to_string().into_bytes(),
+        };
+        Some((id, raw))
+    }
+}
+
+fn parse_id(id: &str) -> Option<u64> {
+    id.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Serialize, Serializer};
+    use std::io::{BufReader, Read};
+
+    use super::*;
+
+    /// A field of a record, serialized as MessagePack binary as Fluentd sends
+    /// it.
+    struct Bin(Vec<u8>);
+
+    impl Serialize for Bin {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+
+    /// Encodes a Fluentd forward message of `entries`, each the time and the
+    /// fields of a record.
+    fn forward(entries: Vec<(u64, Vec<(&str, &str)>)>) -> Vec<u8> {
+        let entries: Vec<(u64, HashMap<&str, Bin>)> = entries
+            .into_iter()
+            .map(|(time, record)| {
+                let record = record
+                    .into_iter()
+                    .map(|(name, value)| (name, Bin(value.as_bytes().to_vec())))
+                    .collect();
+                (time, record)
+            })
+            .collect();
+        rmp_serde::to_vec(&("tag", entries, None::<HashMap<String, String>>)).unwrap()
+    }
+
+    fn decoder(format: EventFormat, id_field: Option<&str>) -> Decoder {
+        Decoder {
+            format,
+            record_field: "message".to_string(),
+            id_field: id_field.map(str::to_string),
+        }
+    }
+
+    fn event(id: u64, raw: &str) -> (u64, Vec<u8>) {
+        (id, raw.as_bytes().to_vec())
+    }
+
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "broken"))
+        }
+    }
+
+    #[test]
+    fn fluentd_records() {
+        let mut payload = forward(vec![
+            (100, vec![("message", "a"), ("id", "7")]),
+            (101, vec![("message", "b")]),
+            (102, vec![("log", "c"), ("id", "8")]),
+            (103, vec![("message", "d"), ("id", "x")]),
+        ]);
+        payload.extend(forward(vec![(104, vec![("message", "e"), ("id", " 9 ")])]));
+
+        let by_id = decoder(EventFormat::Fluentd, Some("id"));
+        assert_eq!(
+            by_id.decode_stream(payload.as_slice()).unwrap(),
+            vec![event(7, "a"), event(9, "e")]
+        );
+        let by_time = decoder(EventFormat::Fluentd, None);
+        assert_eq!(
+            by_time.decode_stream(payload.as_slice()).unwrap(),
+            vec![
+                event(100, "a"),
+                event(101, "b"),
+                event(103, "d"),
+                event(104, "e")
+            ]
+        );
+    }
+
+    #[test]
+    fn fluentd_stops_at_undecodable_message() {
+        let mut payload = forward(vec![(100, vec![("message", "a")])]);
+        payload.extend_from_slice(b"\xc1not a message");
+        payload.extend(forward(vec![(101, vec![("message", "b")])]));
+        let decoder = decoder(EventFormat::Fluentd, None);
+        assert_eq!(
+            decoder.decode_stream(payload.as_slice()).unwrap(),
+            vec![event(100, "a")]
+        );
+
+        let mut truncated = forward(vec![(102, vec![("message", "c")])]);
+        truncated.pop();
+        assert!(decoder
+            .decode_stream(truncated.as_slice())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn ndjson_records() {
+        let lines = b"{\"id\": 1, \"message\": \"a\"}\n\
+            {\"id\": \"2\", \"message\": {\"k\": 1}}\n\
+            {\"id\": 3}\n\
+            {\"message\": \"d\"}\n\
+            not json\n\
+            \n\
+            {\"id\": -4, \"message\": \"e\"}\n\
+            {\"id\": 5, \"message\": \"f\"}";
+        let by_id = decoder(EventFormat::Ndjson, Some("id"));
+        assert_eq!(
+            by_id.decode_stream(&lines[..]).unwrap(),
+            vec![event(1, "a"), event(2, "{\"k\":1}"), event(5, "f")]
+        );
+        let without_id = decoder(EventFormat::Ndjson, None);
+        assert!(without_id.decode_stream(&lines[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_errors() {
+        for format in &[EventFormat::Fluentd, EventFormat::Ndjson] {
+            let decoder = decoder(*format, Some("id"));
+            assert!(decoder.decode_stream(BufReader::new(Broken)).is_err());
+        }
+    }
+}
@@ -1,8 +1,9 @@
 #[macro_use]
 extern crate diesel;
-#[macro_use]
+#[cfg_attr(feature = "sqlite", macro_use)]
 extern crate diesel_migrations;
 
+mod command;
 mod database;
 mod kafka_consumer;
 mod server;
@@ -10,6 +11,13 @@ mod server;
 use actix_web::dev::Server;
 use anyhow::{Context, Result};
 
+/// Runs an administrative command given on the command line, such as
+/// `migrate`, and returns the exit code.
+#[must_use]
+pub fn run_command(args: &[String]) -> i32 {
+    command::run(args)
+}
+
 /// Creates and runs an Actix server.
 ///
 /// # Errors
@@ -18,18 +26,20 @@ use anyhow::{Context, Result};
 /// not set:
 ///
 /// * `DATABASE_URL`
-/// * `KAFKA_URL`
+/// * `KAFKA_BROKERS` (or `KAFKA_URL`)
 /// * `REVIEWD_ADDR`
 ///
-/// or when it fails to run start an Actix server.
+/// or if any of the optional `KAFKA_*` or `REVIEWD_*` variables is invalid, or
+/// when it fails to run start an Actix server.
 pub fn init() -> Result<Server> {
     dotenv::dotenv().ok();
     let database_url = std::env::var("DATABASE_URL").context("DATABASE_URL is not set")?;
     let reviewd_addr = std::env::var("REVIEWD_ADDR").context("REVIEWD_ADDR is not set")?;
-    let kafka_url = std::env::var("KAFKA_URL").context("KAFKA_URL is not set")?;
+    let kafka = kafka_consumer::BrokerConfig::from_env()?;
+    let tls = server::TlsConfig::from_env()?;
     let reviewd_addr = reviewd_addr
         .parse::<std::net::SocketAddr>()
         .with_context(|| format!("invalid IP address/port for review: {}", reviewd_addr))?;
 
-    Ok(server::run(&database_url, &reviewd_addr, kafka_url).context("failed to create server")?)
+    Ok(server::run(&database_url, &reviewd_addr, kafka, tls).context("failed to create server")?)
 }
@@ -1,14 +1,19 @@
 use std::io;
 
-#[actix_rt::main]
-async fn main() -> io::Result<()> {
+fn main() -> io::Result<()> {
     env_logger::init();
-    let server = match review::init() {
-        Ok(server) => server,
-        Err(e) => {
-            log::error!("{:#}", e);
-            std::process::exit(1);
-        }
-    };
-    server.await
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if !args.is_empty() {
+        std::process::exit(review::run_command(&args));
+    }
+    actix_rt::System::new("review").block_on(async {
+        let server = match review::init() {
+            Ok(server) => server,
+            Err(e) => {
+                log::error!("{:#}", e);
+                std::process::exit(1);
+            }
+        };
+        server.await
+    })
 }
@@ -1,20 +1,37 @@
 use actix_files::Files;
 use actix_web::{
-    dev::Server,
+    dev::{Server, Service, ServiceResponse},
+    http::header::{self, HeaderName, HeaderValue},
     middleware,
-    web::{Data, JsonConfig},
-    App, HttpServer, Result,
+    web::{self, Data, JsonConfig, PathConfig, QueryConfig},
+    App, HttpResponse, HttpServer, Result,
 };
-use bigdecimal::FromPrimitive;
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool};
+use futures::future::{self, Either};
 use std::io;
+use std::net::TcpListener;
 use thiserror::Error;
 
-use crate::database::update_event_ids;
+use crate::database::{
+    compress_existing_raw_events, periodically_apply_retention_policies,
+    periodically_broadcast_changes, periodically_compute_minhashes, periodically_index_raw_events,
+    run_migrations, update_event_ids, ApiError, ChangeBroadcaster, Database,
+};
 use crate::kafka_consumer;
 
+mod openapi;
+mod request_id;
 mod route;
+mod tls;
+mod token;
+
+use self::openapi::RequestValidator;
+use self::request_id::RequestId;
+
+pub(crate) use self::tls::TlsConfig;
+use self::tls::{reload_certificate, IngestGuard};
+use self::token::{periodically_reload_tokens, TokenGuard};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -23,23 +40,24 @@ pub enum Error {
     #[error("could not connect to database: {0}")]
     DatabaseConnection(r2d2::Error),
     #[error("could not initialize/migrate database: {0}")]
-    DatabaseMigration(diesel_migrations::RunMigrationsError),
+    DatabaseMigration(crate::database::Error),
     #[error("could not create a database conenction pool: {0}")]
     PoolInitialization(r2d2::Error),
+    #[error("could not configure TLS: {0:#}")]
+    Tls(anyhow::Error),
 }
 
-embed_migrations!();
-
 #[allow(clippy::mutex_atomic)]
-pub fn run(
+pub(crate) fn run(
     database_url: &str,
     reviewd_addr: &std::net::SocketAddr,
-    kafka_url: String,
+    kafka: kafka_consumer::BrokerConfig,
+    tls: Option<TlsConfig>,
 ) -> Result<Server, Error> {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     let pool = Pool::new(manager).map_err(Error::PoolInitialization)?;
     let conn = pool.get().map_err(Error::DatabaseConnection)?;
-    embedded_migrations::run(&conn).map_err(Error::DatabaseMigration)?;
+    run_migrations(&conn).map_err(Error::DatabaseMigration)?;
 
     let frontend_path = if let Ok(path) = std::env::var("FRONTEND_DIR") {
         path
@@ -47,9 +65,47 @@ pub fn run(
         eprintln!("Warning: FRONTEND_DIR is not set. Will use the current directory.");
         ".".to_string()
     };
+    // With TLS, the periodic Kafka task calls the API through a plain HTTP
+    // listener on `REVIEWD_INTERNAL_ADDR`, which needs no certificate.
+    let mut api_addr = *reviewd_addr;
+    let mut ingest_guard = IngestGuard::default();
+    let mut token_guard = TokenGuard::default();
+    let tls = if let Some(tls) = tls {
+        let resolver = tls.load_certificate().map_err(Error::Tls)?;
+        let internal = TcpListener::bind(tls.internal_addr()).map_err(Error::Bind)?;
+        api_addr = internal.local_addr().map_err(Error::Bind)?;
+        let (ingest, ingest_addr) = match tls.ingest_config(&resolver).map_err(Error::Tls)? {
+            Some((addr, config)) => {
+                let listener = TcpListener::bind(addr).map_err(Error::Bind)?;
+                let ingest_addr = listener.local_addr().map_err(Error::Bind)?;
+                ingest_guard = IngestGuard::new(vec![ingest_addr, api_addr]);
+                (Some((listener, config)), Some(ingest_addr))
+            }
+            None => (None, None),
+        };
+        // The internal and the ingest listeners authenticate their clients
+        // otherwise.
+        if tls.require_token() {
+            token_guard = TokenGuard::new(ingest_addr.into_iter().chain(Some(api_addr)).collect());
+            if let Err(e) = token_guard.reload(&conn) {
+                log::error!("Failed to load API tokens: {}", e);
+            }
+            let pool = pool.clone();
+            let token_guard = token_guard.clone();
+            std::thread::spawn(move || periodically_reload_tokens(&pool, &token_guard));
+        }
+        let config = TlsConfig::server_config(&resolver);
+        let interval = tls.reload_interval();
+        std::thread::spawn(move || reload_certificate(&resolver, interval));
+        Some((config, internal, ingest))
+    } else {
+        None
+    };
+
+    let broker = Data::new(kafka.clone());
     let config = kafka_consumer::KafkaConfig::new(
-        kafka_url,
-        reviewd_addr.to_string(),
+        kafka,
+        api_addr.to_string(),
         std::env::var("TASK_TIME_INTERVAL")
             .ok()
             .and_then(|v| v.parse::<u64>().ok()),
@@ -64,6 +120,38 @@ pub fn run(
                 .block_on(kafka_consumer::KafkaConfig::periodically_fetch_kafka_message(config));
         });
     }
+    {
+        let pool = pool.clone();
+        std::thread::spawn(move || compress_existing_raw_events(&pool));
+    }
+    {
+        let pool = pool.clone();
+        std::thread::spawn(move || periodically_index_raw_events(&pool));
+    }
+    {
+        let pool = pool.clone();
+        std::thread::spawn(move || periodically_compute_minhashes(&pool));
+    }
+    let retention_interval = std::env::var("RETENTION_INTERVAL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_else(|| 3600);
+    if retention_interval != 0 {
+        let pool = pool.clone();
+        std::thread::spawn(move || {
+            periodically_apply_retention_policies(
+                &pool,
+                std::time::Duration::from_secs(retention_interval),
+            );
+        });
+    }
+    let broadcaster = ChangeBroadcaster::default();
+    {
+        let pool = pool.clone();
+        let broadcaster = broadcaster.clone();
+        std::thread::spawn(move || periodically_broadcast_changes(&pool, &broadcaster));
+    }
+    let broadcaster = Data::new(broadcaster);
     let max_event_id_num = std::env::var("MAX_EVENT_ID_NUM")
         .ok()
         .and_then(|v| v.parse::<usize>().ok())
@@ -72,21 +160,124 @@ pub fn run(
         "The maximum number of event_ids per cluster/outlier is {}",
         max_event_id_num
     );
-    if let (Ok(conn), Some(num)) = (pool.get(), FromPrimitive::from_usize(max_event_id_num)) {
-        let _ = update_event_ids(&conn, num);
+    if let Ok(conn) = pool.get() {
+        if let Err(e) = update_event_ids(&conn, max_event_id_num) {
+            log::error!("{}", e);
+        }
     }
     let max_event_id_num = Data::new(std::sync::Mutex::new(max_event_id_num));
+    let database_max_pending = std::env::var("DATABASE_MAX_PENDING")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or_else(|| 256);
+    let database_timeout = std::env::var("DATABASE_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_else(|| 30);
+    let database = Database::new(
+        pool,
+        database_max_pending,
+        std::time::Duration::from_secs(database_timeout),
+    );
+    let validator = RequestValidator::default();
     let server = HttpServer::new(move || {
+        let validator = validator.clone();
+        let ingest_guard = ingest_guard.clone();
+        let token_guard = token_guard.clone();
         App::new()
-            .data(JsonConfig::default().limit(1_048_576))
-            .data(pool.clone())
+            .data(
+                JsonConfig::default()
+                    .limit(1_048_576)
+                    .error_handler(route::invalid_request),
+            )
+            .data(PathConfig::default().error_handler(route::invalid_request))
+            .data(QueryConfig::default().error_handler(route::invalid_request))
+            .data(database.clone())
             .app_data(max_event_id_num.clone())
-            .configure(route::init_app)
+            .app_data(broker.clone())
+            .app_data(broadcaster.clone())
+            .service(
+                web::scope("/api/v1")
+                    .configure(route::init_app)
+                    .default_service(web::route().to(route::unknown_endpoint)),
+            )
+            // The paths before `/api/v1`, kept until clients move to it.
+            .service(
+                web::scope("/api")
+                    .configure(route::init_app)
+                    .default_service(web::route().to(route::unknown_endpoint))
+                    .wrap_fn(|req, srv| {
+                        let successor = format!(
+                            "/api/v1{}",
+                            req.path().strip_prefix("/api").unwrap_or_default()
+                        );
+                        let res = srv.call(req);
+                        async move {
+                            let mut res = res.await?;
+                            mark_deprecated(&mut res, &successor);
+                            Ok::<_, actix_web::Error>(res)
+                        }
+                    }),
+            )
             .service(Files::new("/", frontend_path.as_str()).index_file("index.html"))
-            .wrap(middleware::Logger::default())
-    })
-    .bind(reviewd_addr)
-    .map_err(Error::Bind)?
-    .run();
-    Ok(server)
+            .wrap(validator)
+            .wrap_fn(move |req, srv| {
+                if ingest_guard.allows(&req) {
+                    Either::Left(srv.call(req))
+                } else {
+                    Either::Right(future::ok(req.into_response(ApiError::forbidden(
+                        "A client certificate is required. Use the ingest address.",
+                    ))))
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                if token_guard.allows(&req) {
+                    Either::Left(srv.call(req))
+                } else {
+                    let mut res: HttpResponse =
+                        ApiError::unauthorized("A valid API token is required.").into();
+                    res.headers_mut()
+                        .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                    Either::Right(future::ok(req.into_response(res)))
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let request_id = RequestId::of(&req);
+                let res = srv.call(req);
+                async move { Ok::<_, actix_web::Error>(request_id.finish(res.await?)) }
+            })
+            .wrap(middleware::Logger::new(
+                "%a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T request_id=%{x-request-id}o",
+            ))
+    });
+    let server = if let Some((config, internal, ingest)) = tls {
+        let mut server = server
+            .bind_rustls(reviewd_addr, config)
+            .map_err(Error::Bind)?
+            .listen(internal)
+            .map_err(Error::Bind)?;
+        if let Some((listener, config)) = ingest {
+            server = server
+                .listen_rustls(listener, config)
+                .map_err(Error::Bind)?;
+        }
+        server
+    } else {
+        server.bind(reviewd_addr).map_err(Error::Bind)?
+    };
+    Ok(server.run())
+}
+
+/// Marks a response to a deprecated path, with a link to the path replacing
+/// it.
+fn mark_deprecated(res: &mut ServiceResponse, successor: &str) {
+    let headers = res.headers_mut();
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static("true"),
+    );
+    if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+    {
+        headers.insert(header::LINK, link);
+    }
 }
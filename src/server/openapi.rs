@@ -0,0 +1,733 @@
+use actix_web::dev::{Body, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::Method;
+use actix_web::web::BytesMut;
+use actix_web::HttpResponse;
+use futures::future::{self, LocalBoxFuture, Ready};
+use futures::{stream, StreamExt};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::database::ApiError;
+
+/// The document of the API, generated by the build script from the routes,
+/// the types of their parameters, and `docs/openapi.yaml`.
+pub(crate) const OPENAPI: &str = include_str!(concat!(env!("OUT_DIR"), "/openapi.json"));
+
+/// The prefixes the paths in the document are served under.
+const PREFIXES: [&str; 2] = ["/api/v1", "/api"];
+
+/// The depth at which the references in a schema are no longer followed.
+const MAX_SCHEMA_DEPTH: usize = 16;
+
+/// Returns the document of the API.
+pub(super) async fn get_openapi() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(OPENAPI)
+}
+
+/// Checks the path, the query parameters, and the JSON body of a request
+/// against the operation that describes it in the document of the API.
+#[derive(Clone)]
+pub(crate) struct RequestValidator {
+    operations: Arc<Vec<Operation>>,
+}
+
+struct Operation {
+    method: Method,
+    segments: Vec<Segment>,
+    parameters: Vec<Parameter>,
+    body: Option<RequestBody>,
+}
+
+enum Segment {
+    Literal(String),
+    Parameter(String),
+}
+
+struct Parameter {
+    name: String,
+    in_path: bool,
+    required: bool,
+    kind: Kind,
+    /// Whether the parameter can be given more than once.
+    repeated: bool,
+    allowed: Option<Vec<String>>,
+}
+
+/// The JSON body of an operation.
+struct RequestBody {
+    required: bool,
+    schema: Schema,
+}
+
+/// The schema of a JSON value, with its references resolved.
+struct Schema {
+    shape: Shape,
+    nullable: bool,
+    allowed: Option<Vec<Value>>,
+}
+
+enum Shape {
+    Any,
+    Primitive(Kind),
+    Array(Box<Schema>),
+    Object {
+        properties: Vec<(String, Schema)>,
+        required: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Integer,
+    Number,
+    Boolean,
+    String,
+}
+
+impl Default for RequestValidator {
+    fn default() -> Self {
+        let document: Value =
+            serde_json::from_str(OPENAPI).expect("the document of the API is valid JSON");
+        Self::new(&document)
+    }
+}
+
+impl RequestValidator {
+    /// Reads the operations under `paths` of `document`.
+    pub(crate) fn new(document: &Value) -> Self {
+        let mut operations = Vec::new();
+        if let Some(paths) = document["paths"].as_object() {
+            for (path, methods) in paths {
+                let segments = segments_of(path.strip_prefix(PREFIXES[0]).unwrap_or(path));
+                let methods = match methods.as_object() {
+                    Some(methods) => methods,
+                    None => continue,
+                };
+                for (method, operation) in methods {
+                    let method = match Method::from_bytes(method.to_uppercase().as_bytes()) {
+                        Ok(method) => method,
+                        Err(_) => continue,
+                    };
+                    let parameters = operation["parameters"]
+                        .as_array()
+                        .map(|parameters| parameters.iter().filter_map(Parameter::new).collect())
+                        .unwrap_or_default();
+                    operations.push(Operation {
+                        method,
+                        segments: segments
+                            .iter()
+                            .map(|s| {
+                                if s.starts_with('{') && s.ends_with('}') {
+                                    Segment::Parameter(s[1..s.len() - 1].to_string())
+                                } else {
+                                    Segment::Literal((*s).to_string())
+                                }
+                            })
+                            .collect(),
+                        parameters,
+                        body: RequestBody::new(operation, &document["definitions"]),
+                    });
+                }
+            }
+        }
+        Self {
+            operations: Arc::new(operations),
+        }
+    }
+
+    /// Returns the error in the parameters or the body of `req`, if any. The
+    /// body is read to check it, and put back for the handler. Requests no
+    /// operation describes are left to the router.
+    pub(crate) async fn validate(&self, req: &mut ServiceRequest) -> Result<(), ApiError> {
+        let path = match PREFIXES
+            .iter()
+            .filter_map(|prefix| req.path().strip_prefix(prefix))
+            .find(|path| path.is_empty() || path.starts_with('/'))
+        {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let segments = segments_of(path);
+        let operation = match self.find(req.method(), &segments) {
+            Some(operation) => operation,
+            None => return Ok(()),
+        };
+        operation.check_parameters(req, &segments)?;
+
+        if let Some(body) = &operation.body {
+            let mut payload = req.take_payload();
+            let mut bytes = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk.map_err(|e| ApiError::bad_request(e.to_string()))?;
+                bytes.extend_from_slice(&chunk);
+            }
+            let bytes = bytes.freeze();
+            body.check(&bytes)?;
+            let chunk = future::ok::<_, PayloadError>(bytes);
+            req.set_payload(Payload::Stream(Box::pin(stream::once(chunk))));
+        }
+        Ok(())
+    }
+
+    /// Returns the operation for `method` on the path of `segments`. A literal
+    /// segment takes precedence over a parameter, as in the router.
+    fn find(&self, method: &Method, segments: &[&str]) -> Option<&Operation> {
+        self.operations
+            .iter()
+            .filter(|operation| {
+                operation.method == method
+                    && operation.segments.len() == segments.len()
+                    && operation
+                        .segments
+                        .iter()
+                        .zip(segments)
+                        .all(|(segment, value)| match segment {
+                            Segment::Literal(literal) => literal == value,
+                            Segment::Parameter(_) => true,
+                        })
+            })
+            .max_by_key(|operation| {
+                operation
+                    .segments
+                    .iter()
+                    .filter(|segment| matches!(segment, Segment::Literal(_)))
+                    .count()
+            })
+    }
+}
+
+impl<S> Transform<S> for RequestValidator
+where
+    S: Service<
+            Request = ServiceRequest,
+            Response = ServiceResponse<Body>,
+            Error = actix_web::Error,
+        > + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = ValidatingService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ok(ValidatingService {
+            validator: self.clone(),
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+
+/// The service that rejects the requests `RequestValidator` finds invalid.
+pub(crate) struct ValidatingService<S> {
+    validator: RequestValidator,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S> Service for ValidatingService<S>
+where
+    S: Service<
+            Request = ServiceRequest,
+            Response = ServiceResponse<Body>,
+            Error = actix_web::Error,
+        > + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let validator = self.validator.clone();
+        let service = self.service.clone();
+        Box::pin(async move {
+            if let Err(e) = validator.validate(&mut req).await {
+                return Ok(req.into_response(e));
+            }
+            let res = service.borrow_mut().call(req);
+            res.await
+        })
+    }
+}
+
+impl Operation {
+    fn check_parameters(&self, req: &ServiceRequest, segments: &[&str]) -> Result<(), ApiError> {
+        let mut path_values = HashMap::new();
+        for (segment, value) in self.segments.iter().zip(segments) {
+            if let Segment::Parameter(name) = segment {
+                path_values.insert(name.as_str(), *value);
+            }
+        }
+        let mut query_values: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, value) in url::form_urlencoded::parse(req.query_string().as_bytes()) {
+            query_values
+                .entry(name.into_owned())
+                .or_default()
+                .push(value.into_owned());
+        }
+
+        for parameter in &self.parameters {
+            if parameter.in_path {
+                if let Some(value) = path_values.get(parameter.name.as_str()) {
+                    parameter.check(value)?;
+                }
+                continue;
+            }
+            match query_values.get(&parameter.name) {
+                Some(values) => {
+                    if values.len() > 1 && !parameter.repeated {
+                        return Err(ApiError::invalid(
+                            &parameter.name,
+                            format!("{} must be given once", parameter.name),
+                        ));
+                    }
+                    for value in values {
+                        parameter.check(value)?;
+                    }
+                }
+                None if parameter.required => {
+                    return Err(ApiError::invalid(
+                        &parameter.name,
+                        format!("Missing {}", parameter.name),
+                    ));
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Parameter {
+    /// Reads a path or a query parameter. Others are not validated.
+    fn new(parameter: &Value) -> Option<Self> {
+        let in_path = match parameter["in"].as_str()? {
+            "path" => true,
+            "query" => false,
+            _ => return None,
+        };
+        let repeated = parameter["type"] == "array";
+        let schema = if repeated {
+            &parameter["items"]
+        } else {
+            parameter
+        };
+        let kind = match schema["type"].as_str() {
+            Some("integer") => Kind::Integer,
+            Some("number") => Kind::Number,
+            Some("boolean") => Kind::Boolean,
+            _ => Kind::String,
+        };
+        let allowed = schema["enum"].as_array().map(|values| {
+            values
+                .iter()
+                .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                .collect()
+        });
+        Some(Self {
+            name: parameter["name"].as_str()?.to_string(),
+            in_path,
+            required: parameter["required"].as_bool().unwrap_or(in_path),
+            kind,
+            repeated,
+            allowed,
+        })
+    }
+
+    fn check(&self, value: &str) -> Result<(), ApiError> {
+        let valid = match self.kind {
+            Kind::Integer => value.parse::<i64>().is_ok() || value.parse::<u64>().is_ok(),
+            Kind::Number => value.parse::<f64>().is_ok(),
+            Kind::Boolean => value == "true" || value == "false",
+            Kind::String => true,
+        };
+        if !valid {
+            return Err(ApiError::invalid(
+                &self.name,
+                format!("{} must be {}", self.name, self.kind),
+            ));
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.iter().any(|a| a == value) {
+                return Err(ApiError::invalid(
+                    &self.name,
+                    format!("{} must be one of {}", self.name, allowed.join(", ")),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RequestBody {
+    /// Reads the body parameter of `operation` if it takes JSON. Other bodies,
+    /// such as that of a dictionary, are left to their handlers.
+    fn new(operation: &Value, definitions: &Value) -> Option<Self> {
+        let takes_json = operation["consumes"]
+            .as_array()
+            .map_or(true, |types| types.iter().any(|t| t == "application/json"));
+        if !takes_json {
+            return None;
+        }
+        let parameter = operation["parameters"]
+            .as_array()?
+            .iter()
+            .find(|parameter| parameter["in"] == "body")?;
+        Some(Self {
+            required: parameter["required"].as_bool().unwrap_or_default(),
+            schema: Schema::new(&parameter["schema"], definitions, 0),
+        })
+    }
+
+    fn check(&self, bytes: &[u8]) -> Result<(), ApiError> {
+        if bytes.iter().all(u8::is_ascii_whitespace) {
+            if self.required {
+                return Err(ApiError::invalid("body", "Missing body"));
+            }
+            return Ok(());
+        }
+        let value: Value = serde_json::from_slice(bytes).map_err(ApiError::unparsable)?;
+        self.schema.check(&value, "body", false)
+    }
+}
+
+impl Schema {
+    fn new(schema: &Value, definitions: &Value, depth: usize) -> Self {
+        if depth > MAX_SCHEMA_DEPTH {
+            return Self::any();
+        }
+        if let Some(reference) = schema["$ref"].as_str() {
+            return match reference.strip_prefix("#/definitions/") {
+                Some(name) => Self::new(&definitions[name], definitions, depth + 1),
+                None => Self::any(),
+            };
+        }
+        let shape = match schema["type"].as_str() {
+            Some("integer") => Shape::Primitive(Kind::Integer),
+            Some("number") => Shape::Primitive(Kind::Number),
+            Some("boolean") => Shape::Primitive(Kind::Boolean),
+            Some("string") => Shape::Primitive(Kind::String),
+            Some("array") => Shape::Array(Box::new(Self::new(
+                &schema["items"],
+                definitions,
+                depth + 1,
+            ))),
+            Some("object") => Shape::object(schema, definitions, depth),
+            None if schema["properties"].is_object() => Shape::object(schema, definitions, depth),
+            _ => Shape::Any,
+        };
+        Self {
+            shape,
+            nullable: schema["x-nullable"].as_bool().unwrap_or_default(),
+            allowed: schema["enum"].as_array().cloned(),
+        }
+    }
+
+    fn any() -> Self {
+        Self {
+            shape: Shape::Any,
+            nullable: true,
+            allowed: None,
+        }
+    }
+
+    /// Checks `value` found at `path`. A property that is not required may be
+    /// null, as an `Option` field of the handler accepts it.
+    fn check(&self, value: &Value, path: &str, optional: bool) -> Result<(), ApiError> {
+        if value.is_null() {
+            if self.nullable || optional || matches!(self.shape, Shape::Any) {
+                return Ok(());
+            }
+            return Err(ApiError::invalid(
+                path,
+                format!("{} must not be null", path),
+            ));
+        }
+        match &self.shape {
+            Shape::Any => {}
+            Shape::Primitive(kind) => {
+                if !kind.matches(value) {
+                    return Err(ApiError::invalid(
+                        path,
+                        format!("{} must be {}", path, kind),
+                    ));
+                }
+            }
+            Shape::Array(items) => match value.as_array() {
+                Some(values) => {
+                    for (i, value) in values.iter().enumerate() {
+                        items.check(value, &format!("{}[{}]", path, i), false)?;
+                    }
+                }
+                None => {
+                    return Err(ApiError::invalid(
+                        path,
+                        format!("{} must be an array", path),
+                    ));
+                }
+            },
+            Shape::Object {
+                properties,
+                required,
+            } => match value.as_object() {
+                Some(object) => {
+                    for name in required {
+                        if !object.contains_key(name) {
+                            let path = format!("{}.{}", path, name);
+                            return Err(ApiError::invalid(&path, format!("Missing {}", path)));
+                        }
+                    }
+                    for (name, schema) in properties {
+                        if let Some(value) = object.get(name) {
+                            let optional = !required.contains(name);
+                            schema.check(value, &format!("{}.{}", path, name), optional)?;
+                        }
+                    }
+                }
+                None => {
+                    return Err(ApiError::invalid(
+                        path,
+                        format!("{} must be an object", path),
+                    ));
+                }
+            },
+        }
+        if let Some(allowed) = &self.allowed {
+            if !allowed.contains(value) {
+                let allowed = allowed
+                    .iter()
+                    .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                    .collect::<Vec<_>>();
+                return Err(ApiError::invalid(
+                    path,
+                    format!("{} must be one of {}", path, allowed.join(", ")),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Shape {
+    fn object(schema: &Value, definitions: &Value, depth: usize) -> Self {
+        let properties = schema["properties"]
+            .as_object()
+            .map(|properties| {
+                properties
+                    .iter()
+                    .map(|(name, property)| {
+                        (name.clone(), Schema::new(property, definitions, depth + 1))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let required = schema["required"]
+            .as_array()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| name.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Shape::Object {
+            properties,
+            required,
+        }
+    }
+}
+
+impl Kind {
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            Kind::Integer => value.is_i64() || value.is_u64(),
+            Kind::Number => value.is_number(),
+            Kind::Boolean => value.is_boolean(),
+            Kind::String => value.is_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Kind::Integer => "an integer",
+            Kind::Number => "a number",
+            Kind::Boolean => "true or false",
+            Kind::String => "a string",
+        })
+    }
+}
+
+fn segments_of(path: &str) -> Vec<&str> {
+    path.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn body_of(schema: &Value, definitions: &Value) -> RequestBody {
+        RequestBody {
+            required: true,
+            schema: Schema::new(schema, definitions, 0),
+        }
+    }
+
+    fn tag_body() -> RequestBody {
+        body_of(
+            &json!({"type": "array", "items": {"$ref": "#/definitions/OutlierTagBody"}}),
+            &json!({"OutlierTagBody": {
+                "type": "object",
+                "required": ["id", "tags"],
+                "properties": {
+                    "id": {"type": "integer", "format": "uint32"},
+                    "tags": {"type": "array", "items": {"type": "string"}},
+                    "note": {"type": "string"},
+                    "kind": {"type": "string", "enum": ["a", "b"]}
+                }
+            }}),
+        )
+    }
+
+    fn error_of(body: &RequestBody, bytes: &[u8]) -> (Option<String>, String) {
+        let e = body.check(bytes).expect_err("invalid body");
+        (e.field_name().map(str::to_string), e.message().to_string())
+    }
+
+    #[test]
+    fn valid_bodies_pass() {
+        let body = tag_body();
+        assert!(body.check(br"[]").is_ok());
+        assert!(body
+            .check(br#"[{"id": 1, "tags": ["x"], "note": null, "extra": true}]"#)
+            .is_ok());
+        assert!(body
+            .check(br#"[{"id": 2, "tags": [], "kind": "b"}]"#)
+            .is_ok());
+    }
+
+    #[test]
+    fn missing_required_property() {
+        let (field, message) = error_of(&tag_body(), br#"[{"id": 1, "tags": []}, {"id": 2}]"#);
+        assert_eq!(field.as_deref(), Some("body[1].tags"));
+        assert_eq!(message, "Missing body[1].tags");
+    }
+
+    #[test]
+    fn wrong_primitive_type() {
+        let body = tag_body();
+        let (field, message) = error_of(&body, br#"[{"id": "1", "tags": []}]"#);
+        assert_eq!(field.as_deref(), Some("body[0].id"));
+        assert_eq!(message, "body[0].id must be an integer");
+
+        let (field, _) = error_of(&body, br#"[{"id": 1.5, "tags": []}]"#);
+        assert_eq!(field.as_deref(), Some("body[0].id"));
+
+        let (field, message) = error_of(&body, br#"[{"id": 1, "tags": ["x", 2]}]"#);
+        assert_eq!(field.as_deref(), Some("body[0].tags[1]"));
+        assert_eq!(message, "body[0].tags[1] must be a string");
+
+        let (_, message) = error_of(&body, br#"{"id": 1, "tags": []}"#);
+        assert_eq!(message, "body must be an array");
+    }
+
+    #[test]
+    fn null_only_for_optional_or_nullable() {
+        let body = tag_body();
+        let (_, message) = error_of(&body, br#"[{"id": null, "tags": []}]"#);
+        assert_eq!(message, "body[0].id must not be null");
+
+        let nullable = body_of(
+            &json!({"type": "object", "required": ["parent"], "properties": {
+                "parent": {"type": "string", "x-nullable": true}
+            }}),
+            &Value::Null,
+        );
+        assert!(nullable.check(br#"{"parent": null}"#).is_ok());
+    }
+
+    #[test]
+    fn value_not_in_enum() {
+        let (field, message) = error_of(&tag_body(), br#"[{"id": 1, "tags": [], "kind": "c"}]"#);
+        assert_eq!(field.as_deref(), Some("body[0].kind"));
+        assert_eq!(message, "body[0].kind must be one of a, b");
+    }
+
+    #[test]
+    fn missing_or_unparsable_body() {
+        let body = tag_body();
+        let (field, message) = error_of(&body, b" ");
+        assert_eq!(field.as_deref(), Some("body"));
+        assert_eq!(message, "Missing body");
+        assert!(body.check(b"[{").is_err());
+    }
+
+    #[test]
+    fn recursive_definitions_end() {
+        let body = body_of(
+            &json!({"$ref": "#/definitions/Node"}),
+            &json!({"Node": {"type": "object", "properties": {
+                "children": {"type": "array", "items": {"$ref": "#/definitions/Node"}}
+            }}}),
+        );
+        assert!(body.check(br#"{"children": [{"children": []}]}"#).is_ok());
+        assert!(body.check(br#"{"children": [{"children": 1}]}"#).is_err());
+    }
+
+    #[test]
+    fn only_json_bodies_are_checked() {
+        let operation = json!({
+            "consumes": ["application/octet-stream"],
+            "parameters": [{"in": "body", "name": "dictionary", "schema": {"type": "string"}}]
+        });
+        assert!(RequestBody::new(&operation, &Value::Null).is_none());
+        let operation = json!({
+            "parameters": [{"in": "body", "name": "outliers", "schema": {"type": "array"}}]
+        });
+        assert!(RequestBody::new(&operation, &Value::Null).is_some());
+    }
+
+    #[test]
+    fn document_bodies_are_valid() {
+        let document: Value = serde_json::from_str(OPENAPI).expect("valid JSON");
+        let validator = RequestValidator::new(&document);
+        let put_outlier = validator
+            .find(&Method::PUT, &["outlier"])
+            .and_then(|operation| operation.body.as_ref())
+            .expect("PUT /outlier takes a body");
+        assert!(put_outlier
+            .check(
+                br#"[{"id": 1, "outlier": [104, 105], "data_source": "s",
+                      "data_source_type": "log", "event_ids": [1, 2], "size": 2}]"#
+            )
+            .is_ok());
+        let delete_outlier = validator
+            .find(&Method::DELETE, &["outlier"])
+            .and_then(|operation| operation.body.as_ref())
+            .expect("DELETE /outlier takes a body");
+        assert!(delete_outlier.check(br"[[104, 105]]").is_ok());
+        let put_event = validator
+            .find(&Method::PUT, &["event"])
+            .and_then(|operation| operation.body.as_ref())
+            .expect("PUT /event takes a body");
+        assert!(put_event
+            .check(br#"[{"message_id": "12", "data_source_id": 1, "raw_event": null}]"#)
+            .is_ok());
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::dev::{Body, ResponseBody, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use log::{error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::database::ApiError;
+
+/// The header with the id of a request. A client may give its own id, which is
+/// used if valid; the id is set on every response.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The maximum length of an id given by a client.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// The id of a request, used in the log and in the body of an error.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestId(String);
+
+impl RequestId {
+    /// Returns the id given in `X-Request-Id`, or a new one.
+    pub(crate) fn of(req: &ServiceRequest) -> Self {
+        let given = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| is_valid(v));
+        Self(given.map_or_else(generate, str::to_string))
+    }
+
+    /// Sets the id on the response. If the response is an `ApiError`, the id
+    /// is added to its body, and the cause of the error is logged with it.
+    pub(crate) fn finish(&self, res: ServiceResponse<Body>) -> ServiceResponse<Body> {
+        let api_error = res.response().extensions().get::<ApiError>().cloned();
+        let mut res = match api_error {
+            Some(e) => {
+                if let Some(detail) = e.detail() {
+                    if e.status().is_server_error() {
+                        error!("request {}: {}", self.0, detail);
+                    } else {
+                        info!("request {}: {}", self.0, detail);
+                    }
+                }
+                let body = e.body_with_request_id(&self.0);
+                res.map_body(|_, _| ResponseBody::Other(Body::from(body)))
+            }
+            None => res,
+        };
+        if let Ok(value) = HeaderValue::from_str(&self.0) {
+            res.headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+        res
+    }
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Returns the time in milliseconds and a counter, which keeps ids unique
+/// within a process and unlikely to repeat across restarts.
+fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    format!("{:x}-{:x}", millis, COUNTER.fetch_add(1, Ordering::Relaxed))
+}
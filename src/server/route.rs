@@ -1,237 +1,370 @@
 use actix_web::{
-    error, guard,
+    guard,
     web::{delete, get, post, put, resource, Json, PathConfig, Query, ServiceConfig},
-    FromRequest, HttpResponse,
+    FromRequest, HttpRequest, HttpResponse,
 };
 use serde_json::Value;
+use std::fmt;
 
+use super::openapi::get_openapi;
 use crate::database::*;
 
 #[allow(clippy::too_many_lines)]
 pub(crate) fn init_app(cfg: &mut ServiceConfig) {
     cfg.service(
-        resource("/api/category")
+        resource("/category")
             .guard(guard::Any(guard::Get()).or(guard::Post()))
             .route(get().to(get_category_table))
             .data(Query::<NewCategory>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(post().to(add_category)),
     )
     .service(
-        resource("/api/category/{category}")
-            .guard(guard::Put())
+        resource("/category/merge")
+            .guard(guard::Post())
             .guard(guard::Header("content-type", "application/json"))
-            .data(PathConfig::default().error_handler(|err, _| {
-                error::InternalError::from_response(err, HttpResponse::BadRequest().finish()).into()
+            .data(Json::<CategoryMerge>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
             }))
-            .data(Json::<NewCategory>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+            .route(post().to(merge_categories)),
+    )
+    .service(
+        resource("/category/{category}")
+            .guard(guard::Delete())
+            .data(PathConfig::default().error_handler(invalid_request))
+            .data(Query::<CategoryDeleteQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(delete().to(delete_category)),
+    )
+    .service(
+        resource("/category/{category}")
+            .guard(guard::Put())
+            .guard(guard::Header("content-type", "application/json"))
+            .data(PathConfig::default().error_handler(invalid_request))
+            .data(Json::<CategoryUpdate>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
             }))
             .route(put().to(update_category)),
     )
     .service(
-        resource("/api/cluster")
+        resource("/change")
+            .guard(guard::Get())
+            .data(Query::<ChangeStreamQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(get().to(get_change_stream)),
+    )
+    .service(
+        resource("/cluster")
             .guard(guard::Get())
             .route(get().to(get_clusters)),
     )
     .service(
-        resource("/api/cluster")
+        resource("/cluster")
             .guard(guard::Put())
             .guard(guard::Header("content-type", "application/json"))
             .route(put().to(update_clusters)),
     )
     .service(
-        resource("/api/cluster/qualifier")
+        resource("/cluster/qualifier")
             .guard(guard::Put())
             .guard(guard::Header("content-type", "application/json"))
             .route(put().to(update_qualifiers)),
     )
     .service(
-        resource("/api/cluster/{cluster_id}")
+        resource("/cluster/tag")
+            .guard(guard::Any(guard::Put()).or(guard::Delete()))
             .guard(guard::Header("content-type", "application/json"))
-            .data(PathConfig::default().error_handler(|err, _| {
-                error::InternalError::from_response(err, HttpResponse::BadRequest().finish()).into()
-            }))
+            .route(put().to(add_cluster_tags))
+            .route(delete().to(delete_cluster_tags)),
+    )
+    .service(
+        resource("/cluster/{cluster_id}")
+            .guard(guard::Header("content-type", "application/json"))
+            .data(PathConfig::default().error_handler(invalid_request))
             .data(Query::<Value>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .data(Json::<Value>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(put().to(update_cluster)),
     )
     .service(
-        resource("/api/data_source")
+        resource("/cluster/{cluster_id}/events")
+            .guard(guard::Get())
+            .data(Query::<Value>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(get().to(get_cluster_events)),
+    )
+    .service(
+        resource("/cluster/{cluster_id}/similar")
+            .guard(guard::Get())
+            .data(Query::<SimilarClusterQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(get().to(get_similar_clusters)),
+    )
+    .service(
+        resource("/cluster/{cluster_id}/timeline")
+            .guard(guard::Get())
+            .data(Query::<TimelineQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(get().to(get_cluster_timeline)),
+    )
+    .service(
+        resource("/data_source")
             .guard(guard::Any(guard::Get()).or(guard::Post()))
+            .data(Query::<DataSourceSelectQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
             .route(get().to(get_data_source_table))
             .route(post().to(add_data_source)),
     )
     .service(
-        resource("/api/description")
+        resource("/data_source/{topic_name}")
+            .guard(guard::Delete())
+            .data(PathConfig::default().error_handler(invalid_request))
+            .data(Query::<DataSourceDeleteQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(delete().to(delete_data_source)),
+    )
+    .service(
+        resource("/data_source/{topic_name}")
+            .guard(guard::Put())
+            .guard(guard::Header("content-type", "application/json"))
+            .data(PathConfig::default().error_handler(invalid_request))
+            .data(Json::<DataSourceUpdate>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(put().to(update_data_source)),
+    )
+    .service(
+        resource("/data_source/{topic_name}/dictionary")
+            .guard(guard::Put())
+            .data(PathConfig::default().error_handler(invalid_request))
+            .route(put().to(add_compression_dictionary)),
+    )
+    .service(
+        resource("/data_source/{topic_name}/retention")
+            .guard(guard::Get())
+            .data(PathConfig::default().error_handler(invalid_request))
+            .route(get().to(get_retention_policy)),
+    )
+    .service(
+        resource("/data_source/{topic_name}/retention")
+            .guard(guard::Put())
+            .guard(guard::Header("content-type", "application/json"))
+            .data(PathConfig::default().error_handler(invalid_request))
+            .data(Json::<RetentionPolicy>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(put().to(update_retention_policy)),
+    )
+    .service(
+        resource("/description")
             .guard(guard::Get())
             .route(get().to(get_description)),
     )
     .service(
-        resource("/api/description")
+        resource("/description")
             .guard(guard::Put())
             .guard(guard::Header("content-type", "application/json"))
             .route(put().to(add_descriptions)),
     )
     .service(
-        resource("/api/description/round")
+        resource("/description/round")
             .guard(guard::Get())
             .data(Query::<RoundSelectQuery>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(get().to(get_rounds_by_cluster)),
     )
     .service(
-        resource("/api/event")
+        resource("/event")
             .guard(guard::Put())
             .route(put().to(update_events)),
     )
     .service(
-        resource("/api/event/no_raw_events")
+        resource("/event/no_raw_events")
             .guard(guard::Get())
             .data(Query::<Value>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(get().to(get_events_with_no_raw_event)),
     )
     .service(
-        resource("/api/event/search")
+        resource("/event/search")
             .guard(guard::Get())
             .route(get().to(get_events)),
     )
     .service(
-        resource("/api/event_id")
+        resource("/event_id")
             .guard(guard::Get())
             .route(get().to(get_max_event_id_num)),
     )
     .service(
-        resource("/api/event_id")
+        resource("/event_id")
             .guard(guard::Put())
             .route(put().to(update_max_event_id_num)),
     )
     .service(
-        resource("/api/indicator")
+        resource("/indicator")
             .guard(guard::Post())
             .guard(guard::Header("content-type", "application/json"))
             .data(Json::<Value>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(post().to(add_indicator)),
     )
     .service(
-        resource("/api/indicator")
+        resource("/indicator")
             .guard(guard::Get())
             .route(get().to(get_indicators)),
     )
     .service(
-        resource("/api/indicator")
+        resource("/indicator")
             .guard(guard::Delete())
             .data(Query::<Value>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(delete().to(delete_indicator)),
     )
     .service(
-        resource("/api/indicator/{name}")
+        resource("/indicator/tag")
+            .guard(guard::Any(guard::Put()).or(guard::Delete()))
             .guard(guard::Header("content-type", "application/json"))
-            .data(PathConfig::default().error_handler(|err, _| {
-                error::InternalError::from_response(err, HttpResponse::BadRequest().finish()).into()
-            }))
+            .route(put().to(add_indicator_tags))
+            .route(delete().to(delete_indicator_tags)),
+    )
+    .service(
+        resource("/indicator/{name}")
+            .guard(guard::Header("content-type", "application/json"))
+            .data(PathConfig::default().error_handler(invalid_request))
             .data(Json::<Value>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(put().to(update_indicator)),
     )
     .service(
-        resource("/api/kafka_metadata")
+        resource("/kafka_metadata")
             .guard(guard::Get())
             .route(get().to(get_kafka_metadata)),
     )
     .service(
-        resource("/api/kafka_metadata")
+        resource("/kafka_metadata")
             .guard(guard::Put())
             .data(Json::<Vec<KafkaMetadata>>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(put().to(add_kafka_metadata)),
     )
     .service(
-        resource("/api/outlier")
+        resource("/outlier")
             .guard(guard::Get())
             .route(get().to(get_outliers)),
     )
     .service(
-        resource("/api/outlier")
+        resource("/outlier")
             .guard(guard::Put())
             .guard(guard::Header("content-type", "application/json"))
             .route(put().to(update_outliers)),
     )
     .service(
-        resource("/api/outlier")
+        resource("/outlier")
             .guard(guard::Delete())
             .guard(guard::Header("content-type", "application/json"))
             .data(Query::<DataSourceQuery>::configure(|cfg| {
-                cfg.error_handler(|err, _| {
-                    error::InternalError::from_response(err, HttpResponse::BadRequest().finish())
-                        .into()
-                })
+                cfg.error_handler(invalid_request)
             }))
             .route(delete().to(delete_outliers)),
     )
     .service(
-        resource("/api/qualifier")
+        resource("/outlier/{id}/events")
+            .guard(guard::Get())
+            .data(PathConfig::default().error_handler(invalid_request))
+            .data(Query::<Value>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(get().to(get_outlier_events)),
+    )
+    .service(
+        resource("/outlier/tag")
+            .guard(guard::Any(guard::Put()).or(guard::Delete()))
+            .guard(guard::Header("content-type", "application/json"))
+            .route(put().to(add_outlier_tags))
+            .route(delete().to(delete_outlier_tags)),
+    )
+    .service(
+        resource("/openapi.json")
+            .guard(guard::Get())
+            .route(get().to(get_openapi)),
+    )
+    .service(
+        resource("/qualifier")
             .guard(guard::Get())
             .route(get().to(get_qualifier_table)),
     )
     .service(
-        resource("/api/status")
+        resource("/retention/purge")
+            .guard(guard::Post())
+            .data(Query::<PurgeQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(post().to(purge_data)),
+    )
+    .service(
+        resource("/search")
+            .guard(guard::Get())
+            .data(Query::<SearchQuery>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(get().to(search)),
+    )
+    .service(
+        resource("/status")
             .guard(guard::Get())
             .route(get().to(get_status_table)),
     )
     .service(
-        resource("/api/template")
+        resource("/tag")
+            .guard(guard::Get())
+            .route(get().to(get_tags)),
+    )
+    .service(
+        resource("/tag/{tag}")
+            .guard(guard::Put())
+            .guard(guard::Header("content-type", "application/json"))
+            .data(PathConfig::default().error_handler(invalid_request))
+            .data(Json::<NewTag>::configure(|cfg| {
+                cfg.error_handler(invalid_request)
+            }))
+            .route(put().to(update_tag)),
+    )
+    .service(
+        resource("/template")
             .guard(guard::Get())
             .route(get().to(get_template)),
     )
     .service(
-        resource("/api/template")
+        resource("/template")
             .guard(guard::Post())
             .guard(guard::Header("content-type", "application/json"))
             .route(post().to(add_template)),
     );
 }
+
+/// Reports a query string, a path, or a body that cannot be parsed as
+/// `400 Bad Request`, with the field at fault if known.
+pub(super) fn invalid_request<E: fmt::Display>(err: E, _: &HttpRequest) -> actix_web::Error {
+    ApiError::unparsable(err).into()
+}
+
+pub(super) async fn unknown_endpoint() -> HttpResponse {
+    ApiError::not_found("endpoint").into()
+}
@@ -0,0 +1,330 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use anyhow::{anyhow, Context, Result};
+use log::{error, info};
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert, RootCertStore,
+    ServerConfig,
+};
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often a SIGHUP is checked for.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The HTTPS settings of the server.
+#[derive(Clone, Debug)]
+pub(crate) struct TlsConfig {
+    cert: String,
+    key: String,
+    ingest: Option<IngestConfig>,
+    internal_addr: SocketAddr,
+    reload_interval: Duration,
+    require_token: bool,
+}
+
+/// The listener for the detectors, which have to present a client
+/// certificate signed by `client_ca`.
+#[derive(Clone, Debug)]
+struct IngestConfig {
+    addr: SocketAddr,
+    client_ca: String,
+}
+
+impl TlsConfig {
+    /// Reads the configuration from the environment variables. Returns `None`
+    /// if neither `REVIEWD_TLS_CERT` nor `REVIEWD_TLS_KEY` is set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if only one of `REVIEWD_TLS_CERT` and
+    /// `REVIEWD_TLS_KEY`, or of `REVIEWD_TLS_CLIENT_CA` and
+    /// `REVIEWD_INGEST_ADDR`, is set, if `REVIEWD_REQUIRE_TOKEN` is set without
+    /// HTTPS, or if any of the other variables is invalid.
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let require_token = match env::var("REVIEWD_REQUIRE_TOKEN") {
+            Ok(v) => match v.as_str() {
+                "true" | "1" => true,
+                "false" | "0" => false,
+                _ => return Err(anyhow!("invalid REVIEWD_REQUIRE_TOKEN: {}", v)),
+            },
+            Err(_) => false,
+        };
+        let (cert, key) = match (env::var("REVIEWD_TLS_CERT"), env::var("REVIEWD_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            (Err(_), Err(_)) if require_token => {
+                return Err(anyhow!(
+                    "REVIEWD_REQUIRE_TOKEN requires REVIEWD_TLS_CERT and REVIEWD_TLS_KEY"
+                ))
+            }
+            (Err(_), Err(_)) => return Ok(None),
+            _ => {
+                return Err(anyhow!(
+                    "REVIEWD_TLS_CERT and REVIEWD_TLS_KEY must be set together"
+                ))
+            }
+        };
+        let ingest = match (
+            env::var("REVIEWD_TLS_CLIENT_CA"),
+            env::var("REVIEWD_INGEST_ADDR"),
+        ) {
+            (Ok(client_ca), Ok(addr)) => Some(IngestConfig {
+                addr: addr
+                    .parse()
+                    .with_context(|| format!("invalid REVIEWD_INGEST_ADDR: {}", addr))?,
+                client_ca,
+            }),
+            (Err(_), Err(_)) => None,
+            _ => {
+                return Err(anyhow!(
+                    "REVIEWD_TLS_CLIENT_CA and REVIEWD_INGEST_ADDR must be set together"
+                ))
+            }
+        };
+        let internal_addr = match env::var("REVIEWD_INTERNAL_ADDR") {
+            Ok(v) => v
+                .parse()
+                .with_context(|| format!("invalid REVIEWD_INTERNAL_ADDR: {}", v))?,
+            Err(_) => SocketAddr::from(([127, 0, 0, 1], 0)),
+        };
+        let reload_interval = match env::var("REVIEWD_TLS_RELOAD_INTERVAL") {
+            Ok(v) => v
+                .parse::<u64>()
+                .with_context(|| format!("invalid REVIEWD_TLS_RELOAD_INTERVAL: {}", v))?,
+            Err(_) => 60,
+        };
+
+        Ok(Some(Self {
+            cert,
+            key,
+            ingest,
+            internal_addr,
+            reload_interval: Duration::from_secs(reload_interval),
+            require_token,
+        }))
+    }
+
+    /// The address of the plain HTTP listener for the periodic Kafka task.
+    pub(crate) fn internal_addr(&self) -> SocketAddr {
+        self.internal_addr
+    }
+
+    pub(crate) fn reload_interval(&self) -> Duration {
+        self.reload_interval
+    }
+
+    /// Whether the API requests on `REVIEWD_ADDR` need a token.
+    pub(crate) fn require_token(&self) -> bool {
+        self.require_token
+    }
+
+    /// Loads the certificate and its private key.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either file cannot be read or is invalid.
+    pub(crate) fn load_certificate(&self) -> Result<Arc<CertificateResolver>> {
+        let key = load_certified_key(&self.cert, &self.key)?;
+        Ok(Arc::new(CertificateResolver {
+            cert: self.cert.clone(),
+            key: self.key.clone(),
+            current: RwLock::new(key),
+            modified: Mutex::new(modified_times(&self.cert, &self.key)),
+        }))
+    }
+
+    /// Returns the configuration of the listener at `REVIEWD_ADDR`, which does
+    /// not ask for client certificates.
+    pub(crate) fn server_config(resolver: &Arc<CertificateResolver>) -> ServerConfig {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = resolver.clone();
+        config
+    }
+
+    /// Returns the address and the configuration of the ingest listener, if
+    /// any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `REVIEWD_TLS_CLIENT_CA` cannot be read or has no
+    /// valid certificate.
+    pub(crate) fn ingest_config(
+        &self,
+        resolver: &Arc<CertificateResolver>,
+    ) -> Result<Option<(SocketAddr, ServerConfig)>> {
+        let ingest = match &self.ingest {
+            Some(ingest) => ingest,
+            None => return Ok(None),
+        };
+        let mut roots = RootCertStore::empty();
+        let (valid, _) = roots
+            .add_pem_file(&mut open(&ingest.client_ca)?)
+            .map_err(|_| anyhow!("invalid client CA certificate: {}", ingest.client_ca))?;
+        if valid == 0 {
+            return Err(anyhow!("no client CA certificate in {}", ingest.client_ca));
+        }
+        let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(roots));
+        config.cert_resolver = resolver.clone();
+        Ok(Some((ingest.addr, config)))
+    }
+}
+
+/// Serves the certificate loaded last, so that a new certificate is used for
+/// new connections while the existing ones are kept.
+pub(crate) struct CertificateResolver {
+    cert: String,
+    key: String,
+    current: RwLock<CertifiedKey>,
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertificateResolver {
+    /// Loads the certificate again if its files have changed, or if `force` is
+    /// set. Returns `true` if it was loaded.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if either file cannot be read or is invalid. The
+    /// certificate loaded before is kept in that case.
+    fn reload(&self, force: bool) -> Result<bool> {
+        let modified = modified_times(&self.cert, &self.key);
+        {
+            let mut last = self
+                .modified
+                .lock()
+                .map_err(|_| anyhow!("certificate lock poisoned"))?;
+            if !force && *last == modified {
+                return Ok(false);
+            }
+            // Not retried until the files change again, even if loading fails
+            // below.
+            *last = modified;
+        }
+        let key = load_certified_key(&self.cert, &self.key)?;
+        let mut current = self
+            .current
+            .write()
+            .map_err(|_| anyhow!("certificate lock poisoned"))?;
+        *current = key;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        self.current.read().ok().map(|key| key.clone())
+    }
+}
+
+/// Loads the certificate again on SIGHUP, or when its files change. The files
+/// are checked every `interval`, unless it is zero.
+pub(crate) fn reload_certificate(resolver: &CertificateResolver, interval: Duration) {
+    let hangup = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::SIGHUP, hangup.clone()) {
+        error!("Failed to handle SIGHUP: {}", e);
+    }
+    info!(
+        "Starting TLS certificate reload with time interval {} second(s)",
+        interval.as_secs()
+    );
+    let mut last_check = Instant::now();
+    loop {
+        std::thread::sleep(SIGNAL_CHECK_INTERVAL);
+        let force = hangup.swap(false, Ordering::Relaxed);
+        let due = interval != Duration::from_secs(0) && last_check.elapsed() >= interval;
+        if !force && !due {
+            continue;
+        }
+        last_check = Instant::now();
+        match resolver.reload(force) {
+            Ok(true) => info!("Reloaded the TLS certificate from {}", resolver.cert),
+            Ok(false) => {}
+            Err(e) => error!("Failed to reload the TLS certificate: {:#}", e),
+        }
+    }
+}
+
+/// Keeps the detector ingest endpoints to the listeners that authenticate
+/// their clients.
+#[derive(Clone, Default)]
+pub(crate) struct IngestGuard {
+    /// The local addresses of the listeners allowed to serve the ingest
+    /// endpoints. If `None`, every listener is.
+    listeners: Option<Vec<SocketAddr>>,
+}
+
+impl IngestGuard {
+    pub(crate) fn new(listeners: Vec<SocketAddr>) -> Self {
+        Self {
+            listeners: Some(listeners),
+        }
+    }
+
+    /// Returns `true` if `req` may be served by the listener it came in.
+    pub(crate) fn allows(&self, req: &ServiceRequest) -> bool {
+        let listeners = match &self.listeners {
+            Some(listeners) => listeners,
+            None => return true,
+        };
+        !is_ingest(req.method(), req.path()) || listeners.contains(&req.app_config().local_addr())
+    }
+}
+
+/// Returns `true` for the endpoints detectors send their results to, under
+/// `/api/v1` or the deprecated `/api`.
+fn is_ingest(method: &Method, path: &str) -> bool {
+    let path = match path
+        .strip_prefix("/api/v1")
+        .or_else(|| path.strip_prefix("/api"))
+    {
+        Some(path) => path,
+        None => return false,
+    };
+    match (method, path) {
+        (&Method::PUT, "/cluster")
+        | (&Method::PUT, "/description")
+        | (&Method::PUT, "/event")
+        | (&Method::PUT, "/kafka_metadata")
+        | (&Method::PUT, "/outlier")
+        | (&Method::DELETE, "/outlier") => true,
+        _ => false,
+    }
+}
+
+fn load_certified_key(cert: &str, key: &str) -> Result<CertifiedKey> {
+    let certs =
+        pemfile::certs(&mut open(cert)?).map_err(|_| anyhow!("invalid certificate: {}", cert))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in {}", cert));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(key)?)
+        .map_err(|_| anyhow!("invalid private key: {}", key))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(key)?)
+            .map_err(|_| anyhow!("invalid private key: {}", key))?;
+    }
+    let private_key = keys
+        .first()
+        .ok_or_else(|| anyhow!("no private key in {}", key))?;
+    let signing_key = sign::any_supported_type(private_key)
+        .map_err(|_| anyhow!("unsupported private key: {}", key))?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+fn modified_times(cert: &str, key: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+    (modified(cert), modified(key))
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    Ok(BufReader::new(
+        File::open(path).with_context(|| format!("cannot open {}", path))?,
+    ))
+}
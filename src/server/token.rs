@@ -0,0 +1,90 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+use log::{error, info};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::database::{hash_api_token, load_api_token_hashes, Conn, Error, Pool};
+
+/// How often the tokens are read from the database, which is how long a new
+/// or deleted token takes to be accepted or rejected.
+const TOKEN_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Requires a token created by `review create-token` on the API requests that
+/// come in on `REVIEWD_ADDR`.
+#[derive(Clone, Default)]
+pub(crate) struct TokenGuard {
+    /// The hashes of the valid tokens. If `None`, no token is required.
+    hashes: Option<Arc<RwLock<HashSet<Vec<u8>>>>>,
+    /// The local addresses of the listeners that need no token.
+    exempt: Vec<SocketAddr>,
+}
+
+impl TokenGuard {
+    pub(crate) fn new(exempt: Vec<SocketAddr>) -> Self {
+        Self {
+            hashes: Some(Arc::default()),
+            exempt,
+        }
+    }
+
+    /// Returns `true` if `req` has a valid token, or needs none.
+    pub(crate) fn allows(&self, req: &ServiceRequest) -> bool {
+        let hashes = match &self.hashes {
+            Some(hashes) => hashes,
+            None => return true,
+        };
+        if !req.path().starts_with("/api/") || self.exempt.contains(&req.app_config().local_addr())
+        {
+            return true;
+        }
+        let token = match req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+        {
+            Some(token) => token.trim(),
+            None => return false,
+        };
+        hashes
+            .read()
+            .map_or(false, |hashes| hashes.contains(&hash_api_token(token)))
+    }
+
+    /// Reads the tokens from the database.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database fails. The tokens read before are
+    /// kept in that case.
+    pub(crate) fn reload(&self, conn: &Conn) -> Result<(), Error> {
+        if let Some(hashes) = &self.hashes {
+            let loaded = load_api_token_hashes(conn)?;
+            if let Ok(mut hashes) = hashes.write() {
+                *hashes = loaded;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the tokens from the database every `TOKEN_RELOAD_INTERVAL`.
+pub(crate) fn periodically_reload_tokens(pool: &Pool, guard: &TokenGuard) {
+    info!(
+        "Starting API token reload with time interval {} second(s)",
+        TOKEN_RELOAD_INTERVAL.as_secs()
+    );
+    loop {
+        std::thread::sleep(TOKEN_RELOAD_INTERVAL);
+        if let Err(e) = pool
+            .get()
+            .map_err(Error::from)
+            .and_then(|conn| guard.reload(&conn))
+        {
+            error!("Failed to reload API tokens: {}", e);
+        }
+    }
+}